@@ -0,0 +1,903 @@
+//! Generate the build-time configuration from `counter-config.toml`.
+//!
+//! Every setting is a key of the file, named like the constant it becomes in
+//! the `build_config` module in lower case (e.g. `wifi_ssid` for
+//! `WIFI_SSID`). An environment variable named like the constant overrides
+//! the file, e.g. for secrets in CI. Missing required settings, unknown keys
+//! and invalid values fail the build with a message pointing at the file,
+//! instead of an error in the middle of the firmware.
+//!
+//! The values are checked here and become constants of their type (see
+//! [`Kind`]), e.g. a `ZeroStyle` or a `DailyPeriod`, so that the firmware
+//! doesn't parse them again. Only the settings used by the enabled features
+//! become constants, the others are accepted and ignored, so that the same
+//! file works for builds with different features.
+
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::ErrorKind,
+    iter::Peekable,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process,
+    str::Chars,
+};
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+
+/// Name of the configuration file, next to `Cargo.toml`
+const CONFIG_FILE: &str = "counter-config.toml";
+
+/// All settings, in lower case
+const SETTINGS: &[&str] = &[
+    "wifi_ssid",
+    "wifi_pass",
+    "spaceapi_sensor_endpoint",
+    "spaceapi_sensor_extra_endpoints",
+    "spaceapi_sensor_authorization",
+    "spaceapi_sensor_method",
+    "spaceapi_sensor_content_type",
+    "spaceapi_sensor_payload_template",
+    "spaceapi_sensor_name",
+    "spaceapi_sensor_location",
+    "spaceapi_sensor_description",
+    "spaceapi_state_endpoint",
+    "spaceapi_url",
+    "coap_sensor_endpoint",
+    "http_proxy",
+    "http_proxy_authorization",
+    "sync_websocket_url",
+    "syslog_server",
+    "webhook_url",
+    "webhook_thresholds",
+    "display_update_policy",
+    "count_transition",
+    "count_overflow",
+    "boot_animation",
+    "boot_version",
+    "zero_style",
+    "left_tube_encoding",
+    "right_tube_encoding",
+    "left_tube_strike_delay",
+    "right_tube_strike_delay",
+    "debounce_time",
+    "long_press_duration",
+    "double_press_window",
+    "auto_repeat_delay",
+    "auto_repeat_rate",
+    "io_expander",
+    "io_expander_address",
+    "motion_wake_duration",
+    "ntp_server",
+    "time_zone_offset",
+    "time_zone_dst",
+    "quiet_hours",
+    "dimming_hours",
+    "winter_dimming_hours",
+    "dimmed_brightness",
+    "day_brightness",
+    "tube_brightness",
+    "clock_mode_hours",
+    "sync_lag_threshold",
+    "rules",
+];
+
+/// Maximum number of `rules`, like `crate::rules::MAX_RULES`
+const MAX_RULES: usize = 8;
+
+/// Return whether the setting is used with the enabled features. Unused
+/// settings don't become constants.
+fn used(setting: &str) -> bool {
+    let http = !enabled("coap");
+    match setting {
+        "spaceapi_sensor_endpoint"
+        | "spaceapi_sensor_extra_endpoints"
+        | "spaceapi_sensor_authorization"
+        | "spaceapi_sensor_method"
+        | "spaceapi_sensor_content_type"
+        | "spaceapi_sensor_payload_template"
+        | "http_proxy"
+        | "http_proxy_authorization" => http,
+        "spaceapi_sensor_name" | "spaceapi_sensor_location" | "spaceapi_sensor_description" => {
+            http && enabled("spaceapi-v14")
+        }
+        "spaceapi_state_endpoint" => http && enabled("space-state"),
+        "spaceapi_url" => http && enabled("fetch-count"),
+        "coap_sensor_endpoint" => enabled("coap"),
+        "sync_websocket_url" => enabled("websocket"),
+        "syslog_server" => enabled("syslog"),
+        "webhook_url" | "webhook_thresholds" => enabled("webhook"),
+        "long_press_duration" => {
+            enabled("space-state") || enabled("clock-mode") || enabled("resync")
+        }
+        "double_press_window" => enabled("double-press"),
+        "auto_repeat_delay" | "auto_repeat_rate" => enabled("auto-repeat"),
+        "io_expander" | "io_expander_address" => enabled("io-expander"),
+        "motion_wake_duration" => enabled("motion-wake"),
+        "ntp_server" | "time_zone_offset" | "time_zone_dst" => enabled("clock"),
+        "quiet_hours" => enabled("quiet-hours"),
+        "dimming_hours"
+        | "winter_dimming_hours"
+        | "dimmed_brightness"
+        | "day_brightness"
+        | "tube_brightness" => enabled("dimming"),
+        "clock_mode_hours" => enabled("clock-mode"),
+        "rules" => enabled("rules"),
+        _ => true,
+    }
+}
+
+/// Return why the setting is required with the enabled features, or `None`
+/// if it is optional. Required settings become a constant of their type,
+/// all others an `Option` of it.
+fn requirement(setting: &str) -> Option<&'static str> {
+    let required_by = |feature: &str, reason| enabled(feature).then_some(reason);
+    match setting {
+        "wifi_ssid" | "wifi_pass" => {
+            (!enabled("config-store")).then_some("without the `config-store` feature")
+        }
+        "spaceapi_sensor_endpoint" => {
+            (!enabled("config-store")).then_some("without the `config-store` feature")
+        }
+        "coap_sensor_endpoint" => required_by("coap", "by the `coap` feature"),
+        "spaceapi_state_endpoint" => required_by("space-state", "by the `space-state` feature"),
+        "spaceapi_url" => required_by("fetch-count", "by the `fetch-count` feature"),
+        "sync_websocket_url" => required_by("websocket", "by the `websocket` feature"),
+        "syslog_server" => required_by("syslog", "by the `syslog` feature"),
+        "webhook_url" => required_by("webhook", "by the `webhook` feature"),
+        _ => None,
+    }
+}
+
+/// How the value of a setting is checked, and the type of its constant.
+enum Kind {
+    /// Any string, a `&str`
+    Text,
+    /// A string of up to the number of bytes, a `&str`
+    BoundedText(usize),
+    /// A URL with the scheme, a `Url`. Without a port in the URL, the
+    /// default one is used.
+    Url(&'static str, u16),
+    /// URLs with the scheme separated by spaces, a `&[Url]`
+    Urls(&'static str, u16),
+    /// A URL with the scheme of up to the number of bytes, that the firmware
+    /// parses itself since it can also be changed at runtime, a `&str`
+    RuntimeUrl(&'static str, usize),
+    /// `host[:port]`, a `(&str, Option<u16>)`
+    HostPort,
+    /// One of the names (ignoring the case), each with the expression of the
+    /// value, of the type
+    Enum(&'static str, Vec<(&'static str, &'static str)>),
+    /// A decimal integer of the type, in the range
+    Integer(&'static str, RangeInclusive<i64>),
+    /// A hexadecimal integer of up to the maximum, with or without `0x`, a
+    /// `u8`
+    Hex(u8),
+    /// Decimal integers of up to the maximum, separated by spaces, a `&[u8]`
+    Integers(u8),
+    /// The number of percentages, separated by commas, a `[u8; N]`
+    Percentages(usize),
+    /// A period of the day in the format `HH:MM-HH:MM`, a `DailyPeriod`
+    Period,
+    /// 11 hexadecimal digits: the input values for the digits 0-9, followed
+    /// by the one that turns the tube off, a `SymbolMap`
+    Encoding,
+    /// A JSON array of automation rules (see `src/rules.rs`), a
+    /// `&[crate::rules::Rule]`
+    Rules,
+}
+
+/// Return the kind of the setting.
+fn kind(setting: &str) -> Kind {
+    let variants = |names: &[(&'static str, &'static str)]| names.to_vec();
+    match setting {
+        // Like the fields of the stored `Settings`
+        "wifi_ssid" => Kind::BoundedText(32),
+        "wifi_pass" => Kind::BoundedText(64),
+        "spaceapi_sensor_endpoint" => Kind::RuntimeUrl("http", 128),
+        "spaceapi_sensor_extra_endpoints" => Kind::Urls("http", 80),
+        "spaceapi_state_endpoint" | "spaceapi_url" | "http_proxy" | "webhook_url" => {
+            Kind::Url("http", 80)
+        }
+        "coap_sensor_endpoint" => Kind::Url("coap", 5683),
+        "sync_websocket_url" => Kind::Url("ws", 80),
+        "syslog_server" => Kind::HostPort,
+        "spaceapi_sensor_method" => Kind::Enum(
+            "crate::http::UpdateMethod",
+            variants(&[("put", "Put"), ("post", "Post")]),
+        ),
+        "display_update_policy" => Kind::Enum(
+            "crate::experiment::DisplayPolicy",
+            variants(&[("optimistic", "Optimistic"), ("confirmed", "Confirmed")]),
+        ),
+        "count_transition" => {
+            let mut transitions =
+                variants(&[("cut", "Cut"), ("roll", "Roll"), ("slot", "SlotMachine")]);
+            if enabled("dimming") {
+                transitions.push(("fade", "Fade"));
+            }
+            Kind::Enum("crate::display::Transition", transitions)
+        }
+        "count_overflow" => Kind::Enum(
+            "crate::display::Overflow",
+            variants(&[
+                ("cap", "Cap"),
+                ("alternate", "Alternate"),
+                ("blink", "Blink"),
+            ]),
+        ),
+        "boot_animation" => Kind::Enum(
+            "crate::config::BootAnimation",
+            variants(&[
+                ("sweep", "Sweep"),
+                ("slot", "SlotMachine"),
+                ("version", "Version"),
+            ]),
+        ),
+        // Number of parts of the version shown
+        "boot_version" => Kind::Enum(
+            "usize",
+            variants(&[("short", "2"), ("full", "3"), ("none", "0")]),
+        ),
+        "zero_style" => Kind::Enum(
+            "crate::display::ZeroStyle",
+            variants(&[
+                ("blank", "Blank"),
+                ("zeroes", "Zeroes"),
+                ("padded", "Padded"),
+            ]),
+        ),
+        "io_expander" => Kind::Enum(
+            "crate::io_expander::Chip",
+            variants(&[("pcf8574", "Pcf8574"), ("mcp23017", "Mcp23017")]),
+        ),
+        // Whether the European summer time rule applies
+        "time_zone_dst" => Kind::Enum(
+            "bool",
+            variants(&[
+                ("eu", "true"),
+                ("none", "false"),
+                ("true", "true"),
+                ("false", "false"),
+            ]),
+        ),
+        "left_tube_encoding" | "right_tube_encoding" => Kind::Encoding,
+        // Milliseconds, the debounce time at most `MAX_DEBOUNCE_TIME`
+        "debounce_time" => Kind::Integer("u64", 0..=500),
+        // Milliseconds, at most `MAX_STRIKE_DELAY`
+        "left_tube_strike_delay" | "right_tube_strike_delay" => Kind::Integer("u64", 0..=100),
+        "long_press_duration" | "double_press_window" | "auto_repeat_delay" => {
+            Kind::Integer("u64", 0..=60_000)
+        }
+        // Presses per second
+        "auto_repeat_rate" => Kind::Integer("u64", 1..=20),
+        // 7 bit I2C address
+        "io_expander_address" => Kind::Hex(0x7F),
+        // Seconds
+        "motion_wake_duration" => Kind::Integer("u64", 1..=3600),
+        // Minutes east of UTC
+        "time_zone_offset" => Kind::Integer("i64", -12 * 60..=14 * 60),
+        "quiet_hours" | "dimming_hours" | "winter_dimming_hours" | "clock_mode_hours" => {
+            Kind::Period
+        }
+        "dimmed_brightness" | "day_brightness" => Kind::Integer("u8", 0..=100),
+        // Left and right tube
+        "tube_brightness" => Kind::Percentages(2),
+        "webhook_thresholds" => Kind::Integers(u8::MAX),
+        // Seconds
+        "sync_lag_threshold" => Kind::Integer("u64", 1..=u32::MAX.into()),
+        "rules" => Kind::Rules,
+        _ => Kind::Text,
+    }
+}
+
+impl Kind {
+    /// Return the type of the constant.
+    fn type_name(&self) -> String {
+        match self {
+            Kind::Text | Kind::BoundedText(_) | Kind::RuntimeUrl(..) => "&str".to_owned(),
+            Kind::Url(..) => "crate::config::Url".to_owned(),
+            Kind::Urls(..) => "&[crate::config::Url]".to_owned(),
+            Kind::HostPort => "(&str, Option<u16>)".to_owned(),
+            Kind::Enum(ty, _) | Kind::Integer(ty, _) => ty.to_string(),
+            Kind::Hex(_) => "u8".to_owned(),
+            Kind::Integers(..) => "&[u8]".to_owned(),
+            Kind::Percentages(count) => format!("[u8; {}]", count),
+            Kind::Period => "crate::clock::DailyPeriod".to_owned(),
+            Kind::Encoding => "crate::nixie::SymbolMap".to_owned(),
+            Kind::Rules => "&[crate::rules::Rule]".to_owned(),
+        }
+    }
+
+    /// Check the value, and return the expression it becomes, or what is
+    /// expected instead.
+    fn expression(&self, value: &str) -> Result<String, String> {
+        match self {
+            Kind::Text => Ok(format!("{:?}", value)),
+            Kind::BoundedText(max_len) => match value.len() <= *max_len {
+                true => Ok(format!("{:?}", value)),
+                false => Err(format!("at most {} bytes long", max_len)),
+            },
+            Kind::Url(scheme, default_port) => url(value, scheme, *default_port)
+                .ok_or_else(|| format!("a URL like `{}://host[:port]/path`", scheme)),
+            Kind::Urls(scheme, default_port) => value
+                .split_whitespace()
+                .map(|value| url(value, scheme, *default_port))
+                .collect::<Option<Vec<_>>>()
+                .map(|urls| format!("&[{}]", urls.join(", ")))
+                .ok_or_else(|| {
+                    format!(
+                        "URLs like `{}://host[:port]/path`, separated by spaces",
+                        scheme
+                    )
+                }),
+            Kind::RuntimeUrl(scheme, max_len) => url(value, scheme, 0)
+                .filter(|_| value.len() <= *max_len)
+                .map(|_| format!("{:?}", value))
+                .ok_or_else(|| {
+                    format!(
+                        "a URL like `{}://host[:port]/path` of at most {} bytes",
+                        scheme, max_len
+                    )
+                }),
+            Kind::HostPort => {
+                let (host, port) = match value.rsplit_once(':') {
+                    Some((host, port)) => (host, port.parse::<u16>().ok().map(Some)),
+                    None => (value, Some(None)),
+                };
+                match port {
+                    Some(port) if !host.is_empty() => Ok(format!("({:?}, {:?})", host, port)),
+                    _ => Err("`host` or `host:port`".to_owned()),
+                }
+            }
+            Kind::Enum(ty, variants) => variants
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(value))
+                .map(|(_, variant)| match ty.starts_with("crate::") {
+                    true => format!("{}::{}", ty, variant),
+                    false => variant.to_string(),
+                })
+                .ok_or_else(|| {
+                    let names: Vec<_> = variants
+                        .iter()
+                        .map(|(name, _)| format!("`{}`", name))
+                        .collect();
+                    format!("one of {}", names.join(", "))
+                }),
+            Kind::Integer(_, range) => value
+                .parse()
+                .ok()
+                .filter(|value| range.contains(value))
+                .map(|value: i64| value.to_string())
+                .ok_or_else(|| format!("an integer from {} to {}", range.start(), range.end())),
+            Kind::Hex(max) => u8::from_str_radix(value.trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|value| value <= max)
+                .map(|value| format!("{:#04x}", value))
+                .ok_or_else(|| format!("a hexadecimal number up to {:x}", max)),
+            Kind::Integers(max) => value
+                .split_whitespace()
+                .map(|value| value.parse().ok().filter(|value: &u8| value <= max))
+                .collect::<Option<Vec<_>>>()
+                .map(|values| format!("&{:?}", values))
+                .ok_or_else(|| format!("integers up to {}, separated by spaces", max)),
+            Kind::Percentages(count) => value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|value| !value.is_empty())
+                .map(|value| value.parse().ok().filter(|value: &u8| *value <= 100))
+                .collect::<Option<Vec<_>>>()
+                .filter(|values| values.len() == *count)
+                .map(|values| format!("{:?}", values))
+                .ok_or_else(|| format!("{} percentages (0-100), separated by commas", count)),
+            Kind::Period => {
+                let period = value
+                    .split_once('-')
+                    .and_then(|(start, end)| Some((time_of_day(start)?, time_of_day(end)?)));
+                match period {
+                    Some((start, end)) => Ok(format!(
+                        "crate::clock::DailyPeriod::new({}, {})",
+                        start, end
+                    )),
+                    None => Err("a period like `22:00-07:00`".to_owned()),
+                }
+            }
+            Kind::Encoding => {
+                let values = value
+                    .chars()
+                    .map(|c| c.to_digit(16))
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|values| values.len() == 11);
+                match values {
+                    Some(values) => Ok(format!(
+                        "crate::nixie::SymbolMap {{ cathodes: {:?}, blank: {} }}",
+                        &values[..10],
+                        values[10]
+                    )),
+                    None => Err("11 hexadecimal digits, like `0123456789F`".to_owned()),
+                }
+            }
+            Kind::Rules => {
+                let rules = match Json::parse(value) {
+                    Some(Json::Array(rules)) => rules,
+                    _ => return Err("a JSON array of rules".to_owned()),
+                };
+                if rules.len() > MAX_RULES {
+                    return Err(format!("at most {} rules", MAX_RULES));
+                }
+                let rules = rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| {
+                        rule_expression(rule, i + 1)
+                            .map_err(|e| format!("valid rules (rule {}: {})", i + 1, e))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("&[{}]", rules.join(", ")))
+            }
+        }
+    }
+}
+
+/// The operators of a rule condition, each with its `Comparison`. Longer
+/// operators come first, since `<` is a prefix of `<=`.
+const COMPARISONS: [(&str, &str); 6] = [
+    ("<=", "LessOrEqual"),
+    (">=", "GreaterOrEqual"),
+    ("==", "Equal"),
+    ("!=", "NotEqual"),
+    ("<", "Less"),
+    (">", "Greater"),
+];
+
+/// Check the automation rule with the (1-based) number, and return the
+/// expression of its `Rule`, or what is wrong with it.
+fn rule_expression(rule: &Json, number: usize) -> Result<String, String> {
+    const FIELDS: &[&str] = &["name", "when", "for", "during", "after", "then"];
+    let Json::Object(fields) = rule else {
+        return Err("a JSON object".to_owned());
+    };
+    if let Some((key, _)) = fields
+        .iter()
+        .find(|(key, _)| !FIELDS.contains(&key.as_str()))
+    {
+        return Err(format!("unknown field `{}`", key));
+    }
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    };
+    let text = |name: &str| match field(name) {
+        Some(Json::String(text)) => Ok(Some(text.as_str())),
+        Some(_) => Err(format!("`{}` must be a string", name)),
+        None => Ok(None),
+    };
+
+    let condition = text("when")?.ok_or("missing `when`, like `count > 10`")?;
+    let (comparison, value) = condition
+        .trim()
+        .strip_prefix("count")
+        .and_then(|condition| {
+            let condition = condition.trim_start();
+            COMPARISONS.into_iter().find_map(|(operator, comparison)| {
+                let value = condition
+                    .strip_prefix(operator)?
+                    .trim()
+                    .parse::<u8>()
+                    .ok()?;
+                Some((comparison, value))
+            })
+        })
+        .ok_or("`when` must be like `count > 10`")?;
+
+    let hold = match field("for") {
+        Some(Json::Number(seconds)) if *seconds <= 86_400 => *seconds,
+        Some(_) => return Err("`for` must be a number of seconds up to 86400".to_owned()),
+        None => 0,
+    };
+
+    let period = match (text("during")?, text("after")?) {
+        (Some(_), Some(_)) => return Err("only one of `during` and `after`".to_owned()),
+        (Some(during), None) => Some(
+            // Like `quiet_hours`
+            Kind::Period
+                .expression(during)
+                .map_err(|expected| format!("`during` must be {}", expected))?,
+        ),
+        // Until midnight
+        (None, Some(after)) => Some(
+            time_of_day(after)
+                .map(|start| format!("crate::clock::DailyPeriod::new({}, 0)", start))
+                .ok_or("`after` must be a time like `22:00`")?,
+        ),
+        (None, None) => None,
+    };
+    let period = match period {
+        Some(period) => format!("Some({})", period),
+        None => "None".to_owned(),
+    };
+
+    let action = match text("then")? {
+        Some("webhook") if enabled("webhook") => "Webhook",
+        Some("webhook") => return Err("`webhook` needs the `webhook` feature".to_owned()),
+        Some("blank") => "Blank",
+        _ => return Err("`then` must be `webhook` or `blank`".to_owned()),
+    };
+
+    // The webhook payload has room for it, without escaping
+    let name = match text("name")? {
+        Some(name)
+            if name.len() <= 32
+                && !name.contains(|c: char| c == '"' || c == '\\' || c.is_control()) =>
+        {
+            name.to_owned()
+        }
+        Some(_) => {
+            return Err("`name` must be at most 32 bytes, without quotes or backslashes".to_owned())
+        }
+        None => format!("rule {}", number),
+    };
+    Ok(format!(
+        "crate::rules::Rule {{ name: {:?}, comparison: crate::rules::Comparison::{}, \
+         value: {}, hold: embassy_time::Duration::from_secs({}), period: {}, \
+         action: crate::rules::Action::{} }}",
+        name, comparison, value, hold, period, action
+    ))
+}
+
+/// Split a `<scheme>://host[:port]/path` URL, and return the expression of
+/// its `Url`.
+fn url(url: &str, scheme: &str, default_port: u16) -> Option<String> {
+    let rest = url.strip_prefix(scheme)?.strip_prefix("://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, default_port),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "crate::config::Url {{ url: {:?}, host: {:?}, port: {}, path: {:?} }}",
+        url, host, port, path
+    ))
+}
+
+/// Parse a `HH:MM` time into minutes since midnight.
+fn time_of_day(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// A JSON value, as far as the `rules` need it: numbers are non-negative
+/// integers.
+enum Json {
+    /// `true`, `false` or `null`, which no field of a rule takes
+    Literal,
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    /// The fields in their order
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parse a complete JSON document, or return `None` if it is invalid.
+    fn parse(json: &str) -> Option<Self> {
+        let mut chars = json.chars().peekable();
+        let value = Self::parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        chars.peek().is_none().then_some(value)
+    }
+
+    fn parse_value(chars: &mut Peekable<Chars>) -> Option<Self> {
+        skip_whitespace(chars);
+        match chars.peek()? {
+            '{' => {
+                chars.next();
+                let mut fields = Vec::new();
+                skip_whitespace(chars);
+                if chars.next_if_eq(&'}').is_some() {
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    skip_whitespace(chars);
+                    let Json::String(key) = Self::parse_value(chars)? else {
+                        return None;
+                    };
+                    skip_whitespace(chars);
+                    chars.next_if_eq(&':')?;
+                    fields.push((key, Self::parse_value(chars)?));
+                    skip_whitespace(chars);
+                    match chars.next()? {
+                        ',' => continue,
+                        '}' => return Some(Json::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            '[' => {
+                chars.next();
+                let mut items = Vec::new();
+                skip_whitespace(chars);
+                if chars.next_if_eq(&']').is_some() {
+                    return Some(Json::Array(items));
+                }
+                loop {
+                    items.push(Self::parse_value(chars)?);
+                    skip_whitespace(chars);
+                    match chars.next()? {
+                        ',' => continue,
+                        ']' => return Some(Json::Array(items)),
+                        _ => return None,
+                    }
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => return Some(Json::String(text)),
+                        '\\' => text.push(match chars.next()? {
+                            'n' => '\n',
+                            't' => '\t',
+                            'u' => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                            }
+                            escaped @ ('"' | '\\' | '/') => escaped,
+                            _ => return None,
+                        }),
+                        c => text.push(c),
+                    }
+                }
+            }
+            '0'..='9' => {
+                let mut number = String::new();
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    number.push(digit);
+                }
+                number.parse().ok().map(Json::Number)
+            }
+            _ => {
+                let word: String =
+                    std::iter::from_fn(|| chars.next_if(char::is_ascii_alphabetic)).collect();
+                matches!(word.as_str(), "true" | "false" | "null").then_some(Json::Literal)
+            }
+        }
+    }
+}
+
+/// Skip the whitespace before the next JSON token.
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+/// Return whether the cargo feature is enabled.
+fn enabled(feature: &str) -> bool {
+    let name = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+    env::var_os(name).is_some()
+}
+
+/// A value in the configuration file. Numbers and booleans are passed on as
+/// they are written, lists separated by spaces.
+struct Value(Result<String, &'static str>);
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, a number, a boolean or a list of them")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Value, E> {
+        Ok(Value(Ok(value.to_owned())))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Value, E> {
+        Ok(Value(Ok(value.to_string())))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Value, E> {
+        Ok(Value(Ok(value.to_string())))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Value, E> {
+        Ok(Value(Ok(value.to_string())))
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Value, E> {
+        Ok(Value(Ok(value.to_string())))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        while map
+            .next_entry::<de::IgnoredAny, de::IgnoredAny>()?
+            .is_some()
+        {}
+        Ok(Value(Err("a table")))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(Value(item)) = seq.next_element()? {
+            match item {
+                Ok(item) => items.push(item),
+                Err(_) => return Ok(Value(Err("a nested list or table"))),
+            }
+        }
+        Ok(Value(Ok(items.join(" "))))
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let path = manifest_dir.join(CONFIG_FILE);
+    println!("cargo:rerun-if-changed=build.rs");
+    // Watching a missing file would rerun the build every time. A file
+    // created later is only picked up once build.rs changes, or after
+    // `cargo clean`.
+    if path.exists() {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+
+    let config = read_config(&path)?;
+    let found = config.is_some();
+    let mut values = config.unwrap_or_default();
+    for setting in SETTINGS {
+        let name = setting.to_uppercase();
+        println!("cargo:rerun-if-env-changed={}", name);
+        if let Ok(value) = env::var(&name) {
+            values.insert(setting.to_string(), Value(Ok(value)));
+        }
+    }
+
+    let mut errors: Vec<_> = values
+        .keys()
+        .filter(|key| !SETTINGS.contains(&key.as_str()))
+        .map(|key| format!("unknown setting `{}`", key))
+        .collect();
+    let mut module = String::new();
+    for setting in SETTINGS {
+        let name = setting.to_uppercase();
+        let value = match values.remove(*setting) {
+            Some(Value(Ok(value))) => Some(value),
+            Some(Value(Err(kind))) => {
+                errors.push(format!(
+                    "`{}` must be a string, a number or a boolean, not {}",
+                    setting, kind
+                ));
+                continue;
+            }
+            None => None,
+        };
+        if !used(setting) {
+            continue;
+        }
+        let kind = kind(setting);
+        let expression = match value.as_deref().map(|value| kind.expression(value)) {
+            Some(Ok(expression)) => Some(expression),
+            // Secrets don't end up in the build log
+            Some(Err(expected))
+                if *setting == "wifi_pass" || setting.ends_with("_authorization") =>
+            {
+                errors.push(format!("`{}` must be {}", setting, expected));
+                continue;
+            }
+            Some(Err(expected)) => {
+                errors.push(format!(
+                    "`{}` must be {}, not {:?}",
+                    setting,
+                    expected,
+                    value.unwrap_or_default()
+                ));
+                continue;
+            }
+            None => None,
+        };
+        let ty = kind.type_name();
+        // Every variant can be selected, so none of them is dead code even if
+        // this configuration doesn't select it
+        if let Kind::Enum(ty, variants) = &kind {
+            if ty.starts_with("crate::") {
+                let variants: Vec<_> = variants
+                    .iter()
+                    .map(|(_, variant)| format!("{}::{}", ty, variant))
+                    .collect();
+                module += &format!(
+                    "#[allow(dead_code)]\nconst {}_VARIANTS: &[{}] = &[{}];\n",
+                    name,
+                    ty,
+                    variants.join(", ")
+                );
+            }
+        }
+        // Likewise, every comparison and action can be used in a rule
+        if let Kind::Rules = kind {
+            let variants = |ty: &str, variants: &[&str]| {
+                let variants: Vec<_> = variants
+                    .iter()
+                    .map(|variant| format!("crate::rules::{}::{}", ty, variant))
+                    .collect();
+                format!("&[{}]", variants.join(", "))
+            };
+            module += &format!(
+                "#[allow(dead_code)]\nconst RULES_VARIANTS: \
+                 (&[crate::rules::Comparison], &[crate::rules::Action]) = ({}, {});\n",
+                variants("Comparison", &COMPARISONS.map(|(_, comparison)| comparison)),
+                variants("Action", &["Webhook", "Blank"]),
+            );
+        }
+        match (requirement(setting), expression) {
+            (Some(_), Some(expression)) => {
+                module += &format!("pub const {}: {} = {};\n", name, ty, expression);
+            }
+            (Some(reason), None) => {
+                errors.push(format!(
+                    "missing `{}`, which is required {}",
+                    setting, reason
+                ));
+            }
+            (None, Some(expression)) => {
+                module += &format!(
+                    "pub const {}: Option<{}> = Some({});\n",
+                    name, ty, expression
+                );
+            }
+            (None, None) => module += &format!("pub const {}: Option<{}> = None;\n", name, ty),
+        }
+    }
+    if !errors.is_empty() && !found {
+        return Err(format!(
+            "{} not found, copy counter-config.example.toml to it and fill in your settings:\n  {}",
+            CONFIG_FILE,
+            errors.join("\n  ")
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(format!(
+            "invalid {} (see counter-config.example.toml):\n  {}",
+            CONFIG_FILE,
+            errors.join("\n  ")
+        ));
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("build_config.rs"), module)
+        .map_err(|e| format!("could not write the build configuration: {}", e))
+}
+
+/// Read the configuration file, or return `None` if there is none. All
+/// settings can still come from the environment then.
+fn read_config(path: &Path) -> Result<Option<BTreeMap<String, Value>>, String> {
+    match fs::read_to_string(path) {
+        Ok(config) => basic_toml::from_str(&config)
+            .map(Some)
+            .map_err(|e| format!("invalid {}: {}", CONFIG_FILE, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("could not read {}: {}", CONFIG_FILE, e)),
+    }
+}
@@ -0,0 +1,657 @@
+//! WiFi provisioning through a Bluetooth LE GATT service.
+//!
+//! In provisioning mode, the counter advertises as [`DEVICE_NAME`] and offers
+//! a GATT service with one characteristic per setting and a "save"
+//! characteristic. Once a value is written to the latter, the settings are
+//! stored and the counter reboots into normal operation. Any generic BLE app
+//! (e.g. nRF Connect) can be used to write the characteristics.
+//!
+//! The GATT server is implemented directly on top of HCI. It only supports the
+//! default ATT MTU of 23 bytes, longer values are transferred with the long
+//! read and write procedures. Pairing is not supported.
+//!
+//! | Characteristic | UUID                                   | Properties  |
+//! |----------------|----------------------------------------|-------------|
+//! | Service        | `4e430001-1f9c-4a34-a4c6-2b8e5a0f6d21` |             |
+//! | WiFi SSID      | `4e430002-1f9c-4a34-a4c6-2b8e5a0f6d21` | read, write |
+//! | WiFi password  | `4e430003-1f9c-4a34-a4c6-2b8e5a0f6d21` | write       |
+//! | Endpoint URL   | `4e430004-1f9c-4a34-a4c6-2b8e5a0f6d21` | read, write |
+//! | Save           | `4e430005-1f9c-4a34-a4c6-2b8e5a0f6d21` | write       |
+
+use embedded_io_async::{Read, Write};
+use esp_wifi::ble::controller::BleConnector;
+
+use crate::{
+    changelog::{Changelog, Source},
+    provisioning,
+    settings::Settings,
+};
+
+/// Name in the advertising data
+const DEVICE_NAME: &str = "Nixie Counter";
+
+/// Advertising interval, in units of 0.625 ms (100 ms)
+const ADVERTISING_INTERVAL: u16 = 160;
+
+/// The only supported ATT MTU, the minimum for LE
+const ATT_MTU: usize = 23;
+
+/// Maximum length of a buffered long write
+const MAX_WRITE_LEN: usize = 128;
+
+const HCI_COMMAND: u8 = 0x01;
+const HCI_ACL: u8 = 0x02;
+const HCI_EVENT: u8 = 0x04;
+
+const HCI_RESET: u16 = 0x0C03;
+const HCI_LE_SET_ADVERTISING_PARAMETERS: u16 = 0x2006;
+const HCI_LE_SET_ADVERTISING_DATA: u16 = 0x2008;
+const HCI_LE_SET_SCAN_RESPONSE_DATA: u16 = 0x2009;
+const HCI_LE_SET_ADVERTISING_ENABLE: u16 = 0x200A;
+
+const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_COMMAND_COMPLETE: u8 = 0x0E;
+const EVENT_LE_META: u8 = 0x3E;
+const LE_CONNECTION_COMPLETE: u8 = 0x01;
+
+const L2CAP_CID_ATT: u16 = 0x0004;
+const L2CAP_CID_SMP: u16 = 0x0006;
+
+const SMP_PAIRING_REQUEST: u8 = 0x01;
+const SMP_PAIRING_FAILED: u8 = 0x05;
+const SMP_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+const ATT_ERROR_RSP: u8 = 0x01;
+const ATT_EXCHANGE_MTU_REQ: u8 = 0x02;
+const ATT_FIND_INFORMATION_REQ: u8 = 0x04;
+const ATT_FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+const ATT_READ_BY_TYPE_REQ: u8 = 0x08;
+const ATT_READ_REQ: u8 = 0x0A;
+const ATT_READ_BLOB_REQ: u8 = 0x0C;
+const ATT_READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+const ATT_WRITE_REQ: u8 = 0x12;
+const ATT_PREPARE_WRITE_REQ: u8 = 0x16;
+const ATT_EXECUTE_WRITE_REQ: u8 = 0x18;
+const ATT_WRITE_CMD: u8 = 0x52;
+
+const ATT_INVALID_HANDLE: u8 = 0x01;
+const ATT_READ_NOT_PERMITTED: u8 = 0x02;
+const ATT_WRITE_NOT_PERMITTED: u8 = 0x03;
+const ATT_INVALID_PDU: u8 = 0x04;
+const ATT_REQUEST_NOT_SUPPORTED: u8 = 0x06;
+const ATT_INVALID_OFFSET: u8 = 0x07;
+const ATT_ATTRIBUTE_NOT_FOUND: u8 = 0x0A;
+const ATT_INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0D;
+const ATT_UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+/// Application error: The submitted settings are invalid
+const ATT_INVALID_SETTINGS: u8 = 0x80;
+
+const UUID_PRIMARY_SERVICE: u16 = 0x2800;
+const UUID_CHARACTERISTIC: u16 = 0x2803;
+
+const PROPERTY_READ: u8 = 0x02;
+const PROPERTY_WRITE: u8 = 0x08;
+
+/// Handle of the service declaration, followed by the characteristics
+const SERVICE_HANDLE: u16 = 1;
+
+type Pdu = heapless::Vec<u8, ATT_MTU>;
+
+/// A setting exposed as characteristic.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Field {
+    Ssid,
+    Password,
+    #[cfg(not(feature = "coap"))]
+    Endpoint,
+    Save,
+}
+
+impl Field {
+    fn uuid(self) -> [u8; 16] {
+        match self {
+            Field::Ssid => uuid(0x0002),
+            Field::Password => uuid(0x0003),
+            #[cfg(not(feature = "coap"))]
+            Field::Endpoint => uuid(0x0004),
+            Field::Save => uuid(0x0005),
+        }
+    }
+
+    fn properties(self) -> u8 {
+        match self {
+            Field::Ssid => PROPERTY_READ | PROPERTY_WRITE,
+            #[cfg(not(feature = "coap"))]
+            Field::Endpoint => PROPERTY_READ | PROPERTY_WRITE,
+            Field::Password | Field::Save => PROPERTY_WRITE,
+        }
+    }
+}
+
+/// The characteristics of the service. Every characteristic takes up two
+/// handles after the service declaration: The declaration and the value.
+const CHARACTERISTICS: &[Field] = &[
+    Field::Ssid,
+    Field::Password,
+    #[cfg(not(feature = "coap"))]
+    Field::Endpoint,
+    Field::Save,
+];
+
+const LAST_HANDLE: u16 = SERVICE_HANDLE + 2 * CHARACTERISTICS.len() as u16;
+
+/// Return a UUID of the service in little endian byte order, as used on the
+/// wire.
+const fn uuid(short: u16) -> [u8; 16] {
+    let [high, low] = short.to_be_bytes();
+    [
+        0x21, 0x6D, 0x0F, 0x5A, 0x8E, 0x2B, 0xC6, 0xA4, 0x34, 0x4A, 0x9C, 0x1F, low, high, 0x43,
+        0x4E,
+    ]
+}
+
+/// An attribute of the GATT database.
+enum Attribute {
+    Service,
+    Declaration(u16, Field),
+    Value(Field),
+}
+
+impl Attribute {
+    fn get(handle: u16) -> Option<Self> {
+        if handle == SERVICE_HANDLE {
+            return Some(Attribute::Service);
+        }
+        let index = usize::from(handle.checked_sub(SERVICE_HANDLE + 1)? / 2);
+        let field = *CHARACTERISTICS.get(index)?;
+        if (handle - SERVICE_HANDLE) % 2 == 1 {
+            Some(Attribute::Declaration(handle + 1, field))
+        } else {
+            Some(Attribute::Value(field))
+        }
+    }
+
+    /// The attribute type as 16 or 128 bit UUID.
+    fn kind(&self) -> heapless::Vec<u8, 16> {
+        match self {
+            Attribute::Service => heapless::Vec::from_slice(&UUID_PRIMARY_SERVICE.to_le_bytes()),
+            Attribute::Declaration(..) => {
+                heapless::Vec::from_slice(&UUID_CHARACTERISTIC.to_le_bytes())
+            }
+            Attribute::Value(field) => heapless::Vec::from_slice(&field.uuid()),
+        }
+        .unwrap()
+    }
+}
+
+/// Run the GATT server until settings were saved.
+pub async fn run(mut connector: BleConnector<'static>, settings: Option<Settings>) {
+    let mut server = Server::new(settings);
+    if let Err(e) = start_advertising(&mut connector).await {
+        log::error!("Could not start BLE advertising: {}", e);
+        core::future::pending::<()>().await;
+    }
+    log::info!("BLE provisioning service advertised as \"{}\"", DEVICE_NAME);
+
+    let mut buf = [0; 260];
+    loop {
+        let packet = match read_packet(&mut connector, &mut buf).await {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!("BLE: {}", e);
+                continue;
+            }
+        };
+        match packet {
+            Packet::Event(EVENT_LE_META, [LE_CONNECTION_COMPLETE, 0, ..]) => {
+                log::info!("BLE: Connected");
+            }
+            Packet::Event(EVENT_DISCONNECTION_COMPLETE, _) => {
+                log::info!("BLE: Disconnected");
+                server.pending_write = None;
+                // Advertising stops when a connection is established
+                let _ = send_command(&mut connector, HCI_LE_SET_ADVERTISING_ENABLE, &[1]).await;
+            }
+            Packet::Event(..) => {}
+            Packet::Acl(handle, data) => {
+                let Some((cid, payload)) = parse_l2cap(data) else {
+                    continue;
+                };
+                let response = match cid {
+                    L2CAP_CID_ATT => server.handle(payload),
+                    L2CAP_CID_SMP if payload.first() == Some(&SMP_PAIRING_REQUEST) => Some(
+                        Pdu::from_slice(&[SMP_PAIRING_FAILED, SMP_PAIRING_NOT_SUPPORTED]).unwrap(),
+                    ),
+                    _ => None,
+                };
+                if let Some(response) = response {
+                    if send_acl(&mut connector, handle, cid, &response)
+                        .await
+                        .is_err()
+                    {
+                        log::warn!("BLE: Could not send response");
+                    }
+                }
+                if server.saved {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// State of the GATT server.
+struct Server {
+    ssid: heapless::String<32>,
+    password: heapless::String<64>,
+    #[cfg(not(feature = "coap"))]
+    endpoint: heapless::String<128>,
+    /// Handle and data of a long write in progress
+    pending_write: Option<(u16, heapless::Vec<u8, MAX_WRITE_LEN>)>,
+    /// The settings before they were changed, for the changelog
+    previous: Option<Settings>,
+    /// Whether the settings were saved
+    saved: bool,
+}
+
+impl Server {
+    fn new(settings: Option<Settings>) -> Self {
+        let current = settings.as_ref();
+        Self {
+            ssid: current.map(|s| s.wifi_ssid.clone()).unwrap_or_default(),
+            password: current.map(|s| s.wifi_password.clone()).unwrap_or_default(),
+            #[cfg(not(feature = "coap"))]
+            endpoint: current
+                .map(|s| s.sensor_endpoint.clone())
+                .unwrap_or_default(),
+            pending_write: None,
+            previous: settings,
+            saved: false,
+        }
+    }
+
+    /// Handle an ATT request, returning the response (if any).
+    fn handle(&mut self, request: &[u8]) -> Option<Pdu> {
+        let (&opcode, params) = request.split_first()?;
+        let result = match opcode {
+            ATT_EXCHANGE_MTU_REQ => Ok(response(&[0x03], &(ATT_MTU as u16).to_le_bytes())),
+            ATT_FIND_INFORMATION_REQ => self.find_information(params),
+            ATT_FIND_BY_TYPE_VALUE_REQ => self.find_by_type_value(params),
+            ATT_READ_BY_TYPE_REQ => self.read_by_type(params),
+            ATT_READ_REQ | ATT_READ_BLOB_REQ => self.read(opcode, params),
+            ATT_READ_BY_GROUP_TYPE_REQ => self.read_by_group_type(params),
+            ATT_WRITE_REQ | ATT_WRITE_CMD => self.write_request(opcode, params),
+            ATT_PREPARE_WRITE_REQ => self.prepare_write(params),
+            ATT_EXECUTE_WRITE_REQ => self.execute_write(params),
+            // Commands (bit 6 set) never get a response
+            _ if opcode & 0x40 != 0 => return None,
+            _ => Err((0, ATT_REQUEST_NOT_SUPPORTED)),
+        };
+        match result {
+            Ok(response) => response,
+            Err((handle, error)) => {
+                let mut pdu = Pdu::new();
+                let _ = pdu.extend_from_slice(&[ATT_ERROR_RSP, opcode]);
+                let _ = pdu.extend_from_slice(&handle.to_le_bytes());
+                let _ = pdu.push(error);
+                Some(pdu)
+            }
+        }
+    }
+
+    fn find_information(&self, params: &[u8]) -> AttResult {
+        let (start, end) = parse_range(params)?;
+        let mut pdu = Pdu::new();
+        for handle in start..=end.min(LAST_HANDLE) {
+            let kind = Attribute::get(handle).unwrap().kind();
+            // All entries must have the same format (16 or 128 bit UUID)
+            let format = if kind.len() == 2 { 1 } else { 2 };
+            if pdu.is_empty() {
+                let _ = pdu.extend_from_slice(&[0x05, format]);
+            } else if pdu[1] != format || pdu.len() + 2 + kind.len() > ATT_MTU {
+                break;
+            }
+            let _ = pdu.extend_from_slice(&handle.to_le_bytes());
+            let _ = pdu.extend_from_slice(&kind);
+        }
+        if pdu.is_empty() {
+            return Err((start, ATT_ATTRIBUTE_NOT_FOUND));
+        }
+        Ok(Some(pdu))
+    }
+
+    fn find_by_type_value(&self, params: &[u8]) -> AttResult {
+        let (start, end) = parse_range(params)?;
+        let kind = params.get(4..6).ok_or((0, ATT_INVALID_PDU))?;
+        let value = &params[6..];
+        let found = (start..=end).contains(&SERVICE_HANDLE)
+            && kind == UUID_PRIMARY_SERVICE.to_le_bytes()
+            && value == uuid(0x0001);
+        if !found {
+            return Err((start, ATT_ATTRIBUTE_NOT_FOUND));
+        }
+        let mut handles = [0; 4];
+        handles[..2].copy_from_slice(&SERVICE_HANDLE.to_le_bytes());
+        handles[2..].copy_from_slice(&LAST_HANDLE.to_le_bytes());
+        Ok(response(&[0x07], &handles))
+    }
+
+    fn read_by_type(&self, params: &[u8]) -> AttResult {
+        let (start, end) = parse_range(params)?;
+        let kind = &params[4..];
+        for handle in start..=end.min(LAST_HANDLE) {
+            if Attribute::get(handle).unwrap().kind() != kind {
+                continue;
+            }
+            let value = self.value(handle)?;
+            let len = value.len().min(ATT_MTU - 4);
+            let mut pdu = Pdu::new();
+            let _ = pdu.extend_from_slice(&[0x09, 2 + len as u8]);
+            let _ = pdu.extend_from_slice(&handle.to_le_bytes());
+            let _ = pdu.extend_from_slice(&value[..len]);
+            return Ok(Some(pdu));
+        }
+        Err((start, ATT_ATTRIBUTE_NOT_FOUND))
+    }
+
+    fn read(&self, opcode: u8, params: &[u8]) -> AttResult {
+        let handle = parse_handle(params)?;
+        let offset = match opcode {
+            ATT_READ_BLOB_REQ => usize::from(parse_u16(params.get(2..4))?),
+            _ => 0,
+        };
+        let value = self.value(handle)?;
+        let value = value.get(offset..).ok_or((handle, ATT_INVALID_OFFSET))?;
+        Ok(response(&[opcode + 1], value))
+    }
+
+    fn read_by_group_type(&self, params: &[u8]) -> AttResult {
+        let (start, end) = parse_range(params)?;
+        if params[4..] != UUID_PRIMARY_SERVICE.to_le_bytes() {
+            return Err((start, ATT_UNSUPPORTED_GROUP_TYPE));
+        }
+        if !(start..=end).contains(&SERVICE_HANDLE) {
+            return Err((start, ATT_ATTRIBUTE_NOT_FOUND));
+        }
+        let mut pdu = Pdu::new();
+        let _ = pdu.extend_from_slice(&[0x11, 20]);
+        let _ = pdu.extend_from_slice(&SERVICE_HANDLE.to_le_bytes());
+        let _ = pdu.extend_from_slice(&LAST_HANDLE.to_le_bytes());
+        let _ = pdu.extend_from_slice(&uuid(0x0001));
+        Ok(Some(pdu))
+    }
+
+    fn write_request(&mut self, opcode: u8, params: &[u8]) -> AttResult {
+        let handle = parse_handle(params)?;
+        let result = self.write(handle, &params[2..]);
+        match opcode {
+            ATT_WRITE_REQ => result.map(|()| response(&[0x13], &[])),
+            _ => Ok(None),
+        }
+    }
+
+    fn prepare_write(&mut self, params: &[u8]) -> AttResult {
+        let handle = parse_handle(params)?;
+        let offset = usize::from(parse_u16(params.get(2..4))?);
+        let part = &params[4..];
+        let writable = match Attribute::get(handle) {
+            Some(Attribute::Value(field)) => field.properties() & PROPERTY_WRITE != 0,
+            _ => false,
+        };
+        if !writable {
+            return Err((handle, ATT_WRITE_NOT_PERMITTED));
+        }
+        let (pending_handle, data) = self
+            .pending_write
+            .get_or_insert_with(|| (handle, heapless::Vec::new()));
+        // Only a single long write at a time is supported
+        if *pending_handle != handle || offset != data.len() {
+            return Err((handle, ATT_INVALID_OFFSET));
+        }
+        data.extend_from_slice(part)
+            .map_err(|_| (handle, ATT_INVALID_ATTRIBUTE_VALUE_LENGTH))?;
+        Ok(response(&[0x17], params))
+    }
+
+    fn execute_write(&mut self, params: &[u8]) -> AttResult {
+        let flags = *params.first().ok_or((0, ATT_INVALID_PDU))?;
+        if let Some((handle, data)) = self.pending_write.take() {
+            if flags == 0x01 {
+                self.write(handle, &data)?;
+            }
+        }
+        Ok(response(&[0x19], &[]))
+    }
+
+    /// Return the value of an attribute.
+    fn value(&self, handle: u16) -> Result<heapless::Vec<u8, 128>, (u16, u8)> {
+        let value = match Attribute::get(handle).ok_or((handle, ATT_INVALID_HANDLE))? {
+            Attribute::Service => &uuid(0x0001)[..],
+            Attribute::Declaration(value_handle, field) => {
+                let mut declaration = heapless::Vec::new();
+                let _ = declaration.push(field.properties());
+                let _ = declaration.extend_from_slice(&value_handle.to_le_bytes());
+                let _ = declaration.extend_from_slice(&field.uuid());
+                return Ok(declaration);
+            }
+            Attribute::Value(Field::Ssid) => self.ssid.as_bytes(),
+            #[cfg(not(feature = "coap"))]
+            Attribute::Value(Field::Endpoint) => self.endpoint.as_bytes(),
+            Attribute::Value(Field::Password | Field::Save) => {
+                return Err((handle, ATT_READ_NOT_PERMITTED))
+            }
+        };
+        Ok(heapless::Vec::from_slice(value).unwrap())
+    }
+
+    /// Write the value of an attribute.
+    fn write(&mut self, handle: u16, value: &[u8]) -> Result<(), (u16, u8)> {
+        let Some(Attribute::Value(field)) = Attribute::get(handle) else {
+            return Err((handle, ATT_WRITE_NOT_PERMITTED));
+        };
+        let invalid_length = (handle, ATT_INVALID_ATTRIBUTE_VALUE_LENGTH);
+        let text = core::str::from_utf8(value).map_err(|_| invalid_length);
+        match field {
+            Field::Ssid => self.ssid = text?.try_into().map_err(|_| invalid_length)?,
+            Field::Password => self.password = text?.try_into().map_err(|_| invalid_length)?,
+            #[cfg(not(feature = "coap"))]
+            Field::Endpoint => self.endpoint = text?.try_into().map_err(|_| invalid_length)?,
+            Field::Save => {
+                let mut settings = self.previous.clone().unwrap_or_default();
+                settings.wifi_ssid = self.ssid.clone();
+                settings.wifi_password = self.password.clone();
+                #[cfg(not(feature = "coap"))]
+                {
+                    settings.sensor_endpoint = self.endpoint.clone();
+                }
+                if let Err(message) = provisioning::validate(&settings) {
+                    log::warn!("BLE: Rejected settings: {}", message);
+                    return Err((handle, ATT_INVALID_SETTINGS));
+                }
+                if settings.save().is_err() {
+                    return Err((handle, ATT_INVALID_SETTINGS));
+                }
+                log::info!("Stored settings for WiFi \"{}\"", settings.wifi_ssid);
+                Changelog::new().record(Source::Ble, self.previous.as_ref(), &settings);
+                self.previous = Some(settings);
+                self.saved = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Result of an ATT request: The response, or the handle and error code.
+type AttResult = Result<Option<Pdu>, (u16, u8)>;
+
+fn response(opcode: &[u8], params: &[u8]) -> Option<Pdu> {
+    let mut pdu = Pdu::from_slice(opcode).unwrap();
+    let len = params.len().min(ATT_MTU - pdu.len());
+    let _ = pdu.extend_from_slice(&params[..len]);
+    Some(pdu)
+}
+
+fn parse_u16(bytes: Option<&[u8]>) -> Result<u16, (u16, u8)> {
+    match bytes {
+        Some(&[low, high]) => Ok(u16::from_le_bytes([low, high])),
+        _ => Err((0, ATT_INVALID_PDU)),
+    }
+}
+
+fn parse_handle(params: &[u8]) -> Result<u16, (u16, u8)> {
+    parse_u16(params.get(..2))
+}
+
+/// Parse a handle range, which must be valid.
+fn parse_range(params: &[u8]) -> Result<(u16, u16), (u16, u8)> {
+    let start = parse_u16(params.get(..2))?;
+    let end = parse_u16(params.get(2..4))?;
+    if start == 0 || start > end {
+        return Err((start, ATT_INVALID_HANDLE));
+    }
+    Ok((start, end))
+}
+
+/// Parse an L2CAP basic frame into channel ID and payload.
+fn parse_l2cap(data: &[u8]) -> Option<(u16, &[u8])> {
+    let len = usize::from(u16::from_le_bytes([*data.first()?, *data.get(1)?]));
+    let cid = u16::from_le_bytes([*data.get(2)?, *data.get(3)?]);
+    Some((cid, data.get(4..4 + len)?))
+}
+
+/// Reset the controller and start advertising.
+async fn start_advertising(connector: &mut BleConnector<'static>) -> anyhow::Result<()> {
+    send_command(connector, HCI_RESET, &[]).await?;
+
+    // Connectable undirected advertising on all channels
+    let interval = ADVERTISING_INTERVAL.to_le_bytes();
+    let mut parameters = [0; 15];
+    parameters[..2].copy_from_slice(&interval);
+    parameters[2..4].copy_from_slice(&interval);
+    parameters[13] = 0x07;
+    send_command(connector, HCI_LE_SET_ADVERTISING_PARAMETERS, &parameters).await?;
+
+    // Flags (LE general discoverable, BR/EDR not supported) and name
+    let mut data = heapless::Vec::<u8, 32>::new();
+    let _ = data.push(0);
+    let _ = data.extend_from_slice(&[2, 0x01, 0x06]);
+    let _ = data.extend_from_slice(&[1 + DEVICE_NAME.len() as u8, 0x09]);
+    let _ = data.extend_from_slice(DEVICE_NAME.as_bytes());
+    data[0] = data.len() as u8 - 1;
+    data.resize(32, 0).unwrap();
+    send_command(connector, HCI_LE_SET_ADVERTISING_DATA, &data).await?;
+
+    // Complete list of 128 bit service UUIDs
+    let mut data = heapless::Vec::<u8, 32>::new();
+    let _ = data.extend_from_slice(&[18, 17, 0x07]);
+    let _ = data.extend_from_slice(&uuid(0x0001));
+    data.resize(32, 0).unwrap();
+    send_command(connector, HCI_LE_SET_SCAN_RESPONSE_DATA, &data).await?;
+
+    send_command(connector, HCI_LE_SET_ADVERTISING_ENABLE, &[1]).await
+}
+
+/// Send an HCI command and wait for it to complete.
+async fn send_command(
+    connector: &mut BleConnector<'static>,
+    opcode: u16,
+    parameters: &[u8],
+) -> anyhow::Result<()> {
+    let mut command = heapless::Vec::<u8, 40>::new();
+    let _ = command.push(HCI_COMMAND);
+    let _ = command.extend_from_slice(&opcode.to_le_bytes());
+    let _ = command.push(parameters.len() as u8);
+    let _ = command.extend_from_slice(parameters);
+    if connector.write_all(&command).await.is_err() {
+        anyhow::bail!("Could not send HCI command");
+    }
+
+    let mut buf = [0; 260];
+    loop {
+        // Command complete: Number of packets, opcode, status
+        if let Packet::Event(EVENT_COMMAND_COMPLETE, [_, low, high, status, ..]) =
+            read_packet(connector, &mut buf).await?
+        {
+            if u16::from_le_bytes([*low, *high]) == opcode {
+                if *status != 0 {
+                    anyhow::bail!("HCI command {:04x} failed with status {}", opcode, status);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Send an L2CAP basic frame on a connection.
+async fn send_acl(
+    connector: &mut BleConnector<'static>,
+    handle: u16,
+    cid: u16,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut packet = heapless::Vec::<u8, 40>::new();
+    let _ = packet.push(HCI_ACL);
+    // Packet boundary flag 0: First non-automatically-flushable packet
+    let _ = packet.extend_from_slice(&(handle & 0x0FFF).to_le_bytes());
+    let _ = packet.extend_from_slice(&(payload.len() as u16 + 4).to_le_bytes());
+    let _ = packet.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    let _ = packet.extend_from_slice(&cid.to_le_bytes());
+    let _ = packet.extend_from_slice(payload);
+    if connector.write_all(&packet).await.is_err() {
+        anyhow::bail!("Could not send ACL data");
+    }
+    Ok(())
+}
+
+/// An HCI packet received from the controller.
+enum Packet<'a> {
+    /// Event code and parameters
+    Event(u8, &'a [u8]),
+    /// Connection handle and data
+    Acl(u16, &'a [u8]),
+}
+
+/// Read the next event or ACL data packet from the controller.
+async fn read_packet<'a>(
+    connector: &mut BleConnector<'static>,
+    buf: &'a mut [u8; 260],
+) -> anyhow::Result<Packet<'a>> {
+    let mut kind = [0];
+    read_exact(connector, &mut kind).await?;
+    match kind[0] {
+        HCI_EVENT => {
+            let mut header = [0; 2];
+            read_exact(connector, &mut header).await?;
+            let data = &mut buf[..usize::from(header[1])];
+            read_exact(connector, data).await?;
+            Ok(Packet::Event(header[0], data))
+        }
+        HCI_ACL => {
+            let mut header = [0; 4];
+            read_exact(connector, &mut header).await?;
+            let handle = u16::from_le_bytes([header[0], header[1]]) & 0x0FFF;
+            let len = usize::from(u16::from_le_bytes([header[2], header[3]]));
+            if len > buf.len() {
+                // Skip the packet to stay in sync with the controller
+                let mut remaining = len;
+                while remaining > 0 {
+                    let n = remaining.min(buf.len());
+                    read_exact(connector, &mut buf[..n]).await?;
+                    remaining -= n;
+                }
+                anyhow::bail!("ACL packet too long");
+            }
+            let data = &mut buf[..len];
+            read_exact(connector, data).await?;
+            Ok(Packet::Acl(handle, data))
+        }
+        kind => anyhow::bail!("Unexpected HCI packet type {kind}"),
+    }
+}
+
+async fn read_exact(connector: &mut BleConnector<'static>, buf: &mut [u8]) -> anyhow::Result<()> {
+    if connector.read_exact(buf).await.is_err() {
+        anyhow::bail!("Could not read from HCI");
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Broadcast of the count on the local network.
+//!
+//! Whenever the count changes, and once per [`REPEAT_INTERVAL`] otherwise, a
+//! UDP datagram is broadcast to port [`BROADCAST_PORT`] of the local subnet.
+//! Other displays and dashboards can listen for it instead of polling the
+//! SpaceAPI server.
+//!
+//! Packet format (15 bytes, multi-byte fields in network byte order):
+//!
+//! | Offset | Size | Field                                                 |
+//! |--------|------|-------------------------------------------------------|
+//! | 0      | 3    | Magic `NXC`                                           |
+//! | 3      | 1    | Format version, currently 1                           |
+//! | 4      | 6    | Device ID (base MAC address)                          |
+//! | 10     | 4    | Sequence number, incremented with every datagram      |
+//! | 14     | 1    | People now present count                              |
+//!
+//! The sequence number starts at 0 on boot. Receivers should track it per
+//! device to detect reordered or repeated datagrams, and treat a lower number
+//! as a reboot of the device.
+
+use crate::{device_id::DeviceId, jitter::jittered, EspWifiDevice};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+/// UDP port the datagrams are sent to (and from)
+pub const BROADCAST_PORT: u16 = 45123;
+
+/// Interval in which the current count is repeated when it doesn't change
+const REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+const MAGIC: &[u8; 3] = b"NXC";
+const VERSION: u8 = 1;
+const PACKET_LEN: usize = 15;
+
+/// Encode a datagram.
+fn encode(device_id: &[u8; 6], sequence: u32, count: u8) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    packet[0..3].copy_from_slice(MAGIC);
+    packet[3] = VERSION;
+    packet[4..10].copy_from_slice(device_id);
+    packet[10..14].copy_from_slice(&sequence.to_be_bytes());
+    packet[14] = count;
+    packet
+}
+
+/// Task: Broadcast the count signalled through `count` on the local network
+#[embassy_executor::task]
+pub async fn broadcast_task(
+    stack: &'static Stack<EspWifiDevice<'static>>,
+    count: &'static Signal<NoopRawMutex, u8>,
+) {
+    log::info!("Start count broadcast task");
+    let device_id = DeviceId::read().0;
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 2 * PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(BROADCAST_PORT)
+        .expect("Failed to bind broadcast UDP socket");
+
+    // Nothing is sent until the first count is known
+    let mut current = count.wait().await;
+    let mut sequence: u32 = 0;
+    loop {
+        if let Some(config) = stack.config_v4() {
+            let address = config.address.broadcast().unwrap_or(Ipv4Address::BROADCAST);
+            let packet = encode(&device_id, sequence, current);
+            match socket
+                .send_to(&packet, IpEndpoint::new(address.into(), BROADCAST_PORT))
+                .await
+            {
+                Ok(()) => log::debug!("Broadcast count {current} (#{sequence})"),
+                Err(e) => log::warn!("Could not broadcast count: {:?}", e),
+            }
+            sequence = sequence.wrapping_add(1);
+        }
+
+        match select(count.wait(), Timer::after(jittered(REPEAT_INTERVAL))).await {
+            Either::First(new_count) => current = new_count,
+            Either::Second(()) => {}
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Build-time configuration.
+//!
+//! The constants are generated by `build.rs` from `counter-config.toml`, one
+//! for every setting used by the enabled features. The values are already
+//! checked and of their type, e.g. a [`Url`](crate::config::Url). Required
+//! settings are a constant of the type, optional ones an `Option` of it.
+
+include!(concat!(env!("OUT_DIR"), "/build_config.rs"));
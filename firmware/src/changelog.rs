@@ -0,0 +1,262 @@
+use crate::{flash_log::FlashLog, settings::Settings, storage::Sector};
+
+/// Number of changes logged at boot
+const LOGGED_AT_BOOT: usize = 8;
+
+/// Longest value of a field (the sensor endpoint URL)
+const MAX_VALUE_LEN: usize = 128;
+
+/// Length of the entry header: length, source and field
+const HEADER_LEN: usize = 4;
+
+/// Longest entry: header, and both values with their length
+const MAX_ENTRY_LEN: usize = HEADER_LEN + 2 * (1 + MAX_VALUE_LEN) + 2;
+
+type Value = heapless::String<MAX_VALUE_LEN>;
+
+/// Interface through which the settings were changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Portal = 1,
+    Ble = 2,
+    #[cfg(feature = "console")]
+    Console = 3,
+}
+
+impl Source {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Portal),
+            2 => Some(Self::Ble),
+            #[cfg(feature = "console")]
+            3 => Some(Self::Console),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Portal => "portal",
+            Self::Ble => "BLE",
+            #[cfg(feature = "console")]
+            Self::Console => "console",
+        }
+    }
+}
+
+/// A field of the [`Settings`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Field {
+    WifiSsid = 1,
+    WifiPassword = 2,
+    #[cfg(not(feature = "coap"))]
+    SensorEndpoint = 3,
+}
+
+impl Field {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::WifiSsid),
+            2 => Some(Self::WifiPassword),
+            #[cfg(not(feature = "coap"))]
+            3 => Some(Self::SensorEndpoint),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::WifiSsid => "WiFi SSID",
+            Self::WifiPassword => "WiFi password",
+            #[cfg(not(feature = "coap"))]
+            Self::SensorEndpoint => "sensor endpoint",
+        }
+    }
+
+    /// Return whether the values of the field must not be recorded.
+    fn is_secret(self) -> bool {
+        self == Self::WifiPassword
+    }
+}
+
+/// A recorded change of a single field.
+struct Entry {
+    source: Source,
+    field: Field,
+    old: Value,
+    new: Value,
+}
+
+impl Entry {
+    fn log(&self) {
+        if self.field.is_secret() {
+            log::info!(
+                "Settings changed through {}: {} changed",
+                self.source.as_str(),
+                self.field.as_str(),
+            );
+        } else {
+            log::info!(
+                "Settings changed through {}: {} \"{}\" -> \"{}\"",
+                self.source.as_str(),
+                self.field.as_str(),
+                self.old,
+                self.new,
+            );
+        }
+    }
+
+    /// Encode the entry: The header (the length of the whole entry as 16 bit
+    /// value, the source and the field), followed by the length-prefixed old
+    /// and new value, padded to the flash word size.
+    fn encode(&self) -> heapless::Vec<u8, MAX_ENTRY_LEN> {
+        let mut buf = heapless::Vec::new();
+        // Everything is bounded such that it always fits
+        let _ = buf.extend_from_slice(&[0, 0, self.source as u8, self.field as u8]);
+        for value in [&self.old, &self.new] {
+            let _ = buf.push(value.len() as u8);
+            let _ = buf.extend_from_slice(value.as_bytes());
+        }
+        while buf.len() % 4 != 0 {
+            let _ = buf.push(0);
+        }
+        let len = (buf.len() as u16).to_le_bytes();
+        buf[..2].copy_from_slice(&len);
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let source = Source::from_u8(*data.get(2)?)?;
+        let field = Field::from_u8(*data.get(3)?)?;
+        let mut values = data.get(HEADER_LEN..)?;
+        let mut read_value = || -> Option<Value> {
+            let (&len, rest) = values.split_first()?;
+            if rest.len() < usize::from(len) {
+                return None;
+            }
+            let (value, rest) = rest.split_at(usize::from(len));
+            values = rest;
+            core::str::from_utf8(value).ok()?.try_into().ok()
+        };
+        Some(Self {
+            source,
+            field,
+            old: read_value()?,
+            new: read_value()?,
+        })
+    }
+}
+
+/// Changelog of the settings, stored in flash so that it's possible to tell
+/// afterwards what was changed, through which interface.
+///
+/// Like the pending update journal, the changelog is a [`FlashLog`]. When the
+/// sector is full, it is erased and the older changes are lost.
+pub struct Changelog {
+    log: FlashLog,
+}
+
+impl Changelog {
+    /// Open the changelog, scanning it for its end.
+    pub fn new() -> Self {
+        let mut log = FlashLog::new(Sector::Changelog, "settings changelog");
+        log.scan(entry_len);
+        Self { log }
+    }
+
+    /// Record the fields that differ between the `old` (if any) and the `new`
+    /// settings. The values of secret fields are not recorded.
+    pub fn record(&mut self, source: Source, old: Option<&Settings>, new: &Settings) {
+        let fields = [
+            (
+                Field::WifiSsid,
+                old.map(|s| s.wifi_ssid.as_str()),
+                new.wifi_ssid.as_str(),
+            ),
+            (
+                Field::WifiPassword,
+                old.map(|s| s.wifi_password.as_str()),
+                new.wifi_password.as_str(),
+            ),
+            #[cfg(not(feature = "coap"))]
+            (
+                Field::SensorEndpoint,
+                old.map(|s| s.sensor_endpoint.as_str()),
+                new.sensor_endpoint.as_str(),
+            ),
+        ];
+        for (field, old, new) in fields {
+            if old == Some(new) {
+                continue;
+            }
+            let (old, new) = if field.is_secret() {
+                ("", "")
+            } else {
+                (old.unwrap_or_default(), new)
+            };
+            let entry = Entry {
+                source,
+                field,
+                // The fields are bounded such that they always fit
+                old: old.try_into().unwrap_or_default(),
+                new: new.try_into().unwrap_or_default(),
+            };
+            entry.log();
+            self.append(&entry);
+        }
+    }
+
+    /// Log the most recent changes, oldest first.
+    pub fn log_recent(&mut self) {
+        let mut recent = heapless::Deque::<u32, LOGGED_AT_BOOT>::new();
+        let mut offset = 0;
+        while let Some(len) = entry_len(&self.log, offset) {
+            if recent.is_full() {
+                recent.pop_front();
+            }
+            let _ = recent.push_back(offset);
+            offset += len;
+        }
+        for offset in recent {
+            let mut buf = [0; MAX_ENTRY_LEN];
+            let Some(len) = entry_len(&self.log, offset) else {
+                continue;
+            };
+            let data = &mut buf[..len as usize];
+            if !self.log.read(offset, data) {
+                return;
+            }
+            match Entry::decode(data) {
+                Some(entry) => entry.log(),
+                None => log::warn!("Invalid entry in the settings changelog"),
+            }
+        }
+    }
+
+    fn append(&mut self, entry: &Entry) {
+        let data = entry.encode();
+        if !self.log.fits(data.len()) {
+            log::warn!("Settings changelog is full, discarding the older changes");
+        }
+        self.log.append(&data);
+    }
+}
+
+/// Return the length of the entry at `offset`, or `None` at the end of the
+/// changelog.
+fn entry_len(log: &FlashLog, offset: u32) -> Option<u32> {
+    if offset + HEADER_LEN as u32 > Sector::SIZE {
+        return None;
+    }
+    let mut header = [0; HEADER_LEN];
+    if !log.read(offset, &mut header) {
+        return None;
+    }
+    let len = u32::from(u16::from_le_bytes([header[0], header[1]]));
+    let valid = len > HEADER_LEN as u32
+        && len <= MAX_ENTRY_LEN as u32
+        && len % 4 == 0
+        && offset + len <= Sector::SIZE;
+    // An erased header (all bits set) is invalid as well
+    valid.then_some(len)
+}
@@ -0,0 +1,338 @@
+//! Wall clock time, synchronized over SNTP.
+//!
+//! [`clock_task`] queries `NTP_SERVER` (default `pool.ntp.org`) at boot and
+//! then every [`SYNC_INTERVAL`], and remembers the Unix time corresponding to
+//! the boot. The local time is derived from it with the time zone offset in
+//! `TIME_ZONE_OFFSET` (minutes east of UTC, default 60), plus the European
+//! summer time rule unless `TIME_ZONE_DST` is set to `none` (or `false`).
+//! Both are passed to the task in the [`ClockConfig`].
+
+use core::cell::Cell;
+
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::{config::ClockConfig, jitter::jittered, EspWifiDevice};
+
+const NTP_PORT: u16 = 123;
+
+/// Interval between two synchronizations
+const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Delay before retrying a failed synchronization
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Time to wait for the answer of the server
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const PACKET_LEN: usize = 48;
+
+/// Unix time at boot in microseconds, once synchronized
+static UNIX_TIME_AT_BOOT: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    Mutex::new(Cell::new(None));
+
+/// Time zone of the local time, set by [`clock_task`] before the clock is
+/// synchronized
+static TIME_ZONE: Mutex<CriticalSectionRawMutex, Cell<TimeZone>> =
+    Mutex::new(Cell::new(TimeZone {
+        offset: 0,
+        summer_time: false,
+    }));
+
+/// A time zone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    /// Offset of the standard time in minutes east of UTC
+    pub offset: i64,
+    /// Whether the European summer time rule applies
+    pub summer_time: bool,
+}
+
+/// A local date and time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// Whether summer time is in effect
+    pub summer_time: bool,
+}
+
+impl LocalTime {
+    /// Return the number of minutes since midnight.
+    #[cfg(any(
+        feature = "quiet-hours",
+        feature = "dimming",
+        feature = "clock-mode",
+        feature = "rules"
+    ))]
+    pub fn minute_of_day(&self) -> u16 {
+        u16::from(self.hour) * 60 + u16::from(self.minute)
+    }
+}
+
+/// A period of the day, e.g. `22:00-07:00` (which lasts over midnight).
+#[cfg(any(
+    feature = "quiet-hours",
+    feature = "dimming",
+    feature = "clock-mode",
+    feature = "rules"
+))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DailyPeriod {
+    /// Start and end in minutes since midnight
+    start: u16,
+    end: u16,
+}
+
+#[cfg(any(
+    feature = "quiet-hours",
+    feature = "dimming",
+    feature = "clock-mode",
+    feature = "rules"
+))]
+impl DailyPeriod {
+    /// Create a period from its start and end in minutes since midnight, as
+    /// checked by `build.rs`. With only `clock-mode` or `rules`, it is only
+    /// used if `CLOCK_MODE_HOURS` is set or a rule has a time of day.
+    #[cfg_attr(
+        not(any(feature = "quiet-hours", feature = "dimming")),
+        allow(dead_code)
+    )]
+    pub const fn new(start: u16, end: u16) -> Self {
+        Self { start, end }
+    }
+
+    /// Parse a period in the format `HH:MM-HH:MM`, e.g. from the stored
+    /// settings.
+    #[cfg(any(feature = "quiet-hours", feature = "dimming"))]
+    pub fn parse(period: &str) -> Option<Self> {
+        let (start, end) = period.split_once('-')?;
+        Some(Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+
+    /// Return whether the current local time lies in the period. Returns
+    /// `false` as long as the clock isn't synchronized.
+    #[cfg(any(feature = "quiet-hours", feature = "clock-mode", feature = "rules"))]
+    pub fn is_now(&self) -> bool {
+        local_time().is_some_and(|time| self.contains(time.minute_of_day()))
+    }
+
+    /// Return whether the specified time of day lies in the period.
+    pub fn contains(&self, minute_of_day: u16) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute_of_day)
+        } else {
+            // Over midnight
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+/// Parse a `HH:MM` time into minutes since midnight.
+#[cfg(any(feature = "quiet-hours", feature = "dimming"))]
+fn parse_time(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Return the current Unix time in seconds, if the clock is synchronized.
+pub fn unix_time() -> Option<u64> {
+    let at_boot = UNIX_TIME_AT_BOOT.lock(Cell::get)?;
+    Some((at_boot + Instant::now().as_micros()) / 1_000_000)
+}
+
+/// Return when the next minute starts, if the clock is synchronized. Time
+/// zone offsets are whole minutes, so this is the next minute of the local
+/// time as well.
+#[cfg(feature = "clock-mode")]
+pub fn next_minute() -> Option<Instant> {
+    const MINUTE_MICROS: u64 = 60_000_000;
+    let at_boot = UNIX_TIME_AT_BOOT.lock(Cell::get)?;
+    let now = at_boot + Instant::now().as_micros();
+    let next = (now / MINUTE_MICROS + 1) * MINUTE_MICROS;
+    Some(Instant::from_micros(next - at_boot))
+}
+
+/// Return the current local time, if the clock is synchronized.
+pub fn local_time() -> Option<LocalTime> {
+    let unix_time = unix_time()? as i64;
+    let summer_time = is_summer_time(unix_time);
+    let offset = TIME_ZONE.lock(Cell::get).offset + if summer_time { 60 } else { 0 };
+    let local = unix_time + offset * 60;
+    let (year, month, day) = civil_from_days(local.div_euclid(86_400));
+    let seconds = local.rem_euclid(86_400);
+    Some(LocalTime {
+        year,
+        month,
+        day,
+        hour: (seconds / 3600) as u8,
+        minute: (seconds / 60 % 60) as u8,
+        second: (seconds % 60) as u8,
+        summer_time,
+    })
+}
+
+/// Return whether summer time is in effect at the specified Unix time.
+fn is_summer_time(unix_time: i64) -> bool {
+    if !TIME_ZONE.lock(Cell::get).summer_time {
+        return false;
+    }
+    // European summer time: From the last Sunday of March to the last Sunday
+    // of October, both at 01:00 UTC
+    let (year, _, _) = civil_from_days(unix_time.div_euclid(86_400));
+    let start = last_sunday(year, 3) * 86_400 + 3600;
+    let end = last_sunday(year, 10) * 86_400 + 3600;
+    (start..end).contains(&unix_time)
+}
+
+/// Return the days since the Unix epoch of the last Sunday in the month.
+fn last_sunday(year: i32, month: u8) -> i64 {
+    // The last day of the month is the day before the first of the next one
+    let last_day = days_from_civil(year, month + 1, 1) - 1;
+    // 1970-01-01 was a Thursday
+    let weekday = (last_day + 4).rem_euclid(7);
+    last_day - weekday
+}
+
+/// Convert a date to days since the Unix epoch.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html>.
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = i64::from(if month <= 2 { year - 1 } else { year });
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Convert days since the Unix epoch to a date.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html>.
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u8;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year as i32, month, day)
+}
+
+/// Query the server once and return the Unix time in microseconds.
+async fn query(socket: &mut UdpSocket<'_>, remote: IpEndpoint) -> anyhow::Result<u64> {
+    // Version 4, mode 3 (client)
+    let mut request = [0; PACKET_LEN];
+    request[0] = 0x23;
+    if let Err(e) = socket.send_to(&request, remote).await {
+        log::debug!("Could not send SNTP request: {:?}", e);
+        anyhow::bail!("Sending the request failed");
+    }
+    let mut response = [0; PACKET_LEN];
+    let len = match with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut response)).await {
+        Ok(Ok((len, _))) => len,
+        Ok(Err(e)) => {
+            log::debug!("Could not receive SNTP response: {:?}", e);
+            anyhow::bail!("Receiving the response failed");
+        }
+        Err(_) => anyhow::bail!("No response"),
+    };
+    // Transmit timestamp: seconds and fraction since 1900
+    if len < PACKET_LEN {
+        anyhow::bail!("Response too short");
+    }
+    let seconds = u64::from(u32::from_be_bytes(response[40..44].try_into()?));
+    let fraction = u64::from(u32::from_be_bytes(response[44..48].try_into()?));
+    if seconds < NTP_UNIX_OFFSET {
+        anyhow::bail!("Server is not synchronized");
+    }
+    Ok((seconds - NTP_UNIX_OFFSET) * 1_000_000 + ((fraction * 1_000_000) >> 32))
+}
+
+/// Task: Synchronize the clock
+#[embassy_executor::task]
+pub async fn clock_task(stack: &'static Stack<EspWifiDevice<'static>>, config: ClockConfig) {
+    log::info!("Start clock sync task");
+    TIME_ZONE.lock(|cell| cell.set(config.time_zone));
+    let ntp_server = config.ntp_server;
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).expect("Failed to bind SNTP UDP socket");
+
+    loop {
+        stack.wait_config_up().await;
+        let address = if let Ok(address) = ntp_server.parse::<Ipv4Address>() {
+            Some(IpAddress::Ipv4(address))
+        } else {
+            match stack.dns_query(ntp_server, DnsQueryType::A).await {
+                Ok(addresses) => addresses.first().copied(),
+                Err(e) => {
+                    log::debug!("DNS lookup for {} failed: {:?}", ntp_server, e);
+                    None
+                }
+            }
+        };
+        let result = match address {
+            Some(address) => query(&mut socket, IpEndpoint::new(address, NTP_PORT)).await,
+            None => Err(anyhow::anyhow!("Could not resolve {ntp_server}")),
+        };
+        match result {
+            Ok(unix_time) => {
+                let at_boot = unix_time.saturating_sub(Instant::now().as_micros());
+                UNIX_TIME_AT_BOOT.lock(|cell| cell.set(Some(at_boot)));
+                if let Some(time) = local_time() {
+                    log::info!(
+                        "Clock synchronized: {}-{:02}-{:02} {:02}:{:02}:{:02}",
+                        time.year,
+                        time.month,
+                        time.day,
+                        time.hour,
+                        time.minute,
+                        time.second,
+                    );
+                }
+                Timer::after(jittered(SYNC_INTERVAL)).await;
+            }
+            Err(e) => {
+                log::warn!("Could not synchronize the clock: {}", e);
+                Timer::after(jittered(RETRY_DELAY)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Showing the time while the space is empty.
+//!
+//! In clock mode, the tubes show the local time instead of a count of 0,
+//! alternating between the hours and the minutes. It is entered by a long
+//! press down (and left by a long press up), or automatically during the
+//! period configured in `CLOCK_MODE_HOURS` (e.g. `20:00-08:00`, local time,
+//! none by default). As soon as somebody is counted, the count is shown
+//! again.
+//!
+//! Like on a watch face, the minute change is animated: When a minute starts
+//! while the tubes show the hours or the minutes, they roll over to the new
+//! value right on the minute boundary.
+
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    clock::{self, DailyPeriod},
+    display::CounterDisplay,
+};
+
+/// Time the hours and the minutes are shown each
+const CLOCK_DELAY: Duration = Duration::from_millis(2000);
+
+/// Time each step of the roll over to the next minute is shown
+const ROLL_DELAY: Duration = Duration::from_millis(60);
+
+/// State of the clock mode.
+pub struct ClockMode {
+    /// Period in which the clock mode is entered automatically
+    period: Option<DailyPeriod>,
+    /// Whether the clock mode was entered by a long press
+    entered: bool,
+}
+
+impl ClockMode {
+    /// Create a new instance, which enters the clock mode automatically
+    /// during `period`, if any.
+    pub fn new(period: Option<DailyPeriod>) -> Self {
+        Self {
+            period,
+            entered: false,
+        }
+    }
+
+    /// Enter or leave the clock mode, e.g. after a long press.
+    pub fn set_entered(&mut self, entered: bool) {
+        if entered != self.entered {
+            log::info!(
+                "{} clock mode",
+                if entered { "Entering" } else { "Leaving" }
+            );
+        }
+        self.entered = entered;
+    }
+
+    /// Return whether the time is shown instead of the specified count.
+    ///
+    /// As long as the clock isn't synchronized, the count is shown.
+    pub fn is_shown(&self, count: u8) -> bool {
+        count == 0
+            && (self.entered || self.period.is_some_and(|period| period.is_now()))
+            && clock::local_time().is_some()
+    }
+}
+
+/// Keep showing the time, alternating between the hours and the minutes
+/// (with leading zeroes), each followed by a short pause. Never returns, stop
+/// it by dropping the future.
+pub async fn show_clock<const N: usize>(tubes: &mut impl CounterDisplay<N>) -> ! {
+    let delay = tubes.frame_delay(CLOCK_DELAY);
+    let parts: [fn(&clock::LocalTime) -> u8; 2] = [|time| time.hour, |time| time.minute];
+    loop {
+        for part in parts {
+            let Some(time) = clock::local_time() else {
+                Timer::after(delay).await;
+                continue;
+            };
+            let mut shown = u32::from(part(&time));
+            tubes.show_padded(shown);
+            let end = Instant::now() + delay;
+            // Roll over right on the minute boundary, if it comes while the
+            // part is shown
+            while let Some(next_minute) = clock::next_minute().filter(|start| *start < end) {
+                Timer::at(next_minute).await;
+                let Some(time) = clock::local_time() else {
+                    break;
+                };
+                let value = u32::from(part(&time));
+                tubes.roll_padded(shown, value, ROLL_DELAY).await;
+                shown = value;
+            }
+            Timer::at(end).await;
+            tubes.off();
+            Timer::after(delay / 4).await;
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Minimal CoAP (RFC 7252) client for sending count updates.
+//!
+//! Updates are sent as confirmable PUT requests to the resource configured in
+//! `COAP_SENSOR_ENDPOINT` (e.g. `coap://example.com/sensors/people_now_present`).
+//! The payload is the count as a decimal number (content format `text/plain`).
+
+use core::fmt::Write;
+
+use embassy_net::{
+    dns::DnsSocket,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_time::{with_timeout, Duration};
+use embedded_nal_async::{AddrType, Dns, IpAddr};
+use esp_hal::rng::Rng;
+
+use crate::{
+    config::Url, dns_cache::CachingDns, error_code::ErrorCode, transport::CountTransport,
+    EspDnsSocket, EspWifiDevice,
+};
+
+/// Transmission parameters, see RFC 7252 section 4.8
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+/// Time to wait for a separate response after an empty ACK
+const SEPARATE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TYPE_CON: u8 = 0;
+const TYPE_NON: u8 = 1;
+const TYPE_ACK: u8 = 2;
+const TYPE_RST: u8 = 3;
+
+const CODE_EMPTY: u8 = 0x00;
+const CODE_PUT: u8 = 0x03;
+
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+const CONTENT_FORMAT_TEXT_PLAIN: u8 = 0;
+
+const TOKEN_LEN: usize = 4;
+
+type Datagram = heapless::Vec<u8, 256>;
+
+/// Socket buffers of the [`CoapTransport`].
+pub struct CoapBuffers {
+    rx_meta: [PacketMetadata; 1],
+    rx: [u8; 256],
+    tx_meta: [PacketMetadata; 1],
+    tx: [u8; 256],
+}
+
+impl CoapBuffers {
+    pub const fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; 1],
+            rx: [0; 256],
+            tx_meta: [PacketMetadata::EMPTY; 1],
+            tx: [0; 256],
+        }
+    }
+}
+
+/// Update the sensor through a confirmable CoAP PUT request.
+pub struct CoapTransport {
+    dns: CachingDns<EspDnsSocket<'static>>,
+    socket: UdpSocket<'static>,
+    rng: Rng,
+    endpoint: Url,
+    message_id: u16,
+}
+
+impl CoapTransport {
+    /// Create a new instance sending the count to `endpoint`.
+    pub fn new(
+        stack: &'static Stack<EspWifiDevice<'static>>,
+        buffers: &'static mut CoapBuffers,
+        mut rng: Rng,
+        endpoint: Url,
+    ) -> Self {
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut buffers.rx_meta,
+            &mut buffers.rx,
+            &mut buffers.tx_meta,
+            &mut buffers.tx,
+        );
+        socket.bind(0).expect("Failed to bind CoAP UDP socket");
+        Self {
+            dns: CachingDns::new(DnsSocket::new(stack)),
+            socket,
+            endpoint,
+            message_id: rng.random() as u16,
+            rng,
+        }
+    }
+
+    /// Resolve the server address.
+    async fn resolve(&self) -> anyhow::Result<IpEndpoint> {
+        let address = if let Ok(address) = self.endpoint.host.parse::<Ipv4Address>() {
+            IpAddress::Ipv4(address)
+        } else {
+            match self
+                .dns
+                .get_host_by_name(self.endpoint.host, AddrType::IPv4)
+                .await
+            {
+                Ok(IpAddr::V4(address)) => IpAddress::Ipv4(Ipv4Address(address.octets())),
+                Ok(IpAddr::V6(_)) => anyhow::bail!("DNS lookup returned an IPv6 address"),
+                Err(e) => {
+                    log::error!("DNS lookup for {} failed: {:?}", self.endpoint.host, e);
+                    return Err(ErrorCode::DnsFailed.error("DNS lookup failed"));
+                }
+            }
+        };
+        Ok(IpEndpoint::new(address, self.endpoint.port))
+    }
+
+    /// Send a confirmable request and wait for its response.
+    ///
+    /// Returns the response code.
+    async fn request(
+        &mut self,
+        remote: IpEndpoint,
+        message_id: u16,
+        token: [u8; TOKEN_LEN],
+        request: &[u8],
+    ) -> anyhow::Result<u8> {
+        let mut buf = [0; 256];
+
+        // Send request, retransmitting with exponential back-off until ACKed
+        let mut timeout = ACK_TIMEOUT;
+        let mut attempt = 0;
+        let acked = loop {
+            if let Err(e) = self.socket.send_to(request, remote).await {
+                log::error!("CoAP send error: {:?}", e);
+                anyhow::bail!("CoAP request failed");
+            }
+            match with_timeout(timeout, self.receive(&mut buf, message_id, &token)).await {
+                Ok(message) => break message,
+                Err(_) if attempt < MAX_RETRANSMIT => {
+                    attempt += 1;
+                    timeout *= 2;
+                    log::debug!("CoAP: No ACK received, retransmitting ({attempt})");
+                }
+                Err(_) => anyhow::bail!("CoAP request timed out"),
+            }
+        };
+
+        match acked {
+            // Piggybacked response
+            Message {
+                kind: TYPE_ACK,
+                code,
+                ..
+            } if code != CODE_EMPTY => Ok(code),
+            // Empty ACK, the response follows separately
+            Message { kind: TYPE_ACK, .. } => {
+                let response = with_timeout(
+                    SEPARATE_RESPONSE_TIMEOUT,
+                    self.receive_separate(&mut buf, &token),
+                )
+                .await
+                .map_err(|_| anyhow::anyhow!("CoAP separate response timed out"))?;
+                if response.kind == TYPE_CON {
+                    let ack = encode_message(TYPE_ACK, CODE_EMPTY, response.message_id, &[], &[]);
+                    let _ = self.socket.send_to(&ack, remote).await;
+                }
+                Ok(response.code)
+            }
+            _ => anyhow::bail!("CoAP request was rejected with a reset message"),
+        }
+    }
+
+    /// Wait for an ACK or RST matching the request.
+    async fn receive(&mut self, buf: &mut [u8], message_id: u16, token: &[u8]) -> Message {
+        loop {
+            let Ok((len, _)) = self.socket.recv_from(buf).await else {
+                continue;
+            };
+            let Some(message) = Message::parse(&buf[..len]) else {
+                continue;
+            };
+            let matches = match message.kind {
+                TYPE_ACK => message.message_id == message_id && message.token_matches(buf, token),
+                TYPE_RST => message.message_id == message_id,
+                _ => false,
+            };
+            if matches {
+                return message;
+            }
+        }
+    }
+
+    /// Wait for a separate (CON or NON) response with the request token.
+    async fn receive_separate(&mut self, buf: &mut [u8], token: &[u8]) -> Message {
+        loop {
+            let Ok((len, _)) = self.socket.recv_from(buf).await else {
+                continue;
+            };
+            let Some(message) = Message::parse(&buf[..len]) else {
+                continue;
+            };
+            if matches!(message.kind, TYPE_CON | TYPE_NON) && message.token_matches(buf, token) {
+                return message;
+            }
+        }
+    }
+}
+
+impl CountTransport for CoapTransport {
+    async fn send_count(&mut self, people_count: u8) -> anyhow::Result<Option<u8>> {
+        let remote = self.resolve().await?;
+
+        // Prepare request
+        self.message_id = self.message_id.wrapping_add(1);
+        let message_id = self.message_id;
+        let mut token = [0; TOKEN_LEN];
+        self.rng.read(&mut token);
+        let mut payload = heapless::String::<3>::new();
+        write!(payload, "{people_count}")?;
+        let mut options = heapless::Vec::<(u16, &[u8]), 8>::new();
+        for segment in self.endpoint.path.split('/').filter(|s| !s.is_empty()) {
+            if options.push((OPTION_URI_PATH, segment.as_bytes())).is_err() {
+                anyhow::bail!("Too many path segments in CoAP URL");
+            }
+        }
+        let _ = options.push((OPTION_CONTENT_FORMAT, &[CONTENT_FORMAT_TEXT_PLAIN]));
+        let request = encode_request(message_id, &token, &options, payload.as_bytes())?;
+
+        // Send request
+        log::info!("-> CoAP PUT {}", self.endpoint.url);
+        let code = match self.request(remote, message_id, token, &request).await {
+            Ok(code) => code,
+            Err(e) => {
+                // The address might have changed, resolve it again next time
+                self.dns.invalidate();
+                return Err(e);
+            }
+        };
+
+        // Process response
+        log::info!("<- CoAP {}.{:02}", code >> 5, code & 0x1F);
+        if code >> 5 == 2 {
+            log::info!("Successfully set people now present count to {people_count}");
+            Ok(None)
+        } else {
+            Err(ErrorCode::for_status_class(u16::from(code >> 5))
+                .error("Received unexpected CoAP response code when sending status update"))
+        }
+    }
+}
+
+/// The header fields of a received message.
+struct Message {
+    kind: u8,
+    code: u8,
+    message_id: u16,
+    token_len: usize,
+}
+
+impl Message {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 || data[0] >> 6 != 1 {
+            return None;
+        }
+        let token_len = usize::from(data[0] & 0x0F);
+        if token_len > 8 || data.len() < 4 + token_len {
+            return None;
+        }
+        Some(Self {
+            kind: (data[0] >> 4) & 0x03,
+            code: data[1],
+            message_id: u16::from_be_bytes([data[2], data[3]]),
+            token_len,
+        })
+    }
+
+    fn token_matches(&self, data: &[u8], token: &[u8]) -> bool {
+        &data[4..4 + self.token_len] == token
+    }
+}
+
+/// Encode a confirmable PUT request. Options must be sorted by number.
+fn encode_request(
+    message_id: u16,
+    token: &[u8],
+    options: &[(u16, &[u8])],
+    payload: &[u8],
+) -> anyhow::Result<Datagram> {
+    let mut message = encode_message(TYPE_CON, CODE_PUT, message_id, token, &[]);
+    let mut previous = 0;
+    for (number, value) in options {
+        encode_option(&mut message, number - previous, value)
+            .ok_or_else(|| anyhow::anyhow!("CoAP request too long"))?;
+        previous = *number;
+    }
+    if message.push(0xFF).is_err() || message.extend_from_slice(payload).is_err() {
+        anyhow::bail!("CoAP request too long");
+    }
+    Ok(message)
+}
+
+/// Encode a message header with token and payload, without options.
+fn encode_message(kind: u8, code: u8, message_id: u16, token: &[u8], payload: &[u8]) -> Datagram {
+    let mut message = Datagram::new();
+    let _ = message.push(0x40 | (kind << 4) | token.len() as u8);
+    let _ = message.push(code);
+    let _ = message.extend_from_slice(&message_id.to_be_bytes());
+    let _ = message.extend_from_slice(token);
+    if !payload.is_empty() {
+        let _ = message.push(0xFF);
+        let _ = message.extend_from_slice(payload);
+    }
+    message
+}
+
+/// Append an option with the specified delta to the previous option number.
+fn encode_option(message: &mut Datagram, delta: u16, value: &[u8]) -> Option<()> {
+    fn nibble(value: u16) -> (u8, heapless::Vec<u8, 2>) {
+        match value {
+            0..=12 => (value as u8, heapless::Vec::new()),
+            13..=268 => (
+                13,
+                heapless::Vec::from_slice(&[(value - 13) as u8]).unwrap(),
+            ),
+            _ => (
+                14,
+                heapless::Vec::from_slice(&(value - 269).to_be_bytes()).unwrap(),
+            ),
+        }
+    }
+    let (delta_nibble, delta_ext) = nibble(delta);
+    let (len_nibble, len_ext) = nibble(value.len() as u16);
+    message.push((delta_nibble << 4) | len_nibble).ok()?;
+    message.extend_from_slice(&delta_ext).ok()?;
+    message.extend_from_slice(&len_ext).ok()?;
+    message.extend_from_slice(value).ok()
+}
@@ -0,0 +1,552 @@
+//! Configuration of the counter.
+//!
+//! [`Config`] collects the configuration at boot, by section, instead of it
+//! being read wherever it is used. The network and endpoint sections are the
+//! [`Settings`], which can also be stored at runtime (serialized with
+//! postcard, with `config-store`), and are checked by [`Settings::validate`]
+//! then. The other sections are specified at build time in
+//! `counter-config.toml` and checked by `build.rs` (see
+//! [`crate::build_config`]), each with a default if unset. They are constants
+//! in the image, so they aren't serialized. Only settings that depend on each
+//! other are checked here, and stop the counter at boot with `Invalid
+//! <NAME>`, before the tubes are driven.
+//!
+//! Apart from the defaults of the [`Settings`], this module is the only one
+//! reading [`crate::build_config`]. The few settings needed at compile time
+//! (the tube encodings for the panic handler, the number of additional sensor
+//! endpoints for the socket count) are constants here, the others are passed
+//! to the modules through the sections.
+
+use embassy_time::Duration;
+
+#[cfg(any(feature = "quiet-hours", feature = "dimming", feature = "clock-mode"))]
+use crate::clock::DailyPeriod;
+#[cfg(feature = "clock")]
+use crate::clock::TimeZone;
+#[cfg(feature = "dimming")]
+use crate::dimming::{DimmingConfig, FULL_BRIGHTNESS};
+#[cfg(not(feature = "coap"))]
+use crate::http::UpdateMethod;
+#[cfg(feature = "io-expander")]
+use crate::io_expander::Chip;
+#[cfg(feature = "rules")]
+use crate::rules::Rule;
+#[cfg(feature = "auto-repeat")]
+use crate::toggle_switch::AutoRepeat;
+use crate::{
+    display::{Overflow, Transition, ZeroStyle},
+    experiment::DisplayPolicy,
+    nixie::SymbolMap,
+    settings::Settings,
+    toggle_switch::ToggleSwitchConfig,
+};
+
+/// Number of tubes on the board. Every backend drives this many, and the
+/// digits shown are arrays of it.
+pub const TUBE_COUNT: usize = 2;
+
+/// Longest debounce time of the toggle switch. Longer ones would swallow
+/// quick presses. `build.rs` checks `DEBOUNCE_TIME` against it as well.
+#[cfg(feature = "console")]
+pub const MAX_DEBOUNCE_TIME: Duration = Duration::from_millis(500);
+
+/// Longest strike delay of a tube, longer ones would slow down the animations
+/// too much. `build.rs` checks the `*_TUBE_STRIKE_DELAY` settings against it
+/// as well.
+#[cfg(any(feature = "console", feature = "config-store"))]
+pub const MAX_STRIKE_DELAY: Duration = Duration::from_millis(100);
+
+/// Configuration of the counter.
+pub struct Config {
+    /// Network and endpoint settings, and the display settings that can be
+    /// changed at runtime
+    pub settings: Settings,
+    pub display: DisplayConfig,
+    pub input: InputConfig,
+    pub status: StatusConfig,
+    #[cfg(feature = "clock")]
+    pub clock: ClockConfig,
+    #[cfg(not(feature = "coap"))]
+    pub http: HttpConfig,
+    #[cfg(feature = "coap")]
+    pub coap: CoapConfig,
+    #[cfg(any(feature = "syslog", feature = "websocket", feature = "webhook"))]
+    pub services: ServicesConfig,
+    /// The automation rules, none by default
+    #[cfg(feature = "rules")]
+    pub rules: &'static [Rule],
+}
+
+impl Config {
+    /// Load the stored settings, and the configuration specified at build
+    /// time.
+    pub fn load() -> Self {
+        Self {
+            settings: Settings::load(),
+            display: DisplayConfig::from_build_env(),
+            input: InputConfig::from_build_env(),
+            status: StatusConfig::from_build_env(),
+            #[cfg(feature = "clock")]
+            clock: ClockConfig::from_build_env(),
+            #[cfg(not(feature = "coap"))]
+            http: HttpConfig::from_build_env(),
+            #[cfg(feature = "coap")]
+            coap: CoapConfig::from_build_env(),
+            #[cfg(any(feature = "syslog", feature = "websocket", feature = "webhook"))]
+            services: ServicesConfig::from_build_env(),
+            #[cfg(feature = "rules")]
+            rules: crate::build_config::RULES.unwrap_or(&[]),
+        }
+    }
+}
+
+/// Configuration of the tubes.
+pub struct DisplayConfig {
+    /// Encoding of the digits of the left tube
+    pub left_symbols: SymbolMap,
+    /// Encoding of the digits of the right tube
+    pub right_symbols: SymbolMap,
+    /// Strike delay of the left and the right tube, unless the stored
+    /// settings override it
+    pub strike_delays: [Duration; 2],
+    pub zero_style: ZeroStyle,
+    pub boot_animation: BootAnimation,
+    /// Number of parts of the firmware version shown after the boot
+    /// animation
+    pub boot_version_parts: usize,
+    /// Animation of count changes
+    pub transition: Transition,
+    /// How counts that don't fit on the tubes are shown
+    pub overflow: Overflow,
+    /// When a count changed by the toggle switch is shown
+    pub update_policy: DisplayPolicy,
+    /// Maximum brightness of the left and the right tube, in percent
+    #[cfg(feature = "dimming")]
+    pub tube_levels: [u8; 2],
+    /// Dimming schedule, unless the stored settings override it
+    #[cfg(feature = "dimming")]
+    pub dimming: DimmingConfig,
+    /// Period in which the tubes are blanked, unless the stored settings
+    /// override it
+    #[cfg(feature = "quiet-hours")]
+    pub quiet_hours: DailyPeriod,
+    /// Period in which the time is shown instead of a count of 0, if any
+    #[cfg(feature = "clock-mode")]
+    pub clock_mode_hours: Option<DailyPeriod>,
+}
+
+impl DisplayConfig {
+    fn from_build_env() -> Self {
+        Self {
+            left_symbols: LEFT_TUBE_SYMBOLS,
+            right_symbols: RIGHT_TUBE_SYMBOLS,
+            strike_delays: strike_delays_from_env(),
+            zero_style: zero_style_from_env(),
+            boot_animation: boot_animation_from_env(),
+            boot_version_parts: boot_version_parts_from_env(),
+            transition: count_transition_from_env(),
+            overflow: count_overflow_from_env(),
+            update_policy: display_update_policy_from_env(),
+            #[cfg(feature = "dimming")]
+            tube_levels: tube_brightness_from_env(),
+            #[cfg(feature = "dimming")]
+            dimming: dimming_config_from_env(),
+            #[cfg(feature = "quiet-hours")]
+            quiet_hours: quiet_hours_from_env(),
+            #[cfg(feature = "clock-mode")]
+            clock_mode_hours: crate::build_config::CLOCK_MODE_HOURS,
+        }
+    }
+
+    /// Return the strike delay of the left and the right tube, the stored
+    /// ones if set.
+    pub fn strike_delays(&self, settings: &Settings) -> [Duration; 2] {
+        let mut strike_delays = self.strike_delays;
+        for (delay, stored) in strike_delays.iter_mut().zip(settings.strike_delays) {
+            if let Some(millis) = stored {
+                *delay = Duration::from_millis(millis.into());
+            }
+        }
+        strike_delays
+    }
+}
+
+/// Configuration of the toggle switch.
+pub struct InputConfig {
+    pub toggle_switch: ToggleSwitchConfig,
+    /// Chip and I2C address of the I/O expander
+    #[cfg(feature = "io-expander")]
+    pub io_expander: (Chip, u8),
+    /// How long motion lights the blanked tubes
+    #[cfg(feature = "motion-wake")]
+    pub motion_wake_duration: Duration,
+}
+
+impl InputConfig {
+    fn from_build_env() -> Self {
+        let toggle_switch = toggle_switch_config_from_env();
+        // Presses shorter than the debounce time are ignored
+        assert!(
+            toggle_switch
+                .long_press_duration
+                .map_or(true, |duration| duration > toggle_switch.debounce_time),
+            "Invalid LONG_PRESS_DURATION"
+        );
+        Self {
+            toggle_switch,
+            #[cfg(feature = "io-expander")]
+            io_expander: (io_expander_chip_from_env(), io_expander_address_from_env()),
+            #[cfg(feature = "motion-wake")]
+            motion_wake_duration: motion_wake_duration_from_env(),
+        }
+    }
+}
+
+/// Configuration of the status LED.
+pub struct StatusConfig {
+    /// Sync lag after which the LED pattern is escalated, see
+    /// [`SyncLag`](crate::status::SyncLag)
+    pub sync_lag_threshold: Duration,
+}
+
+impl StatusConfig {
+    fn from_build_env() -> Self {
+        Self {
+            sync_lag_threshold: sync_lag_threshold_from_env(),
+        }
+    }
+}
+
+/// Configuration of the wall clock.
+#[cfg(feature = "clock")]
+#[derive(Debug, Copy, Clone)]
+pub struct ClockConfig {
+    /// Host name or IPv4 address of the SNTP server
+    pub ntp_server: &'static str,
+    pub time_zone: TimeZone,
+}
+
+#[cfg(feature = "clock")]
+impl ClockConfig {
+    fn from_build_env() -> Self {
+        Self {
+            ntp_server: crate::build_config::NTP_SERVER.unwrap_or("pool.ntp.org"),
+            time_zone: time_zone_from_env(),
+        }
+    }
+}
+
+/// Configuration of count updates over HTTP, besides the sensor endpoint in
+/// the [`Settings`].
+#[cfg(not(feature = "coap"))]
+#[derive(Debug, Copy, Clone)]
+pub struct HttpConfig {
+    pub method: UpdateMethod,
+    /// Value of the `Authorization` header, if any (e.g. `Bearer <token>`)
+    pub authorization: Option<&'static str>,
+    /// Content type, overriding the one of the payload format
+    pub content_type: Option<&'static str>,
+    /// Template of the request body, in which every `{count}` is replaced by
+    /// the count. Takes precedence over the format selected by the features.
+    pub payload_template: Option<&'static str>,
+    /// Metadata of the sensor, sent in the SpaceAPI v14 format
+    #[cfg(feature = "spaceapi-v14")]
+    pub sensor_metadata: SensorMetadata,
+    /// Additional sensor endpoints the count is sent to as well
+    pub extra_endpoints: &'static [Url],
+    /// HTTP proxy all requests are sent through, if any
+    pub proxy: Option<Url>,
+    /// Value of the `Proxy-Authorization` header, if any
+    pub proxy_authorization: Option<&'static str>,
+    /// URL of the SpaceAPI JSON the count is fetched from at boot
+    #[cfg(feature = "fetch-count")]
+    pub spaceapi_url: Url,
+    /// URL the open state of the space is sent to
+    #[cfg(feature = "space-state")]
+    pub state_endpoint: Url,
+}
+
+#[cfg(not(feature = "coap"))]
+impl HttpConfig {
+    fn from_build_env() -> Self {
+        Self {
+            method: crate::build_config::SPACEAPI_SENSOR_METHOD.unwrap_or(UpdateMethod::Put),
+            authorization: crate::build_config::SPACEAPI_SENSOR_AUTHORIZATION,
+            content_type: crate::build_config::SPACEAPI_SENSOR_CONTENT_TYPE,
+            payload_template: crate::build_config::SPACEAPI_SENSOR_PAYLOAD_TEMPLATE,
+            #[cfg(feature = "spaceapi-v14")]
+            sensor_metadata: SensorMetadata {
+                name: crate::build_config::SPACEAPI_SENSOR_NAME,
+                location: crate::build_config::SPACEAPI_SENSOR_LOCATION,
+                description: crate::build_config::SPACEAPI_SENSOR_DESCRIPTION,
+            },
+            extra_endpoints: SPACEAPI_SENSOR_EXTRA_ENDPOINTS,
+            proxy: crate::build_config::HTTP_PROXY,
+            proxy_authorization: crate::build_config::HTTP_PROXY_AUTHORIZATION,
+            #[cfg(feature = "fetch-count")]
+            spaceapi_url: crate::build_config::SPACEAPI_URL,
+            #[cfg(feature = "space-state")]
+            state_endpoint: crate::build_config::SPACEAPI_STATE_ENDPOINT,
+        }
+    }
+}
+
+/// Optional metadata of the sensor, see [`HttpConfig::sensor_metadata`].
+#[cfg(all(not(feature = "coap"), feature = "spaceapi-v14"))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SensorMetadata {
+    pub name: Option<&'static str>,
+    pub location: Option<&'static str>,
+    pub description: Option<&'static str>,
+}
+
+/// Additional sensor endpoints, selected through
+/// `SPACEAPI_SENSOR_EXTRA_ENDPOINTS` (none by default). Each of them needs
+/// its own socket, which are counted at compile time.
+#[cfg(not(feature = "coap"))]
+pub const SPACEAPI_SENSOR_EXTRA_ENDPOINTS: &[Url] =
+    match crate::build_config::SPACEAPI_SENSOR_EXTRA_ENDPOINTS {
+        Some(endpoints) => endpoints,
+        None => &[],
+    };
+
+/// Configuration of count updates over CoAP.
+#[cfg(feature = "coap")]
+#[derive(Debug, Copy, Clone)]
+pub struct CoapConfig {
+    /// Resource the count is sent to
+    pub endpoint: Url,
+}
+
+#[cfg(feature = "coap")]
+impl CoapConfig {
+    fn from_build_env() -> Self {
+        Self {
+            endpoint: crate::build_config::COAP_SENSOR_ENDPOINT,
+        }
+    }
+}
+
+/// Configuration of the optional services besides the count updates.
+#[cfg(any(feature = "syslog", feature = "websocket", feature = "webhook"))]
+#[derive(Debug, Copy, Clone)]
+pub struct ServicesConfig {
+    /// Host and port of the syslog server
+    #[cfg(feature = "syslog")]
+    pub syslog_server: (&'static str, u16),
+    /// URL of the WebSocket server the count is synchronized with
+    #[cfg(feature = "websocket")]
+    pub sync_websocket_url: Url,
+    /// URL notified when the count crosses one of the thresholds
+    #[cfg(feature = "webhook")]
+    pub webhook_url: Url,
+    #[cfg(feature = "webhook")]
+    pub webhook_thresholds: &'static [u8],
+}
+
+#[cfg(any(feature = "syslog", feature = "websocket", feature = "webhook"))]
+impl ServicesConfig {
+    fn from_build_env() -> Self {
+        Self {
+            #[cfg(feature = "syslog")]
+            syslog_server: syslog_server_from_env(),
+            #[cfg(feature = "websocket")]
+            sync_websocket_url: crate::build_config::SYNC_WEBSOCKET_URL,
+            #[cfg(feature = "webhook")]
+            webhook_url: crate::build_config::WEBHOOK_URL,
+            // By default, only the arrival of the first person is notified
+            #[cfg(feature = "webhook")]
+            webhook_thresholds: crate::build_config::WEBHOOK_THRESHOLDS.unwrap_or(&[1]),
+        }
+    }
+}
+
+/// Encoding of the digits of the tubes for BCD decoders other than the
+/// K155ID1, selected through `LEFT_TUBE_ENCODING` and `RIGHT_TUBE_ENCODING`
+/// (by default [`SymbolMap::IDENTITY`])
+pub const LEFT_TUBE_SYMBOLS: SymbolMap = match crate::build_config::LEFT_TUBE_ENCODING {
+    Some(symbols) => symbols,
+    None => SymbolMap::IDENTITY,
+};
+pub const RIGHT_TUBE_SYMBOLS: SymbolMap = match crate::build_config::RIGHT_TUBE_ENCODING {
+    Some(symbols) => symbols,
+    None => SymbolMap::IDENTITY,
+};
+
+/// A URL specified at build time, split into its parts by `build.rs`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// The whole URL
+    pub url: &'static str,
+    pub host: &'static str,
+    pub port: u16,
+    pub path: &'static str,
+}
+
+/// Animation shown on the tubes at startup, selected through
+/// `BOOT_ANIMATION`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BootAnimation {
+    /// Light every cathode in turn, i.e. the self-test (`sweep`, the default)
+    Sweep,
+    /// Spin the tubes like a slot machine (`slot`)
+    SlotMachine,
+    /// Show the major, minor and patch version of the firmware in turn
+    /// (`version`)
+    Version,
+}
+
+/// Return when a count changed by the toggle switch is shown, selected
+/// through `DISPLAY_UPDATE_POLICY`: `optimistic` (default) or `confirmed`.
+fn display_update_policy_from_env() -> DisplayPolicy {
+    crate::build_config::DISPLAY_UPDATE_POLICY.unwrap_or(DisplayPolicy::Optimistic)
+}
+
+/// Return the maximum brightness of the left and the right tube, selected
+/// through `TUBE_BRIGHTNESS` in percent (by default full brightness for
+/// both).
+#[cfg(feature = "dimming")]
+fn tube_brightness_from_env() -> [u8; 2] {
+    crate::build_config::TUBE_BRIGHTNESS.unwrap_or([FULL_BRIGHTNESS; 2])
+}
+
+/// Return the dimming schedule, selected through `DIMMING_HOURS` (default
+/// `22:00-07:00`), `WINTER_DIMMING_HOURS` (by default the same),
+/// `DIMMED_BRIGHTNESS` in percent (default 30) and `DAY_BRIGHTNESS` in
+/// percent (default 100).
+#[cfg(feature = "dimming")]
+fn dimming_config_from_env() -> DimmingConfig {
+    DimmingConfig {
+        hours: crate::build_config::DIMMING_HOURS.unwrap_or(DailyPeriod::new(22 * 60, 7 * 60)),
+        winter_hours: crate::build_config::WINTER_DIMMING_HOURS,
+        brightness: crate::build_config::DIMMED_BRIGHTNESS.unwrap_or(30),
+        day_brightness: crate::build_config::DAY_BRIGHTNESS.unwrap_or(FULL_BRIGHTNESS),
+    }
+}
+
+/// Return the quiet hours, selected through `QUIET_HOURS` (default
+/// `02:00-08:00`).
+#[cfg(feature = "quiet-hours")]
+fn quiet_hours_from_env() -> DailyPeriod {
+    crate::build_config::QUIET_HOURS.unwrap_or(DailyPeriod::new(2 * 60, 8 * 60))
+}
+
+/// Return the time zone, selected through `TIME_ZONE_OFFSET` in minutes east
+/// of UTC (default 60) and `TIME_ZONE_DST`: `eu` (default, the European
+/// summer time rule) or `none`.
+#[cfg(feature = "clock")]
+fn time_zone_from_env() -> TimeZone {
+    TimeZone {
+        offset: crate::build_config::TIME_ZONE_OFFSET.unwrap_or(60),
+        summer_time: crate::build_config::TIME_ZONE_DST.unwrap_or(true),
+    }
+}
+
+/// Return the host and port of the syslog server, selected through
+/// `SYSLOG_SERVER` (`host[:port]`, by default port 514).
+#[cfg(feature = "syslog")]
+fn syslog_server_from_env() -> (&'static str, u16) {
+    let (host, port) = crate::build_config::SYSLOG_SERVER;
+    (host, port.unwrap_or(514))
+}
+
+/// Return the animation selected through `COUNT_TRANSITION` for count
+/// changes: `cut` (default), `roll`, `slot` or (with `dimming`) `fade`.
+fn count_transition_from_env() -> Transition {
+    crate::build_config::COUNT_TRANSITION.unwrap_or(Transition::Cut)
+}
+
+/// Return how counts that don't fit on the tubes are shown, selected through
+/// `COUNT_OVERFLOW`: `cap` (default), `alternate` or `blink`.
+fn count_overflow_from_env() -> Overflow {
+    crate::build_config::COUNT_OVERFLOW.unwrap_or(Overflow::Cap)
+}
+
+/// Return the boot animation selected through `BOOT_ANIMATION`: `sweep`
+/// (default), `slot` or `version`.
+fn boot_animation_from_env() -> BootAnimation {
+    crate::build_config::BOOT_ANIMATION.unwrap_or(BootAnimation::Sweep)
+}
+
+/// Return how many parts of the firmware version are shown after the boot
+/// animation, selected through `BOOT_VERSION`: `short` (default, the major
+/// and minor version), `full` (also the patch version) or `none`.
+fn boot_version_parts_from_env() -> usize {
+    crate::build_config::BOOT_VERSION.unwrap_or(2)
+}
+
+/// Return the timing of the toggle switch input. The times are selected in
+/// milliseconds through `DEBOUNCE_TIME` (default 30), `LONG_PRESS_DURATION`
+/// (default 1500, used by `space-state` and `clock-mode`),
+/// `DOUBLE_PRESS_WINDOW` (default 400, with `double-press`) and
+/// `AUTO_REPEAT_DELAY` (default 1000, with `auto-repeat`), the repeat rate
+/// through `AUTO_REPEAT_RATE` in presses per second (1-20, default 5).
+fn toggle_switch_config_from_env() -> ToggleSwitchConfig {
+    let millis = |value: Option<u64>, default| Duration::from_millis(value.unwrap_or(default));
+    ToggleSwitchConfig {
+        debounce_time: millis(crate::build_config::DEBOUNCE_TIME, 30),
+        #[cfg(any(feature = "space-state", feature = "clock-mode", feature = "resync"))]
+        long_press_duration: Some(millis(crate::build_config::LONG_PRESS_DURATION, 1500)),
+        #[cfg(not(any(feature = "space-state", feature = "clock-mode", feature = "resync")))]
+        long_press_duration: None,
+        #[cfg(feature = "double-press")]
+        double_press_window: Some(millis(crate::build_config::DOUBLE_PRESS_WINDOW, 400)),
+        #[cfg(not(feature = "double-press"))]
+        double_press_window: None,
+        #[cfg(feature = "auto-repeat")]
+        auto_repeat: Some(AutoRepeat {
+            delay: millis(crate::build_config::AUTO_REPEAT_DELAY, 1000),
+            interval: Duration::from_millis(
+                1000 / crate::build_config::AUTO_REPEAT_RATE.unwrap_or(5),
+            ),
+        }),
+        #[cfg(not(feature = "auto-repeat"))]
+        auto_repeat: None,
+        detect_chords: cfg!(any(feature = "reset-chord", feature = "lock-mode")),
+    }
+}
+
+/// Return the sync lag threshold, selected through `SYNC_LAG_THRESHOLD` in
+/// seconds (by default 300).
+fn sync_lag_threshold_from_env() -> Duration {
+    Duration::from_secs(crate::build_config::SYNC_LAG_THRESHOLD.unwrap_or(300))
+}
+
+/// Return how long motion lights the blanked tubes, selected through
+/// `MOTION_WAKE_DURATION` in seconds (by default 60).
+#[cfg(feature = "motion-wake")]
+fn motion_wake_duration_from_env() -> Duration {
+    Duration::from_secs(crate::build_config::MOTION_WAKE_DURATION.unwrap_or(60))
+}
+
+/// Return the I/O expander chip selected through `IO_EXPANDER`: `pcf8574`
+/// (default, also for the PCF8574A) or `mcp23017`.
+#[cfg(feature = "io-expander")]
+fn io_expander_chip_from_env() -> Chip {
+    crate::build_config::IO_EXPANDER.unwrap_or(Chip::Pcf8574)
+}
+
+/// Return the I2C address of the I/O expander, selected through
+/// `IO_EXPANDER_ADDRESS` in hexadecimal (by default `20`, with all address
+/// pins low).
+#[cfg(feature = "io-expander")]
+fn io_expander_address_from_env() -> u8 {
+    crate::build_config::IO_EXPANDER_ADDRESS.unwrap_or(0x20)
+}
+
+/// Return the strike delay of the left and the right tube, selected in
+/// milliseconds through `LEFT_TUBE_STRIKE_DELAY` and
+/// `RIGHT_TUBE_STRIKE_DELAY` (default 0).
+fn strike_delays_from_env() -> [Duration; 2] {
+    [
+        crate::build_config::LEFT_TUBE_STRIKE_DELAY,
+        crate::build_config::RIGHT_TUBE_STRIKE_DELAY,
+    ]
+    .map(|millis| Duration::from_millis(millis.unwrap_or(0)))
+}
+
+/// Return how zeroes are shown, selected through `ZERO_STYLE`: `blank`
+/// (default), `zeroes` or `padded`.
+fn zero_style_from_env() -> ZeroStyle {
+    crate::build_config::ZERO_STYLE.unwrap_or(ZeroStyle::Blank)
+}
@@ -0,0 +1,521 @@
+//! Commands on the serial console.
+//!
+//! [`console_task`] reads commands from the USB serial/JTAG interface, one
+//! per line, and logs the result. That way, production testing can exercise
+//! the tubes without the toggle switch or a server:
+//!
+//! - `display <n>`: Show the number `n` (0-99 with two tubes, with leading
+//!   zeroes) until the next count is shown
+//! - `display off`: Turn the tubes off until the next count is shown
+//! - `selftest`: Light every cathode in turn, then show the count again
+//! - `debounce <ms>`: Change the debounce time of the toggle switch (until
+//!   the next reboot), to tune it for a different switch
+//! - `strike left|right <ms>`: Change the strike delay of a tube, to
+//!   calibrate an old tube while watching it (with `config-store`, `save`
+//!   stores it)
+//! - `lock on|off`: Lock or unlock the toggle switch (with `lock-mode`)
+//! - `edges [clear]`: Log the raw edges of the toggle switch recorded during
+//!   the last presses, and with `clear` forget them (with `edge-log`)
+//! - `status`: Log the sync lag, how long the server hasn't confirmed the
+//!   count
+//!
+//! With `config-store`, the console also edits the stored settings, so that
+//! a counter can be set up over USB without the provisioning portal:
+//!
+//! - `set <key> [value]`: Change a setting (see [`Key`]), or without a value
+//!   unset it, so that the one specified at build time is used
+//! - `show config`: Log the settings, as edited on the console
+//! - `save`: Store the edited settings in flash
+//!
+//! - `reboot`: Restart the counter, e.g. to connect with new settings
+
+use embedded_io_async::Read;
+use esp_hal::{usb_serial_jtag::UsbSerialJtagRx, Async};
+
+use embassy_time::Duration;
+
+#[cfg(all(feature = "config-store", feature = "provisioning"))]
+use crate::changelog::{Changelog, Source};
+#[cfg(feature = "config-store")]
+use crate::settings::Settings;
+use crate::{
+    config::TUBE_COUNT,
+    display::{self, CounterDisplay},
+    display_task::{DisplayCommand, DisplaySender},
+    status, toggle_switch, Tubes,
+};
+
+/// The largest number `display` shows
+const MAX_NUMBER: u32 = <Tubes as CounterDisplay<TUBE_COUNT>>::MAX;
+
+/// Maximum length of a command line, enough to set the endpoint URL
+const LINE_LEN: usize = 160;
+
+/// Longest value of a setting (the sensor endpoint URL)
+#[cfg(feature = "config-store")]
+const VALUE_LEN: usize = 128;
+
+/// A setting that can be changed on the console, by its key.
+#[cfg(feature = "config-store")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Key {
+    /// `wifi.ssid`
+    WifiSsid,
+    /// `wifi.password`
+    WifiPassword,
+    /// `endpoint`, the URL of the sensor endpoint
+    #[cfg(not(feature = "coap"))]
+    Endpoint,
+    /// `strike.left` and `strike.right`, the strike delay of a tube in
+    /// milliseconds
+    StrikeDelay(Side),
+    /// `dimming.brightness`, in percent
+    #[cfg(feature = "dimming")]
+    DimmedBrightness,
+    /// `dimming.hours`, as `HH:MM-HH:MM`
+    #[cfg(feature = "dimming")]
+    DimmingHours,
+    /// `quiet.hours`, as `HH:MM-HH:MM`
+    #[cfg(feature = "quiet-hours")]
+    QuietHours,
+}
+
+#[cfg(feature = "config-store")]
+impl Key {
+    const ALL: &'static [Self] = &[
+        Self::WifiSsid,
+        Self::WifiPassword,
+        #[cfg(not(feature = "coap"))]
+        Self::Endpoint,
+        Self::StrikeDelay(Side::Left),
+        Self::StrikeDelay(Side::Right),
+        #[cfg(feature = "dimming")]
+        Self::DimmedBrightness,
+        #[cfg(feature = "dimming")]
+        Self::DimmingHours,
+        #[cfg(feature = "quiet-hours")]
+        Self::QuietHours,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::WifiSsid => "wifi.ssid",
+            Self::WifiPassword => "wifi.password",
+            #[cfg(not(feature = "coap"))]
+            Self::Endpoint => "endpoint",
+            Self::StrikeDelay(Side::Left) => "strike.left",
+            Self::StrikeDelay(Side::Right) => "strike.right",
+            #[cfg(feature = "dimming")]
+            Self::DimmedBrightness => "dimming.brightness",
+            #[cfg(feature = "dimming")]
+            Self::DimmingHours => "dimming.hours",
+            #[cfg(feature = "quiet-hours")]
+            Self::QuietHours => "quiet.hours",
+        }
+    }
+
+    fn parse(key: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|k| k.as_str() == key)
+    }
+
+    /// Set the value of the setting, or unset it for an empty value.
+    fn set(self, settings: &mut Settings, value: &str) -> Result<(), &'static str> {
+        const TOO_LONG: &str = "Value too long";
+        match self {
+            Self::WifiSsid => settings.wifi_ssid = value.try_into().map_err(|_| TOO_LONG)?,
+            Self::WifiPassword => {
+                settings.wifi_password = value.try_into().map_err(|_| TOO_LONG)?
+            }
+            #[cfg(not(feature = "coap"))]
+            Self::Endpoint => settings.sensor_endpoint = value.try_into().map_err(|_| TOO_LONG)?,
+            Self::StrikeDelay(side) => {
+                settings.strike_delays[side as usize] = match value {
+                    "" => None,
+                    value => Some(
+                        value
+                            .parse()
+                            .map_err(|_| "Expected a time in milliseconds")?,
+                    ),
+                }
+            }
+            #[cfg(feature = "dimming")]
+            Self::DimmedBrightness => {
+                settings.dimmed_brightness = match value {
+                    "" => None,
+                    value => Some(value.parse().map_err(|_| "Expected a percentage")?),
+                }
+            }
+            #[cfg(feature = "dimming")]
+            Self::DimmingHours => {
+                settings.dimming_hours = Some(value)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.try_into().map_err(|_| "Expected HH:MM-HH:MM"))
+                    .transpose()?
+            }
+            #[cfg(feature = "quiet-hours")]
+            Self::QuietHours => {
+                settings.quiet_hours = Some(value)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.try_into().map_err(|_| "Expected HH:MM-HH:MM"))
+                    .transpose()?
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the value of the setting, without revealing the password.
+    fn log(self, settings: &Settings) {
+        let value: &str = match self {
+            Self::WifiSsid => &settings.wifi_ssid,
+            Self::WifiPassword if settings.wifi_password.is_empty() => "",
+            Self::WifiPassword => "********",
+            #[cfg(not(feature = "coap"))]
+            Self::Endpoint => &settings.sensor_endpoint,
+            Self::StrikeDelay(side) => {
+                match settings.strike_delays[side as usize] {
+                    Some(millis) => log::info!("  {} = {}", self.as_str(), millis),
+                    None => log::info!("  {} (unset)", self.as_str()),
+                }
+                return;
+            }
+            #[cfg(feature = "dimming")]
+            Self::DimmedBrightness => {
+                match settings.dimmed_brightness {
+                    Some(brightness) => log::info!("  {} = {}", self.as_str(), brightness),
+                    None => log::info!("  {} (unset)", self.as_str()),
+                }
+                return;
+            }
+            #[cfg(feature = "dimming")]
+            Self::DimmingHours => settings.dimming_hours.as_deref().unwrap_or_default(),
+            #[cfg(feature = "quiet-hours")]
+            Self::QuietHours => settings.quiet_hours.as_deref().unwrap_or_default(),
+        };
+        if value.is_empty() {
+            log::info!("  {} (unset)", self.as_str());
+        } else {
+            log::info!("  {} = {}", self.as_str(), value);
+        }
+    }
+}
+
+/// A tube, by its side.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Side {
+    Left = 0,
+    Right = 1,
+}
+
+impl Side {
+    fn parse(side: &str) -> Option<Self> {
+        match side {
+            "left" => Some(Self::Left),
+            "right" => Some(Self::Right),
+            _ => None,
+        }
+    }
+}
+
+/// The settings as edited on the console.
+#[cfg(feature = "config-store")]
+struct Config {
+    settings: Settings,
+    /// Whether the settings were changed since they were stored
+    unsaved: bool,
+}
+
+/// A command entered on the console.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    /// Show a number, or turn the tubes off for `None`
+    Display(Option<u32>),
+    /// Run the self-test of the tubes
+    Selftest,
+    /// Set the debounce time of the toggle switch, in milliseconds
+    Debounce(u16),
+    /// Set the strike delay of a tube, in milliseconds
+    Strike(Side, u16),
+    /// Lock or unlock the toggle switch
+    #[cfg(feature = "lock-mode")]
+    Lock(bool),
+    /// Log the recorded edges of the toggle switch, and whether to clear
+    /// them afterwards
+    #[cfg(feature = "edge-log")]
+    Edges(bool),
+    /// Log the sync lag
+    Status,
+    /// Change (or with an empty value unset) a setting
+    #[cfg(feature = "config-store")]
+    Set(Key, heapless::String<VALUE_LEN>),
+    /// Log the settings
+    #[cfg(feature = "config-store")]
+    ShowConfig,
+    /// Store the settings
+    #[cfg(feature = "config-store")]
+    Save,
+    /// Restart the counter
+    Reboot,
+}
+
+impl Command {
+    /// Parse a command line, or return why it is invalid.
+    fn parse(line: &str) -> Result<Self, &'static str> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some("display") => match words.next() {
+                Some("off") => Self::Display(None),
+                Some(number) => Self::Display(Some(
+                    number
+                        .parse()
+                        .ok()
+                        .filter(|number| *number <= MAX_NUMBER)
+                        .ok_or("Expected a number the tubes can show, or `off`")?,
+                )),
+                None => return Err("Usage: display <number|off>"),
+            },
+            Some("selftest") => Self::Selftest,
+            Some("debounce") => Self::Debounce(
+                words
+                    .next()
+                    .ok_or("Usage: debounce <ms>")?
+                    .parse()
+                    .map_err(|_| "Expected a time in milliseconds")?,
+            ),
+            Some("strike") => {
+                const USAGE: &str = "Usage: strike <left|right> <ms>";
+                let side = words.next().and_then(Side::parse).ok_or(USAGE)?;
+                let millis = words.next().ok_or(USAGE)?;
+                let millis = millis
+                    .parse()
+                    .map_err(|_| "Expected a time in milliseconds")?;
+                Self::Strike(side, millis)
+            }
+            #[cfg(feature = "config-store")]
+            Some("set") => {
+                let key = words.next().ok_or("Usage: set <key> [value]")?;
+                let key = Key::parse(key).ok_or("Unknown key, see `show config`")?;
+                // The value is the rest of the line, e.g. an SSID with spaces
+                let value = line
+                    .trim_start()
+                    .strip_prefix("set")
+                    .and_then(|rest| rest.trim_start().strip_prefix(key.as_str()))
+                    .unwrap_or_default()
+                    .trim();
+                let value = value.try_into().map_err(|_| "Value too long")?;
+                return Ok(Self::Set(key, value));
+            }
+            #[cfg(feature = "config-store")]
+            Some("show") if words.next() == Some("config") => Self::ShowConfig,
+            #[cfg(feature = "config-store")]
+            Some("save") => Self::Save,
+            Some("status") => Self::Status,
+            Some("reboot") => Self::Reboot,
+            #[cfg(feature = "lock-mode")]
+            Some("lock") => match words.next() {
+                Some("on") => Self::Lock(true),
+                Some("off") => Self::Lock(false),
+                _ => return Err("Usage: lock <on|off>"),
+            },
+            #[cfg(feature = "edge-log")]
+            Some("edges") => match words.next() {
+                None => Self::Edges(false),
+                Some("clear") => Self::Edges(true),
+                Some(_) => return Err("Usage: edges [clear]"),
+            },
+            _ => return Err("Unknown command"),
+        };
+        if words.next().is_some() {
+            return Err("Too many arguments");
+        }
+        Ok(command)
+    }
+
+    /// Run the command.
+    async fn run(
+        self,
+        display: DisplaySender,
+        #[cfg(feature = "config-store")] config: &mut Config,
+    ) {
+        let command = match self {
+            Self::Display(Some(number)) => {
+                DisplayCommand::ShowDigits(display::digits(number).map(Some))
+            }
+            Self::Display(None) => DisplayCommand::ShowDigits([None; TUBE_COUNT]),
+            Self::Selftest => DisplayCommand::Selftest,
+            Self::Debounce(millis) => {
+                let debounce_time = Duration::from_millis(millis.into());
+                if debounce_time > crate::config::MAX_DEBOUNCE_TIME {
+                    log::warn!(
+                        "Console: Debounce time too long, at most {} ms",
+                        crate::config::MAX_DEBOUNCE_TIME.as_millis()
+                    );
+                    return;
+                }
+                toggle_switch::set_config(toggle_switch::ToggleSwitchConfig {
+                    debounce_time,
+                    ..toggle_switch::config()
+                });
+                return;
+            }
+            Self::Strike(side, millis) => {
+                let strike_delay = Duration::from_millis(millis.into());
+                if strike_delay > crate::config::MAX_STRIKE_DELAY {
+                    log::warn!(
+                        "Console: Strike delay too long, at most {} ms",
+                        crate::config::MAX_STRIKE_DELAY.as_millis()
+                    );
+                    return;
+                }
+                #[cfg(feature = "config-store")]
+                {
+                    config.settings.strike_delays[side as usize] = Some(millis);
+                    config.unsaved = true;
+                    log::info!("Console: `save` to keep the strike delay");
+                }
+                DisplayCommand::StrikeDelay(side as usize, strike_delay)
+            }
+            #[cfg(feature = "lock-mode")]
+            Self::Lock(locked) => {
+                crate::lock::set_locked(locked);
+                return;
+            }
+            #[cfg(feature = "edge-log")]
+            Self::Edges(clear) => {
+                toggle_switch::log_edges(clear);
+                return;
+            }
+            Self::Status => {
+                log::info!("Console: Sync lag {} s", status::sync_lag().as_secs());
+                return;
+            }
+            #[cfg(feature = "config-store")]
+            Self::Set(key, value) => {
+                match key.set(&mut config.settings, &value) {
+                    Ok(()) => {
+                        config.unsaved = true;
+                        log::info!("Console: Changed {}, `save` to store it", key.as_str());
+                    }
+                    Err(e) => log::warn!("Console: {}", e),
+                }
+                return;
+            }
+            #[cfg(feature = "config-store")]
+            Self::ShowConfig => {
+                log::info!(
+                    "Console: Settings{}:",
+                    if config.unsaved { " (not saved)" } else { "" }
+                );
+                for key in Key::ALL {
+                    key.log(&config.settings);
+                }
+                return;
+            }
+            #[cfg(feature = "config-store")]
+            Self::Save => {
+                #[cfg(feature = "provisioning")]
+                let previous = Settings::load();
+                if config.settings.save().is_ok() {
+                    config.unsaved = false;
+                    #[cfg(feature = "provisioning")]
+                    Changelog::new().record(Source::Console, Some(&previous), &config.settings);
+                    log::info!("Console: Settings saved, `reboot` to use them");
+                }
+                return;
+            }
+            Self::Reboot => {
+                #[cfg(feature = "config-store")]
+                if config.unsaved {
+                    log::warn!("Console: Rebooting without saving the changed settings");
+                }
+                esp_hal::reset::software_reset();
+                return;
+            }
+        };
+        display.send(command).await;
+    }
+}
+
+/// Task: Read and run commands from the serial console
+#[embassy_executor::task]
+pub async fn console_task(
+    mut rx: UsbSerialJtagRx<'static, Async>,
+    display: DisplaySender,
+    #[cfg(feature = "config-store")] settings: Settings,
+) {
+    log::info!("Start console task");
+    #[cfg(feature = "config-store")]
+    let mut config = Config {
+        settings,
+        unsaved: false,
+    };
+    let mut line = heapless::Vec::<u8, LINE_LEN>::new();
+    let mut too_long = false;
+    let mut buf = [0; 16];
+    loop {
+        let len = match rx.read(&mut buf).await {
+            Ok(len) => len,
+            Err(never) => match never {},
+        };
+        for &byte in &buf[..len] {
+            if byte != b'\r' && byte != b'\n' {
+                // Echo printable characters, the monitor doesn't
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    esp_println::print!("{}", char::from(byte));
+                }
+                too_long |= line.push(byte).is_err();
+                continue;
+            }
+            if !line.is_empty() || too_long {
+                esp_println::println!();
+                run(
+                    &line,
+                    too_long,
+                    display,
+                    #[cfg(feature = "config-store")]
+                    &mut config,
+                )
+                .await;
+            }
+            line.clear();
+            too_long = false;
+        }
+    }
+}
+
+/// Run a command line, and log the result.
+async fn run(
+    line: &[u8],
+    too_long: bool,
+    display: DisplaySender,
+    #[cfg(feature = "config-store")] config: &mut Config,
+) {
+    if too_long {
+        log::warn!("Console: Line too long (max {} characters)", LINE_LEN);
+        return;
+    }
+    let Ok(line) = core::str::from_utf8(line) else {
+        log::warn!("Console: Invalid UTF-8");
+        return;
+    };
+    match Command::parse(line) {
+        Ok(command) => {
+            // Don't log the values, e.g. the WiFi password
+            #[cfg(feature = "config-store")]
+            if let Command::Set(key, _) = &command {
+                log::info!("Console: Set {}", key.as_str());
+            } else {
+                log::info!("Console: {:?}", command);
+            }
+            #[cfg(not(feature = "config-store"))]
+            log::info!("Console: {:?}", command);
+            command
+                .run(
+                    display,
+                    #[cfg(feature = "config-store")]
+                    config,
+                )
+                .await;
+        }
+        Err(e) => log::warn!("Console: {}", e),
+    }
+}
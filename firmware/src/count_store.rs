@@ -0,0 +1,77 @@
+//! The count, stored in flash so that it survives reboots.
+//!
+//! After a power blip during an event, the counter continues with the count
+//! it had instead of resetting it (and the server) to 0. [`count_store_task`]
+//! writes the count a while after it changed, so that a busy door causes at
+//! most one write per [`STORE_DELAY`]. At boot, a pending update in the
+//! journal and the count on the server (with `fetch-count`) take precedence,
+//! since they are at least as recent.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{flash_log::FlashLog, storage::Sector};
+
+/// Marker in the upper bytes of a word recording the count.
+const COUNT_MARKER: u32 = 0xC0C0_0000;
+
+/// How long after a change the count is written, taking over the changes
+/// meanwhile
+const STORE_DELAY: Duration = Duration::from_secs(5);
+
+/// The count in flash.
+///
+/// Like the pending update journal, the sector is a [`FlashLog`] of 32 bit
+/// words, each recording the count at that time.
+pub struct CountStore {
+    log: FlashLog,
+    /// The count in flash, if any
+    stored: Option<u8>,
+}
+
+impl CountStore {
+    /// Open the store, scanning it for the latest count.
+    pub fn new() -> Self {
+        let mut log = FlashLog::new(Sector::Count, "stored count");
+        let mut stored = None;
+        log.scan_words(|word| {
+            if word & 0xFFFF_FF00 == COUNT_MARKER {
+                stored = Some(word as u8);
+            }
+        });
+        Self { log, stored }
+    }
+
+    /// Return the count stored before the reboot.
+    pub fn stored(&self) -> Option<u8> {
+        self.stored
+    }
+
+    /// Store the count, unless it is already stored.
+    fn store(&mut self, count: u8) {
+        if self.stored == Some(count) {
+            return;
+        }
+        self.log.append_word(COUNT_MARKER | u32::from(count));
+        self.stored = Some(count);
+        log::debug!("Stored count {count}");
+    }
+}
+
+/// Task: Store the count in flash, at most once per [`STORE_DELAY`]
+#[embassy_executor::task]
+pub async fn count_store_task(mut store: CountStore, count: &'static Signal<NoopRawMutex, u8>) {
+    log::info!("Start count store task");
+    loop {
+        let mut latest = count.wait().await;
+        if store.stored == Some(latest) {
+            continue;
+        }
+        let deadline = Instant::now() + STORE_DELAY;
+        while let Either::First(changed) = select(count.wait(), Timer::at(deadline)).await {
+            latest = changed;
+        }
+        store.store(latest);
+    }
+}
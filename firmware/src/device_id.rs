@@ -0,0 +1,41 @@
+//! Stable identity of the device.
+//!
+//! The device ID is the base MAC address burned into the efuses, so it stays
+//! the same across reflashing. It's sent with every request, so that backends
+//! can tell several counters apart.
+
+use core::fmt;
+
+use esp_hal::efuse::Efuse;
+
+/// ID of a device, displayed as 12 lowercase hex digits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceId(pub [u8; 6]);
+
+impl DeviceId {
+    /// Read the ID of this device from the efuses.
+    pub fn read() -> Self {
+        Self(Efuse::read_base_mac_address())
+    }
+
+    /// Format the `User-Agent` header value of requests, containing the
+    /// firmware version and the ID.
+    #[cfg(any(not(feature = "coap"), feature = "websocket"))]
+    pub fn user_agent(self) -> heapless::String<48> {
+        let mut user_agent = heapless::String::new();
+        let _ = fmt::write(
+            &mut user_agent,
+            format_args!("nixie-counter/{} ({self})", crate::VERSION),
+        );
+        user_agent
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! Dimming of the tubes in the evening and at night.
+//!
+//! The brightness follows two profiles. During the period configured in
+//! `DIMMING_HOURS` (default `22:00-07:00`, local time), the evening profile
+//! dims the tubes to the brightness in `DIMMED_BRIGHTNESS` (percent, default
+//! 30). Otherwise, the day profile shows them at `DAY_BRIGHTNESS` (percent,
+//! default 100). Since it gets dark earlier in winter, the evening profile
+//! can start at other times while summer time isn't in effect, configured in
+//! `WINTER_DIMMING_HOURS` (by default the same as `DIMMING_HOURS`). Pressing
+//! the toggle switch switches to the day profile until the period ends.
+//! Stored settings can override the period (for the whole year) and the
+//! dimmed brightness.
+//!
+//! There is no light sensor, so the schedule is the only control of the
+//! brightness.
+//!
+//! Tubes that glow brighter than others can be matched to them through
+//! `TUBE_BRIGHTNESS`, the maximum brightness of the left and the right tube
+//! (percent, e.g. `100,80`, default full brightness for both).
+//!
+//! Only the backends that switch the tubes on and off continuously can dim
+//! them, so the feature needs `multiplexed` (or `seven-segment`, whose
+//! module dims all digits at once).
+
+use crate::clock::{self, DailyPeriod, LocalTime};
+
+/// Full brightness, in percent
+pub const FULL_BRIGHTNESS: u8 = 100;
+
+/// The dimming schedule configured at build time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DimmingConfig {
+    /// Period of the evening profile
+    pub hours: DailyPeriod,
+    /// Period of the evening profile while summer time isn't in effect, if
+    /// it differs
+    pub winter_hours: Option<DailyPeriod>,
+    /// Brightness of the evening profile, in percent
+    pub brightness: u8,
+    /// Brightness of the day profile, in percent
+    pub day_brightness: u8,
+}
+
+/// A brightness profile.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Profile {
+    Day,
+    Evening,
+}
+
+impl Profile {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Evening => "evening",
+        }
+    }
+}
+
+/// State of the dimming schedule.
+pub struct Dimming {
+    period: DailyPeriod,
+    /// Period while summer time isn't in effect, if it differs
+    winter_period: Option<DailyPeriod>,
+    /// Brightness of the evening and the day profile, in percent
+    dimmed_brightness: u8,
+    day_brightness: u8,
+    /// The current profile, `None` until the first update
+    profile: Option<Profile>,
+    /// Whether the day profile was restored by a press during the current
+    /// period
+    overridden: bool,
+}
+
+impl Dimming {
+    /// Create a new instance with the stored schedule if any, otherwise the
+    /// configured one.
+    pub fn new(
+        config: DimmingConfig,
+        stored_hours: Option<&str>,
+        stored_brightness: Option<u8>,
+    ) -> Self {
+        let stored_brightness = stored_brightness.filter(|brightness| {
+            let valid = *brightness <= FULL_BRIGHTNESS;
+            if !valid {
+                log::warn!(
+                    "Invalid stored dimmed brightness {} %, ignoring it",
+                    brightness
+                );
+            }
+            valid
+        });
+        let dimmed_brightness = stored_brightness.unwrap_or(config.brightness);
+        let period = stored_hours.and_then(|stored| {
+            let period = DailyPeriod::parse(stored);
+            if period.is_none() {
+                log::warn!("Invalid stored dimming hours \"{}\", ignoring them", stored);
+            }
+            period
+        });
+        Self {
+            // Stored hours apply all year
+            winter_period: config.winter_hours.filter(|_| period.is_none()),
+            period: period.unwrap_or(config.hours),
+            dimmed_brightness,
+            day_brightness: config.day_brightness,
+            profile: None,
+            overridden: false,
+        }
+    }
+
+    /// Check the current time. Returns the new brightness in percent, if the
+    /// profile changed.
+    ///
+    /// As long as the clock isn't synchronized, the day profile is used.
+    pub fn update(&mut self) -> Option<u8> {
+        let in_period = clock::local_time().is_some_and(|time| self.in_period(&time));
+        if !in_period {
+            self.overridden = false;
+        }
+        let profile = if in_period && !self.overridden {
+            Profile::Evening
+        } else {
+            Profile::Day
+        };
+        if self.profile == Some(profile) {
+            return None;
+        }
+        self.profile = Some(profile);
+        let brightness = match profile {
+            Profile::Day => self.day_brightness,
+            Profile::Evening => self.dimmed_brightness,
+        };
+        log::info!(
+            "Switching to the {} profile, {} %",
+            profile.as_str(),
+            brightness
+        );
+        Some(brightness)
+    }
+
+    /// Return whether the time lies in the period of the evening profile of
+    /// the season.
+    fn in_period(&self, time: &LocalTime) -> bool {
+        let period = match self.winter_period {
+            Some(winter_period) if !time.summer_time => winter_period,
+            _ => self.period,
+        };
+        period.contains(time.minute_of_day())
+    }
+
+    /// Switch to the day profile until the period ends, e.g. after a press.
+    /// Returns its brightness, if the evening profile was active.
+    pub fn override_brightness(&mut self) -> Option<u8> {
+        if self.profile != Some(Profile::Evening) {
+            return None;
+        }
+        log::info!("Day profile restored during the dimming period");
+        self.profile = Some(Profile::Day);
+        self.overridden = true;
+        Some(self.day_brightness)
+    }
+}
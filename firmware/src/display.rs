@@ -0,0 +1,685 @@
+//! Showing the count, independent of the backend.
+//!
+//! [`CounterDisplay`] is everything the application shows, from the count
+//! and its transitions to the boot animation. A backend only implements how
+//! digits are lit (and dimmed), the animations are provided on top of that,
+//! so that they work the same with nixie tubes
+//! ([`NixieTubeArray`](crate::nixie::NixieTubeArray)) and other displays.
+
+#[cfg(feature = "anti-poisoning")]
+use embassy_time::Instant;
+use embassy_time::{Duration, Timer};
+
+/// Number of steps of a fade, see [`CounterDisplay::fade_to`]
+#[cfg(feature = "dimming")]
+const FADE_STEPS: u32 = 10;
+
+/// A display of `N` digits showing the people now present count.
+///
+/// The application shows the count through this trait, so that the nixie
+/// tubes can be substituted by other backends (e.g. LEDs or a simulator).
+/// The first digit is the leftmost (most significant) one.
+///
+/// While the display is blanked, numbers passed to
+/// [`show_number`](Self::show_number) are only remembered, and shown once
+/// it's no longer blanked.
+pub trait CounterDisplay<const N: usize> {
+    /// The largest number the display can show
+    const MAX: u32 = 10u32.pow(N as u32) - 1;
+
+    /// Return what the display shows, kept by the implementation for the
+    /// provided methods.
+    fn state(&self) -> &DisplayState;
+
+    /// Return what the display shows, to change it.
+    fn state_mut(&mut self) -> &mut DisplayState;
+
+    /// Show a digit (or nothing, for `None`) on every position, from left to
+    /// right.
+    ///
+    /// Unlike with [`show_number`](Self::show_number), the digits are not
+    /// remembered, and are shown even while blanked.
+    fn show_digits(&mut self, digits: [Option<u8>; N]);
+
+    /// Light the cathode with the specified index on every position,
+    /// ignoring any mapping of the digits to the cathodes. Displays without
+    /// cathodes show the digits instead.
+    fn show_cathodes(&mut self, cathodes: [u8; N]) {
+        self.show_digits(cathodes.map(Some));
+    }
+
+    /// Turn off the display.
+    fn off(&mut self) {
+        self.show_digits([None; N]);
+    }
+
+    /// Return the minimal time a digit must be shown during animations, so
+    /// that it reliably lights up (by default, `delay`).
+    fn frame_delay(&self, delay: Duration) -> Duration {
+        delay
+    }
+
+    /// Set the brightness of the selected digits in percent, without
+    /// remembering it.
+    #[cfg(feature = "dimming")]
+    fn apply_brightness(&mut self, selected: [bool; N], percent: u8);
+
+    /// Set the maximum brightness of every digit in percent (by default, 100
+    /// for all), if the display can dim them individually.
+    #[cfg(feature = "dimming")]
+    fn set_tube_levels(&mut self, _levels: [u8; N]) {}
+
+    /// Change the strike delay of the digit at `index` (counted from the
+    /// left), if it has one.
+    #[cfg(feature = "console")]
+    fn set_strike_delay(&mut self, _index: usize, _delay: Duration) {}
+
+    /// Show the count. Counts that don't fit on the display are capped to the
+    /// largest one it can show.
+    fn show(&mut self, count: u8) {
+        self.show_number(u32::from(count).min(Self::MAX));
+    }
+
+    /// Select how zeroes are shown (by default, [`ZeroStyle::Blank`]), and
+    /// show the last number again.
+    fn set_zero_style(&mut self, zero_style: ZeroStyle) {
+        self.state_mut().zero_style = zero_style;
+        self.show_number(self.state().value);
+    }
+
+    /// Show a number. Only the lower digits are shown if it has more digits
+    /// than the display.
+    ///
+    /// Whether leading zeroes and the number 0 are shown depends on the
+    /// [`ZeroStyle`]. If you need to show other zeroes, use
+    /// [`show_digits`](Self::show_digits).
+    fn show_number(&mut self, val: u32) {
+        let state = self.state_mut();
+        state.value = val;
+        if state.blanked {
+            self.off();
+            return;
+        }
+        let digits = state.zero_style.visible_digits(val);
+        self.show_digits(digits);
+    }
+
+    /// Change the number shown to `val` (like with
+    /// [`show_number`](Self::show_number)) with the specified animation,
+    /// with [`delay`] between each step (or the frame delay, if longer).
+    ///
+    /// Only the digits that change are animated. The digits spin upwards if
+    /// the number increases, and downwards otherwise. Digits that are turned
+    /// on or off (leading zeroes) switch right away, unless they are faded.
+    async fn transition_to(&mut self, val: u32, transition: Transition, delay: Duration) {
+        let DisplayState {
+            blanked,
+            zero_style,
+            value: old,
+            ..
+        } = *self.state();
+        if blanked || transition == Transition::Cut || old == val {
+            self.show_number(val);
+            return;
+        }
+        let delay = self.frame_delay(delay);
+        let up = val > old;
+        let old_digits = zero_style.visible_digits::<N>(old);
+        let new_digits = zero_style.visible_digits::<N>(val);
+        #[cfg(feature = "dimming")]
+        if transition == Transition::Fade {
+            let changed = core::array::from_fn(|i| old_digits[i] != new_digits[i]);
+            let brightness = self.state().brightness;
+            fade(self, changed, brightness, 0, delay).await;
+            self.show_number(val);
+            fade(self, changed, 0, brightness, delay).await;
+            return;
+        }
+        let spins: [Spin; N] =
+            core::array::from_fn(|i| Spin::new(old_digits[i], new_digits[i], transition, up));
+        animate(self, spins, delay).await;
+        self.show_number(val);
+    }
+
+    /// Roll the digits from `from` to `to`, both with leading zeroes like
+    /// with [`show_padded`](Self::show_padded), e.g. for the time. The digits
+    /// always roll upwards, so that 59 rolls over to 00.
+    ///
+    /// Like with [`show_padded`](Self::show_padded), the number isn't
+    /// remembered.
+    #[cfg(feature = "clock-mode")]
+    async fn roll_padded(&mut self, from: u32, to: u32, delay: Duration) {
+        let delay = self.frame_delay(delay);
+        let (from_digits, to_digits) = (digits::<N>(from), digits::<N>(to));
+        let spins: [Spin; N] = core::array::from_fn(|i| {
+            Spin::new(
+                Some(from_digits[i]),
+                Some(to_digits[i]),
+                Transition::Roll,
+                true,
+            )
+        });
+        animate(self, spins, delay).await;
+        self.show_padded(to);
+    }
+
+    /// Count from the number last shown to `val` (like with
+    /// [`show_number`](Self::show_number)), showing every number in between
+    /// for `delay` (or the frame delay, if longer).
+    ///
+    /// While blanked, `val` is just remembered.
+    async fn count_to(&mut self, val: u32, delay: Duration) {
+        if self.state().blanked {
+            self.show_number(val);
+            return;
+        }
+        let delay = self.frame_delay(delay);
+        while self.state().value != val {
+            Timer::after(delay).await;
+            let value = self.state().value;
+            let next = if val > value { value + 1 } else { value - 1 };
+            self.show_number(next);
+        }
+    }
+
+    /// Keep showing `val`, even if it has more digits than the display, as
+    /// selected by `overflow`:
+    ///
+    /// - [`Overflow::Cap`]: Show the largest number that fits instead
+    /// - [`Overflow::Alternate`]: Show the upper digits and the lower digits
+    ///   (with leading zeroes) for [`delay`] each, followed by a short pause
+    /// - [`Overflow::Blink`]: Blink the largest number that fits, with
+    ///   [`delay`] between turning the display on and off
+    ///
+    /// Numbers that fit are just shown. Never returns, stop it by dropping
+    /// the future (e.g. with `select`). Afterwards, the display may show only
+    /// a part of the number, until the next one is shown.
+    async fn show_overflowing(&mut self, val: u32, overflow: Overflow, delay: Duration) -> ! {
+        self.show_number(val.min(Self::MAX));
+        if val > Self::MAX && !self.state().blanked {
+            let delay = self.frame_delay(delay);
+            match overflow {
+                Overflow::Cap => {}
+                Overflow::Alternate => loop {
+                    self.show_digits(ZeroStyle::Blank.visible_digits(val / (Self::MAX + 1)));
+                    Timer::after(delay).await;
+                    self.show_digits(digits(val).map(Some));
+                    Timer::after(delay).await;
+                    self.off();
+                    Timer::after(delay / 4).await;
+                },
+                Overflow::Blink => loop {
+                    Timer::after(delay).await;
+                    self.off();
+                    Timer::after(delay).await;
+                    self.show_number(Self::MAX);
+                },
+            }
+        }
+        // Nothing to animate
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+
+    /// Flash a number the specified number of times, with [`delay`] between
+    /// turning the display on and off. Afterwards, the number is shown like
+    /// with [`show_number`](Self::show_number).
+    ///
+    /// Unlike there, leading zeroes are lit while flashing, so that even the
+    /// number 0 is visible.
+    #[cfg(any(
+        feature = "doorbell",
+        feature = "space-state",
+        feature = "reset-chord",
+        feature = "lock-mode",
+        not(feature = "offline-counting")
+    ))]
+    async fn flash(&mut self, val: u32, times: usize, delay: Duration) {
+        let delay = self.frame_delay(delay);
+        for _ in 0..times {
+            self.show_digits(digits(val).map(Some));
+            Timer::after(delay).await;
+            self.off();
+            Timer::after(delay).await;
+        }
+        self.show_number(val);
+    }
+
+    /// Flash a code the specified number of times, with [`delay`] between
+    /// turning the display on and off, like [`flash`](Self::flash).
+    /// Afterwards, the number last passed to
+    /// [`show_number`](Self::show_number) is shown again.
+    ///
+    /// Nothing is lit while blanked.
+    #[cfg(feature = "error-codes")]
+    async fn flash_code(&mut self, code: u32, times: usize, delay: Duration) {
+        if self.state().blanked {
+            return;
+        }
+        let delay = self.frame_delay(delay);
+        for _ in 0..times {
+            self.show_digits(digits(code).map(Some));
+            Timer::after(delay).await;
+            self.off();
+            Timer::after(delay).await;
+        }
+        self.show_number(self.state().value);
+    }
+
+    /// Scroll a sequence of digits (`None` for a gap) across the display,
+    /// from right to left, with [`delay`] between each step (or the frame
+    /// delay, if longer). Afterwards, the number last passed to
+    /// [`show_number`](Self::show_number) is shown again.
+    ///
+    /// Leading zeroes are lit like any other digit. Nothing is lit while
+    /// blanked.
+    #[cfg(feature = "show-ip")]
+    async fn scroll(&mut self, digits: &[Option<u8>], delay: Duration) {
+        if self.state().blanked {
+            return;
+        }
+        let delay = self.frame_delay(delay);
+        // The sequence enters on the right and leaves on the left
+        for step in 0..digits.len() + N - 1 {
+            self.show_digits(core::array::from_fn(|i| {
+                (step + i + 1)
+                    .checked_sub(N)
+                    .and_then(|index| digits.get(index))
+                    .copied()
+                    .flatten()
+            }));
+            Timer::after(delay).await;
+        }
+        self.show_number(self.state().value);
+    }
+
+    /// Return whether the display is blanked.
+    #[cfg(any(
+        feature = "clock-mode",
+        feature = "room-temperature",
+        feature = "screensaver"
+    ))]
+    fn is_blanked(&self) -> bool {
+        self.state().blanked
+    }
+
+    /// Blank the display, or show the last number again.
+    #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+    fn set_blanked(&mut self, blanked: bool) {
+        self.state_mut().blanked = blanked;
+        self.show_number(self.state().value);
+    }
+
+    /// Light the cathodes of all digits in turn for the specified duration,
+    /// with [`delay`] between each step (or the frame delay, if longer), like
+    /// a slot machine. Afterwards, the number last passed to
+    /// [`show_number`](Self::show_number) is shown again.
+    ///
+    /// Cathodes that stay unlit for long periods get poisoned and no longer
+    /// glow evenly. Since the counter mostly shows low numbers, lighting all
+    /// cathodes regularly prevents that. Nothing is lit while blanked.
+    #[cfg(feature = "anti-poisoning")]
+    async fn cycle_cathodes(&mut self, duration: Duration, delay: Duration) {
+        if self.state().blanked {
+            return;
+        }
+        let delay = self.frame_delay(delay);
+        let end = Instant::now() + duration;
+        let mut cathode = 0;
+        while Instant::now() < end {
+            // Neighbouring digits count in opposite directions
+            self.show_cathodes(core::array::from_fn(|i| {
+                if i % 2 == 0 {
+                    cathode
+                } else {
+                    9 - cathode
+                }
+            }));
+            Timer::after(delay).await;
+            cathode = (cathode + 1) % 10;
+        }
+        self.show_number(self.state().value);
+    }
+
+    /// Test the wiring of every digit, holding every step for `delay` (or the
+    /// frame delay, if longer), and log what should be lit in each step.
+    /// Afterwards, the number last passed to
+    /// [`show_number`](Self::show_number) is shown again. Displays that
+    /// can't be tested any better just run the [`selftest`](Self::selftest).
+    #[cfg(feature = "diagnostics")]
+    async fn diagnose(&mut self, delay: Duration) {
+        self.selftest(delay).await;
+        self.show_number(self.state().value);
+    }
+
+    /// Spin all digits like the reels of a slot machine, with [`delay`]
+    /// between each step (or the frame delay, if longer). The digits stop one
+    /// after the other from the left, on 0, and are turned off afterwards.
+    async fn spin(&mut self, delay: Duration) {
+        let delay = self.frame_delay(delay);
+        // Every digit spins one turn longer than its left neighbour
+        let steps = |i: usize| 20 + 10 * i;
+        for step in 0..=steps(N - 1) {
+            self.show_digits(core::array::from_fn(|i| {
+                // Neighbouring digits differ while spinning
+                let left = steps(i).saturating_sub(step);
+                Some(if left == 0 {
+                    0
+                } else {
+                    ((left + 3 * i) % 10) as u8
+                })
+            }));
+            Timer::after(delay).await;
+        }
+        self.off();
+    }
+
+    /// Show numbers one after the other, with leading zeroes, each for
+    /// `delay` (or the frame delay, if longer) followed by a short pause. The
+    /// display is off afterwards.
+    async fn show_each(&mut self, numbers: impl IntoIterator<Item = u32>, delay: Duration) {
+        let delay = self.frame_delay(delay);
+        for number in numbers {
+            self.show_padded(number);
+            Timer::after(delay).await;
+            self.off();
+            Timer::after(delay / 4).await;
+        }
+    }
+
+    /// Show a number with leading zeroes, regardless of the [`ZeroStyle`].
+    ///
+    /// Unlike with [`show_number`](Self::show_number), the number is not
+    /// remembered, and is shown even while blanked.
+    fn show_padded(&mut self, val: u32) {
+        self.show_digits(digits(val).map(Some));
+    }
+
+    /// Fade to the brightness `percent` over the specified duration. The
+    /// steps are gamma corrected, so that the brightness seems to change
+    /// evenly. If the future is dropped, the brightness is set right away.
+    #[cfg(feature = "dimming")]
+    async fn fade_to(&mut self, percent: u8, duration: Duration) {
+        let from = self.state().brightness;
+        self.state_mut().brightness = percent;
+        fade(self, [true; N], from, percent, duration / FADE_STEPS).await;
+    }
+
+    /// Fade out and blank the display, or show the last number again and
+    /// fade in, over the specified duration each (see
+    /// [`fade_to`](Self::fade_to)).
+    #[cfg(all(
+        any(feature = "quiet-hours", feature = "space-state", feature = "rules"),
+        feature = "dimming"
+    ))]
+    async fn fade_blanked(&mut self, blanked: bool, duration: Duration) {
+        let DisplayState {
+            blanked: was_blanked,
+            brightness,
+            ..
+        } = *self.state();
+        let step_delay = duration / FADE_STEPS;
+        if blanked == was_blanked {
+            self.set_blanked(blanked);
+        } else if blanked {
+            // Remembered right away, the display goes off once faded out
+            self.state_mut().blanked = true;
+            fade(self, [true; N], brightness, 0, step_delay).await;
+            self.off();
+            self.apply_brightness([true; N], brightness);
+        } else {
+            self.apply_brightness([true; N], 0);
+            self.set_blanked(false);
+            fade(self, [true; N], 0, brightness, step_delay).await;
+        }
+    }
+
+    /// Light every cathode on all digits, with [`delay`] between each cathode
+    /// (or the frame delay, if longer), then turn the display off.
+    async fn selftest(&mut self, delay: Duration) {
+        let delay = self.frame_delay(delay);
+        for cathode in 0..=9 {
+            self.show_cathodes([cathode; N]);
+            Timer::after(delay).await;
+        }
+        self.off();
+    }
+}
+
+/// What a [`CounterDisplay`] shows, and how.
+#[derive(Debug, Copy, Clone)]
+pub struct DisplayState {
+    blanked: bool,
+    zero_style: ZeroStyle,
+    /// The number last passed to `show_number`
+    value: u32,
+    /// The brightness last set, in percent
+    #[cfg(feature = "dimming")]
+    brightness: u8,
+}
+
+impl DisplayState {
+    /// Nothing shown yet, at full brightness.
+    pub const fn new() -> Self {
+        Self {
+            blanked: false,
+            zero_style: ZeroStyle::Blank,
+            value: 0,
+            #[cfg(feature = "dimming")]
+            brightness: 100,
+        }
+    }
+
+    /// Return the number last passed to
+    /// [`show_number`](CounterDisplay::show_number).
+    #[cfg(feature = "diagnostics")]
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// Return the brightness last set, in percent.
+    #[cfg(feature = "dimming")]
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+}
+
+/// Show the steps of the spins, each for `delay`.
+async fn animate<D: CounterDisplay<N> + ?Sized, const N: usize>(
+    display: &mut D,
+    spins: [Spin; N],
+    delay: Duration,
+) {
+    let steps = spins.iter().map(|spin| spin.steps).max().unwrap_or(0);
+    for step in 1..=steps {
+        display.show_digits(spins.each_ref().map(|spin| spin.digit(step)));
+        Timer::after(delay).await;
+    }
+}
+
+/// Change the brightness of the selected digits from `from` to `to` in gamma
+/// corrected steps, with `step_delay` between each step. If the future is
+/// dropped, the remembered brightness and number are shown right away.
+#[cfg(feature = "dimming")]
+async fn fade<D: CounterDisplay<N> + ?Sized, const N: usize>(
+    display: &mut D,
+    selected: [bool; N],
+    from: u8,
+    to: u8,
+    step_delay: Duration,
+) {
+    let mut fade = Fade {
+        display,
+        finished: false,
+    };
+    let (from, to) = (perceived(from), perceived(to));
+    for step in 1..=FADE_STEPS {
+        Timer::after(step_delay).await;
+        let level = if to > from {
+            from + (to - from) * step / FADE_STEPS
+        } else {
+            from - (from - to) * step / FADE_STEPS
+        };
+        fade.display.apply_brightness(selected, gamma(level));
+    }
+    fade.finished = true;
+}
+
+/// How numbers with more digits than the display are shown, see
+/// [`CounterDisplay::show_overflowing`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    /// Show the largest number that fits
+    Cap,
+    /// Alternate between the upper and the lower digits
+    Alternate,
+    /// Blink the largest number that fits
+    Blink,
+}
+
+/// How zeroes are shown by [`CounterDisplay::show_number`], see
+/// [`CounterDisplay::set_zero_style`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZeroStyle {
+    /// Leading zeroes are off, and so are all digits for the number 0
+    Blank,
+    /// Leading zeroes are off, but the number 0 is shown with all digits
+    /// (e.g. `00`)
+    Zeroes,
+    /// All digits are always lit, with leading zeroes (e.g. `05`)
+    Padded,
+}
+
+impl ZeroStyle {
+    /// Return the digits of a number that are lit with this style.
+    fn visible_digits<const N: usize>(self, val: u32) -> [Option<u8>; N] {
+        match self {
+            Self::Zeroes if val == 0 => [Some(0); N],
+            Self::Blank | Self::Zeroes => {
+                let mut leading = true;
+                digits(val).map(|digit| {
+                    leading &= digit == 0;
+                    (!leading).then_some(digit)
+                })
+            }
+            Self::Padded => digits(val).map(Some),
+        }
+    }
+}
+
+/// Animation when changing the number shown, see
+/// [`CounterDisplay::transition_to`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Transition {
+    /// Switch to the new digits immediately
+    Cut,
+    /// Count through the digits between the old and the new one
+    Roll,
+    /// Spin through all digits once before settling on the new one, like a
+    /// slot machine
+    SlotMachine,
+    /// Fade the old digits out and the new ones in
+    #[cfg(feature = "dimming")]
+    Fade,
+}
+
+/// Restores the brightness and the number shown if a fade is interrupted.
+#[cfg(feature = "dimming")]
+struct Fade<'a, D: CounterDisplay<N> + ?Sized, const N: usize> {
+    display: &'a mut D,
+    finished: bool,
+}
+
+#[cfg(feature = "dimming")]
+impl<D: CounterDisplay<N> + ?Sized, const N: usize> Drop for Fade<'_, D, N> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let DisplayState {
+                brightness, value, ..
+            } = *self.display.state();
+            self.display.apply_brightness([true; N], brightness);
+            self.display.show_number(value);
+        }
+    }
+}
+
+/// Return the brightness in percent as perceived by the eye, for the duty
+/// cycle `percent`. The perceived brightness is roughly proportional to the
+/// square root of the light emitted, i.e. a gamma of 2.
+#[cfg(feature = "dimming")]
+fn perceived(percent: u8) -> u32 {
+    let light = u32::from(percent.min(100)) * 100;
+    (0..=100).rev().find(|p| p * p <= light).unwrap_or(0)
+}
+
+/// Return the duty cycle in percent for the perceived brightness `level`,
+/// the inverse of [`perceived`].
+#[cfg(feature = "dimming")]
+fn gamma(level: u32) -> u8 {
+    (level.min(100) * level.min(100)).div_ceil(100) as u8
+}
+
+/// The digits shown on a single position during a transition.
+struct Spin {
+    from: u8,
+    to: Option<u8>,
+    /// Number of steps until the new digit is reached
+    steps: u8,
+    up: bool,
+}
+
+impl Spin {
+    fn new(from: Option<u8>, to: Option<u8>, transition: Transition, up: bool) -> Self {
+        let steps = match (from, to) {
+            (Some(from), Some(to)) if from != to => {
+                let distance = if up {
+                    (to + 10 - from) % 10
+                } else {
+                    (from + 10 - to) % 10
+                };
+                match transition {
+                    Transition::Cut => 0,
+                    Transition::Roll => distance,
+                    Transition::SlotMachine => 10 + distance,
+                    // Faded instead of spun
+                    #[cfg(feature = "dimming")]
+                    Transition::Fade => 0,
+                }
+            }
+            // Turned on or off, or unchanged
+            _ => 0,
+        };
+        Self {
+            from: from.unwrap_or(0),
+            to,
+            steps,
+            up,
+        }
+    }
+
+    /// Return the digit to show at the specified step (starting at 1), or
+    /// `None` if the position is off.
+    fn digit(&self, step: u8) -> Option<u8> {
+        if step >= self.steps {
+            return self.to;
+        }
+        let step = step % 10;
+        Some(if self.up {
+            (self.from + step) % 10
+        } else {
+            (self.from + 10 - step) % 10
+        })
+    }
+}
+
+/// Return the lowest `N` decimal digits of a number, most significant first.
+pub fn digits<const N: usize>(val: u32) -> [u8; N] {
+    let mut digits = [0; N];
+    let mut rest = val;
+    for digit in digits.iter_mut().rev() {
+        *digit = (rest % 10) as u8;
+        rest /= 10;
+    }
+    digits
+}
@@ -0,0 +1,352 @@
+//! Driving the tubes from a task of their own.
+//!
+//! After the startup, the main loop doesn't touch the tubes anymore, but
+//! sends [`DisplayCommand`]s to [`display_task`], like with the LED control
+//! task. That way, animations don't delay the count updates, and slow
+//! requests don't stall the animations. Each command interrupts the one
+//! still running, e.g. a press ends the cathode cycling right away. While
+//! there is nothing else to do, the task keeps showing the count.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    channel::{Receiver, Sender},
+};
+use embassy_time::Duration;
+#[cfg(feature = "screensaver")]
+use embassy_time::Timer;
+use esp_hal::rng::Rng;
+
+#[cfg(feature = "clock-mode")]
+use crate::clock_mode::{self, ClockMode};
+#[cfg(feature = "error-codes")]
+use crate::error_code::ErrorCode;
+#[cfg(feature = "screensaver")]
+use crate::screensaver::{self, Screensaver};
+use crate::{
+    config::{DisplayConfig, TUBE_COUNT},
+    display::{CounterDisplay, Overflow, Transition},
+    Tubes,
+};
+
+/// Number of commands that can be queued
+pub const QUEUE_LEN: usize = 4;
+
+/// Time each step of the count transition animation is shown
+const COUNT_TRANSITION_DELAY: Duration = Duration::from_millis(40);
+
+/// Time each part of a count that doesn't fit on the tubes is shown
+const COUNT_OVERFLOW_DELAY: Duration = Duration::from_millis(1000);
+
+/// Time each intermediate count is shown when counting through a larger
+/// correction
+const COUNT_STEP_DELAY: Duration = Duration::from_millis(100);
+
+/// How often and how fast the count is flashed when an update failed
+#[cfg(not(feature = "offline-counting"))]
+const FAILURE_FLASH_COUNT: usize = 3;
+#[cfg(not(feature = "offline-counting"))]
+const FAILURE_FLASH_DELAY: Duration = Duration::from_millis(150);
+
+/// How often and how fast error codes are flashed
+#[cfg(feature = "error-codes")]
+const ERROR_FLASH_COUNT: usize = 3;
+#[cfg(feature = "error-codes")]
+const ERROR_FLASH_DELAY: Duration = Duration::from_millis(400);
+
+/// Duration of the cathode cycling, and how long each step is shown
+#[cfg(feature = "anti-poisoning")]
+const CATHODE_CYCLE_DURATION: Duration = Duration::from_secs(5);
+#[cfg(feature = "anti-poisoning")]
+const CATHODE_CYCLE_DELAY: Duration = Duration::from_millis(50);
+
+/// Duration of a fade to a new brightness, and of fading in or out when
+/// blanking
+#[cfg(feature = "dimming")]
+const FADE_DURATION: Duration = Duration::from_millis(1000);
+
+/// Time each cathode is lit by the self-test
+#[cfg(any(feature = "console", feature = "resync"))]
+const SELFTEST_DELAY: Duration = Duration::from_millis(100);
+
+/// Time each step of the tube diagnostics is shown
+#[cfg(feature = "diagnostics")]
+const DIAGNOSTICS_STEP_DELAY: Duration = Duration::from_millis(2000);
+
+pub type DisplaySender = Sender<'static, NoopRawMutex, DisplayCommand, QUEUE_LEN>;
+pub type DisplayReceiver = Receiver<'static, NoopRawMutex, DisplayCommand, QUEUE_LEN>;
+
+/// Command for the [`display_task`].
+pub enum DisplayCommand {
+    /// Show a new count
+    ShowCount { count: u8, change: CountChange },
+    /// Show the specified digits instead of the count, until the next count
+    /// is shown
+    #[cfg(any(feature = "provisioning", feature = "console"))]
+    ShowDigits([Option<u8>; TUBE_COUNT]),
+    /// Flash the count to get attention
+    #[cfg(any(
+        feature = "doorbell",
+        feature = "space-state",
+        feature = "reset-chord",
+        feature = "lock-mode"
+    ))]
+    Flash { times: usize, delay: Duration },
+    /// Flash an error code
+    #[cfg(feature = "error-codes")]
+    Error(ErrorCode),
+    /// Flash the count shown, since the update with it failed, then go back
+    /// to the last confirmed `count` (and flash the error code)
+    #[cfg(not(feature = "offline-counting"))]
+    UpdateFailed {
+        count: u8,
+        #[cfg(feature = "error-codes")]
+        code: ErrorCode,
+    },
+    /// Cycle all cathodes to prevent cathode poisoning
+    #[cfg(feature = "anti-poisoning")]
+    CycleCathodes,
+    /// Light every cathode in turn, like at startup
+    #[cfg(any(feature = "console", feature = "resync"))]
+    Selftest,
+    /// Test the wiring of the tubes, see [`CounterDisplay::diagnose`]
+    #[cfg(feature = "diagnostics")]
+    Diagnose,
+    /// Blank the tubes, or turn them on again
+    #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+    Blank(bool),
+    /// Set the brightness of the tubes, in percent
+    #[cfg(feature = "dimming")]
+    Brightness(u8),
+    /// Set the strike delay of the tube at the index (counted from the left)
+    #[cfg(feature = "console")]
+    StrikeDelay(usize, Duration),
+    /// Enter or leave the clock mode
+    #[cfg(feature = "clock-mode")]
+    ClockMode(bool),
+}
+
+/// Why the count shown changed, which selects the animation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CountChange {
+    /// Changed by the toggle switch (or back after a failed update): Use the
+    /// configured transition
+    Press,
+    /// Changed remotely, by the server or another counter: Count through
+    /// the values in between if it changed by more than one
+    Remote,
+}
+
+/// The tubes (or another display), and the state of what they show.
+pub struct Display<D> {
+    tubes: D,
+    /// The count last sent with [`DisplayCommand::ShowCount`]
+    count: u8,
+    /// Digits shown instead of the count, if any
+    digits: Option<[Option<u8>; TUBE_COUNT]>,
+    transition: Transition,
+    overflow: Overflow,
+    #[cfg(feature = "clock-mode")]
+    clock_mode: ClockMode,
+    #[cfg(feature = "screensaver")]
+    screensaver: Screensaver,
+}
+
+impl<D: CounterDisplay<TUBE_COUNT>> Display<D> {
+    /// Take over the tubes, which keep showing `digits` until the first
+    /// count. Count changes use the configured transition, and counts that
+    /// don't fit on the tubes are shown as configured.
+    pub fn new(
+        tubes: D,
+        digits: [Option<u8>; TUBE_COUNT],
+        config: &DisplayConfig,
+        rng: Rng,
+    ) -> Self {
+        #[cfg(not(feature = "screensaver"))]
+        let _ = rng;
+        Self {
+            tubes,
+            count: 0,
+            digits: Some(digits),
+            transition: config.transition,
+            overflow: config.overflow,
+            #[cfg(feature = "clock-mode")]
+            clock_mode: ClockMode::new(config.clock_mode_hours),
+            #[cfg(feature = "screensaver")]
+            screensaver: Screensaver::new(rng, 0),
+        }
+    }
+
+    /// Run a command. Commands that take a while may be interrupted by
+    /// dropping the future, so the state is updated first.
+    async fn run(&mut self, command: DisplayCommand) {
+        match command {
+            DisplayCommand::ShowCount { count, change } => {
+                let from = self.count;
+                self.count = count;
+                self.digits = None;
+                let to = shown::<D>(count);
+                if count == from {
+                    // Nothing to animate, but the tubes may show something
+                    // else meanwhile
+                    self.tubes.show(count);
+                } else if change == CountChange::Remote && shown::<D>(from).abs_diff(to) > 1 {
+                    self.tubes.count_to(to, COUNT_STEP_DELAY).await;
+                } else {
+                    self.tubes
+                        .transition_to(to, self.transition, COUNT_TRANSITION_DELAY)
+                        .await;
+                }
+            }
+            #[cfg(any(feature = "provisioning", feature = "console"))]
+            DisplayCommand::ShowDigits(digits) => {
+                self.digits = Some(digits);
+            }
+            #[cfg(any(
+                feature = "doorbell",
+                feature = "space-state",
+                feature = "reset-chord",
+                feature = "lock-mode"
+            ))]
+            DisplayCommand::Flash { times, delay } => {
+                self.tubes.flash(shown::<D>(self.count), times, delay).await;
+            }
+            #[cfg(feature = "error-codes")]
+            DisplayCommand::Error(code) => self.show_error(code).await,
+            #[cfg(not(feature = "offline-counting"))]
+            DisplayCommand::UpdateFailed {
+                count,
+                #[cfg(feature = "error-codes")]
+                code,
+            } => {
+                let failed = self.count;
+                self.count = count;
+                self.tubes
+                    .flash(shown::<D>(failed), FAILURE_FLASH_COUNT, FAILURE_FLASH_DELAY)
+                    .await;
+                self.tubes
+                    .transition_to(shown::<D>(count), self.transition, COUNT_TRANSITION_DELAY)
+                    .await;
+                #[cfg(feature = "error-codes")]
+                self.show_error(code).await;
+            }
+            #[cfg(feature = "anti-poisoning")]
+            DisplayCommand::CycleCathodes => {
+                log::debug!("Cycling the cathodes");
+                self.tubes
+                    .cycle_cathodes(CATHODE_CYCLE_DURATION, CATHODE_CYCLE_DELAY)
+                    .await;
+            }
+            #[cfg(any(feature = "console", feature = "resync"))]
+            DisplayCommand::Selftest => self.tubes.selftest(SELFTEST_DELAY).await,
+            #[cfg(feature = "diagnostics")]
+            DisplayCommand::Diagnose => self.tubes.diagnose(DIAGNOSTICS_STEP_DELAY).await,
+            #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+            DisplayCommand::Blank(blanked) => {
+                #[cfg(feature = "dimming")]
+                self.tubes.fade_blanked(blanked, FADE_DURATION).await;
+                #[cfg(not(feature = "dimming"))]
+                self.tubes.set_blanked(blanked);
+            }
+            #[cfg(feature = "dimming")]
+            DisplayCommand::Brightness(percent) => {
+                self.tubes.fade_to(percent, FADE_DURATION).await;
+            }
+            #[cfg(feature = "console")]
+            DisplayCommand::StrikeDelay(index, delay) => self.tubes.set_strike_delay(index, delay),
+            #[cfg(feature = "clock-mode")]
+            DisplayCommand::ClockMode(entered) => self.clock_mode.set_entered(entered),
+        }
+    }
+
+    /// Flash an error code, then show the count again.
+    #[cfg(feature = "error-codes")]
+    async fn show_error(&mut self, code: ErrorCode) {
+        log::info!("Showing {} on the tubes", code);
+        self.tubes
+            .flash_code(code.code(), ERROR_FLASH_COUNT, ERROR_FLASH_DELAY)
+            .await;
+    }
+
+    /// Keep showing the count (see [`show_count`]), or the time in clock
+    /// mode, until the future is dropped. Meanwhile, the screensaver drifts
+    /// through random digits if the count stays the same for long. Neither
+    /// the time nor the screensaver are shown while the tubes are blanked.
+    async fn idle(&mut self) -> ! {
+        if let Some(digits) = self.digits {
+            self.tubes.show_digits(digits);
+            loop {
+                core::future::pending::<()>().await;
+            }
+        }
+        #[cfg(feature = "clock-mode")]
+        if self.clock_mode.is_shown(self.count) && !self.tubes.is_blanked() {
+            clock_mode::show_clock(&mut self.tubes).await;
+        }
+        #[cfg(feature = "screensaver")]
+        {
+            let mut next_drift = self.screensaver.next_drift(self.count);
+            loop {
+                select(
+                    show_count(&mut self.tubes, self.count, self.overflow),
+                    Timer::at(next_drift),
+                )
+                .await;
+                if !self.tubes.is_blanked() {
+                    self.screensaver.drift(&mut self.tubes).await;
+                }
+                next_drift += screensaver::DRIFT_INTERVAL;
+            }
+        }
+        #[cfg(not(feature = "screensaver"))]
+        show_count(&mut self.tubes, self.count, self.overflow).await
+    }
+}
+
+/// Return the count as shown by the display, capped to the largest number it
+/// can show.
+fn shown<D: CounterDisplay<TUBE_COUNT>>(count: u8) -> u32 {
+    u32::from(count).min(D::MAX)
+}
+
+/// Keep showing the count, see [`CounterDisplay::show_overflowing`]. With
+/// `room-temperature`, the room temperature is shown in turn.
+async fn show_count<const N: usize>(
+    tubes: &mut impl CounterDisplay<N>,
+    count: u8,
+    overflow: Overflow,
+) -> ! {
+    #[cfg(feature = "room-temperature")]
+    crate::room_temperature::show_alternating(
+        tubes,
+        u32::from(count),
+        overflow,
+        COUNT_OVERFLOW_DELAY,
+    )
+    .await;
+    #[cfg(not(feature = "room-temperature"))]
+    tubes
+        .show_overflowing(u32::from(count), overflow, COUNT_OVERFLOW_DELAY)
+        .await
+}
+
+/// Task: Drive the tubes (tasks can't be generic, so this one takes the
+/// [`Tubes`] of the board)
+#[embassy_executor::task]
+pub async fn display_task(mut display: Display<Tubes>, receiver: DisplayReceiver) {
+    log::info!("Start display task");
+    let mut next = None;
+    loop {
+        let command = match next.take() {
+            Some(command) => command,
+            None => match select(receiver.receive(), display.idle()).await {
+                Either::First(command) => command,
+                Either::Second(never) => match never {},
+            },
+        };
+        // A new command interrupts the running one
+        if let Either::First(command) = select(receiver.receive(), display.run(command)).await {
+            next = Some(command);
+        }
+    }
+}
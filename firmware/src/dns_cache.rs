@@ -0,0 +1,114 @@
+//! Caching of DNS results.
+//!
+//! Every count update used to resolve the host of the endpoint again, which
+//! costs a round trip to the DNS server each time. [`CachingDns`] wraps the
+//! resolver of a transport and reuses an address for a fixed 300 seconds
+//! ([`DNS_CACHE_TTL`]), since embassy-net doesn't expose the TTL of the
+//! answer. Up to [`DNS_CACHE_SIZE`] hosts are cached. When connecting fails,
+//! [`CachingDns::invalidate`] clears every entry, not just the one of the
+//! failed host, so that all hosts are resolved again.
+
+use core::cell::RefCell;
+
+use embassy_time::{Duration, Instant};
+use embedded_nal_async::{AddrType, Dns, IpAddr};
+
+/// How long a resolved address is reused.
+///
+/// The DNS client of embassy-net doesn't expose the TTL of the answer, so a
+/// fixed value is used that is shorter than the TTL of typical records.
+const DNS_CACHE_TTL: Duration = Duration::from_secs(300);
+
+const DNS_CACHE_SIZE: usize = 4;
+
+type Host = heapless::String<64>;
+
+struct Entry {
+    host: Host,
+    ipv6: bool,
+    address: IpAddr,
+    expires: Instant,
+}
+
+/// A DNS resolver that caches the results of the wrapped resolver.
+///
+/// Call [`CachingDns::invalidate`] when connecting to a resolved address
+/// fails, so that the next request resolves the host again.
+pub struct CachingDns<D> {
+    resolver: D,
+    entries: RefCell<heapless::Vec<Entry, DNS_CACHE_SIZE>>,
+}
+
+impl<D: Dns> CachingDns<D> {
+    /// Create a new instance.
+    pub fn new(resolver: D) -> Self {
+        Self {
+            resolver,
+            entries: RefCell::new(heapless::Vec::new()),
+        }
+    }
+
+    /// Remove all cached addresses.
+    pub fn invalidate(&self) {
+        self.entries.borrow_mut().clear();
+    }
+
+    fn lookup(&self, host: &str, ipv6: bool) -> Option<IpAddr> {
+        let now = Instant::now();
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|entry| entry.expires > now);
+        entries
+            .iter()
+            .find(|entry| entry.host == host && entry.ipv6 == ipv6)
+            .map(|entry| entry.address)
+    }
+
+    fn insert(&self, host: &str, ipv6: bool, address: IpAddr) {
+        let Ok(host) = Host::try_from(host) else {
+            // Too long to be cached
+            return;
+        };
+        let mut entries = self.entries.borrow_mut();
+        if entries.is_full() {
+            // Evict the entry expiring first
+            let oldest = entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(i, _)| i);
+            if let Some(i) = oldest {
+                entries.swap_remove(i);
+            }
+        }
+        let _ = entries.push(Entry {
+            host,
+            ipv6,
+            address,
+            expires: Instant::now() + DNS_CACHE_TTL,
+        });
+    }
+}
+
+impl<D: Dns> Dns for CachingDns<D> {
+    type Error = D::Error;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<IpAddr, D::Error> {
+        let ipv6 = matches!(addr_type, AddrType::IPv6);
+        if let Some(address) = self.lookup(host, ipv6) {
+            log::debug!("DNS cache hit for {host}: {address}");
+            return Ok(address);
+        }
+        let address = self.resolver.get_host_by_name(host, addr_type).await?;
+        log::debug!("Resolved {host}: {address}");
+        self.insert(host, ipv6, address);
+        Ok(address)
+    }
+
+    async fn get_host_by_address(
+        &self,
+        addr: IpAddr,
+        result: &mut [u8],
+    ) -> Result<usize, D::Error> {
+        self.resolver.get_host_by_address(addr, result).await
+    }
+}
@@ -0,0 +1,85 @@
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    gpio::{Input, InputPin},
+    peripheral::Peripheral,
+};
+
+use crate::{
+    display_task::DisplayCommand,
+    driver::{Context, Driver},
+};
+
+/// Time the input must stay active to count as ring, filtering out glitches
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(50);
+
+/// Rings within this time after the previous one are ignored, so that holding
+/// or repeatedly pushing the button doesn't restart the notification.
+const RING_HOLDOFF: Duration = Duration::from_secs(5);
+
+/// How often and how fast the tubes flash when the doorbell rings
+const FLASH_COUNT: usize = 5;
+const FLASH_DELAY: Duration = Duration::from_millis(200);
+
+/// Input connected to the doorbell circuit.
+///
+/// The input is active low, e.g. through an optocoupler pulling it to GND
+/// while the bell rings. The internal pull-up resistor is enabled.
+pub struct Doorbell<'a> {
+    pin: Input<'a>,
+    last_ring: Option<Instant>,
+}
+
+impl<'a> Doorbell<'a> {
+    /// Construct a new [`Doorbell`] on the specified pin.
+    pub fn new(pin: impl Peripheral<P = impl InputPin> + 'a) -> Self {
+        Self {
+            pin: Input::new(pin, esp_hal::gpio::Pull::Up),
+            last_ring: None,
+        }
+    }
+
+    /// Wait until the doorbell rings.
+    pub async fn wait_for_ring(&mut self) {
+        loop {
+            self.pin.wait_for_low().await;
+            Timer::after(DEBOUNCE_DELAY).await;
+            if self.pin.is_high() {
+                continue;
+            }
+            let now = Instant::now();
+            let held_off = self
+                .last_ring
+                .is_some_and(|last_ring| now - last_ring < RING_HOLDOFF);
+            self.last_ring = Some(now);
+            if !held_off {
+                return;
+            }
+            self.pin.wait_for_high().await;
+        }
+    }
+}
+
+/// Flashes the count when the doorbell rings, to get attention.
+impl Driver for Doorbell<'_> {
+    type Event = ();
+
+    async fn poll(&mut self) {
+        self.wait_for_ring().await;
+    }
+
+    async fn handle_event(&mut self, (): (), context: &Context) {
+        log::info!("Doorbell rang");
+        #[cfg(feature = "temperature")]
+        if crate::temperature::is_overheated() {
+            log::info!("Not flashing the tubes, the chip is overheated");
+            return;
+        }
+        context
+            .display
+            .send(DisplayCommand::Flash {
+                times: FLASH_COUNT,
+                delay: FLASH_DELAY,
+            })
+            .await;
+    }
+}
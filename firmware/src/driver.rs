@@ -0,0 +1,130 @@
+//! Registry of optional peripheral drivers.
+//!
+//! Optional peripherals that raise events, like the doorbell or the motion
+//! sensor, implement [`Driver`] and are registered in a [`Registry`] when
+//! the firmware starts. The main loop then waits for the next event of any
+//! registered driver and lets the driver handle it, instead of knowing every
+//! optional device.
+//!
+//! Peripherals that only sample a value (the chip temperature, the RSSI, the
+//! room temperature) and the I/O expander run as tasks of their own instead,
+//! since the main loop doesn't poll while it sends a count update.
+
+use core::convert::Infallible;
+
+use embassy_futures::select::{select, Either};
+
+use crate::display_task::DisplaySender;
+
+/// What a driver may use while handling an event.
+// Only fully used by the motion sensor
+#[cfg_attr(not(feature = "motion-wake"), allow(dead_code))]
+pub struct Context {
+    /// The display task
+    pub display: DisplaySender,
+    /// Whether the tubes are blanked, during quiet hours or since the space
+    /// was closed
+    pub blanked: bool,
+}
+
+/// An optional peripheral, which raises events for the main loop.
+pub trait Driver {
+    /// What the peripheral reports
+    type Event;
+
+    /// Wait for the next event of the peripheral.
+    ///
+    /// The main loop drops the future whenever another event arrives, so the
+    /// state that must survive that has to be kept in the driver.
+    async fn poll(&mut self) -> Self::Event;
+
+    /// Handle an event returned by [`poll`](Self::poll).
+    async fn handle_event(&mut self, event: Self::Event, context: &Context);
+
+    /// The toggle switch was pressed. Returns whether the driver had lit the
+    /// blanked tubes, so that the press is counted instead of only lighting
+    /// them again.
+    fn on_press(&mut self) -> bool {
+        false
+    }
+}
+
+/// No driver, which never raises an event.
+impl Driver for () {
+    type Event = Infallible;
+
+    async fn poll(&mut self) -> Self::Event {
+        core::future::pending().await
+    }
+
+    async fn handle_event(&mut self, event: Self::Event, _context: &Context) {
+        match event {}
+    }
+}
+
+/// Two drivers, waiting for the events of both.
+impl<A: Driver, B: Driver> Driver for (A, B) {
+    type Event = Either<A::Event, B::Event>;
+
+    async fn poll(&mut self) -> Self::Event {
+        select(self.0.poll(), self.1.poll()).await
+    }
+
+    async fn handle_event(&mut self, event: Self::Event, context: &Context) {
+        match event {
+            Either::First(event) => self.0.handle_event(event, context).await,
+            Either::Second(event) => self.1.handle_event(event, context).await,
+        }
+    }
+
+    fn on_press(&mut self) -> bool {
+        // Both drivers are told about the press
+        self.0.on_press() | self.1.on_press()
+    }
+}
+
+/// The registered drivers.
+///
+/// Drivers are added with [`register`](Self::register), which only changes
+/// the type of the registry, so that no allocation or dynamic dispatch is
+/// needed.
+pub struct Registry<D> {
+    drivers: D,
+}
+
+impl Registry<()> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self { drivers: () }
+    }
+}
+
+impl<D: Driver> Registry<D> {
+    /// Register another driver.
+    #[cfg_attr(
+        not(any(feature = "doorbell", feature = "motion-wake")),
+        allow(dead_code)
+    )]
+    pub fn register<N: Driver>(self, driver: N) -> Registry<(D, N)> {
+        Registry {
+            drivers: (self.drivers, driver),
+        }
+    }
+
+    /// Wait for the next event of any registered driver.
+    pub async fn poll(&mut self) -> D::Event {
+        self.drivers.poll().await
+    }
+
+    /// Let the driver that raised the event handle it.
+    pub async fn handle_event(&mut self, event: D::Event, context: &Context) {
+        self.drivers.handle_event(event, context).await;
+    }
+
+    /// Tell every driver that the toggle switch was pressed, see
+    /// [`Driver::on_press`]. Returns whether any of them had lit the tubes.
+    #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+    pub fn on_press(&mut self) -> bool {
+        self.drivers.on_press()
+    }
+}
@@ -0,0 +1,119 @@
+use embassy_time::Instant;
+use esp_wifi::wifi::WifiState;
+
+use crate::config::TUBE_COUNT;
+
+/// Idle draw of the board: ESP32-C3 without radio activity, the two K155ID1
+/// drivers, level shifter, power LED and the losses in the 5V LDO.
+const BASE_POWER_MW: u64 = 550;
+
+/// Additional draw per lit IN-12B tube, including the losses in the
+/// NCH6100HV boost converter.
+const TUBE_POWER_MW: u64 = 600;
+
+/// Additional draw while the radio is associated (modem sleep between beacons).
+const WIFI_CONNECTED_POWER_MW: u64 = 250;
+
+/// Additional draw while the radio is scanning or trying to connect (RX is
+/// active almost all the time).
+const WIFI_CONNECTING_POWER_MW: u64 = 400;
+
+/// The power relevant state of the device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PowerState {
+    /// Number of tubes currently lit, up to `TUBE_COUNT`
+    pub tubes_lit: u8,
+    /// Current activity of the WiFi radio
+    pub wifi: WifiActivity,
+}
+
+/// Activity of the WiFi radio, as far as its power draw is concerned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WifiActivity {
+    Off,
+    Connecting,
+    Connected,
+}
+
+impl From<WifiState> for WifiActivity {
+    fn from(state: WifiState) -> Self {
+        match state {
+            WifiState::StaConnected => Self::Connected,
+            WifiState::StaStarted | WifiState::StaDisconnected => Self::Connecting,
+            _ => Self::Off,
+        }
+    }
+}
+
+impl PowerState {
+    /// Determine the power state for the specified displayed count.
+    pub fn for_count(count: u8, wifi: WifiActivity) -> Self {
+        // Without leading zeroes, as many tubes as the count has digits
+        let digits = count.checked_ilog10().map_or(0, |log| log + 1);
+        let tubes_lit = digits.min(TUBE_COUNT as u32) as u8;
+        Self { tubes_lit, wifi }
+    }
+
+    /// Return the estimated power draw in this state in milliwatts.
+    pub fn power_mw(&self) -> u64 {
+        let wifi = match self.wifi {
+            WifiActivity::Off => 0,
+            WifiActivity::Connecting => WIFI_CONNECTING_POWER_MW,
+            WifiActivity::Connected => WIFI_CONNECTED_POWER_MW,
+        };
+        BASE_POWER_MW + u64::from(self.tubes_lit) * TUBE_POWER_MW + wifi
+    }
+}
+
+/// Approximates the energy used by the device by integrating the modelled
+/// power draw of each state over the time spent in it.
+///
+/// The values are rough estimates based on datasheet figures, not
+/// measurements. They are good enough to compare policies (e.g. turning the
+/// tubes off), not to bill anyone.
+pub struct EnergyEstimator {
+    start: Instant,
+    last_change: Instant,
+    state: PowerState,
+    /// Accumulated energy in millijoules
+    energy_mj: u64,
+}
+
+impl EnergyEstimator {
+    /// Create a new estimator, starting in the specified state.
+    pub fn new(state: PowerState) -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last_change: now,
+            state,
+            energy_mj: 0,
+        }
+    }
+
+    /// Account for the time spent in the previous state and switch to the
+    /// specified one.
+    pub fn update(&mut self, state: PowerState) {
+        let now = Instant::now();
+        let elapsed_ms = (now - self.last_change).as_millis();
+        self.energy_mj += self.state.power_mw() * elapsed_ms / 1000;
+        self.last_change = now;
+        self.state = state;
+    }
+
+    /// Return the energy used since boot in watt hours.
+    pub fn total_wh(&self) -> f32 {
+        self.energy_mj as f32 / 3_600_000.0
+    }
+
+    /// Return the projected energy usage per day in watt hours, based on the
+    /// average power draw since boot.
+    pub fn projected_wh_per_day(&self) -> f32 {
+        let elapsed_ms = (self.last_change - self.start).as_millis();
+        if elapsed_ms == 0 {
+            return self.state.power_mw() as f32 * 24.0 / 1000.0;
+        }
+        let average_mw = self.energy_mj as f32 * 1000.0 / elapsed_ms as f32;
+        average_mw * 24.0 / 1000.0
+    }
+}
@@ -0,0 +1,77 @@
+//! Two-digit error codes, flashed on the tubes.
+//!
+//! The installed counter has no serial console, so errors that would otherwise
+//! only be logged are briefly flashed on the tubes as a code, before the count
+//! is shown again. The transports attach the code to their errors (see
+//! [`ErrorCode::error`]), so that it can be recovered from the returned
+//! [`anyhow::Error`] with [`ErrorCode::of`].
+//!
+//! | Code | Meaning                                                 |
+//! |------|---------------------------------------------------------|
+//! | 10   | WiFi down                                               |
+//! | 20   | DNS lookup failed                                       |
+//! | 30   | Request failed (no connection, timeout, other errors)   |
+//! | 40   | Client error response (HTTP 4xx, CoAP 4.xx)             |
+//! | 50   | Server error response (HTTP 5xx, CoAP 5.xx)             |
+//! | 60   | Heap low                                                |
+
+use core::fmt;
+
+#[cfg(feature = "error-codes")]
+use esp_wifi::wifi::WifiState;
+
+/// An error code shown on the tubes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "error-codes"), allow(dead_code))]
+pub enum ErrorCode {
+    WifiDown = 10,
+    DnsFailed = 20,
+    RequestFailed = 30,
+    ClientError = 40,
+    ServerError = 50,
+    HeapLow = 60,
+}
+
+impl ErrorCode {
+    /// Return the code for an unexpected response with the specified status
+    /// class (e.g. 4 for HTTP 404 or CoAP 4.04).
+    pub fn for_status_class(class: u16) -> Self {
+        match class {
+            4 => Self::ClientError,
+            5 => Self::ServerError,
+            _ => Self::RequestFailed,
+        }
+    }
+
+    /// Return an error with the specified message, carrying the code.
+    pub fn error(self, message: &'static str) -> anyhow::Error {
+        anyhow::Error::msg(self).context(message)
+    }
+
+    /// Return the code for an error returned by a transport.
+    ///
+    /// If the WiFi connection is down, that's reported instead of the
+    /// resulting error.
+    #[cfg(feature = "error-codes")]
+    pub fn of(error: &anyhow::Error) -> Self {
+        if esp_wifi::wifi::wifi_state() != WifiState::StaConnected {
+            return Self::WifiDown;
+        }
+        error
+            .downcast_ref::<Self>()
+            .copied()
+            .unwrap_or(Self::RequestFailed)
+    }
+
+    /// Return the number shown on the tubes.
+    #[cfg(feature = "error-codes")]
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {:02}", *self as u8)
+    }
+}
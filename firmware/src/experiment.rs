@@ -0,0 +1,26 @@
+//! Alternative behaviors, selected at build time.
+//!
+//! Some policies can't be settled in theory. They can be selected per counter,
+//! so that one counter can run each variant for a while and the logs can be
+//! compared (e.g. the drift reported by the server, and the update latency)
+//! before standardizing on one.
+
+/// When the tubes show a count changed by the toggle switch, selected with
+/// `DISPLAY_UPDATE_POLICY`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisplayPolicy {
+    /// Show the new count immediately and go back if the update fails
+    /// (`optimistic`, the default)
+    Optimistic,
+    /// Only show the new count once the server confirmed it (`confirmed`)
+    Confirmed,
+}
+
+impl DisplayPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Optimistic => "optimistic",
+            Self::Confirmed => "confirmed",
+        }
+    }
+}
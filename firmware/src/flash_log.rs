@@ -0,0 +1,129 @@
+//! Append-only logs in flash.
+//!
+//! The pending update journal, the settings changelog, the lock and the
+//! stored count each append to a [`FlashLog`] in their sector of the `nvs`
+//! partition, see [`Sector`]. The sector is only erased when it is full,
+//! which keeps the flash wear low.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_storage::FlashStorage;
+
+use crate::storage::Sector;
+
+/// An erased (not yet written) word.
+#[cfg(any(feature = "journal", feature = "lock-mode", feature = "persist-count"))]
+const EMPTY: u32 = 0xFFFF_FFFF;
+
+/// An append-only log of records in a flash sector.
+///
+/// Records are a multiple of 32 bit words long. When the next record doesn't
+/// fit anymore, the sector is erased and the older records are lost. Errors
+/// are logged with the name of the log, and otherwise ignored.
+#[derive(Debug, Copy, Clone)]
+pub struct FlashLog {
+    sector: Sector,
+    /// What is stored, for the error messages
+    name: &'static str,
+    /// Offset of the next empty word, relative to the sector
+    next: u32,
+}
+
+impl FlashLog {
+    /// Return the log in the sector. Its end is found by [`scan`](Self::scan)
+    /// or [`scan_words`](Self::scan_words).
+    pub const fn new(sector: Sector, name: &'static str) -> Self {
+        Self {
+            sector,
+            name,
+            next: 0,
+        }
+    }
+
+    /// Scan the log for its end. `record_len` returns the length of the
+    /// record at the specified position, or `None` after the last record.
+    ///
+    /// If the sector isn't erased after the last record, nothing can be
+    /// appended, so the log is handled as full.
+    #[cfg(feature = "provisioning")]
+    pub fn scan(&mut self, mut record_len: impl FnMut(&Self, u32) -> Option<u32>) {
+        self.next = 0;
+        while let Some(len) = record_len(self, self.next) {
+            self.next += len;
+        }
+        if !self.is_erased(self.next) {
+            self.next = Sector::SIZE;
+        }
+    }
+
+    /// Scan a log of single words, passing each of them to `record`, oldest
+    /// first.
+    #[cfg(any(feature = "journal", feature = "lock-mode", feature = "persist-count"))]
+    pub fn scan_words(&mut self, mut record: impl FnMut(u32)) {
+        self.next = 0;
+        while self.next < Sector::SIZE {
+            let mut word = [0; 4];
+            if !self.read(self.next, &mut word) {
+                // Don't write over what couldn't be read
+                self.next = Sector::SIZE;
+                break;
+            }
+            match u32::from_le_bytes(word) {
+                EMPTY => break,
+                word => record(word),
+            }
+            self.next += 4;
+        }
+    }
+
+    /// Read from the sector at `position`. Returns whether that succeeded.
+    pub fn read(&self, position: u32, buf: &mut [u8]) -> bool {
+        let Some(offset) = self.sector.offset() else {
+            return false;
+        };
+        match FlashStorage::new().read(offset + position, buf) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Could not read the {}: {:?}", self.name, e);
+                false
+            }
+        }
+    }
+
+    /// Return whether the word at `position` is erased, i.e. can be written.
+    #[cfg(feature = "provisioning")]
+    fn is_erased(&self, position: u32) -> bool {
+        let mut word = [0; 4];
+        position < Sector::SIZE && self.read(position, &mut word) && word == [0xFF; 4]
+    }
+
+    /// Return whether a record of `len` bytes fits without erasing the sector.
+    #[cfg(feature = "provisioning")]
+    pub fn fits(&self, len: usize) -> bool {
+        self.next + len as u32 <= Sector::SIZE
+    }
+
+    /// Append a record, erasing the sector first if it doesn't fit anymore.
+    pub fn append(&mut self, record: &[u8]) {
+        let Some(offset) = self.sector.offset() else {
+            return;
+        };
+        let mut flash = FlashStorage::new();
+        if self.next + record.len() as u32 > Sector::SIZE {
+            if let Err(e) = flash.erase(offset, offset + Sector::SIZE) {
+                log::error!("Could not erase the {}: {:?}", self.name, e);
+                return;
+            }
+            self.next = 0;
+        }
+        if let Err(e) = flash.write(offset + self.next, record) {
+            log::error!("Could not write to the {}: {:?}", self.name, e);
+        }
+        self.next += record.len() as u32;
+    }
+
+    /// Append a single word.
+    #[cfg(any(feature = "journal", feature = "lock-mode", feature = "persist-count"))]
+    pub fn append_word(&mut self, word: u32) {
+        self.append(&word.to_le_bytes());
+    }
+}
@@ -0,0 +1,86 @@
+//! Detection of dead WiFi associations.
+//!
+//! The WiFi driver may keep reporting a connection although no packets get
+//! through anymore. [`health_check_task`] periodically opens a TCP connection
+//! to the gateway. Any answer, including a rejected connection, shows that
+//! the link works. If the gateway repeatedly doesn't answer at all, the
+//! connection task is asked through [`REASSOCIATE`] to drop the association
+//! and connect again.
+
+use embassy_net::{
+    tcp::{ConnectError, TcpSocket},
+    IpEndpoint, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Ticker};
+use esp_wifi::wifi::WifiState;
+
+use crate::{jitter::jittered, EspWifiDevice};
+
+/// Interval between two checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time to wait for the gateway to answer the connection attempt
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of consecutive failed checks after which the association is reset
+const FAILURES_BEFORE_REASSOCIATION: u8 = 3;
+
+/// Port probed on the gateway. It doesn't need to be open, a reset answer is
+/// sufficient.
+const CHECK_PORT: u16 = 80;
+
+/// Signaled when the association should be dropped and established again.
+pub static REASSOCIATE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Check whether the gateway answers.
+///
+/// Returns `None` if there is no gateway to check.
+async fn check_gateway(stack: &'static Stack<EspWifiDevice<'static>>) -> Option<bool> {
+    let gateway = stack.config_v4()?.gateway?;
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; 64];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    let remote = IpEndpoint::new(gateway.into(), CHECK_PORT);
+    let alive = match with_timeout(CHECK_TIMEOUT, socket.connect(remote)).await {
+        Ok(Ok(()) | Err(ConnectError::ConnectionReset)) => true,
+        Ok(Err(e)) => {
+            log::debug!("Health check: Connecting to gateway failed: {e:?}");
+            false
+        }
+        Err(_) => false,
+    };
+    // Don't leave a half-open connection on the gateway
+    socket.abort();
+    let _ = with_timeout(Duration::from_secs(1), socket.flush()).await;
+    Some(alive)
+}
+
+/// Task: Periodically check the connectivity and request a reassociation if
+/// the connection is dead
+#[embassy_executor::task]
+pub async fn health_check_task(stack: &'static Stack<EspWifiDevice<'static>>) {
+    log::info!("Start health check task");
+    let mut ticker = Ticker::every(jittered(CHECK_INTERVAL));
+    let mut failures = 0;
+    loop {
+        ticker.next().await;
+        if esp_wifi::wifi::wifi_state() != WifiState::StaConnected {
+            failures = 0;
+            continue;
+        }
+        match check_gateway(stack).await {
+            Some(true) => failures = 0,
+            Some(false) => {
+                failures += 1;
+                log::warn!("Health check: Gateway did not answer ({failures} times in a row)");
+                if failures >= FAILURES_BEFORE_REASSOCIATION {
+                    log::warn!("Health check: Connection seems dead, requesting reassociation");
+                    REASSOCIATE.signal(());
+                    failures = 0;
+                }
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,713 @@
+//! Count updates over HTTP, the default transport.
+//!
+//! [`HttpTransport`] sends the count to the SpaceAPI sensor endpoint, by
+//! default as `PUT` with the form data `value=N`. The method, the content type
+//! and the body format can be changed at build time, and the count can also
+//! be sent to additional endpoints. Connections are kept alive between
+//! updates, requests can go through a plain HTTP proxy, and host names are
+//! resolved through the [`CachingDns`]. With `fetch-count` and `space-state`,
+//! the transport also fetches the count at boot and sends the open state of
+//! the space. Only plain HTTP is supported, no TLS.
+
+use core::fmt::Write;
+
+use embassy_net::{
+    dns::DnsSocket,
+    tcp::client::{TcpClient, TcpClientState, TcpConnection},
+    Stack,
+};
+use embedded_nal_async::{AddrType, Dns, SocketAddr, TcpConnect};
+use reqwless::{
+    client::{HttpConnection, HttpResource},
+    request::{Method, RequestBuilder},
+    response::{Status, StatusCode},
+};
+
+#[cfg(feature = "spaceapi-v14")]
+use crate::config::SensorMetadata;
+use crate::{
+    config::{HttpConfig, Url},
+    device_id::DeviceId,
+    dns_cache::CachingDns,
+    error_code::ErrorCode,
+    status::EndpointHealth,
+    transport::CountTransport,
+    EspDnsSocket, EspWifiDevice,
+};
+
+/// Name of the header carrying the device ID
+const DEVICE_ID_HEADER: &str = "x-device-id";
+
+/// Placeholder for the count in the payload template
+const COUNT_PLACEHOLDER: &str = "{count}";
+
+/// Key of the `people_now_present` sensors in the SpaceAPI JSON
+const PEOPLE_NOW_PRESENT_KEY: &str = "\"people_now_present\"";
+
+/// HTTP method of count updates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateMethod {
+    Put,
+    Post,
+}
+
+impl UpdateMethod {
+    /// Return whether sending the update twice has the same effect as once.
+    /// A `PUT` sets the count, a `POST` may be handled as a new event.
+    fn is_idempotent(self) -> bool {
+        self == Self::Put
+    }
+
+    fn method(self) -> Method {
+        match self {
+            Self::Put => Method::PUT,
+            Self::Post => Method::POST,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Put => "PUT",
+            Self::Post => "POST",
+        }
+    }
+}
+
+/// Format of the request body sent to the sensor endpoint.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PayloadFormat {
+    /// `value=<count>`, as expected by spaceapi-server-rs
+    #[cfg(not(any(feature = "spaceapi-v14", feature = "json-payload")))]
+    Form,
+    /// `{"value":<count>,"unit":"people"}`
+    #[cfg(all(feature = "json-payload", not(feature = "spaceapi-v14")))]
+    Json,
+    /// A `people_now_present` sensor object of the SpaceAPI v14 schema,
+    /// including the configured metadata
+    #[cfg(feature = "spaceapi-v14")]
+    SpaceApiV14(SensorMetadata),
+    /// The configured template, with every `{count}` replaced by the count
+    Template(&'static str),
+}
+
+type Payload = heapless::String<256>;
+
+impl PayloadFormat {
+    /// Return the configured format. The payload template takes precedence
+    /// over the format selected through the features.
+    fn new(config: &HttpConfig) -> Self {
+        if let Some(template) = config.payload_template {
+            return PayloadFormat::Template(template);
+        }
+        #[cfg(not(any(feature = "spaceapi-v14", feature = "json-payload")))]
+        let format = PayloadFormat::Form;
+        #[cfg(all(feature = "json-payload", not(feature = "spaceapi-v14")))]
+        let format = PayloadFormat::Json;
+        #[cfg(feature = "spaceapi-v14")]
+        let format = PayloadFormat::SpaceApiV14(config.sensor_metadata);
+        format
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            #[cfg(not(any(feature = "spaceapi-v14", feature = "json-payload")))]
+            PayloadFormat::Form => "application/x-www-form-urlencoded",
+            #[cfg(all(feature = "json-payload", not(feature = "spaceapi-v14")))]
+            PayloadFormat::Json => "application/json",
+            #[cfg(feature = "spaceapi-v14")]
+            PayloadFormat::SpaceApiV14(_) => "application/json",
+            PayloadFormat::Template(_) => "text/plain",
+        }
+    }
+
+    /// Return whether the response status confirms the update.
+    ///
+    /// SpaceAPI servers answer with `204 No Content`, or `200 OK` if they
+    /// report the count in the body. Other backends, talked to through a
+    /// template, may answer with any successful status.
+    fn is_confirmation(self, status: StatusCode) -> bool {
+        match self {
+            PayloadFormat::Template(_) => status.is_successful(),
+            _ => status == Status::NoContent || status == Status::Ok,
+        }
+    }
+
+    /// Format the request body for the specified count.
+    fn format(self, people_count: u8) -> Result<Payload, core::fmt::Error> {
+        let mut payload = Payload::new();
+        match self {
+            #[cfg(not(any(feature = "spaceapi-v14", feature = "json-payload")))]
+            PayloadFormat::Form => write!(payload, "value={people_count}")?,
+            #[cfg(all(feature = "json-payload", not(feature = "spaceapi-v14")))]
+            PayloadFormat::Json => {
+                write!(payload, "{{\"value\":{people_count},\"unit\":\"people\"}}")?
+            }
+            #[cfg(feature = "spaceapi-v14")]
+            PayloadFormat::SpaceApiV14(sensor) => {
+                write!(payload, "{{\"value\":{people_count}")?;
+                let metadata = [
+                    ("name", sensor.name),
+                    ("location", sensor.location),
+                    ("description", sensor.description),
+                ];
+                for (key, value) in metadata {
+                    if let Some(value) = value {
+                        write!(payload, ",\"{key}\":")?;
+                        write_json_string(&mut payload, value)?;
+                    }
+                }
+                payload.push('}').map_err(|_| core::fmt::Error)?;
+            }
+            PayloadFormat::Template(template) => {
+                let mut parts = template.split(COUNT_PLACEHOLDER);
+                payload
+                    .push_str(parts.next().unwrap_or_default())
+                    .map_err(|_| core::fmt::Error)?;
+                for part in parts {
+                    write!(payload, "{people_count}{part}")?;
+                }
+            }
+        }
+        Ok(payload)
+    }
+}
+
+/// Number of additional sensor endpoints. Each of them needs its own socket.
+pub const EXTRA_ENDPOINT_COUNT: usize = crate::config::SPACEAPI_SENSOR_EXTRA_ENDPOINTS.len();
+
+/// Number of endpoints the count is sent to, including the primary one
+const TARGET_COUNT: usize = 1 + EXTRA_ENDPOINT_COUNT;
+
+type EspTcpClient<'a> = TcpClient<'a, EspWifiDevice<'a>, TARGET_COUNT>;
+type EspTcpConnection<'a> = TcpConnection<'a, TARGET_COUNT, 1024, 1024>;
+
+/// An open HTTP connection to the sensor endpoint host.
+///
+/// The base path is left empty, requests use the full path of the endpoint.
+type EspHttpConnection = HttpResource<'static, EspTcpConnection<'static>>;
+
+/// Where and how requests for a URL are sent.
+///
+/// Without a proxy, the connection is opened to the host of the URL and the
+/// request carries the path. With a proxy, the connection is opened to the
+/// proxy and the request carries the full URL (absolute form), which the
+/// proxy forwards. Only plain HTTP is supported, so there is no need for
+/// `CONNECT` tunnels.
+#[derive(Debug, Copy, Clone)]
+struct Route {
+    /// Host and port the connection is opened to
+    server: &'static str,
+    port: u16,
+    /// Value of the `Host` header
+    host: &'static str,
+    /// Request target
+    target: &'static str,
+}
+
+impl Route {
+    /// Return the route for the URL, through the proxy if any.
+    fn new(url: Url, proxy: Option<Url>) -> Self {
+        match proxy {
+            Some(proxy) => Self {
+                server: proxy.host,
+                port: proxy.port,
+                host: url.host,
+                target: url.url,
+            },
+            None => Self {
+                server: url.host,
+                port: url.port,
+                host: url.host,
+                target: url.path,
+            },
+        }
+    }
+
+    /// Parse the URL of the sensor endpoint, which unlike the other URLs can
+    /// be changed at runtime.
+    fn parse(url: &'static str, proxy: Option<Url>) -> Option<Self> {
+        let (host, port, path) = parse_url(url)?;
+        Some(Self::new(
+            Url {
+                url,
+                host,
+                port,
+                path,
+            },
+            proxy,
+        ))
+    }
+}
+
+/// A sensor endpoint the count is sent to.
+struct Target {
+    endpoint: &'static str,
+    route: Route,
+    connection: Option<EspHttpConnection>,
+    /// Reachability of the endpoint. Only tracked for the additional
+    /// endpoints, the primary one is tracked by the caller.
+    health: EndpointHealth,
+}
+
+/// Method, content type and body format of count updates, as configured at
+/// build time.
+#[derive(Debug, Copy, Clone)]
+struct UpdateRequest {
+    method: UpdateMethod,
+    content_type: &'static str,
+    format: PayloadFormat,
+}
+
+impl UpdateRequest {
+    fn new(config: &HttpConfig) -> Self {
+        let format = PayloadFormat::new(config);
+        Self {
+            method: config.method,
+            content_type: config.content_type.unwrap_or(format.content_type()),
+            format,
+        }
+    }
+}
+
+impl Target {
+    fn new(endpoint: &'static str, route: Route) -> Self {
+        Self {
+            endpoint,
+            route,
+            connection: None,
+            health: EndpointHealth::new(),
+        }
+    }
+}
+
+/// Update the sensor through an HTTP request to the SpaceAPI sensor endpoint,
+/// with the configured method (PUT by default, or POST).
+///
+/// The method, the content type and the body can be changed at build time,
+/// see [`UpdateRequest`].
+///
+/// Optionally, every update is also sent to the additional endpoints in
+/// `SPACEAPI_SENSOR_EXTRA_ENDPOINTS`, after the primary endpoint. The result
+/// of an update is the one of the primary endpoint, failures of the other
+/// endpoints are only logged.
+///
+/// The connections to the servers are kept alive and reused for subsequent
+/// updates. If a request on a reused connection fails, e.g. because the server
+/// closed it in the meantime, a `PUT` is repeated on a new connection. A
+/// `POST` isn't, since the server may have applied it already; the update
+/// fails, and the next one opens a new connection.
+pub struct HttpTransport {
+    tcp_client: &'static EspTcpClient<'static>,
+    dns: &'static CachingDns<EspDnsSocket<'static>>,
+    /// The primary endpoint, followed by the additional ones
+    targets: heapless::Vec<Target, TARGET_COUNT>,
+    config: HttpConfig,
+    update: UpdateRequest,
+    /// Values of the `User-Agent` and device ID headers sent with every
+    /// request
+    user_agent: heapless::String<48>,
+    device_id: heapless::String<12>,
+    /// Buffer for the response headers and body
+    rx_buf: &'static mut [u8; 4096],
+}
+
+impl HttpTransport {
+    /// Create a new instance for the specified primary endpoint URL (without
+    /// TLS support for now).
+    ///
+    /// If a proxy is configured, all requests are sent through it.
+    pub fn new(
+        stack: &'static Stack<EspWifiDevice<'static>>,
+        endpoint: &'static str,
+        config: HttpConfig,
+    ) -> Self {
+        let mut targets = heapless::Vec::new();
+        // Checked by `build.rs`, or by `Settings::validate` if it was stored
+        let route = Route::parse(endpoint, config.proxy).expect("Invalid sensor endpoint URL");
+        let _ = targets.push(Target::new(endpoint, route));
+        for endpoint in config.extra_endpoints {
+            // There is a slot for every endpoint
+            let route = Route::new(*endpoint, config.proxy);
+            let _ = targets.push(Target::new(endpoint.url, route));
+        }
+
+        let client_state = &*mk_static!(
+            TcpClientState<TARGET_COUNT, 1024, 1024>,
+            TcpClientState::<TARGET_COUNT, 1024, 1024>::new()
+        );
+        let tcp_client = &*mk_static!(
+            TcpClient<'static, EspWifiDevice<'static>, TARGET_COUNT>,
+            TcpClient::new(stack, client_state)
+        );
+        let dns = &*mk_static!(
+            CachingDns<EspDnsSocket<'_>>,
+            CachingDns::new(DnsSocket::new(stack))
+        );
+        let device_id = DeviceId::read();
+        let mut device_id_string = heapless::String::new();
+        // 12 hex digits always fit
+        let _ = write!(device_id_string, "{device_id}");
+        Self {
+            tcp_client,
+            dns,
+            targets,
+            config,
+            update: UpdateRequest::new(&config),
+            user_agent: device_id.user_agent(),
+            device_id: device_id_string,
+            rx_buf: mk_static!([u8; 4096], [0; 4096]),
+        }
+    }
+
+    /// Open a new connection for requests on the specified route.
+    async fn connect(
+        tcp_client: &'static EspTcpClient<'static>,
+        dns: &CachingDns<EspDnsSocket<'static>>,
+        route: Route,
+    ) -> anyhow::Result<EspHttpConnection> {
+        let server = route.server;
+        let address = match dns.get_host_by_name(server, AddrType::Either).await {
+            Ok(address) => address,
+            Err(e) => {
+                log::error!("DNS lookup for {} failed: {:?}", server, e);
+                return Err(ErrorCode::DnsFailed.error("DNS lookup failed"));
+            }
+        };
+        let connection = match tcp_client
+            .connect(SocketAddr::new(address, route.port))
+            .await
+        {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("Could not connect to {}: {:?}", server, e);
+                anyhow::bail!("HTTP request failed");
+            }
+        };
+        log::debug!("Opened HTTP connection to {}", server);
+        Ok(HttpResource {
+            conn: HttpConnection::Plain(connection),
+            host: route.host,
+            base_path: "",
+        })
+    }
+
+    /// Send the update request to the target at `index` on the open
+    /// connection, opening a new one if needed.
+    ///
+    /// Returns the response status and the count reported in the response
+    /// body, if any. On failure, the connection is closed.
+    async fn send_update(
+        &mut self,
+        index: usize,
+        payload: &[u8],
+        authorization: Option<&str>,
+    ) -> anyhow::Result<(StatusCode, Option<u8>)> {
+        let mut headers = common_headers(
+            &self.user_agent,
+            &self.device_id,
+            self.config.proxy_authorization,
+        );
+        let _ = headers.push(("content-type", self.update.content_type));
+        if let Some(value) = authorization {
+            let _ = headers.push(("authorization", value));
+        }
+
+        let target = &mut self.targets[index];
+        let connection = match target.connection.as_mut() {
+            Some(connection) => connection,
+            None => {
+                let connection = Self::connect(self.tcp_client, self.dns, target.route).await?;
+                target.connection.insert(connection)
+            }
+        };
+        let result = connection
+            .request(self.update.method.method(), target.route.target)
+            .headers(&headers)
+            .body(payload)
+            .send(&mut self.rx_buf[..])
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                log::debug!("HTTP request error: {:?}", e);
+                target.connection = None;
+                anyhow::bail!("HTTP request failed");
+            }
+        };
+
+        // The body must be consumed before the connection can be reused
+        let status = response.status;
+        let reported_count = if status.is_successful() {
+            match response.body().read_to_end().await {
+                Ok(body) => parse_count(body),
+                Err(e) => {
+                    log::debug!("HTTP response error: {:?}", e);
+                    target.connection = None;
+                    None
+                }
+            }
+        } else {
+            if response.body().discard().await.is_err() {
+                target.connection = None;
+            }
+            None
+        };
+        Ok((status, reported_count))
+    }
+
+    /// Send the count to the target at `index`.
+    ///
+    /// Returns the count reported by the server, if any.
+    async fn send_count_to(
+        &mut self,
+        index: usize,
+        people_count: u8,
+        payload: &[u8],
+    ) -> anyhow::Result<Option<u8>> {
+        // Send request
+        log::info!(
+            "-> {} {}",
+            self.update.method.as_str(),
+            self.targets[index].endpoint
+        );
+        let reused = self.targets[index].connection.is_some();
+        let authorization = self.config.authorization;
+        let mut result = self.send_update(index, payload, authorization).await;
+        // The server probably closed the idle connection, try a new one. The
+        // request may have reached the server anyway (e.g. if the response
+        // timed out), so only repeat it if that is harmless.
+        if result.is_err() && reused && self.update.method.is_idempotent() {
+            log::debug!("Kept-alive HTTP connection failed, reconnecting");
+            result = self.send_update(index, payload, authorization).await;
+        }
+        let (status, reported_count) = match result {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Could not update the sensor: {}", e);
+                // The address might have changed, resolve it again next time
+                self.dns.invalidate();
+                return Err(e);
+            }
+        };
+
+        // Process response
+        log::info!("<- HTTP {}", status.0);
+        if self.update.format.is_confirmation(status) {
+            log::info!("Successfully set people now present count to {people_count}");
+            if let Some(count) = reported_count {
+                log::info!("Server reported count {count}");
+            }
+            Ok(reported_count)
+        } else {
+            self.targets[index].connection = None;
+            Err(ErrorCode::for_status_class(status.0 / 100)
+                .error("Received unexpected HTTP status code when sending status update"))
+        }
+    }
+
+    /// Open a connection for a one-off request on the specified route.
+    #[cfg(any(feature = "fetch-count", feature = "space-state"))]
+    async fn connect_one_off(&mut self, route: Route) -> anyhow::Result<EspHttpConnection> {
+        // Every socket may be in use by a kept-alive connection, free one
+        self.targets[0].connection = None;
+        Self::connect(self.tcp_client, self.dns, route).await
+    }
+}
+
+impl CountTransport for HttpTransport {
+    #[cfg(feature = "fetch-count")]
+    async fn fetch_count(&mut self) -> anyhow::Result<Option<u8>> {
+        let url = self.config.spaceapi_url;
+        let route = Route::new(url, self.config.proxy);
+
+        log::info!("-> GET {}", url.url);
+        let mut connection = self.connect_one_off(route).await?;
+        let headers = common_headers(
+            &self.user_agent,
+            &self.device_id,
+            self.config.proxy_authorization,
+        );
+        let response = match connection
+            .request(Method::GET, route.target)
+            .headers(&headers)
+            .send(&mut self.rx_buf[..])
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                log::debug!("HTTP request error: {:?}", e);
+                anyhow::bail!("HTTP request failed");
+            }
+        };
+        log::info!("<- HTTP {}", response.status.0);
+        if !response.status.is_successful() {
+            return Err(ErrorCode::for_status_class(response.status.0 / 100)
+                .error("Received unexpected HTTP status code when fetching the count"));
+        }
+        let body = match response.body().read_to_end().await {
+            Ok(body) => body,
+            Err(e) => {
+                log::debug!("HTTP response error: {:?}", e);
+                anyhow::bail!("Could not read SpaceAPI response (larger than 4 KiB?)");
+            }
+        };
+        let count = parse_people_now_present(body)
+            .ok_or_else(|| anyhow::anyhow!("No people_now_present value in SpaceAPI response"))?;
+        Ok(Some(count))
+    }
+
+    #[cfg(feature = "space-state")]
+    async fn send_state(&mut self, open: bool) -> anyhow::Result<()> {
+        let url = self.config.state_endpoint;
+        let route = Route::new(url, self.config.proxy);
+        let payload = if open { "open=true" } else { "open=false" };
+
+        log::info!("-> PUT {}", url.url);
+        let mut connection = self.connect_one_off(route).await?;
+        let mut headers = common_headers(
+            &self.user_agent,
+            &self.device_id,
+            self.config.proxy_authorization,
+        );
+        let _ = headers.push(("content-type", "application/x-www-form-urlencoded"));
+        if let Some(value) = self.config.authorization {
+            let _ = headers.push(("authorization", value));
+        }
+        let response = match connection
+            .request(Method::PUT, route.target)
+            .headers(&headers)
+            .body(payload.as_bytes())
+            .send(&mut self.rx_buf[..])
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                log::debug!("HTTP request error: {:?}", e);
+                anyhow::bail!("HTTP request failed");
+            }
+        };
+        log::info!("<- HTTP {}", response.status.0);
+        if response.status.is_successful() {
+            log::info!(
+                "Successfully set space state to {}",
+                if open { "open" } else { "closed" }
+            );
+            Ok(())
+        } else {
+            Err(ErrorCode::for_status_class(response.status.0 / 100)
+                .error("Received unexpected HTTP status code when setting the space state"))
+        }
+    }
+
+    async fn send_count(&mut self, people_count: u8) -> anyhow::Result<Option<u8>> {
+        // Prepare payload
+        let payload_string = self
+            .update
+            .format
+            .format(people_count)
+            .map_err(|_| anyhow::anyhow!("Sensor payload too long"))?;
+        let payload = payload_string.as_bytes();
+
+        // Send to the primary endpoint, then to the additional ones. Only the
+        // count reported by the primary endpoint is authoritative.
+        let result = self.send_count_to(0, people_count, payload).await;
+        for index in 1..self.targets.len() {
+            let success = self
+                .send_count_to(index, people_count, payload)
+                .await
+                .is_ok();
+            let target = &mut self.targets[index];
+            match target.health.record(success) {
+                Some(true) => log::info!("Endpoint {} is reachable again", target.endpoint),
+                Some(false) => log::warn!("Endpoint {} is unreachable", target.endpoint),
+                None => {}
+            }
+        }
+        result
+    }
+}
+
+/// Return the headers sent with every request.
+fn common_headers<'a>(
+    user_agent: &'a str,
+    device_id: &'a str,
+    proxy_authorization: Option<&'a str>,
+) -> heapless::Vec<(&'a str, &'a str), 6> {
+    let mut headers = heapless::Vec::new();
+    let _ = headers.push(("user-agent", user_agent));
+    let _ = headers.push((DEVICE_ID_HEADER, device_id));
+    if let Some(value) = proxy_authorization {
+        let _ = headers.push(("proxy-authorization", value));
+    }
+    headers
+}
+
+/// Return whether the URL can be used as sensor endpoint.
+#[cfg(feature = "config-store")]
+pub fn is_valid_endpoint(url: &str) -> bool {
+    parse_url(url).is_some()
+}
+
+/// Parse the count reported in the body of an update response.
+///
+/// Only SpaceAPI JSON with a `people_now_present` sensor counts, other bodies
+/// (e.g. `1` or `{"ok":true,"value":0}` from a backend that doesn't report
+/// the count) are ignored instead of overwriting the count.
+fn parse_count(body: &[u8]) -> Option<u8> {
+    let json = core::str::from_utf8(body).ok()?;
+    let sensor = &json[json.find(PEOPLE_NOW_PRESENT_KEY)?..];
+    parse_value(sensor)
+}
+
+/// Find the value of the first `people_now_present` sensor in a SpaceAPI JSON
+/// document, or of a sensor object on its own, as returned by a companion
+/// endpoint for `SPACEAPI_URL`.
+#[cfg(feature = "fetch-count")]
+fn parse_people_now_present(json: &[u8]) -> Option<u8> {
+    let json = core::str::from_utf8(json).ok()?;
+    match json.find(PEOPLE_NOW_PRESENT_KEY) {
+        Some(i) => parse_value(&json[i..]),
+        None => parse_value(json),
+    }
+}
+
+/// Parse the first `"value"` in the JSON.
+///
+/// This is not a full JSON parser, but good enough for the SpaceAPI schema,
+/// where the sensor objects only contain strings and the value.
+fn parse_value(sensor: &str) -> Option<u8> {
+    let value = &sensor[sensor.find("\"value\"")? + "\"value\"".len()..];
+    let value = value.trim_start().strip_prefix(':')?.trim_start();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    value[..digits].parse().ok()
+}
+
+/// Append a string as quoted and escaped JSON string.
+#[cfg(feature = "spaceapi-v14")]
+fn write_json_string(out: &mut impl Write, value: &str) -> core::fmt::Result {
+    out.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            c if u32::from(c) < 0x20 => write!(out, "\\u{:04x}", u32::from(c))?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+/// Split a `http://host[:port]/path` URL into its host, port and path.
+fn parse_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    match authority.split_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?, path)),
+        None => Some((authority, 80, path)),
+    }
+}
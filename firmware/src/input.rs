@@ -0,0 +1,110 @@
+//! Reading the toggle switch from a task of its own.
+//!
+//! [`input_task`] classifies the presses of the [`ToggleSwitch`] and sends
+//! them to the main loop as [`InputEvent`]s, like the main loop sends
+//! commands to the display task. That way, the switch is still read while
+//! the main loop waits for a slow request, and the presses meanwhile are
+//! counted once it is done, instead of being lost.
+
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex,
+    channel::{Receiver, Sender},
+};
+#[cfg(not(feature = "io-expander"))]
+use esp_hal::gpio::Input;
+
+#[cfg(feature = "io-expander")]
+use crate::io_expander::ExpanderPin;
+use crate::toggle_switch::{Direction, SwitchEvent, ToggleSwitch};
+#[cfg(feature = "touch")]
+use crate::touch::TouchPad;
+
+/// Number of events that can be queued
+pub const QUEUE_LEN: usize = 8;
+
+pub type InputSender = Sender<'static, NoopRawMutex, InputEvent, QUEUE_LEN>;
+pub type InputReceiver = Receiver<'static, NoopRawMutex, InputEvent, QUEUE_LEN>;
+
+/// A pin of the toggle switch, directly on a GPIO or on the I/O expander
+#[cfg(not(feature = "io-expander"))]
+type RawPin = Input<'static>;
+#[cfg(feature = "io-expander")]
+type RawPin = ExpanderPin;
+
+/// A pin of the toggle switch, or with `touch` a touch pad
+#[cfg(not(feature = "touch"))]
+pub type SwitchPin = RawPin;
+#[cfg(feature = "touch")]
+pub type SwitchPin = TouchPad<RawPin>;
+
+/// An input on the toggle switch, see
+/// [`ToggleSwitch::wait_for_event`](crate::toggle_switch::ToggleSwitch::wait_for_event).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Pressed up, or repeated while held up
+    Up,
+    /// Pressed down, or repeated while held down
+    Down,
+    /// Held up for the long press duration
+    LongUp,
+    /// Held down for the long press duration
+    LongDown,
+    /// Pressed up twice
+    DoubleUp,
+    /// Pressed down twice
+    DoubleDown,
+    /// Pressed up and down at once
+    Chord,
+    /// Released after any of the other events
+    Release,
+}
+
+impl InputEvent {
+    /// Return the event of a single press.
+    fn press(direction: Direction) -> Self {
+        match direction {
+            Direction::Up => Self::Up,
+            Direction::Down => Self::Down,
+        }
+    }
+}
+
+impl From<SwitchEvent> for InputEvent {
+    fn from(event: SwitchEvent) -> Self {
+        match event {
+            SwitchEvent::Press(direction) => Self::press(direction),
+            SwitchEvent::LongPress(Direction::Up) => Self::LongUp,
+            SwitchEvent::LongPress(Direction::Down) => Self::LongDown,
+            SwitchEvent::DoublePress(Direction::Up) => Self::DoubleUp,
+            SwitchEvent::DoublePress(Direction::Down) => Self::DoubleDown,
+            SwitchEvent::Chord => Self::Chord,
+        }
+    }
+}
+
+/// Task: Read the toggle switch
+#[embassy_executor::task]
+pub async fn input_task(
+    mut toggle_switch: ToggleSwitch<SwitchPin, SwitchPin>,
+    sender: InputSender,
+) {
+    log::info!("Start input task");
+    loop {
+        let event = toggle_switch.wait_for_event().await;
+        log::info!("{:?}", event);
+        sender.send(event.into()).await;
+        match event {
+            // Repeat presses while the switch is held, with auto-repeat
+            SwitchEvent::Press(_) | SwitchEvent::DoublePress(_) => {
+                while let Some(direction) = toggle_switch.wait_for_repeat().await {
+                    log::debug!("Repeating {:?}", direction);
+                    sender.send(InputEvent::press(direction)).await;
+                }
+            }
+            SwitchEvent::LongPress(_) | SwitchEvent::Chord => {
+                toggle_switch.wait_for_release().await;
+            }
+        }
+        sender.send(InputEvent::Release).await;
+    }
+}
@@ -0,0 +1,186 @@
+//! Inputs on an I2C GPIO expander.
+//!
+//! To free GPIOs for the display, the contacts of the toggle switch (and the
+//! motion sensor of `motion-wake`) can be connected to a PCF8574 or MCP23017
+//! (port A) instead, which shares the I2C bus with the room temperature
+//! sensor. [`io_expander_task`] reads the port whenever the expander signals
+//! a change on its interrupt output, and publishes it. Each [`ExpanderPin`]
+//! follows one bit of it, and implements the embedded-hal traits like an
+//! input pin, so that the [`ToggleSwitch`](crate::toggle_switch::ToggleSwitch)
+//! works on it unchanged.
+
+use core::convert::Infallible;
+
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    watch::{Receiver, Watch},
+};
+use embassy_time::{Duration, Timer};
+use embedded_hal::{
+    digital::{ErrorType, InputPin},
+    i2c::I2c,
+};
+use embedded_hal_async::digital::Wait;
+use esp_hal::gpio::Input;
+
+use crate::SharedI2c;
+
+/// Maximum number of [`ExpanderPin`]s
+const MAX_PINS: usize = 4;
+
+/// Interval between two reads if no interrupt arrives, in case one was
+/// missed
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// MCP23017 registers, with the default bank layout
+const MCP23017_GPINTENA: u8 = 0x04;
+const MCP23017_GPPUA: u8 = 0x0C;
+const MCP23017_GPIOA: u8 = 0x12;
+
+/// The latest state of the port, one bit per pin, high while released
+static PORT: Watch<CriticalSectionRawMutex, u8, MAX_PINS> = Watch::new();
+
+/// A supported expander chip.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Chip {
+    /// PCF8574 or PCF8574A, whose pins have weak pull-ups
+    Pcf8574,
+    /// MCP23017, with the inputs on port A and the internal pull-ups enabled
+    Mcp23017,
+}
+
+/// An expander on the I2C bus.
+struct IoExpander<I2C> {
+    i2c: I2C,
+    chip: Chip,
+    address: u8,
+}
+
+impl<I2C: I2c> IoExpander<I2C> {
+    /// Configure all pins as inputs with pull-ups, with an interrupt on every
+    /// change.
+    fn init(&mut self) -> Result<(), I2C::Error> {
+        match self.chip {
+            // Pins written high are inputs
+            Chip::Pcf8574 => self.i2c.write(self.address, &[0xFF]),
+            Chip::Mcp23017 => {
+                self.i2c.write(self.address, &[MCP23017_GPPUA, 0xFF])?;
+                self.i2c.write(self.address, &[MCP23017_GPINTENA, 0xFF])
+            }
+        }
+    }
+
+    /// Read the port, which also clears the interrupt.
+    fn read(&mut self) -> Result<u8, I2C::Error> {
+        let mut port = [0];
+        match self.chip {
+            Chip::Pcf8574 => self.i2c.read(self.address, &mut port)?,
+            Chip::Mcp23017 => self
+                .i2c
+                .write_read(self.address, &[MCP23017_GPIOA], &mut port)?,
+        }
+        Ok(port[0])
+    }
+}
+
+/// Task: Read the GPIO expander on every interrupt
+#[embassy_executor::task]
+pub async fn io_expander_task(
+    i2c: SharedI2c,
+    mut interrupt: Input<'static>,
+    chip: Chip,
+    address: u8,
+) {
+    log::info!("Start I/O expander task ({:?} at {:#04x})", chip, address);
+    let mut expander = IoExpander { i2c, chip, address };
+    if let Err(e) = expander.init() {
+        log::warn!("Could not configure the I/O expander: {:?}", e);
+    }
+    let sender = PORT.sender();
+    loop {
+        match expander.read() {
+            Ok(port) => sender.send_if_modified(|latest| {
+                let modified = *latest != Some(port);
+                *latest = Some(port);
+                modified
+            }),
+            Err(e) => log::warn!("Could not read the I/O expander: {:?}", e),
+        }
+        // The interrupt output is active low, until the port is read
+        select(interrupt.wait_for_low(), Timer::after(POLL_INTERVAL)).await;
+    }
+}
+
+/// A pin of the expander, low while its contact is closed.
+pub struct ExpanderPin {
+    port: Receiver<'static, CriticalSectionRawMutex, u8, MAX_PINS>,
+    mask: u8,
+}
+
+impl ExpanderPin {
+    /// Follow the pin with the specified number (0-7, P0-P7 or GPA0-GPA7).
+    pub fn new(pin: u8) -> Self {
+        Self {
+            port: PORT.receiver().expect("Too many expander pins"),
+            mask: 1 << pin,
+        }
+    }
+
+    /// Return whether the pin is high, or `None` before the first read.
+    fn level(&mut self) -> Option<bool> {
+        let mask = self.mask;
+        self.port.try_get().map(|port| port & mask != 0)
+    }
+
+    /// Wait until the pin has the specified level.
+    async fn wait_for_level(&mut self, high: bool) {
+        let mask = self.mask;
+        self.port.get_and(|port| (port & mask != 0) == high).await;
+    }
+}
+
+impl ErrorType for ExpanderPin {
+    type Error = Infallible;
+}
+
+/// Pins count as high (released) until the port was read.
+impl InputPin for ExpanderPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.level().unwrap_or(true))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.level().unwrap_or(true))
+    }
+}
+
+impl Wait for ExpanderPin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(false).await;
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(true).await;
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        let high = self.level().unwrap_or(true);
+        self.wait_for_level(!high).await;
+        Ok(())
+    }
+}
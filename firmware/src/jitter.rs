@@ -0,0 +1,37 @@
+//! Per-boot jitter of scheduled intervals.
+//!
+//! Counters that are powered on at the same time, e.g. after a power outage,
+//! would otherwise send their periodic requests in lockstep, and keep doing so
+//! on round timestamps. Every scheduled interval is therefore stretched by a
+//! random fraction of up to [`MAX_JITTER_PERMILLE`]. The fraction is chosen
+//! once at boot from the hardware RNG, so the schedules of different counters
+//! drift apart while each one keeps a steady period.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::Duration;
+
+/// Maximum amount an interval is stretched by, in per mille
+const MAX_JITTER_PERMILLE: u32 = 100;
+
+/// Amount the intervals are stretched by on this boot, in per mille
+static JITTER_PERMILLE: AtomicU32 = AtomicU32::new(0);
+
+/// Choose the jitter of this boot from the specified random number.
+///
+/// Must be called before any task using [`jittered`] is spawned.
+pub fn init(random: u32) {
+    let permille = random % (MAX_JITTER_PERMILLE + 1);
+    JITTER_PERMILLE.store(permille, Ordering::Relaxed);
+    log::debug!(
+        "Scheduled intervals are stretched by {}.{}%",
+        permille / 10,
+        permille % 10
+    );
+}
+
+/// Return the specified interval stretched by the jitter of this boot.
+pub fn jittered(interval: Duration) -> Duration {
+    let permille = u64::from(JITTER_PERMILLE.load(Ordering::Relaxed));
+    interval + Duration::from_micros(interval.as_micros() * permille / 1000)
+}
@@ -0,0 +1,111 @@
+use crate::{flash_log::FlashLog, storage::Sector};
+
+/// Marker in the upper bytes of a word recording a pending update. Words
+/// with the marker of older firmware (`0x5A5A`), which recorded the count
+/// only, are ignored, since the change they made can't be told.
+const PENDING_MARKER: u32 = 0x5A5B_0000;
+
+/// Word recording that the previous pending count was resolved (either
+/// confirmed by the server, or rejected and not applied locally).
+const CLEARED: u32 = 0x0000_0000;
+
+/// Journal of count changes that have not yet been confirmed by the server,
+/// stored in flash so that it survives reboots.
+///
+/// The journal is a [`FlashLog`] of 32 bit words. A pending update is
+/// recorded before sending it, and a "cleared" word is appended once the
+/// request completed. The last word describes the complete state, so no
+/// history needs to be carried over when the sector is erased.
+pub struct Journal {
+    log: FlashLog,
+    /// The current pending update, if any
+    pending: Option<PendingUpdate>,
+}
+
+/// An update that was recorded as pending, but never cleared.
+///
+/// Both the count it changed and the count it sent are recorded, so that
+/// the change can be replayed onto another count, e.g. the count on the
+/// server after a reboot, which may have changed meanwhile.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PendingUpdate {
+    /// The last confirmed count the presses were applied to
+    pub base: u8,
+    /// The count sent to the server
+    pub count: u8,
+}
+
+impl PendingUpdate {
+    /// Return the change of the count by the presses.
+    pub fn delta(&self) -> i16 {
+        i16::from(self.count) - i16::from(self.base)
+    }
+
+    /// Return the count after applying the change to `count`.
+    pub fn apply(&self, count: u8) -> u8 {
+        (i16::from(count) + self.delta()).clamp(0, u8::MAX.into()) as u8
+    }
+
+    fn to_word(self) -> u32 {
+        PENDING_MARKER | u32::from(self.base) << 8 | u32::from(self.count)
+    }
+
+    fn from_word(word: u32) -> Option<Self> {
+        (word & 0xFFFF_0000 == PENDING_MARKER).then_some(Self {
+            base: (word >> 8) as u8,
+            count: word as u8,
+        })
+    }
+}
+
+impl Journal {
+    /// Open the journal, scanning it for the latest entry.
+    pub fn new() -> Self {
+        let mut log = FlashLog::new(Sector::Journal, "pending update journal");
+        let mut pending = None;
+        log.scan_words(|word| {
+            pending = PendingUpdate::from_word(word);
+        });
+        Self { log, pending }
+    }
+
+    /// Return the update that was recorded as pending but never cleared.
+    pub fn pending(&self) -> Option<PendingUpdate> {
+        self.pending
+    }
+
+    /// Record that `count` is about to be sent, changed by presses from the
+    /// last confirmed count `base`. While an earlier update is still
+    /// pending, its base is kept, since it was never confirmed either.
+    pub fn record_pending(&mut self, base: u8, count: u8) {
+        let base = self.pending.map_or(base, |pending| pending.base);
+        self.record(PendingUpdate { base, count });
+    }
+
+    /// Replay the pending update onto `base`, e.g. the count fetched from
+    /// the server, and record the result as pending instead. Returns the
+    /// count to send, if an update is pending.
+    pub fn rebase(&mut self, base: u8) -> Option<u8> {
+        let pending = self.pending?;
+        let count = pending.apply(base);
+        self.record(PendingUpdate { base, count });
+        Some(count)
+    }
+
+    fn record(&mut self, pending: PendingUpdate) {
+        if self.pending == Some(pending) {
+            return;
+        }
+        self.log.append_word(pending.to_word());
+        self.pending = Some(pending);
+    }
+
+    /// Record that the request for the pending count has completed.
+    pub fn clear(&mut self) {
+        if self.pending.is_none() {
+            return;
+        }
+        self.log.append_word(CLEARED);
+        self.pending = None;
+    }
+}
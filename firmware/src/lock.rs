@@ -0,0 +1,59 @@
+//! Child lock, which ignores the toggle switch.
+//!
+//! While locked, e.g. at an open-house event, presses don't change the count,
+//! and only blink the tubes to show that the counter is locked. The lock is
+//! toggled by holding the chord of both directions, or through the console,
+//! and survives reboots.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{flash_log::FlashLog, storage::Sector};
+
+/// Word recording that the counter was locked.
+const LOCKED_WORD: u32 = 0x4C4F_434B;
+
+/// Word recording that the counter was unlocked.
+const UNLOCKED_WORD: u32 = 0x0000_0000;
+
+/// State of the lock, shared by the main loop and the console
+static STATE: Mutex<CriticalSectionRawMutex, Cell<State>> = Mutex::new(Cell::new(State {
+    locked: false,
+    log: FlashLog::new(Sector::Lock, "lock state"),
+}));
+
+#[derive(Debug, Copy, Clone)]
+struct State {
+    locked: bool,
+    log: FlashLog,
+}
+
+/// Load the lock state stored in flash. Call once at boot.
+///
+/// Like the pending update journal, the sector is a [`FlashLog`] of 32 bit
+/// words, each recording a change of the lock.
+pub fn load() {
+    let mut state = STATE.lock(Cell::get);
+    state
+        .log
+        .scan_words(|word| state.locked = word == LOCKED_WORD);
+    STATE.lock(|cell| cell.set(state));
+}
+
+/// Return whether the counter is locked.
+pub fn is_locked() -> bool {
+    STATE.lock(|cell| cell.get().locked)
+}
+
+/// Lock or unlock the counter, and store it in flash.
+pub fn set_locked(locked: bool) {
+    let mut state = STATE.lock(Cell::get);
+    if state.locked == locked {
+        return;
+    }
+    state.locked = locked;
+    let word = if locked { LOCKED_WORD } else { UNLOCKED_WORD };
+    state.log.append_word(word);
+    STATE.lock(|cell| cell.set(state));
+}
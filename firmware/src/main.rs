@@ -1,26 +1,53 @@
 #![no_std]
 #![no_main]
 
-use core::{fmt::Write, str::FromStr};
+use core::str::FromStr;
 
+#[cfg(any(feature = "room-temperature", feature = "io-expander"))]
+use core::cell::RefCell;
 use embassy_executor::Spawner;
-use embassy_futures::select::{select, Either};
-use embassy_net::{
-    dns::DnsSocket,
-    tcp::client::{TcpClient, TcpClientState},
-    DhcpConfig, Stack, StackResources,
-};
+use embassy_futures::select::{select, select4, Either, Either4};
+#[cfg(feature = "show-ip")]
+use embassy_net::Ipv4Address;
+use embassy_net::{dns::DnsSocket, DhcpConfig, Stack, StackResources};
+#[cfg(any(
+    feature = "websocket",
+    feature = "broadcast",
+    feature = "webhook",
+    feature = "persist-count"
+))]
+use embassy_sync::signal::Signal;
 use embassy_sync::{
     blocking_mutex::raw::NoopRawMutex,
     channel::{Channel, Receiver, Sender},
 };
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use esp_alloc as _;
 use esp_backtrace as _;
+#[cfg(all(
+    feature = "seven-segment",
+    not(any(feature = "multiplexed", feature = "shift-register"))
+))]
+use esp_hal::gpio::OutputOpenDrain;
+
+#[cfg(any(feature = "room-temperature", feature = "io-expander"))]
+use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
+#[cfg(any(feature = "room-temperature", feature = "io-expander"))]
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+#[cfg(any(feature = "room-temperature", feature = "io-expander"))]
+use esp_hal::i2c::master::{Config as I2cConfig, I2c};
+#[cfg(feature = "multiplexed")]
+use esp_hal::interrupt::{software::SoftwareInterruptControl, Priority};
+#[cfg(feature = "console")]
+use esp_hal::usb_serial_jtag::UsbSerialJtag;
 use esp_hal::{
-    gpio::{Level, Output},
+    gpio::{GpioPin, Input, Level, Output, Pull},
     timer::timg::TimerGroup,
 };
+#[cfg(all(feature = "shift-register", not(feature = "multiplexed")))]
+use esp_hal::{spi::master::Spi, Blocking};
+#[cfg(feature = "multiplexed")]
+use esp_hal_embassy::InterruptExecutor;
 use esp_println::println;
 use esp_wifi::{
     wifi::{
@@ -29,45 +56,329 @@ use esp_wifi::{
     },
     EspWifiController,
 };
-use reqwless::{
-    client::HttpClient,
-    request::{Method, RequestBuilder},
-    response::Status,
-};
 use toggle_switch::Direction;
 
+// The I2C bus of the room temperature sensor and the I/O expander uses pins
+// of the right tube, which are only free with the other backends
+#[cfg(all(
+    any(feature = "room-temperature", feature = "io-expander"),
+    not(any(
+        feature = "multiplexed",
+        feature = "shift-register",
+        feature = "seven-segment"
+    ))
+))]
+compile_error!(
+    "`room-temperature` and `io-expander` need the `multiplexed`, `shift-register` or \
+    `seven-segment` feature"
+);
+
+// The other backends light the tubes continuously, so they can't dim them
+#[cfg(all(
+    feature = "dimming",
+    not(any(feature = "multiplexed", feature = "seven-segment"))
+))]
+compile_error!("`dimming` needs the `multiplexed` or `seven-segment` feature");
+
+// Motion only wakes tubes that are blanked by something else
+#[cfg(all(
+    feature = "motion-wake",
+    not(any(feature = "quiet-hours", feature = "space-state"))
+))]
+compile_error!("`motion-wake` needs the `quiet-hours` or `space-state` feature");
+
+// Note: When you are okay with using a nightly compiler it's better to
+// use https://docs.rs/static_cell/2.1.0/static_cell/macro.make_static.html
+macro_rules! mk_static {
+    ($t:ty,$val:expr) => {{
+        static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
+        #[deny(unused_attributes)]
+        let x = STATIC_CELL.uninit().write(($val));
+        x
+    }};
+}
+
+#[cfg(feature = "ble-provisioning")]
+mod ble_provisioning;
+#[cfg(feature = "broadcast")]
+mod broadcast;
+mod build_config;
+#[cfg(feature = "provisioning")]
+mod changelog;
+#[cfg(feature = "clock")]
+mod clock;
+#[cfg(feature = "clock-mode")]
+mod clock_mode;
+#[cfg(feature = "coap")]
+mod coap;
+mod config;
+#[cfg(feature = "console")]
+mod console;
+#[cfg(feature = "persist-count")]
+mod count_store;
+mod device_id;
+#[cfg(feature = "dimming")]
+mod dimming;
+mod display;
+mod display_task;
+mod dns_cache;
+#[cfg(feature = "doorbell")]
+mod doorbell;
+mod driver;
+#[cfg(feature = "energy")]
+mod energy;
+mod error_code;
+mod experiment;
+#[cfg(any(
+    feature = "journal",
+    feature = "provisioning",
+    feature = "lock-mode",
+    feature = "persist-count"
+))]
+mod flash_log;
+#[cfg(feature = "health-check")]
+mod health_check;
+#[cfg(not(feature = "coap"))]
+mod http;
+mod input;
+#[cfg(feature = "io-expander")]
+mod io_expander;
+mod jitter;
+#[cfg(feature = "journal")]
+mod journal;
+#[cfg(feature = "lock-mode")]
+mod lock;
+#[cfg(feature = "motion-wake")]
+mod motion;
+#[cfg(feature = "multiplexed")]
+mod multiplex;
+#[cfg(feature = "neon-dots")]
+mod neon_dots;
 mod nixie;
+#[cfg(feature = "provisioning")]
+mod provisioning;
+#[cfg(feature = "queue-stats")]
+mod queue_stats;
+#[cfg(feature = "quiet-hours")]
+mod quiet_hours;
+#[cfg(feature = "room-temperature")]
+mod room_temperature;
+#[cfg(feature = "rssi")]
+mod rssi;
+#[cfg(feature = "rules")]
+mod rules;
+#[cfg(feature = "screensaver")]
+mod screensaver;
+mod settings;
+#[cfg(all(
+    feature = "seven-segment",
+    not(any(feature = "multiplexed", feature = "shift-register"))
+))]
+mod seven_segment;
+#[cfg(all(feature = "shift-register", not(feature = "multiplexed")))]
+mod shift_register;
+mod status;
+#[cfg(any(
+    feature = "journal",
+    feature = "config-store",
+    feature = "lock-mode",
+    feature = "persist-count"
+))]
+mod storage;
+#[cfg(feature = "syslog")]
+mod syslog;
+#[cfg(feature = "temperature")]
+mod temperature;
 mod toggle_switch;
+#[cfg(feature = "touch")]
+mod touch;
+mod transport;
+#[cfg(feature = "webhook")]
+mod webhook;
+#[cfg(feature = "websocket")]
+mod websocket;
 
+#[cfg(feature = "coap")]
+use crate::coap::{CoapBuffers, CoapTransport};
+#[cfg(any(
+    not(feature = "seven-segment"),
+    feature = "multiplexed",
+    feature = "shift-register"
+))]
+use crate::config::{LEFT_TUBE_SYMBOLS, RIGHT_TUBE_SYMBOLS};
+#[cfg(feature = "persist-count")]
+use crate::count_store::CountStore;
+#[cfg(feature = "dimming")]
+use crate::dimming::Dimming;
+#[cfg(feature = "doorbell")]
+use crate::doorbell::Doorbell;
+#[cfg(feature = "energy")]
+use crate::energy::{EnergyEstimator, PowerState};
+#[cfg(feature = "error-codes")]
+use crate::error_code::ErrorCode;
+#[cfg(not(feature = "coap"))]
+use crate::http::HttpTransport;
+#[cfg(feature = "io-expander")]
+use crate::io_expander::ExpanderPin;
+#[cfg(feature = "journal")]
+use crate::journal::Journal;
+#[cfg(feature = "motion-wake")]
+use crate::motion::MotionSensor;
+#[cfg(feature = "multiplexed")]
+use crate::multiplex::{MultiplexPins, MultiplexedTube};
+#[cfg(feature = "neon-dots")]
+use crate::nixie::NeonDot;
+#[cfg(any(
+    feature = "multiplexed",
+    not(any(feature = "shift-register", feature = "seven-segment"))
+))]
+use crate::nixie::NixieTube;
+#[cfg(any(
+    not(feature = "seven-segment"),
+    feature = "multiplexed",
+    feature = "shift-register"
+))]
+use crate::nixie::NixieTubeArray;
+#[cfg(feature = "provisioning")]
+use crate::provisioning::{GuidedSetup, SetupStep};
+#[cfg(feature = "quiet-hours")]
+use crate::quiet_hours::QuietHours;
+#[cfg(all(feature = "rules", feature = "webhook"))]
+use crate::rules::Notification;
+#[cfg(feature = "rules")]
+use crate::rules::Rules;
+#[cfg(all(
+    feature = "seven-segment",
+    not(any(feature = "multiplexed", feature = "shift-register"))
+))]
+use crate::seven_segment::Tm1637;
+#[cfg(all(feature = "shift-register", not(feature = "multiplexed")))]
+use crate::shift_register::{ShiftRegisterTube, ShiftRegisters};
+#[cfg(feature = "touch")]
+use crate::touch::TouchPad;
 use crate::{
-    nixie::{NixieTube, NixieTubePair},
+    config::{BootAnimation, Config, TUBE_COUNT},
+    display::CounterDisplay,
+    display_task::{CountChange, Display, DisplayCommand, DisplaySender},
+    experiment::DisplayPolicy,
+    input::{InputEvent, InputReceiver},
+    status::{EndpointHealth, LedPattern, StartupStage, SyncLag, WifiStatus},
     toggle_switch::ToggleSwitch,
+    transport::CountTransport,
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const WIFI_SSID: &str = env!("WIFI_SSID");
-const WIFI_PASS: &str = env!("WIFI_PASS");
-const SPACEAPI_SENSOR_ENDPOINT: &str = env!("SPACEAPI_SENSOR_ENDPOINT");
+/// Time each step of the boot animation is shown (the sweep and the spin)
+const BOOT_ANIMATION_DELAY: Duration = Duration::from_millis(100);
+
+/// Time each part of the version is shown at startup
+const BOOT_VERSION_DELAY: Duration = Duration::from_millis(800);
+
+/// Time each step is shown while scrolling the IP address
+#[cfg(feature = "show-ip")]
+const IP_SCROLL_DELAY: Duration = Duration::from_millis(400);
 
 const DHCP_HOSTNAME: &str = "Nixie Counter";
+
+/// Number of sockets in the network stack: DHCP, DNS and the count transport,
+/// plus one for every optional feature opening its own socket and for every
+/// additional sensor endpoint.
+const SOCKET_COUNT: usize = 3
+    + cfg!(feature = "websocket") as usize
+    + cfg!(feature = "syslog") as usize
+    + cfg!(feature = "health-check") as usize
+    + cfg!(feature = "broadcast") as usize
+    + cfg!(feature = "webhook") as usize
+    + cfg!(feature = "clock") as usize
+    + EXTRA_ENDPOINT_COUNT;
+
+/// Every additional sensor endpoint needs its own socket
+#[cfg(not(feature = "coap"))]
+const EXTRA_ENDPOINT_COUNT: usize = http::EXTRA_ENDPOINT_COUNT;
+#[cfg(feature = "coap")]
+const EXTRA_ENDPOINT_COUNT: usize = 0;
 const PERIODIC_COUNT_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
 
+/// Presses following each other within this time are sent as one update
+const PRESS_COALESCING_WINDOW: Duration = Duration::from_millis(1000);
+
+/// Number of people counted by a double press (with `double-press`)
+const DOUBLE_PRESS_STEP: u8 = 5;
+
+/// Presses that start the tube diagnostics when they follow each other within
+/// the [`PRESS_COALESCING_WINDOW`]
+#[cfg(feature = "diagnostics")]
+const DIAGNOSTICS_COMBO: [Direction; 4] = [
+    Direction::Up,
+    Direction::Down,
+    Direction::Up,
+    Direction::Down,
+];
+
+/// How often and how fast the tubes flash before the count is reset
+#[cfg(feature = "reset-chord")]
+const RESET_FLASH_COUNT: usize = 3;
+#[cfg(feature = "reset-chord")]
+const RESET_FLASH_DELAY: Duration = Duration::from_millis(250);
+
+/// How long both directions are held to toggle the lock (with `lock-mode`)
+#[cfg(feature = "lock-mode")]
+const LOCK_HOLD_DURATION: Duration = Duration::from_secs(3);
+
+/// How fast the tubes blink when a press is ignored, or the lock is toggled
+#[cfg(feature = "lock-mode")]
+const LOCK_FLASH_DELAY: Duration = Duration::from_millis(150);
+
+/// Free heap below which the heap is reported as low
+#[cfg(feature = "error-codes")]
+const HEAP_LOW_THRESHOLD: usize = 8 * 1024;
+
+/// Interval in which the cathodes are cycled to prevent cathode poisoning
+#[cfg(feature = "anti-poisoning")]
+const CATHODE_CYCLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Time after boot after which the provisioning portal is started if the WiFi
+/// connection could not be established.
+#[cfg(feature = "provisioning")]
+const PROVISIONING_FALLBACK_DELAY: Duration = Duration::from_secs(120);
+
 type EspWifiDevice<'a> = WifiDevice<'a, WifiStaDevice>;
-type EspTcpClient<'a> = TcpClient<'a, EspWifiDevice<'a>, 1>;
 type EspDnsSocket<'a> = DnsSocket<'a, EspWifiDevice<'a>>;
-type EspHttpClient<'a> = HttpClient<'a, EspTcpClient<'a>, EspDnsSocket<'a>>;
 
-// Note: When you are okay with using a nightly compiler it's better to
-// use https://docs.rs/static_cell/2.1.0/static_cell/macro.make_static.html
-macro_rules! mk_static {
-    ($t:ty,$val:expr) => {{
-        static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
-        #[deny(unused_attributes)]
-        let x = STATIC_CELL.uninit().write(($val));
-        x
-    }};
-}
+/// The tubes of the counter, each connected through four output pins
+#[cfg(not(any(
+    feature = "multiplexed",
+    feature = "shift-register",
+    feature = "seven-segment"
+)))]
+type Tubes = NixieTubeArray<
+    NixieTube<Output<'static>, Output<'static>, Output<'static>, Output<'static>>,
+    TUBE_COUNT,
+>;
+
+/// The tubes of the counter, multiplexed through a single K155ID1
+#[cfg(feature = "multiplexed")]
+type Tubes = NixieTubeArray<MultiplexedTube, TUBE_COUNT>;
+
+/// The tubes of the counter, connected through 74HC595 shift registers
+#[cfg(all(feature = "shift-register", not(feature = "multiplexed")))]
+type Tubes = NixieTubeArray<
+    ShiftRegisterTube<Spi<'static, Blocking>, Output<'static>, TUBE_COUNT>,
+    TUBE_COUNT,
+>;
+
+/// A TM1637 7-segment LED module instead of the tubes
+#[cfg(all(
+    feature = "seven-segment",
+    not(any(feature = "multiplexed", feature = "shift-register"))
+))]
+type Tubes = Tm1637<Output<'static>, OutputOpenDrain<'static>>;
+
+/// A device on the I2C bus, which is shared by the room temperature sensor and
+/// the I/O expander
+#[cfg(any(feature = "room-temperature", feature = "io-expander"))]
+type SharedI2c = I2cDevice<'static, NoopRawMutex, I2c<'static, esp_hal::Blocking>>;
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
@@ -76,7 +387,10 @@ async fn main(spawner: Spawner) {
 
     // Initialize logging
     println!("--- start of main() ---");
+    #[cfg(not(feature = "syslog"))]
     esp_println::logger::init_logger(log::LevelFilter::Debug);
+    #[cfg(feature = "syslog")]
+    syslog::init_logger(log::LevelFilter::Debug);
 
     // Initialize peripherals
     let peripherals = esp_hal::init(esp_hal::Config::default());
@@ -87,30 +401,174 @@ async fn main(spawner: Spawner) {
     esp_hal_embassy::init(timg0.timer0);
 
     log::info!("Starting nixie firmware v{VERSION}...");
+    log::info!("Device ID: {}", device_id::DeviceId::read());
+
+    // Load the configuration, which panics on invalid values before anything
+    // is set up
+    let config = Config::load();
 
-    // Set up toggle switch
-    let mut toggle_switch = ToggleSwitch::new(peripherals.GPIO1, peripherals.GPIO0);
+    // Set up I2C bus
+    #[cfg(any(feature = "room-temperature", feature = "io-expander"))]
+    let i2c_bus = {
+        let i2c = I2c::new(peripherals.I2C0, I2cConfig::default());
+        // With direct drive, the pins belong to the right tube and the
+        // `compile_error!` above is all that should be reported
+        #[cfg(any(
+            feature = "multiplexed",
+            feature = "shift-register",
+            feature = "seven-segment"
+        ))]
+        let i2c = i2c.with_sda(peripherals.GPIO9).with_scl(peripherals.GPIO10);
+        &*mk_static!(
+            BlockingMutex<NoopRawMutex, RefCell<I2c<'static, esp_hal::Blocking>>>,
+            BlockingMutex::new(RefCell::new(i2c))
+        )
+    };
+
+    // Set up toggle switch, on the I/O expander (with its interrupt output on
+    // GPIO1) or on GPIO1 and GPIO0
+    toggle_switch::set_config(config.input.toggle_switch);
+    #[cfg(feature = "io-expander")]
+    let (pin_up, pin_down) = {
+        spawner.must_spawn(io_expander::io_expander_task(
+            I2cDevice::new(i2c_bus),
+            Input::new(peripherals.GPIO1, Pull::Up),
+            config.input.io_expander.0,
+            config.input.io_expander.1,
+        ));
+        (ExpanderPin::new(0), ExpanderPin::new(1))
+    };
+    #[cfg(not(any(feature = "io-expander", feature = "touch")))]
+    let (pin_up, pin_down) = (
+        Input::new(peripherals.GPIO1, Pull::Up),
+        Input::new(peripherals.GPIO0, Pull::Up),
+    );
+    // With touch pads, the outputs of the touch modules instead
+    #[cfg(all(feature = "touch", not(feature = "io-expander")))]
+    let (pin_up, pin_down) = {
+        let mut pad_up = TouchPad::new(Input::new(peripherals.GPIO1, Pull::Down));
+        let mut pad_down = TouchPad::new(Input::new(peripherals.GPIO0, Pull::Down));
+        touch::check_calibration(&mut pad_up, "up");
+        touch::check_calibration(&mut pad_down, "down");
+        (pad_up, pad_down)
+    };
+    #[cfg(all(feature = "touch", feature = "io-expander"))]
+    let (pin_up, pin_down) = (TouchPad::new(pin_up), TouchPad::new(pin_down));
+    let toggle_switch = ToggleSwitch::new(pin_up, pin_down);
+
+    // Register the optional peripherals that raise events: the doorbell
+    // input, and the motion sensor on the I/O expander
+    let drivers = driver::Registry::new();
+    #[cfg(feature = "doorbell")]
+    let drivers = drivers.register(Doorbell::new(peripherals.GPIO2));
+    #[cfg(feature = "motion-wake")]
+    let drivers = drivers.register(MotionSensor::new(
+        ExpanderPin::new(motion::SENSOR_PIN),
+        config.input.motion_wake_duration,
+    ));
+    let mut drivers = drivers;
 
     // Set up LEDs
     let _led_pwr = Output::new(peripherals.GPIO20, Level::High);
     let led_wifi = Output::new(peripherals.GPIO21, Level::Low);
 
-    // Initialize tubes
-    let mut tubes = NixieTubePair::new(
+    // Initialize tubes (keep the pins in sync with `custom_pre_backtrace`)
+    let left_symbols = config.display.left_symbols;
+    let right_symbols = config.display.right_symbols;
+    let [left_strike_delay, right_strike_delay] = config.display.strike_delays(&config.settings);
+    #[cfg(not(any(
+        feature = "multiplexed",
+        feature = "shift-register",
+        feature = "seven-segment"
+    )))]
+    let mut tubes: Tubes = NixieTubeArray::new([
         NixieTube {
             pin_a: Output::new(peripherals.GPIO6, Level::Low),
             pin_b: Output::new(peripherals.GPIO4, Level::Low),
             pin_c: Output::new(peripherals.GPIO3, Level::Low),
             pin_d: Output::new(peripherals.GPIO5, Level::Low),
+            symbols: left_symbols,
+            strike_delay: left_strike_delay,
         },
         NixieTube {
             pin_a: Output::new(peripherals.GPIO9, Level::Low),
             pin_b: Output::new(peripherals.GPIO8, Level::Low),
             pin_c: Output::new(peripherals.GPIO7, Level::Low),
             pin_d: Output::new(peripherals.GPIO10, Level::Low),
+            symbols: right_symbols,
+            strike_delay: right_strike_delay,
         },
-    );
-    tubes.selftest(Duration::from_millis(100)).await;
+    ]);
+    #[cfg(feature = "multiplexed")]
+    let mut tubes: Tubes = {
+        let pins = MultiplexPins {
+            decoder: NixieTube {
+                pin_a: Output::new(peripherals.GPIO6, Level::Low),
+                pin_b: Output::new(peripherals.GPIO4, Level::Low),
+                pin_c: Output::new(peripherals.GPIO3, Level::Low),
+                pin_d: Output::new(peripherals.GPIO5, Level::Low),
+                symbols: left_symbols,
+                // Unused, the task waits for the strike delay of every tube
+                strike_delay: Duration::MIN,
+            },
+            anodes: [
+                Output::new(peripherals.GPIO7, Level::Low),
+                Output::new(peripherals.GPIO8, Level::Low),
+            ],
+        };
+        // Refresh the tubes from a higher priority than the other tasks
+        let software_interrupts = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
+        let executor = mk_static!(
+            InterruptExecutor<2>,
+            InterruptExecutor::new(software_interrupts.software_interrupt2)
+        );
+        executor
+            .start(Priority::Priority2)
+            .must_spawn(multiplex::multiplex_task(pins));
+        NixieTubeArray::new([
+            MultiplexedTube::new(0, left_symbols, left_strike_delay),
+            MultiplexedTube::new(1, right_symbols, right_strike_delay),
+        ])
+    };
+    #[cfg(all(feature = "shift-register", not(feature = "multiplexed")))]
+    let mut tubes: Tubes = {
+        let spi = Spi::new(peripherals.SPI2)
+            .with_sck(peripherals.GPIO4)
+            .with_mosi(peripherals.GPIO6);
+        let registers = &*mk_static!(
+            ShiftRegisters<Spi<'static, Blocking>, Output<'static>, TUBE_COUNT>,
+            ShiftRegisters::new(spi, Output::new(peripherals.GPIO3, Level::Low))
+        );
+        NixieTubeArray::new([
+            ShiftRegisterTube::new(registers, 0, left_symbols, left_strike_delay),
+            ShiftRegisterTube::new(registers, 1, right_symbols, right_strike_delay),
+        ])
+    };
+    #[cfg(all(
+        feature = "seven-segment",
+        not(any(feature = "multiplexed", feature = "shift-register"))
+    ))]
+    let mut tubes: Tubes = {
+        // LEDs need neither symbol maps nor strike delays
+        let _ = (
+            left_symbols,
+            right_symbols,
+            left_strike_delay,
+            right_strike_delay,
+        );
+        Tm1637::new(
+            Output::new(peripherals.GPIO4, Level::High),
+            OutputOpenDrain::new(peripherals.GPIO6, Level::High, Pull::Up),
+        )
+    };
+    let boot_animation = config.display.boot_animation;
+    show_boot_animation(&mut tubes, boot_animation).await;
+    if boot_animation != BootAnimation::Version {
+        show_version(&mut tubes, config.display.boot_version_parts).await;
+    }
+    tubes.set_zero_style(config.display.zero_style);
+    #[cfg(feature = "dimming")]
+    tubes.set_tube_levels(config.display.tube_levels);
 
     // Initialize WiFi
     let timg1 = TimerGroup::new(peripherals.TIMG1);
@@ -118,11 +576,80 @@ async fn main(spawner: Spawner) {
         EspWifiController<'static>,
         esp_wifi::init(timg1.timer0, rng, peripherals.RADIO_CLK,).expect("Failed to init esp_wifi")
     );
+    let seed: u64 = rng.random().into();
+    log::debug!("Network stack seed: {seed}");
+    jitter::init(rng.random());
+
+    // Restore the lock, before the console can change it
+    #[cfg(feature = "lock-mode")]
+    {
+        lock::load();
+        if lock::is_locked() {
+            log::info!("Counter is locked, ignoring the toggle switch");
+        }
+    }
+
+    // Load settings, or ask for them through the provisioning portal (or
+    // the console)
+    let settings = config.settings;
+    let display_channel = mk_static!(
+        Channel::<NoopRawMutex, DisplayCommand, { display_task::QUEUE_LEN }>,
+        Channel::new()
+    );
+    // The console starts before connecting, so that missing settings can be
+    // entered on it. Its display commands wait until the display task runs.
+    #[cfg(feature = "console")]
+    spawner.must_spawn(console::console_task(
+        UsbSerialJtag::new(peripherals.USB_DEVICE)
+            .into_async()
+            .split()
+            .0,
+        display_channel.sender(),
+        #[cfg(feature = "config-store")]
+        settings.clone(),
+    ));
+    #[cfg(feature = "provisioning")]
+    changelog::Changelog::new().log_recent();
+    #[cfg(feature = "provisioning")]
+    if !settings.is_complete() || provisioning::take_request() {
+        provisioning::run(
+            spawner,
+            wifi_init,
+            peripherals.WIFI,
+            peripherals.BT,
+            led_wifi,
+            &mut tubes,
+            seed,
+            settings,
+        )
+        .await;
+    }
+    #[cfg(all(
+        feature = "console",
+        feature = "config-store",
+        not(feature = "provisioning")
+    ))]
+    if !settings.is_complete() {
+        log::error!("No WiFi credentials or sensor endpoint configured, set them on the console");
+        loop {
+            core::future::pending::<()>().await;
+        }
+    }
+    assert!(
+        settings.is_complete(),
+        "No WiFi credentials or sensor endpoint configured"
+    );
+    show_startup_stage(&mut tubes, StartupStage::ConnectWifi);
+    #[cfg(feature = "provisioning")]
+    let mut guided_setup = GuidedSetup::resume();
+    #[cfg(feature = "provisioning")]
+    guided_setup.show(&mut tubes, SetupStep::ConnectWifi);
+
     let (wifi_interface, wifi_controller) =
         esp_wifi::wifi::new_with_mode(wifi_init, peripherals.WIFI, WifiStaDevice).unwrap();
     let wifi_config = ClientConfiguration {
-        ssid: WIFI_SSID.try_into().unwrap(),
-        password: WIFI_PASS.try_into().unwrap(),
+        ssid: settings.wifi_ssid.clone(),
+        password: settings.wifi_password.clone(),
         ..Default::default()
     };
 
@@ -135,15 +662,16 @@ async fn main(spawner: Spawner) {
         );
         config
     };
-    let config = embassy_net::Config::dhcpv4(dhcp_config);
-    let seed: u64 = rng.random().into();
-    log::debug!("Network stack seed: {seed}");
+    let net_config = embassy_net::Config::dhcpv4(dhcp_config);
     let stack = &*mk_static!(
         Stack<WifiDevice<'_, WifiStaDevice>>,
         Stack::new(
             wifi_interface,
-            config,
-            mk_static!(StackResources<3>, StackResources::<3>::new()),
+            net_config,
+            mk_static!(
+                StackResources<SOCKET_COUNT>,
+                StackResources::<SOCKET_COUNT>::new()
+            ),
             seed
         )
     );
@@ -154,14 +682,77 @@ async fn main(spawner: Spawner) {
         Channel::<NoopRawMutex, LedControlCommand, 3>::new()
     );
     spawner.must_spawn(led_control_task(led_wifi, led_control_channel.receiver()));
+    #[cfg(feature = "queue-stats")]
+    spawner.must_spawn(queue_stats::queue_stats_task(led_control_channel));
+    let led_control_sender = led_control_channel.sender();
 
-    // Spawn connection tasks
-    spawner.must_spawn(connection(
-        wifi_controller,
-        wifi_config,
-        led_control_channel.sender(),
+    // Set up neon dots
+    #[cfg(feature = "neon-dots")]
+    spawner.must_spawn(neon_dots::neon_dots_task(
+        NeonDot::new(Output::new(peripherals.GPIO18, Level::Low)),
+        NeonDot::new(Output::new(peripherals.GPIO19, Level::Low)),
     ));
+
+    // Spawn connection tasks
+    spawner.must_spawn(connection(wifi_controller, wifi_config, led_control_sender));
     spawner.must_spawn(net_task(stack));
+    #[cfg(feature = "rssi")]
+    spawner.must_spawn(rssi::rssi_task());
+    #[cfg(feature = "temperature")]
+    spawner.must_spawn(temperature::temperature_task());
+    #[cfg(feature = "room-temperature")]
+    spawner.must_spawn(room_temperature::room_temperature_task(I2cDevice::new(
+        i2c_bus,
+    )));
+    #[cfg(feature = "clock")]
+    spawner.must_spawn(clock::clock_task(stack, config.clock));
+    #[cfg(feature = "health-check")]
+    spawner.must_spawn(health_check::health_check_task(stack));
+    #[cfg(feature = "syslog")]
+    spawner.must_spawn(syslog::syslog_task(stack, config.services.syslog_server));
+
+    // Spawn WebSocket sync task
+    #[cfg(feature = "websocket")]
+    let (sync_local_count, sync_remote_count) = {
+        let local_count = &*mk_static!(Signal<NoopRawMutex, u8>, Signal::new());
+        let remote_count = &*mk_static!(Signal<NoopRawMutex, u8>, Signal::new());
+        spawner.must_spawn(websocket::websocket_task(
+            stack,
+            config.services.sync_websocket_url,
+            rng,
+            local_count,
+            remote_count,
+        ));
+        (local_count, remote_count)
+    };
+
+    // Spawn LAN broadcast task
+    #[cfg(feature = "broadcast")]
+    let broadcast_count = {
+        let count = &*mk_static!(Signal<NoopRawMutex, u8>, Signal::new());
+        spawner.must_spawn(broadcast::broadcast_task(stack, count));
+        count
+    };
+
+    // Spawn webhook notification task, which also notifies for the rules
+    #[cfg(all(feature = "rules", feature = "webhook"))]
+    let rule_notifications = mk_static!(
+        Channel::<NoopRawMutex, Notification, { rules::NOTIFICATION_QUEUE_LEN }>,
+        Channel::new()
+    );
+    #[cfg(feature = "webhook")]
+    let webhook_count = {
+        let count = &*mk_static!(Signal<NoopRawMutex, u8>, Signal::new());
+        spawner.must_spawn(webhook::webhook_task(
+            stack,
+            config.services.webhook_url,
+            config.services.webhook_thresholds,
+            count,
+            #[cfg(feature = "rules")]
+            rule_notifications.receiver(),
+        ));
+        count
+    };
 
     // Wait for link
     loop {
@@ -173,90 +764,865 @@ async fn main(spawner: Spawner) {
 
     // Wait for IP
     log::info!("Waiting to get IP address...");
+    show_startup_stage(&mut tubes, StartupStage::Dhcp);
     loop {
         if let Some(config) = stack.config_v4() {
             log::info!("Got IP: {}", config.address);
+            #[cfg(feature = "show-ip")]
+            tubes
+                .scroll(&ip_digits(config.address.address()), IP_SCROLL_DELAY)
+                .await;
+            show_startup_stage(&mut tubes, StartupStage::FirstUpdate);
+            #[cfg(feature = "provisioning")]
+            guided_setup.show(&mut tubes, SetupStep::FirstUpdate);
             break;
         }
         Timer::after(Duration::from_millis(200)).await;
     }
 
-    // Create HTTP client (without TLS support for now)
-    let client_state = &*mk_static!(
-        TcpClientState<1, 1024, 1024>,
-        TcpClientState::<1, 1024, 1024>::new()
+    // Create transport for count updates
+    #[cfg(not(feature = "coap"))]
+    let mut transport = HttpTransport::new(
+        stack,
+        mk_static!(heapless::String<128>, settings.sensor_endpoint.clone()),
+        config.http,
     );
-    let tcp_client = &*mk_static!(
-        TcpClient<'static, EspWifiDevice<'static>, 1>,
-        TcpClient::new(stack, client_state)
+    #[cfg(feature = "coap")]
+    let mut transport = CoapTransport::new(
+        stack,
+        mk_static!(CoapBuffers, CoapBuffers::new()),
+        rng,
+        config.coap.endpoint,
     );
-    let dns = &*mk_static!(EspDnsSocket<'_>, DnsSocket::new(stack));
-    let mut http_client = HttpClient::new(tcp_client, dns);
 
-    // Send initial count
-    if let Err(e) = update_people_now_present(&mut http_client, 0).await {
-        log::warn!("Failed to initialize SpaceAPI endpoint count: {}", e);
+    // Spawn display task, which keeps showing the startup stage (or the step
+    // of the guided setup) until the first count
+    let startup_digits = StartupStage::FirstUpdate.digits();
+    #[cfg(feature = "provisioning")]
+    let startup_digits = guided_setup
+        .digits(SetupStep::FirstUpdate)
+        .unwrap_or(startup_digits);
+    spawner.must_spawn(display_task::display_task(
+        Display::new(tubes, startup_digits, &config.display, rng),
+        display_channel.receiver(),
+    ));
+    let display = display_channel.sender();
+
+    // Send initial count. Continue with the count known to the server if
+    // enabled, or else the one stored in flash, instead of resetting it. If
+    // an update was still pending when the device rebooted, its change is
+    // replayed onto the fetched count, since the server may have changed
+    // meanwhile, or else the pending count is sent again.
+    #[cfg(feature = "fetch-count")]
+    let fetched_count = fetch_count(&mut transport).await;
+    #[cfg(not(feature = "fetch-count"))]
+    let fetched_count = None;
+    #[cfg(feature = "journal")]
+    let mut journal = Journal::new();
+    #[cfg(feature = "journal")]
+    let replayed_count = journal.pending().map(|pending| match fetched_count {
+        Some(fetched) => {
+            log::info!(
+                "Replaying pending change {:+} from journal onto count {fetched}",
+                pending.delta()
+            );
+            journal.rebase(fetched).unwrap_or(fetched)
+        }
+        None => {
+            log::info!("Replaying pending count {} from journal", pending.count);
+            pending.count
+        }
+    });
+    #[cfg(not(feature = "journal"))]
+    let replayed_count = None;
+    #[cfg(feature = "persist-count")]
+    let count_store = CountStore::new();
+    #[cfg(feature = "persist-count")]
+    let stored_count = count_store.stored();
+    #[cfg(not(feature = "persist-count"))]
+    let stored_count = None;
+    let mut initial_count = replayed_count
+        .or(fetched_count)
+        .unwrap_or_else(|| restore_count(stored_count));
+
+    // Spawn count store task, which writes the count to flash
+    #[cfg(feature = "persist-count")]
+    let persist_count = {
+        let count = &*mk_static!(Signal<NoopRawMutex, u8>, Signal::new());
+        spawner.must_spawn(count_store::count_store_task(count_store, count));
+        count
+    };
+    let mut endpoint_health = EndpointHealth::new();
+    let mut sync_lag = SyncLag::new(config.status.sync_lag_threshold);
+    let result = send_count(&mut transport, initial_count).await;
+    record_endpoint_result(
+        &mut endpoint_health,
+        &mut sync_lag,
+        led_control_sender,
+        result.is_ok(),
+    )
+    .await;
+    #[cfg(feature = "provisioning")]
+    guided_setup.record_update(result.is_ok());
+    // Error code last flashed, so that repeated failures are only shown once
+    #[cfg(feature = "error-codes")]
+    let mut last_error = None;
+    match result {
+        Ok(reported_count) => {
+            #[cfg(feature = "journal")]
+            journal.clear();
+            if let Some(reported_count) = reported_count {
+                initial_count = reported_count;
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to initialize SpaceAPI endpoint count: {}", e);
+            #[cfg(feature = "error-codes")]
+            {
+                last_error = Some(ErrorCode::of(&e));
+            }
+        }
     }
+    show_count(display, initial_count, CountChange::Press).await;
+    #[cfg(feature = "error-codes")]
+    if let Some(code) = last_error {
+        display.send(DisplayCommand::Error(code)).await;
+    }
+    #[cfg(feature = "provisioning")]
+    if let Some(digits) = guided_setup.digits(SetupStep::FirstUpdate) {
+        display.send(DisplayCommand::ShowDigits(digits)).await;
+    }
+    #[cfg(feature = "broadcast")]
+    broadcast_count.signal(initial_count);
+    #[cfg(feature = "webhook")]
+    webhook_count.signal(initial_count);
 
     // Periodic update timer
-    let mut periodic_update_interval = Ticker::every(PERIODIC_COUNT_UPDATE_INTERVAL);
+    let mut periodic_update_interval =
+        Ticker::every(jitter::jittered(PERIODIC_COUNT_UPDATE_INTERVAL));
+
+    // Energy usage estimation
+    #[cfg(feature = "energy")]
+    let mut energy = EnergyEstimator::new(current_power_state(initial_count));
+
+    // Quiet hours
+    #[cfg(feature = "quiet-hours")]
+    let mut quiet_hours =
+        QuietHours::new(config.display.quiet_hours, settings.quiet_hours.as_deref());
+
+    // Whether the tubes are blanked since the space was closed
+    #[cfg(feature = "space-state")]
+    let mut closed_blanked = false;
+
+    // Automation rules
+    #[cfg(feature = "rules")]
+    let mut rules = Rules::new(config.rules);
+
+    // Dimming schedule
+    #[cfg(feature = "dimming")]
+    let mut dimming = Dimming::new(
+        config.display.dimming,
+        settings.dimming_hours.as_deref(),
+        settings.dimmed_brightness,
+    );
+
+    // Heap monitoring
+    #[cfg(feature = "error-codes")]
+    let mut heap_low = false;
+
+    // Cathode poisoning prevention
+    #[cfg(feature = "anti-poisoning")]
+    let mut next_cathode_cycle = Instant::now() + jitter::jittered(CATHODE_CYCLE_INTERVAL);
+
+    // Experiment variants
+    let display_policy = config.display.update_policy;
+    log::info!("Display update policy: {}", display_policy.as_str());
+
+    // Spawn input task, which reads the toggle switch while the main loop is
+    // busy
+    let input_channel = mk_static!(
+        Channel::<NoopRawMutex, InputEvent, { input::QUEUE_LEN }>,
+        Channel::new()
+    );
+    spawner.must_spawn(input::input_task(toggle_switch, input_channel.sender()));
+    let input = input_channel.receiver();
 
     // Main loop
     log::info!("Starting main loop");
-    let mut count = 0u8;
+    let mut count = initial_count;
+    // Presses counted while the server couldn't be reached, not yet
+    // reconciled with it
+    #[cfg(feature = "offline-counting")]
+    let mut offline_delta: i16 = 0;
+    // Input event that ended the coalescing of presses, handled next
+    let mut next_input = None;
     loop {
-        // Wait for event: Either timer or button press
-        let direction = match select(
-            periodic_update_interval.next(),
-            toggle_switch.wait_for_press(),
-        )
-        .await
+        // Store the count, if it changed
+        #[cfg(feature = "persist-count")]
+        persist_count.signal(count);
+
+        // Apply the automation rules to the count, after every event and
+        // with the periodic update
+        #[cfg(feature = "rules")]
         {
-            Either::First(()) => {
+            let blanked = rules.update(count, |_rule| {
+                // Dropped if the webhook can't keep up
+                #[cfg(feature = "webhook")]
+                let _ = rule_notifications.try_send(Notification { rule: _rule, count });
+            });
+            if let Some(blanked) = blanked {
+                #[cfg(feature = "quiet-hours")]
+                let blanked = blanked || quiet_hours.is_blanked();
+                #[cfg(feature = "space-state")]
+                let blanked = blanked || closed_blanked;
+                display.send(DisplayCommand::Blank(blanked)).await;
+            }
+        }
+
+        // Count changes pushed by the sync server
+        #[cfg(feature = "websocket")]
+        let remote_count_update = sync_remote_count.wait();
+        #[cfg(not(feature = "websocket"))]
+        let remote_count_update = core::future::pending::<u8>();
+
+        // Wait for event: Either timer, button press, remote count change,
+        // or an event of a registered driver
+        let event = match next_input.take() {
+            Some(input_event) => Either4::Second(input_event),
+            None => {
+                select4(
+                    periodic_update_interval.next(),
+                    input.receive(),
+                    remote_count_update,
+                    drivers.poll(),
+                )
+                .await
+            }
+        };
+        let input_event = match event {
+            Either4::First(()) => {
+                // Blank the tubes during quiet hours (and keep them blanked
+                // afterwards while the space is closed)
+                #[cfg(feature = "quiet-hours")]
+                if let Some(blanked) = quiet_hours.update() {
+                    #[cfg(feature = "space-state")]
+                    let blanked = blanked || closed_blanked;
+                    #[cfg(feature = "rules")]
+                    let blanked = blanked || rules.is_blanked();
+                    display.send(DisplayCommand::Blank(blanked)).await;
+                }
+
+                // Dim the tubes according to the schedule
+                #[cfg(feature = "dimming")]
+                if let Some(brightness) = dimming.update() {
+                    display.send(DisplayCommand::Brightness(brightness)).await;
+                }
+
+                // Reconcile the presses counted offline with the count on
+                // the server, which may have changed meanwhile
+                #[cfg(all(feature = "offline-counting", feature = "fetch-count"))]
+                if offline_delta != 0 {
+                    if let Some(server_count) = fetch_count(&mut transport).await {
+                        count = apply_delta(server_count, offline_delta);
+                    }
+                }
+
                 // Periodic count update
-                if let Err(e) = update_people_now_present(&mut http_client, count).await {
-                    log::warn!("Failed to refresh SpaceAPI endpoint count: {}", e);
+                let result = send_count(&mut transport, count).await;
+                #[cfg(all(feature = "offline-counting", feature = "journal"))]
+                if result.is_ok() {
+                    journal.clear();
+                }
+                #[cfg(feature = "offline-counting")]
+                if result.is_ok() && offline_delta != 0 {
+                    log::info!(
+                        "Reconciled {offline_delta:+} presses counted offline, count is {count}"
+                    );
+                    offline_delta = 0;
+                    show_count(display, count, CountChange::Remote).await;
+                    #[cfg(feature = "websocket")]
+                    sync_local_count.signal(count);
+                    #[cfg(feature = "broadcast")]
+                    broadcast_count.signal(count);
+                    #[cfg(feature = "webhook")]
+                    webhook_count.signal(count);
+                }
+                record_endpoint_result(
+                    &mut endpoint_health,
+                    &mut sync_lag,
+                    led_control_sender,
+                    result.is_ok(),
+                )
+                .await;
+                #[cfg(feature = "provisioning")]
+                if guided_setup.record_update(result.is_ok()) {
+                    show_count(display, count, CountChange::Press).await;
+                }
+                #[cfg(feature = "error-codes")]
+                if result.is_ok() {
+                    last_error = None;
+                }
+                match result {
+                    Ok(Some(reported_count)) if reported_count != count => {
+                        // Changed on the server, e.g. by another counter
+                        log::info!("Adopting count {reported_count} reported by the server");
+                        count = reported_count;
+                        show_count(display, count, CountChange::Remote).await;
+                        #[cfg(feature = "websocket")]
+                        sync_local_count.signal(count);
+                        #[cfg(feature = "broadcast")]
+                        broadcast_count.signal(count);
+                        #[cfg(feature = "webhook")]
+                        webhook_count.signal(count);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("Failed to refresh SpaceAPI endpoint count: {}", e);
+                        // Only show the error again if it changed
+                        #[cfg(feature = "error-codes")]
+                        {
+                            let code = ErrorCode::of(&e);
+                            if last_error != Some(code) {
+                                display.send(DisplayCommand::Error(code)).await;
+                            }
+                            last_error = Some(code);
+                        }
+                    }
+                }
+
+                // Report a low heap once, when it becomes low
+                #[cfg(feature = "error-codes")]
+                {
+                    let free = esp_alloc::HEAP.free();
+                    let was_low = heap_low;
+                    heap_low = free < HEAP_LOW_THRESHOLD;
+                    if heap_low && !was_low {
+                        log::warn!("Heap low, {free} bytes free");
+                        display
+                            .send(DisplayCommand::Error(ErrorCode::HeapLow))
+                            .await;
+                    }
+                }
+
+                // Report energy usage
+                #[cfg(feature = "energy")]
+                {
+                    energy.update(current_power_state(count));
+                    log::info!(
+                        "Estimated energy usage: {:.1} Wh since boot, {:.1} Wh/day",
+                        energy.total_wh(),
+                        energy.projected_wh_per_day(),
+                    );
+                }
+
+                // Cycle the cathodes, until the next count is shown
+                #[cfg(feature = "anti-poisoning")]
+                if Instant::now() >= next_cathode_cycle {
+                    next_cathode_cycle = Instant::now() + jitter::jittered(CATHODE_CYCLE_INTERVAL);
+                    display.send(DisplayCommand::CycleCathodes).await;
                 }
                 continue;
             }
-            Either::Second(direction) => {
+            Either4::Second(InputEvent::Release) => continue,
+            Either4::Second(input_event) => {
+                // A press during quiet hours or while the space is closed
+                // only turns the tubes on again. If a driver already lit
+                // them (e.g. for motion), they stay on and the press is
+                // counted.
+                #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+                let lit_by_driver = drivers.on_press();
+                #[cfg(feature = "quiet-hours")]
+                let woken = quiet_hours.wake();
+                #[cfg(all(
+                    any(feature = "space-state", feature = "rules"),
+                    not(feature = "quiet-hours")
+                ))]
+                let woken = false;
+                #[cfg(feature = "space-state")]
+                let woken = core::mem::take(&mut closed_blanked) | woken;
+                #[cfg(feature = "rules")]
+                let woken = rules.wake() | woken;
+                #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+                let woken = woken && !lit_by_driver;
+                #[cfg(any(feature = "quiet-hours", feature = "space-state", feature = "rules"))]
+                if woken {
+                    display.send(DisplayCommand::Blank(false)).await;
+                    while input.receive().await != InputEvent::Release {}
+                    continue;
+                }
+
+                // A press while locked only blinks the tubes. The chord
+                // still gets through, since holding it unlocks.
+                #[cfg(feature = "lock-mode")]
+                if lock::is_locked() && input_event != InputEvent::Chord {
+                    log::info!("Locked, ignoring {:?}", input_event);
+                    display
+                        .send(DisplayCommand::Flash {
+                            times: 1,
+                            delay: LOCK_FLASH_DELAY,
+                        })
+                        .await;
+                    while input.receive().await != InputEvent::Release {}
+                    continue;
+                }
+
+                // A press while dimmed restores the day profile, and is
+                // counted as usual
+                #[cfg(feature = "dimming")]
+                if let Some(brightness) = dimming.override_brightness() {
+                    display.send(DisplayCommand::Brightness(brightness)).await;
+                }
+
                 // Toggle switch pressed, carry on with processing
-                direction
+                input_event
+            }
+            Either4::Third(new_count) => {
+                // Count was changed elsewhere, the sync server already knows about it
+                log::info!("Count changed remotely to {new_count}");
+                count = new_count;
+                // Keep the presses counted offline, until they are sent
+                #[cfg(feature = "offline-counting")]
+                {
+                    count = apply_delta(count, offline_delta);
+                }
+                show_count(display, count, CountChange::Remote).await;
+                #[cfg(feature = "energy")]
+                energy.update(current_power_state(count));
+                #[cfg(feature = "broadcast")]
+                broadcast_count.signal(count);
+                #[cfg(feature = "webhook")]
+                webhook_count.signal(count);
+                continue;
+            }
+            Either4::Fourth(driver_event) => {
+                // A registered driver raised an event, e.g. the doorbell
+                // rang or someone walked into the doorway
+                #[cfg(feature = "quiet-hours")]
+                let blanked = quiet_hours.is_blanked();
+                #[cfg(not(feature = "quiet-hours"))]
+                let blanked = false;
+                #[cfg(feature = "space-state")]
+                let blanked = blanked || closed_blanked;
+                #[cfg(feature = "rules")]
+                let blanked = blanked || rules.is_blanked();
+                let context = driver::Context { display, blanked };
+                drivers.handle_event(driver_event, &context).await;
+                continue;
+            }
+        };
+
+        // Whether the switch is still held after the event
+        let mut held = true;
+
+        // Long press: Open (up) or close (down) the space, and leave or enter
+        // the clock mode
+        let (mut direction, mut step) = match input_event {
+            InputEvent::LongUp | InputEvent::LongDown => {
+                let direction = if input_event == InputEvent::LongUp {
+                    Direction::Up
+                } else {
+                    Direction::Down
+                };
+                // At a count of 0, a long press down resynchronizes with the
+                // server instead, and tests the tubes
+                #[cfg(feature = "resync")]
+                if direction == Direction::Down && count == 0 {
+                    log::info!("Long press at 0, resynchronizing with the server");
+                    if let Some(fetched_count) = fetch_count(&mut transport).await {
+                        count = fetched_count;
+                        show_count(display, count, CountChange::Remote).await;
+                        #[cfg(feature = "websocket")]
+                        sync_local_count.signal(count);
+                        #[cfg(feature = "broadcast")]
+                        broadcast_count.signal(count);
+                        #[cfg(feature = "webhook")]
+                        webhook_count.signal(count);
+                    }
+                    display.send(DisplayCommand::Selftest).await;
+                    continue;
+                }
+                #[cfg(feature = "space-state")]
+                {
+                    let open = direction == Direction::Up;
+                    log::info!(
+                        "Long press, {} the space",
+                        if open { "opening" } else { "closing" }
+                    );
+                    match transport.send_state(open).await {
+                        // Confirm by flashing the count
+                        Ok(()) if open => {
+                            display
+                                .send(DisplayCommand::Flash {
+                                    times: 2,
+                                    delay: Duration::from_millis(300),
+                                })
+                                .await
+                        }
+                        // Blank the tubes until the next press, so that they
+                        // don't show 0 while the space is empty
+                        Ok(()) => {
+                            closed_blanked = true;
+                            display.send(DisplayCommand::Blank(true)).await;
+                        }
+                        Err(e) => log::error!("Failed to update the space state: {}", e),
+                    }
+                }
+                #[cfg(feature = "clock-mode")]
+                display
+                    .send(DisplayCommand::ClockMode(direction == Direction::Down))
+                    .await;
+                #[cfg(not(any(feature = "space-state", feature = "clock-mode")))]
+                let _ = direction;
+                continue;
+            }
+            // Both directions: Reset the count to zero, after blinking it to
+            // confirm. Held, toggle the lock instead.
+            InputEvent::Chord => {
+                #[cfg(feature = "lock-mode")]
+                {
+                    match select(input.receive(), Timer::after(LOCK_HOLD_DURATION)).await {
+                        // Released early, reset the count unless locked
+                        Either::First(_) => {
+                            #[cfg(feature = "reset-chord")]
+                            {
+                                held = false;
+                            }
+                        }
+                        Either::Second(()) => {
+                            let locked = !lock::is_locked();
+                            log::info!(
+                                "Chord held, {} the counter",
+                                if locked { "locking" } else { "unlocking" }
+                            );
+                            lock::set_locked(locked);
+                            display
+                                .send(DisplayCommand::Flash {
+                                    times: if locked { 3 } else { 1 },
+                                    delay: LOCK_FLASH_DELAY,
+                                })
+                                .await;
+                            while input.receive().await != InputEvent::Release {}
+                            continue;
+                        }
+                    }
+                    if lock::is_locked() {
+                        log::info!("Locked, ignoring the chord");
+                        display
+                            .send(DisplayCommand::Flash {
+                                times: 1,
+                                delay: LOCK_FLASH_DELAY,
+                            })
+                            .await;
+                        continue;
+                    }
+                }
+                #[cfg(feature = "reset-chord")]
+                {
+                    log::info!("Chord, resetting the count");
+                    display
+                        .send(DisplayCommand::Flash {
+                            times: RESET_FLASH_COUNT,
+                            delay: RESET_FLASH_DELAY,
+                        })
+                        .await;
+                    Timer::after(RESET_FLASH_DELAY * 2 * RESET_FLASH_COUNT as u32).await;
+                    (Direction::Down, count)
+                }
+                #[cfg(not(feature = "reset-chord"))]
+                continue;
             }
+            // Presses (and their repetitions)
+            input_event => match press_of(input_event) {
+                Some(press) => press,
+                None => continue,
+            },
         };
 
-        // Wait for toggle switch press
-        log::info!("Pressed {:?}", direction);
+        // Count (and with the optimistic policy show) immediately, but
+        // coalesce quickly following presses (e.g. when a group walks in) and
+        // auto-repeated ones into a single update
+        let pressed_at = Instant::now();
+        let mut new_count = count;
+        #[cfg(feature = "diagnostics")]
+        let mut presses = heapless::Vec::<Direction, { DIAGNOSTICS_COMBO.len() }>::new();
+        #[cfg(feature = "diagnostics")]
+        let mut combo = true;
+        loop {
+            new_count = apply_press(new_count, direction, step);
+            #[cfg(feature = "diagnostics")]
+            {
+                combo &= presses.push(direction).is_ok();
+            }
+            if display_policy == DisplayPolicy::Optimistic {
+                show_count(display, new_count, CountChange::Press).await;
+            }
+            // Wait for the next press or repetition. Other events end the
+            // coalescing, and are handled after the update.
+            let Some(event) = next_coalesced_input(input, &mut held).await else {
+                break;
+            };
+            (direction, step) = match press_of(event) {
+                Some(press) => press,
+                None => {
+                    next_input = Some(event);
+                    break;
+                }
+            };
+        }
 
-        // Debouncing
-        Timer::after(Duration::from_millis(250)).await;
+        // The diagnostics combo doesn't change the count
+        #[cfg(feature = "diagnostics")]
+        if combo && presses == DIAGNOSTICS_COMBO {
+            show_count(display, count, CountChange::Press).await;
+            display.send(DisplayCommand::Diagnose).await;
+            continue;
+        }
 
         // Update SpaceAPI
-        let new_count = match direction {
-            Direction::Up => count.saturating_add(1),
-            Direction::Down => count.saturating_sub(1),
-        };
-        match update_people_now_present(&mut http_client, new_count).await {
-            Ok(()) => {
-                // Success, update nixie tubes
-                tubes.show(new_count.min(99));
-                count = new_count
+        #[cfg(feature = "journal")]
+        journal.record_pending(count, new_count);
+        let result = send_count(&mut transport, new_count).await;
+        // When counting offline, a failed update stays pending, and is
+        // replayed after a reboot
+        #[cfg(feature = "journal")]
+        if result.is_ok() || !cfg!(feature = "offline-counting") {
+            journal.clear();
+        }
+        log::info!(
+            "Update {} {} ms after the first press ({} display)",
+            if result.is_ok() {
+                "confirmed"
+            } else {
+                "failed"
+            },
+            pressed_at.elapsed().as_millis(),
+            display_policy.as_str(),
+        );
+        record_endpoint_result(
+            &mut endpoint_health,
+            &mut sync_lag,
+            led_control_sender,
+            result.is_ok(),
+        )
+        .await;
+        match result {
+            Ok(reported_count) => {
+                // Success, show the new count, or the one reported by the
+                // server if it differs (e.g. changed by another counter in the
+                // meantime)
+                count = reported_count.unwrap_or(new_count);
+                #[cfg(feature = "offline-counting")]
+                {
+                    offline_delta = 0;
+                }
+                if count != new_count {
+                    log::info!("Adopting count {count} reported by the server");
+                }
+                let change = if count != new_count {
+                    CountChange::Remote
+                } else {
+                    CountChange::Press
+                };
+                show_count(display, count, change).await;
+                #[cfg(feature = "energy")]
+                energy.update(current_power_state(count));
+                #[cfg(feature = "websocket")]
+                sync_local_count.signal(count);
+                #[cfg(feature = "broadcast")]
+                broadcast_count.signal(count);
+                #[cfg(feature = "webhook")]
+                webhook_count.signal(count);
             }
             Err(e) => {
-                // Failed to update SpaceAPI
-                log::error!("Failed to update SpaceAPI endpoint: {}", e)
+                // Failed to update SpaceAPI, flash the count shown so that the
+                // press is noticed as lost, and show the last confirmed count
+                // again. The press should have changed the count, so always
+                // tell why it didn't.
+                log::error!("Failed to update SpaceAPI endpoint: {}", e);
+                #[cfg(feature = "error-codes")]
+                let code = ErrorCode::of(&e);
+                // Keep counting locally instead, and send the count once the
+                // server can be reached again. Only show the error if it
+                // changed, since every press fails while offline.
+                #[cfg(feature = "offline-counting")]
+                {
+                    offline_delta += i16::from(new_count) - i16::from(count);
+                    count = new_count;
+                    log::info!("Counting offline, {offline_delta:+} presses not yet sent");
+                    show_count(display, count, CountChange::Press).await;
+                    #[cfg(feature = "energy")]
+                    energy.update(current_power_state(count));
+                    #[cfg(feature = "error-codes")]
+                    if last_error != Some(code) {
+                        display.send(DisplayCommand::Error(code)).await;
+                    }
+                }
+                #[cfg(not(feature = "offline-counting"))]
+                display
+                    .send(DisplayCommand::UpdateFailed {
+                        count,
+                        #[cfg(feature = "error-codes")]
+                        code,
+                    })
+                    .await;
+                #[cfg(feature = "error-codes")]
+                {
+                    last_error = Some(code);
+                }
             }
         }
+    }
+}
+
+/// Send the count, with the update-in-flight dot lit meanwhile.
+async fn send_count(transport: &mut impl CountTransport, count: u8) -> anyhow::Result<Option<u8>> {
+    #[cfg(feature = "neon-dots")]
+    neon_dots::set_update_in_flight(true);
+    let result = transport.send_count(count).await;
+    #[cfg(feature = "neon-dots")]
+    neon_dots::set_update_in_flight(false);
+    result
+}
 
-        // Wait for toggle switch release
-        toggle_switch.wait_for_release().await;
+/// Fetch the current count from the server, and log the result. Returns
+/// `None` if the server doesn't know the count, or it couldn't be fetched.
+#[cfg(feature = "fetch-count")]
+async fn fetch_count(transport: &mut impl CountTransport) -> Option<u8> {
+    match transport.fetch_count().await {
+        Ok(Some(count)) => {
+            log::info!("Fetched current count {count} from server");
+            Some(count)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Failed to fetch current count: {}", e);
+            None
+        }
     }
 }
 
+/// Return the count stored in flash before the reboot, or 0.
+fn restore_count(stored_count: Option<u8>) -> u8 {
+    match stored_count {
+        Some(stored) => {
+            log::info!("Restoring count {stored} stored before the reboot");
+            stored
+        }
+        None => 0,
+    }
+}
+
+/// Show a count on the tubes, animated according to why it changed.
+async fn show_count(display: DisplaySender, count: u8, change: CountChange) {
+    display
+        .send(DisplayCommand::ShowCount { count, change })
+        .await;
+}
+
+/// Show the stage of the startup on the tubes.
+fn show_startup_stage(tubes: &mut impl CounterDisplay<TUBE_COUNT>, stage: StartupStage) {
+    log::debug!("Startup stage {:?}", stage);
+    tubes.show_digits(stage.digits());
+}
+
+/// Show the boot animation, leaving the tubes off.
+async fn show_boot_animation<const N: usize>(
+    tubes: &mut impl CounterDisplay<N>,
+    animation: BootAnimation,
+) {
+    match animation {
+        BootAnimation::Sweep => tubes.selftest(BOOT_ANIMATION_DELAY).await,
+        BootAnimation::SlotMachine => tubes.spin(BOOT_ANIMATION_DELAY).await,
+        BootAnimation::Version => show_version(tubes, 3).await,
+    }
+}
+
+/// Show the first `parts` parts of the firmware version one after the
+/// other, leaving the tubes off.
+async fn show_version<const N: usize>(tubes: &mut impl CounterDisplay<N>, parts: usize) {
+    let parts = VERSION
+        .split('.')
+        .take(parts)
+        .map(|part| part.parse().unwrap_or_default());
+    tubes.show_each(parts, BOOT_VERSION_DELAY).await;
+}
+
+/// Return the direction of a press of the toggle switch, and the number of
+/// people it counts, or `None` if the event isn't a press.
+fn press_of(event: InputEvent) -> Option<(Direction, u8)> {
+    match event {
+        InputEvent::Up => Some((Direction::Up, 1)),
+        InputEvent::Down => Some((Direction::Down, 1)),
+        // Double press: Count several people at once
+        InputEvent::DoubleUp => Some((Direction::Up, DOUBLE_PRESS_STEP)),
+        InputEvent::DoubleDown => Some((Direction::Down, DOUBLE_PRESS_STEP)),
+        _ => None,
+    }
+}
+
+/// Wait for the next input event while coalescing presses: Until the toggle
+/// switch is released (`held`), and then within [`PRESS_COALESCING_WINDOW`].
+/// Returns `None` once the window passed.
+async fn next_coalesced_input(input: InputReceiver, held: &mut bool) -> Option<InputEvent> {
+    loop {
+        let event = if *held {
+            input.receive().await
+        } else {
+            match select(input.receive(), Timer::after(PRESS_COALESCING_WINDOW)).await {
+                Either::First(event) => event,
+                Either::Second(()) => return None,
+            }
+        };
+        *held = event != InputEvent::Release;
+        if *held {
+            return Some(event);
+        }
+    }
+}
+
+/// Return the count after applying the presses counted offline.
+#[cfg(feature = "offline-counting")]
+fn apply_delta(count: u8, delta: i16) -> u8 {
+    (i16::from(count) + delta).clamp(0, u8::MAX.into()) as u8
+}
+
+/// Return the count after a press of the toggle switch, counting `step`
+/// people.
+fn apply_press(count: u8, direction: Direction, step: u8) -> u8 {
+    match direction {
+        Direction::Up => count.saturating_add(step),
+        Direction::Down => count.saturating_sub(step),
+    }
+}
+
+/// Return the digits of an IPv4 address for scrolling it across the tubes:
+/// The decimal digits of each octet, separated by a gap.
+#[cfg(feature = "show-ip")]
+fn ip_digits(address: Ipv4Address) -> heapless::Vec<Option<u8>, 15> {
+    let mut digits = heapless::Vec::new();
+    for (i, &octet) in address.as_bytes().iter().enumerate() {
+        if i > 0 {
+            let _ = digits.push(None);
+        }
+        // At most 4 times 3 digits and 3 gaps, which always fits
+        if octet >= 100 {
+            let _ = digits.push(Some(octet / 100));
+        }
+        if octet >= 10 {
+            let _ = digits.push(Some(octet / 10 % 10));
+        }
+        let _ = digits.push(Some(octet % 10));
+    }
+    digits
+}
+
 enum LedControlCommand {
-    TurnOn,
-    TurnOff,
-    Blink { delay: Duration },
+    /// The WiFi connection status changed
+    Wifi(WifiStatus),
+    /// The reachability of the endpoint changed
+    Endpoint { reachable: bool },
+    /// The sync lag exceeded the threshold, or is below it again
+    SyncLag { lagging: bool },
 }
 
 /// Task: Control WiFi LEDs
@@ -266,12 +1632,25 @@ async fn led_control_task(
     command_receiver: Receiver<'static, NoopRawMutex, LedControlCommand, 3>,
 ) {
     log::info!("Start LED connection task");
+    let mut wifi = WifiStatus::Disconnected;
+    let mut endpoint_reachable = true;
+    let mut sync_lagging = false;
     led.set_low();
     loop {
-        match command_receiver.receive().await {
-            LedControlCommand::TurnOn => led.set_high(),
-            LedControlCommand::TurnOff => led.set_low(),
-            LedControlCommand::Blink { delay } => 'blink: loop {
+        let command = command_receiver.receive().await;
+        #[cfg(feature = "queue-stats")]
+        queue_stats::LED_COMMANDS.received(command_receiver.len());
+        match command {
+            LedControlCommand::Wifi(status) => wifi = status,
+            LedControlCommand::Endpoint { reachable } => endpoint_reachable = reachable,
+            LedControlCommand::SyncLag { lagging } => sync_lagging = lagging,
+        }
+        #[cfg(feature = "neon-dots")]
+        neon_dots::set_offline(wifi != WifiStatus::Connected || !endpoint_reachable);
+        match LedPattern::for_status(wifi, endpoint_reachable, sync_lagging) {
+            LedPattern::On => led.set_high(),
+            LedPattern::Off => led.set_low(),
+            LedPattern::Blink { delay } => 'blink: loop {
                 led.toggle();
                 if command_receiver.is_empty() {
                     // No new command arrives, keep on blinking
@@ -299,8 +1678,22 @@ async fn connection(
         #[allow(clippy::single_match)]
         match esp_wifi::wifi::wifi_state() {
             WifiState::StaConnected => {
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                led_command_sender.send(LedControlCommand::TurnOff).await;
+                let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+                #[cfg(feature = "health-check")]
+                {
+                    // Drop the association if the health check considers it dead
+                    health_check::REASSOCIATE.reset();
+                    let result = select(disconnected, health_check::REASSOCIATE.wait()).await;
+                    if let Either::Second(()) = result {
+                        log::warn!("Dropping dead WiFi association");
+                        let _ = controller.disconnect_async().await;
+                    }
+                }
+                #[cfg(not(feature = "health-check"))]
+                disconnected.await;
+                led_command_sender
+                    .send(LedControlCommand::Wifi(WifiStatus::Disconnected))
+                    .await;
                 if previously_connected {
                     log::info!("WiFi connection lost");
                 }
@@ -311,9 +1704,7 @@ async fn connection(
 
         // Blink LED to indicate connecting status
         led_command_sender
-            .send(LedControlCommand::Blink {
-                delay: Duration::from_millis(250),
-            })
+            .send(LedControlCommand::Wifi(WifiStatus::Connecting))
             .await;
 
         // Start WiFi
@@ -330,11 +1721,20 @@ async fn connection(
         match controller.connect_async().await {
             Ok(_) => {
                 log::info!("WiFi \"{}\" connected!", config.ssid);
-                led_command_sender.send(LedControlCommand::TurnOn).await;
+                led_command_sender
+                    .send(LedControlCommand::Wifi(WifiStatus::Connected))
+                    .await;
                 previously_connected = true;
             }
             Err(e) => {
                 log::info!("Failed to connect to WiFi: {e:?}");
+                #[cfg(feature = "provisioning")]
+                if !previously_connected
+                    && Instant::now().as_millis() > PROVISIONING_FALLBACK_DELAY.as_millis()
+                {
+                    // The credentials probably don't work, ask for new ones
+                    provisioning::request();
+                }
                 Timer::after(Duration::from_millis(2000)).await
             }
         }
@@ -347,44 +1747,137 @@ async fn net_task(stack: &'static Stack<EspWifiDevice<'static>>) {
     stack.run().await
 }
 
-/// Update the "people now present" sensor through HTTP.
-async fn update_people_now_present<'a>(
-    client: &mut EspHttpClient<'a>,
-    people_count: u8,
-) -> anyhow::Result<()> {
-    // Prepare URL and payload
-    let url = SPACEAPI_SENSOR_ENDPOINT;
-    let mut payload_string = heapless::String::<9>::new();
-    write!(payload_string, "value={people_count}")?;
-    let payload = payload_string.as_bytes();
-
-    // Send request
-    let mut rx_buf = [0; 4096];
-    let request_handle = match client.request(Method::PUT, url).await {
-        Ok(handle) => handle,
-        Err(e) => {
-            log::error!("Could not create HTTP request handle: {:?}", e);
-            anyhow::bail!("HTTP request failed");
+/// Record the result of a count update, and update the LED if the
+/// reachability of the endpoint or the sync lag state changed.
+async fn record_endpoint_result(
+    health: &mut EndpointHealth,
+    sync_lag: &mut SyncLag,
+    led_command_sender: Sender<'static, NoopRawMutex, LedControlCommand, 3>,
+    success: bool,
+) {
+    if let Some(lagging) = sync_lag.record(success) {
+        if lagging {
+            log::warn!(
+                "Count not confirmed by the server for {} s",
+                sync_lag.lag().as_secs()
+            );
+        } else {
+            log::info!("Count confirmed by the server again");
         }
-    };
-    let mut request = request_handle
-        .headers(&[("content-type", "application/x-www-form-urlencoded")])
-        .body(payload);
-    log::info!("-> PUT {}", url);
-    let response = match request.send(&mut rx_buf).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("HTTP request error: {:?}", e);
-            anyhow::bail!("HTTP request failed");
+        led_command_sender
+            .send(LedControlCommand::SyncLag { lagging })
+            .await;
+    }
+    if let Some(reachable) = health.record(success) {
+        if reachable {
+            log::info!("Endpoint is reachable again");
+        } else {
+            log::warn!("Endpoint is unreachable");
         }
-    };
+        led_command_sender
+            .send(LedControlCommand::Endpoint { reachable })
+            .await;
+    }
+}
 
-    // Process response
-    log::info!("<- HTTP {}", response.status.0);
-    if response.status == Status::NoContent {
-        log::info!("Successfully set people now present count to {people_count}");
-        Ok(())
-    } else {
-        anyhow::bail!("Received unexpected HTTP status code when sending status update")
+/// Return the current power relevant state for the energy estimation.
+#[cfg(feature = "energy")]
+fn current_power_state(count: u8) -> PowerState {
+    PowerState::for_count(count, esp_wifi::wifi::wifi_state().into())
+}
+
+/// Turn off the tubes before a panic or exception is reported.
+///
+/// Called by esp-backtrace. Afterwards, the firmware halts with all outputs
+/// left as they are, which would keep the last digits lit with the driver
+/// inputs in whatever state the crash left them. Every display backend is
+/// turned off through its own pins, without touching the others (e.g. the
+/// I2C bus on GPIO9/10):
+///
+/// - Directly driven and multiplexed tubes: The driver inputs are driven to
+///   the blanking value of the tubes (by default, the out-of-range value
+///   0b1111), and the anode switches are turned off.
+/// - Shift registers: The blanking values are shifted in and latched.
+/// - TM1637 module: The display is turned off by its display control
+///   command.
+///
+/// The HV supply can't be switched off, its shutdown input is tied to GND.
+#[no_mangle]
+fn custom_pre_backtrace() {
+    // SAFETY: The firmware doesn't continue after a panic or exception, so
+    // the pins owned by the display are never used again.
+    #[cfg(any(
+        not(feature = "seven-segment"),
+        feature = "multiplexed",
+        feature = "shift-register"
+    ))]
+    unsafe {
+        blank_tubes();
+    }
+    // CLK on GPIO4, DIO on GPIO6
+    #[cfg(all(
+        feature = "seven-segment",
+        not(any(feature = "multiplexed", feature = "shift-register"))
+    ))]
+    unsafe {
+        seven_segment::turn_off(
+            Output::new(GpioPin::<4>::steal(), Level::High),
+            OutputOpenDrain::new(GpioPin::<6>::steal(), Level::High, Pull::Up),
+        );
+    }
+}
+
+/// Drive the inputs of the tube drivers to the blanking values, and turn off
+/// the anode switches.
+///
+/// # Safety
+///
+/// Steals the pins of the tubes, so the tubes must not be used afterwards.
+#[cfg(any(
+    not(feature = "seven-segment"),
+    feature = "multiplexed",
+    feature = "shift-register"
+))]
+unsafe fn blank_tubes() {
+    let (left, right) = (LEFT_TUBE_SYMBOLS.blank, RIGHT_TUBE_SYMBOLS.blank);
+    let bit = |value: u8, bit: u8| Level::from(value & (1 << bit) != 0);
+    #[cfg(not(any(feature = "multiplexed", feature = "shift-register")))]
+    {
+        let _ = Output::new(GpioPin::<6>::steal(), bit(left, 0));
+        let _ = Output::new(GpioPin::<4>::steal(), bit(left, 1));
+        let _ = Output::new(GpioPin::<3>::steal(), bit(left, 2));
+        let _ = Output::new(GpioPin::<5>::steal(), bit(left, 3));
+        let _ = Output::new(GpioPin::<9>::steal(), bit(right, 0));
+        let _ = Output::new(GpioPin::<8>::steal(), bit(right, 1));
+        let _ = Output::new(GpioPin::<7>::steal(), bit(right, 2));
+        let _ = Output::new(GpioPin::<10>::steal(), bit(right, 3));
+    }
+    // The shared decoder shows the left tube, turn off both anode switches
+    #[cfg(feature = "multiplexed")]
+    {
+        let _ = right;
+        let _ = Output::new(GpioPin::<6>::steal(), bit(left, 0));
+        let _ = Output::new(GpioPin::<4>::steal(), bit(left, 1));
+        let _ = Output::new(GpioPin::<3>::steal(), bit(left, 2));
+        let _ = Output::new(GpioPin::<5>::steal(), bit(left, 3));
+        let _ = Output::new(GpioPin::<7>::steal(), Level::Low);
+        let _ = Output::new(GpioPin::<8>::steal(), Level::Low);
+    }
+    // Shift the blanking values into all registers (SER on GPIO6, SRCLK on
+    // GPIO4, most significant bit first) and latch them (RCLK on GPIO3)
+    #[cfg(all(feature = "shift-register", not(feature = "multiplexed")))]
+    {
+        let mut data = Output::new(GpioPin::<6>::steal(), Level::Low);
+        let mut clock = Output::new(GpioPin::<4>::steal(), Level::Low);
+        let register = (left & 0x0F) | (right & 0x0F) << 4;
+        for _ in 0..shift_register::REGISTER_COUNT {
+            for i in (0..8).rev() {
+                data.set_level(bit(register, i));
+                clock.set_high();
+                clock.set_low();
+            }
+        }
+        let mut latch = Output::new(GpioPin::<3>::steal(), Level::Low);
+        latch.set_high();
     }
 }
@@ -0,0 +1,142 @@
+//! Waking the blanked tubes when someone is in the doorway.
+//!
+//! A PIR motion sensor (e.g. an HC-SR501, whose output is high while it
+//! sees motion) is connected to P2 of the I/O expander (GPA2 of an
+//! MCP23017). While the tubes are blanked, during quiet hours or since the
+//! space was closed, motion lights them for `MOTION_WAKE_DURATION` (seconds,
+//! default 60), so that people entering the room see the count. Continued
+//! motion keeps them lit. Afterwards they are blanked again, unless the
+//! reason for blanking them has ended meanwhile.
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::digital::Wait;
+
+use crate::{
+    display_task::DisplayCommand,
+    driver::{Context, Driver},
+    io_expander::ExpanderPin,
+};
+
+/// Expander pin of the sensor output
+pub const SENSOR_PIN: u8 = 2;
+
+/// What the sensor reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MotionEvent {
+    /// The sensor started seeing motion
+    Motion,
+    /// The tubes lit for motion must be blanked again
+    Timeout,
+}
+
+/// The motion sensor, and how long the tubes stay lit for it.
+pub struct MotionSensor {
+    pin: ExpanderPin,
+    wake_duration: Duration,
+    /// When the tubes lit for motion are blanked again, if they are
+    lit_until: Option<Instant>,
+    /// Whether the output was low since the last motion. The output counts
+    /// as high until the expander was read, so it is only armed once it was
+    /// seen low.
+    armed: bool,
+}
+
+impl MotionSensor {
+    pub fn new(pin: ExpanderPin, wake_duration: Duration) -> Self {
+        Self {
+            pin,
+            wake_duration,
+            lit_until: None,
+            armed: false,
+        }
+    }
+
+    /// Wait until the sensor sees motion, or the tubes lit for it must be
+    /// blanked again.
+    pub async fn wait(&mut self) -> MotionEvent {
+        loop {
+            // The state is kept in between, since the main loop cancels this
+            // whenever another event arrives
+            let armed = self.armed;
+            let pin = &mut self.pin;
+            let level = async move {
+                if armed {
+                    pin.wait_for_high().await
+                } else {
+                    pin.wait_for_low().await
+                }
+            };
+            let lit_until = self.lit_until;
+            let timeout = async move {
+                match lit_until {
+                    Some(lit_until) => Timer::at(lit_until).await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select(level, timeout).await {
+                Either::First(_) => {
+                    self.armed = !armed;
+                    if armed {
+                        return MotionEvent::Motion;
+                    }
+                }
+                // Motion that continues keeps the tubes lit
+                Either::Second(()) if !armed => self.light(),
+                Either::Second(()) => {
+                    self.lit_until = None;
+                    return MotionEvent::Timeout;
+                }
+            }
+        }
+    }
+
+    /// Keep the tubes lit for the wake duration from now.
+    pub fn light(&mut self) {
+        if self.lit_until.is_none() {
+            log::info!("Motion seen, lighting the tubes");
+        }
+        self.lit_until = Some(Instant::now() + self.wake_duration);
+    }
+
+    /// Return whether the tubes are lit for motion.
+    pub fn is_lit(&self) -> bool {
+        self.lit_until.is_some()
+    }
+
+    /// Stop lighting the tubes for motion, e.g. once they are woken by a
+    /// press. Returns whether they were lit for it.
+    pub fn end(&mut self) -> bool {
+        self.lit_until.take().is_some()
+    }
+}
+
+/// Lights the blanked tubes for motion, and blanks them again afterwards.
+impl Driver for MotionSensor {
+    type Event = MotionEvent;
+
+    async fn poll(&mut self) -> MotionEvent {
+        self.wait().await
+    }
+
+    async fn handle_event(&mut self, event: MotionEvent, context: &Context) {
+        match event {
+            MotionEvent::Motion if context.blanked => {
+                if !self.is_lit() {
+                    context.display.send(DisplayCommand::Blank(false)).await;
+                }
+                self.light();
+            }
+            MotionEvent::Timeout if context.blanked => {
+                log::info!("No more motion, blanking the tubes again");
+                context.display.send(DisplayCommand::Blank(true)).await;
+            }
+            MotionEvent::Motion | MotionEvent::Timeout => {}
+        }
+    }
+
+    /// A press ends the wake, the tubes stay lit since it woke them anyway.
+    fn on_press(&mut self) -> bool {
+        self.end()
+    }
+}
@@ -0,0 +1,152 @@
+//! Multiplexed driving of the tubes.
+//!
+//! On boards with a single K155ID1 shared by all tubes, every tube has its own
+//! anode switch instead. [`multiplex_task`] lights one tube after the other,
+//! fast enough that all of them appear to glow continuously. The tubes
+//! ([`MultiplexedTube`]) only record the cathode that should be lit. Since
+//! every tube strikes anew in each of its slots, it stays lit for its strike
+//! delay on top of the time it glows.
+//!
+//! The task runs in an interrupt executor with a higher priority than the
+//! rest of the firmware, so that busy tasks don't make the tubes flicker.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::Output;
+
+use crate::{
+    config::TUBE_COUNT,
+    nixie::{NixieTube, SymbolMap, Tube},
+};
+
+/// Time each tube is lit in turn, i.e. a refresh rate of 200 Hz for two tubes
+/// (unless a long strike delay stretches the slot of a tube)
+const SLOT_DURATION: Duration = Duration::from_micros(2500);
+
+/// Time all anodes are off before the next tube is lit, so that it doesn't
+/// briefly glow with the digit of the previous one
+const BLANKING_DURATION: Duration = Duration::from_micros(100);
+
+/// Shortest time a tube glows at full brightness, once struck. The slot of a
+/// tube whose strike delay leaves less of it is stretched.
+const MIN_GLOW_DURATION: Duration = Duration::from_micros(1000);
+
+/// Brightness of every tube in percent, i.e. the part of its slot it is lit
+#[cfg(feature = "dimming")]
+static BRIGHTNESS: Mutex<CriticalSectionRawMutex, Cell<[u8; TUBE_COUNT]>> =
+    Mutex::new(Cell::new([100; TUBE_COUNT]));
+
+/// Cathode to light on every tube (`None` for off)
+static CATHODES: Mutex<CriticalSectionRawMutex, Cell<[Option<u8>; TUBE_COUNT]>> =
+    Mutex::new(Cell::new([None; TUBE_COUNT]));
+
+/// Strike delay of every tube, which it is lit for before it glows
+static STRIKE_DELAYS: Mutex<CriticalSectionRawMutex, Cell<[Duration; TUBE_COUNT]>> =
+    Mutex::new(Cell::new([Duration::MIN; TUBE_COUNT]));
+
+/// A tube lit through the multiplexed driver.
+pub struct MultiplexedTube {
+    index: usize,
+    symbols: SymbolMap,
+}
+
+impl MultiplexedTube {
+    /// Create the tube with the specified index (the anode switch it is
+    /// connected to), symbol map and strike delay.
+    pub fn new(index: usize, symbols: SymbolMap, strike_delay: Duration) -> Self {
+        let mut tube = Self { index, symbols };
+        tube.set_strike_delay(strike_delay);
+        tube
+    }
+}
+
+impl Tube for MultiplexedTube {
+    fn show_cathode(&mut self, cathode: u8) {
+        let cathode = Some(cathode).filter(|cathode| *cathode != self.symbols.blank);
+        CATHODES.lock(|cathodes| {
+            let mut value = cathodes.get();
+            value[self.index] = cathode;
+            cathodes.set(value);
+        });
+    }
+
+    fn symbols(&self) -> SymbolMap {
+        self.symbols
+    }
+
+    fn strike_delay(&self) -> Duration {
+        STRIKE_DELAYS.lock(|delays| delays.get()[self.index])
+    }
+
+    fn set_strike_delay(&mut self, delay: Duration) {
+        STRIKE_DELAYS.lock(|delays| {
+            let mut value = delays.get();
+            value[self.index] = delay;
+            delays.set(value);
+        });
+    }
+
+    #[cfg(feature = "dimming")]
+    fn set_brightness(&mut self, percent: u8) {
+        BRIGHTNESS.lock(|brightness| {
+            let mut value = brightness.get();
+            value[self.index] = percent.min(100);
+            brightness.set(value);
+        });
+    }
+}
+
+/// The pins of the multiplexed driver.
+pub struct MultiplexPins {
+    /// The inputs of the shared K155ID1, driven like those of a single tube
+    /// (only its blanking value is used, the digits are mapped by every
+    /// tube)
+    pub decoder: NixieTube<Output<'static>, Output<'static>, Output<'static>, Output<'static>>,
+    /// The anode switch of every tube, lit while high
+    pub anodes: [Output<'static>; TUBE_COUNT],
+}
+
+/// Task: Light the multiplexed tubes in turn
+#[embassy_executor::task]
+pub async fn multiplex_task(mut pins: MultiplexPins) {
+    log::info!("Start tube multiplexing task");
+    let mut current = 0;
+    loop {
+        for anode in &mut pins.anodes {
+            anode.set_low();
+        }
+        let cathode = CATHODES.lock(|cathodes| cathodes.get()[current]);
+        match cathode {
+            Some(cathode) => pins.decoder.show_cathode(cathode),
+            None => pins.decoder.off(),
+        }
+        Timer::after(BLANKING_DURATION).await;
+        // The tube only glows once struck, for the rest of its slot
+        let strike_delay = STRIKE_DELAYS.lock(|delays| delays.get()[current]);
+        let lit_duration = SLOT_DURATION - BLANKING_DURATION;
+        let glow_duration = if lit_duration > strike_delay + MIN_GLOW_DURATION {
+            lit_duration - strike_delay
+        } else {
+            MIN_GLOW_DURATION
+        };
+        let slot_duration = strike_delay + glow_duration;
+        // Tubes that are off stay dark, even if the K155ID1 leaks
+        if cathode.is_some() {
+            // Dim the tube by turning it off early
+            #[cfg(feature = "dimming")]
+            let on_duration =
+                strike_delay + glow_duration * u32::from(BRIGHTNESS.lock(Cell::get)[current]) / 100;
+            #[cfg(not(feature = "dimming"))]
+            let on_duration = slot_duration;
+            pins.anodes[current].set_high();
+            Timer::after(on_duration).await;
+            pins.anodes[current].set_low();
+            Timer::after(slot_duration - on_duration).await;
+        } else {
+            Timer::after(slot_duration).await;
+        }
+        current = (current + 1) % TUBE_COUNT;
+    }
+}
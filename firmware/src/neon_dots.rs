@@ -0,0 +1,94 @@
+//! Status indication through the two neon dots between the tubes.
+//!
+//! The left dot is lit while a count update is in flight. The right dot blinks
+//! while the counter is offline, i.e. the WiFi is disconnected or the endpoint
+//! is unreachable. With the `room-temperature` feature, the right dot is lit
+//! instead while the tubes show the temperature.
+
+use core::cell::Cell;
+
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::Duration;
+use esp_hal::gpio::Output;
+
+use crate::nixie::NeonDot;
+
+/// Delay between turning the offline dot on and off
+const OFFLINE_BLINK_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Copy, Clone)]
+struct State {
+    update_in_flight: bool,
+    offline: bool,
+    #[cfg(feature = "room-temperature")]
+    temperature_shown: bool,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<State>> = Mutex::new(Cell::new(State {
+    update_in_flight: false,
+    offline: true,
+    #[cfg(feature = "room-temperature")]
+    temperature_shown: false,
+}));
+
+/// Signaled whenever the state changed
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn update(f: impl FnOnce(&mut State)) {
+    STATE.lock(|state| {
+        let mut value = state.get();
+        f(&mut value);
+        state.set(value);
+    });
+    CHANGED.signal(());
+}
+
+/// Record whether a count update is in flight.
+pub fn set_update_in_flight(in_flight: bool) {
+    update(|state| state.update_in_flight = in_flight);
+}
+
+/// Record whether the counter is offline.
+pub fn set_offline(offline: bool) {
+    update(|state| state.offline = offline);
+}
+
+/// Record whether the tubes show the room temperature.
+#[cfg(feature = "room-temperature")]
+pub fn set_temperature_shown(shown: bool) {
+    update(|state| state.temperature_shown = shown);
+}
+
+/// Task: Show the status on the neon dots
+#[embassy_executor::task]
+pub async fn neon_dots_task(
+    mut in_flight_dot: NeonDot<Output<'static>>,
+    mut offline_dot: NeonDot<Output<'static>>,
+) {
+    log::info!("Start neon dots task");
+    loop {
+        let state = STATE.lock(Cell::get);
+        if state.update_in_flight {
+            in_flight_dot.on();
+        } else {
+            in_flight_dot.off();
+        }
+        #[cfg(feature = "room-temperature")]
+        if state.temperature_shown {
+            offline_dot.on();
+            CHANGED.wait().await;
+            continue;
+        }
+        if state.offline {
+            // Keep on blinking until the state changes
+            select(CHANGED.wait(), offline_dot.blink(OFFLINE_BLINK_DELAY)).await;
+        } else {
+            offline_dot.off();
+            CHANGED.wait().await;
+        }
+    }
+}
@@ -1,124 +1,348 @@
-use embassy_time::{Duration, Timer};
+use embassy_time::Duration;
+#[cfg(any(feature = "diagnostics", feature = "neon-dots"))]
+use embassy_time::Timer;
 use embedded_hal::digital::OutputPin;
 
+use crate::display::{CounterDisplay, DisplayState};
+
 /// A nixie tube.
 ///
 /// The struct needs to be initialized with the four output pins connected to
-/// the K155ID1 BCD encoder.
+/// the K155ID1 BCD encoder, the symbol map of the tube and its strike delay.
+///
+/// The strike delay is the time a newly selected cathode needs until it
+/// reliably glows. Animations show every digit for at least this long. Old
+/// tubes may need a longer delay than new ones.
 pub struct NixieTube<A, B, C, D> {
     pub pin_a: A,
     pub pin_b: B,
     pub pin_c: C,
     pub pin_d: D,
+    pub symbols: SymbolMap,
+    pub strike_delay: Duration,
 }
 
-/// A pair of two nixie tubes.
-pub struct NixieTubePair<A, B, C, D, E, F, G, H> {
-    left: NixieTube<A, B, C, D>,
-    right: NixieTube<E, F, G, H>,
+/// Maps the digits 0-9 to the cathode index (K155ID1 input value) that is lit
+/// to display them, and to the input value that turns the tube off.
+///
+/// Regular numeric tubes on a K155ID1 use [`SymbolMap::IDENTITY`]. Tubes with
+/// Cyrillic or symbol cathodes in some positions can remap the digits to
+/// other cathodes, and 74141 clones with another mapping or blanking code
+/// can be fixed without rewiring the board.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SymbolMap {
+    /// The input value for each digit
+    pub cathodes: [u8; 10],
+    /// The input value that turns the tube off
+    pub blank: u8,
 }
 
-impl<A, B, C, D, E, F, G, H> NixieTubePair<A, B, C, D, E, F, G, H>
-where
-    A: OutputPin,
-    B: OutputPin,
-    C: OutputPin,
-    D: OutputPin,
-    E: OutputPin,
-    F: OutputPin,
-    G: OutputPin,
-    H: OutputPin,
-{
-    /// Create a new instance.
-    pub fn new(left: NixieTube<A, B, C, D>, right: NixieTube<E, F, G, H>) -> Self {
-        Self { left, right }
+impl SymbolMap {
+    /// Every digit is shown on the cathode with the same index, and the
+    /// out-of-range value 0b1111 turns the tube off.
+    pub const IDENTITY: Self = Self {
+        cathodes: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+        blank: 0x0F,
+    };
+
+    /// Return the cathode index for the specified digit.
+    ///
+    /// Digits above 9 map to the blanking value, which turns the tube off.
+    pub fn cathode(&self, digit: u8) -> u8 {
+        self.cathodes
+            .get(usize::from(digit))
+            .copied()
+            .unwrap_or(self.blank)
+    }
+}
+
+impl Default for SymbolMap {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// A single tube of a [`NixieTubeArray`].
+///
+/// Implemented by [`NixieTube`]. Arrays of tubes with different pin types can
+/// be built from `&mut dyn Tube` references.
+pub trait Tube {
+    /// Light the cathode with the specified index, ignoring the symbol map.
+    ///
+    /// The value must be between 0 and 9. Otherwise, the tube will be turned off.
+    fn show_cathode(&mut self, cathode: u8);
+
+    /// Return the symbol map of the tube.
+    fn symbols(&self) -> SymbolMap;
+
+    /// Return the strike delay of the tube, see [`NixieTube`].
+    fn strike_delay(&self) -> Duration;
+
+    /// Change the strike delay of the tube, e.g. to calibrate it at runtime.
+    fn set_strike_delay(&mut self, delay: Duration);
+
+    /// Show the specified digit, using the symbol map of the tube.
+    ///
+    /// The value must be between 0 and 9. Otherwise, the tube will be turned off.
+    fn show_digit(&mut self, digit: u8) {
+        let cathode = self.symbols().cathode(digit);
+        self.show_cathode(cathode);
+    }
+
+    /// Turn off the tube, using the blanking value of the symbol map.
+    fn off(&mut self) {
+        let blank = self.symbols().blank;
+        self.show_cathode(blank);
+    }
+
+    /// Set the brightness in percent, if the tube can be dimmed. Tubes that
+    /// are lit continuously can't, and ignore it.
+    #[cfg(feature = "dimming")]
+    fn set_brightness(&mut self, _percent: u8) {}
+}
+
+impl<T: Tube + ?Sized> Tube for &mut T {
+    fn show_cathode(&mut self, cathode: u8) {
+        (**self).show_cathode(cathode);
+    }
+
+    fn symbols(&self) -> SymbolMap {
+        (**self).symbols()
+    }
+
+    fn strike_delay(&self) -> Duration {
+        (**self).strike_delay()
+    }
+
+    fn set_strike_delay(&mut self, delay: Duration) {
+        (**self).set_strike_delay(delay);
+    }
+
+    #[cfg(feature = "dimming")]
+    fn set_brightness(&mut self, percent: u8) {
+        (**self).set_brightness(percent);
+    }
+}
+
+/// A row of `N` nixie tubes, showing a decimal number. The first tube is the
+/// leftmost (most significant) digit.
+///
+/// The animations are provided by [`CounterDisplay`]. The array lights the
+/// digits through the symbol maps of the tubes, shows them for at least the
+/// strike delay of the tubes, and dims every tube on its own.
+pub struct NixieTubeArray<T, const N: usize> {
+    tubes: [T; N],
+    state: DisplayState,
+    /// The maximum brightness of every tube, in percent
+    #[cfg(feature = "dimming")]
+    levels: [u8; N],
+}
+
+// Unused with only the `seven-segment` backend
+#[cfg_attr(
+    all(
+        feature = "seven-segment",
+        not(any(feature = "multiplexed", feature = "shift-register"))
+    ),
+    allow(dead_code)
+)]
+impl<T: Tube, const N: usize> NixieTubeArray<T, N> {
+    /// Create a new instance, with the tubes from left to right.
+    pub fn new(tubes: [T; N]) -> Self {
+        Self {
+            tubes,
+            state: DisplayState::new(),
+            #[cfg(feature = "dimming")]
+            levels: [100; N],
+        }
+    }
+}
+
+impl<T: Tube, const N: usize> CounterDisplay<N> for NixieTubeArray<T, N> {
+    fn state(&self) -> &DisplayState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut DisplayState {
+        &mut self.state
+    }
+
+    /// Show a digit (or nothing, for `None`) on every tube, from left to
+    /// right, using the symbol maps of the tubes.
+    fn show_digits(&mut self, digits: [Option<u8>; N]) {
+        for (tube, digit) in self.tubes.iter_mut().zip(digits) {
+            match digit {
+                Some(digit) => tube.show_digit(digit),
+                None => tube.off(),
+            }
+        }
+    }
+
+    fn show_cathodes(&mut self, cathodes: [u8; N]) {
+        for (tube, cathode) in self.tubes.iter_mut().zip(cathodes) {
+            tube.show_cathode(cathode);
+        }
     }
 
-    /// Return mutable reference to the left tube.
-    pub fn left(&mut self) -> &mut NixieTube<A, B, C, D> {
-        &mut self.left
+    /// Return the minimal time a digit must be shown during animations, so
+    /// that it reliably lights up on all tubes: `delay`, or the strike delay
+    /// of the tubes if longer.
+    fn frame_delay(&self, delay: Duration) -> Duration {
+        self.tubes
+            .iter()
+            .map(|tube| tube.strike_delay())
+            .fold(delay, Duration::max)
     }
 
-    /// Return mutable reference to the right tube.
-    pub fn right(&mut self) -> &mut NixieTube<E, F, G, H> {
-        &mut self.right
+    /// Set the brightness of the selected tubes, scaled by their levels (see
+    /// [`set_tube_levels`](Self::set_tube_levels)). Tubes that are lit
+    /// continuously ignore it.
+    #[cfg(feature = "dimming")]
+    fn apply_brightness(&mut self, selected: [bool; N], percent: u8) {
+        let tubes = self.tubes.iter_mut().zip(self.levels).zip(selected);
+        for ((tube, level), _) in tubes.filter(|(_, selected)| *selected) {
+            let scaled = u16::from(percent.min(100)) * u16::from(level.min(100)) / 100;
+            tube.set_brightness(scaled as u8);
+        }
     }
 
-    /// Show a number between 1 and 99.
+    /// Set the maximum brightness of every tube in percent, e.g. to match an
+    /// older tube that glows dimmer than the others. The brightness set for
+    /// the array is scaled by it.
+    #[cfg(feature = "dimming")]
+    fn set_tube_levels(&mut self, levels: [u8; N]) {
+        self.levels = levels;
+        self.apply_brightness([true; N], self.state.brightness());
+    }
+
+    /// Change the strike delay of the tube at `index`. Out of range indices
+    /// are ignored.
+    #[cfg(feature = "console")]
+    fn set_strike_delay(&mut self, index: usize, delay: Duration) {
+        if let Some(tube) = self.tubes.get_mut(index) {
+            tube.set_strike_delay(delay);
+        }
+    }
+
+    /// Test the BCD lines (the inputs A-D of the K155ID1) of every tube
+    /// individually, from left to right, holding every step for `delay` (or
+    /// the strike delay of the tubes, if longer). The other tubes are off.
+    /// Afterwards, the number last passed to
+    /// [`show_number`](CounterDisplay::show_number) is shown again.
     ///
-    /// Leading zeroes as well as the number 0 will not be shown. If you need
-    /// to show zeroes, use the `show_digit` method on the tube directly.
-    pub fn show(&mut self, val: u8) {
-        let tens = (val / 10) % 100;
-        let ones = val % 10;
-        if tens > 0 {
-            self.left.show_digit(tens);
-            self.right.show_digit(ones);
-        } else if ones > 0 {
-            self.left.off();
-            self.right.show_digit(ones);
-        } else {
-            self.off();
+    /// Every step logs the cathode that should glow, which tells which line
+    /// is broken if another one does: First, all lines are low (cathode 0),
+    /// so a line stuck high lights its cathode instead (A: 1, B: 2, C: 4,
+    /// D: 8). Then every line is high on its own, which lights cathode 0 if
+    /// that line is broken. The symbol maps are ignored.
+    #[cfg(feature = "diagnostics")]
+    async fn diagnose(&mut self, delay: Duration) {
+        const LINES: [char; 4] = ['A', 'B', 'C', 'D'];
+        let delay = self.frame_delay(delay);
+        log::info!("Starting tube diagnostics");
+        self.off();
+        for i in 0..N {
+            log::info!(
+                "Tube {}, all lines low: expecting cathode 0, another one is a line stuck high",
+                i + 1
+            );
+            self.tubes[i].show_cathode(0);
+            Timer::after(delay).await;
+            for (bit, line) in LINES.iter().enumerate() {
+                let cathode = 1 << bit;
+                log::info!(
+                    "Tube {}, line {line} high: expecting cathode {cathode}, 0 if line {line} is broken",
+                    i + 1
+                );
+                self.tubes[i].show_cathode(cathode);
+                Timer::after(delay).await;
+            }
+            self.tubes[i].off();
         }
+        log::info!("Tube diagnostics done");
+        self.show_number(self.state.value());
     }
+}
+
+/// A neon indicator lamp, e.g. an INS-1 dot between the tubes, switched
+/// through an output pin. The lamp is lit while the pin is high.
+#[cfg(feature = "neon-dots")]
+pub struct NeonDot<P> {
+    pin: P,
+}
 
-    /// Turn off both tubes.
+#[cfg(feature = "neon-dots")]
+impl<P: OutputPin> NeonDot<P> {
+    /// Create a new instance. The lamp is turned off.
+    pub fn new(pin: P) -> Self {
+        let mut dot = Self { pin };
+        dot.off();
+        dot
+    }
+
+    /// Light the lamp.
+    pub fn on(&mut self) {
+        let _ = self.pin.set_high();
+    }
+
+    /// Turn off the lamp.
     pub fn off(&mut self) {
-        self.left.off();
-        self.right.off();
+        let _ = self.pin.set_low();
     }
 
-    /// Show every digit on both tubes, with [`delay`] between each digit.
-    pub async fn selftest(&mut self, delay: Duration) {
-        for i in 0..=9 {
-            self.left().show_digit(i);
-            self.right().show_digit(i);
+    /// Blink the lamp, with `delay` between turning it on and off.
+    ///
+    /// Never returns, stop it by dropping the future (e.g. with `select`).
+    /// The lamp is left in its current state.
+    pub async fn blink(&mut self, delay: Duration) -> ! {
+        loop {
+            self.on();
+            Timer::after(delay).await;
+            self.off();
             Timer::after(delay).await;
         }
-        self.off();
     }
 }
 
-impl<A, B, C, D> NixieTube<A, B, C, D>
+impl<A, B, C, D> Tube for NixieTube<A, B, C, D>
 where
     A: OutputPin,
     B: OutputPin,
     C: OutputPin,
     D: OutputPin,
 {
-    /// Show the specified digit.
-    ///
-    /// The value must be between 0 and 9. Otherwise, the tube will be turned off.
-    pub fn show_digit(&mut self, digit: u8) {
-        if digit & 0x01 > 0 {
+    fn show_cathode(&mut self, cathode: u8) {
+        if cathode & 0x01 > 0 {
             let _ = self.pin_a.set_high();
         } else {
             let _ = self.pin_a.set_low();
         }
-        if digit & 0x02 > 0 {
+        if cathode & 0x02 > 0 {
             let _ = self.pin_b.set_high();
         } else {
             let _ = self.pin_b.set_low();
         }
-        if digit & 0x04 > 0 {
+        if cathode & 0x04 > 0 {
             let _ = self.pin_c.set_high();
         } else {
             let _ = self.pin_c.set_low();
         }
-        if digit & 0x08 > 0 {
+        if cathode & 0x08 > 0 {
             let _ = self.pin_d.set_high();
         } else {
             let _ = self.pin_d.set_low();
         }
     }
 
-    /// Turn off the tube.
-    pub fn off(&mut self) {
-        // The value 0b1111 is out of range and will result
-        // in the tube being turned off.
-        let _ = self.pin_a.set_high();
-        let _ = self.pin_b.set_high();
-        let _ = self.pin_c.set_high();
-        let _ = self.pin_d.set_high();
+    fn symbols(&self) -> SymbolMap {
+        self.symbols
+    }
+
+    fn strike_delay(&self) -> Duration {
+        self.strike_delay
+    }
+
+    fn set_strike_delay(&mut self, delay: Duration) {
+        self.strike_delay = delay;
     }
 }
@@ -0,0 +1,825 @@
+//! WiFi provisioning through a captive portal.
+//!
+//! When no settings are available, or the WiFi connection could not be
+//! established with the configured credentials, the counter reboots into
+//! provisioning mode. It then opens the access point [`PORTAL_SSID`], hands out
+//! addresses through a minimal DHCP server and answers all DNS queries with its
+//! own address, so that phones show the settings form as captive portal. Once
+//! the settings are submitted, they are stored and the counter reboots into
+//! normal operation.
+//!
+//! On the first boot, when no settings are stored at all, the tubes guide
+//! through the setup by showing the current [`SetupStep`], until the first
+//! count update succeeded.
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, select4, Either4};
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
+};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use esp_hal::{
+    gpio::Output,
+    peripherals::{BT, WIFI},
+};
+#[cfg(feature = "ble-provisioning")]
+use esp_wifi::ble::controller::BleConnector;
+use esp_wifi::{
+    wifi::{AccessPointConfiguration, AuthMethod, Configuration, WifiApDevice, WifiDevice},
+    EspWifiController,
+};
+
+use crate::{
+    changelog::{Changelog, Source},
+    config::TUBE_COUNT,
+    display::{self, CounterDisplay},
+    settings::Settings,
+};
+
+/// SSID of the open access point of the portal
+const PORTAL_SSID: &str = "Nixie Counter Setup";
+
+/// Address of the counter in the portal network (a /24 network)
+const PORTAL_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+
+/// Time after which the counter reboots to retry the stored settings, in
+/// case the WiFi network was only temporarily unavailable.
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Number of sockets: DHCP server, DNS server and HTTP server
+const PORTAL_SOCKET_COUNT: usize = 3;
+
+/// Lease time announced by the DHCP server, in seconds
+const DHCP_LEASE_TIME: u32 = 3600;
+
+/// Number of clients the DHCP server hands out addresses to
+const DHCP_LEASE_COUNT: usize = 8;
+
+/// Value of [`PROVISIONING_REQUEST`] if provisioning mode was requested.
+const PROVISIONING_REQUESTED: u32 = 0x5052_4F56;
+
+/// Request to start in provisioning mode after the next reset.
+#[esp_hal::macros::ram(rtc_fast, persistent)]
+static mut PROVISIONING_REQUEST: u32 = 0;
+
+/// Value of [`SETUP_IN_PROGRESS`] while the guided setup is in progress.
+const SETUP_MARKER: u32 = 0x5345_5455;
+
+/// Marks that the settings were stored during the guided setup, which
+/// continues after the reboot.
+#[esp_hal::macros::ram(rtc_fast, persistent)]
+static mut SETUP_IN_PROGRESS: u32 = 0;
+
+type EspApDevice<'a> = WifiDevice<'a, WifiApDevice>;
+
+/// Steps of the guided setup on the first boot, shown on the tubes as `01`,
+/// `02` and so on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SetupStep {
+    /// Connect to the portal and submit the settings
+    EnterSettings = 1,
+    /// The settings were stored, connecting to the WiFi network
+    ConnectWifi = 2,
+    /// Connected, waiting for the first successful count update
+    FirstUpdate = 3,
+}
+
+impl SetupStep {
+    fn digits(self) -> [Option<u8>; TUBE_COUNT] {
+        display::digits(self as u32).map(Some)
+    }
+
+    fn show(self, tubes: &mut impl CounterDisplay<TUBE_COUNT>) {
+        tubes.show_digits(self.digits());
+    }
+}
+
+/// The guided setup, continued after the settings were stored.
+pub struct GuidedSetup {
+    active: bool,
+}
+
+impl GuidedSetup {
+    /// Continue the guided setup, if it is in progress.
+    pub fn resume() -> Self {
+        // SAFETY: Only accessed from the main thread, without creating a reference
+        let active =
+            unsafe { core::ptr::addr_of!(SETUP_IN_PROGRESS).read_volatile() } == SETUP_MARKER;
+        if active {
+            log::info!("Continuing guided setup");
+        }
+        Self { active }
+    }
+
+    /// Show the specified step while the setup is in progress.
+    pub fn show(&self, tubes: &mut impl CounterDisplay<TUBE_COUNT>, step: SetupStep) {
+        if let Some(digits) = self.digits(step) {
+            tubes.show_digits(digits);
+        }
+    }
+
+    /// Return the digits shown for the specified step, if the setup is in
+    /// progress.
+    pub fn digits(&self, step: SetupStep) -> Option<[Option<u8>; TUBE_COUNT]> {
+        self.active.then_some(step.digits())
+    }
+
+    /// Record the result of a count update. The first successful one
+    /// completes the setup.
+    ///
+    /// Returns whether the setup was just completed.
+    pub fn record_update(&mut self, success: bool) -> bool {
+        if !self.active || !success {
+            return false;
+        }
+        log::info!("Guided setup completed");
+        // SAFETY: Only accessed from the main thread, without creating a reference
+        unsafe { core::ptr::addr_of_mut!(SETUP_IN_PROGRESS).write_volatile(0) };
+        self.active = false;
+        true
+    }
+}
+
+/// Reboot into provisioning mode.
+pub fn request() -> ! {
+    log::warn!("Rebooting into provisioning mode");
+    // SAFETY: Only accessed from the main thread, without creating a reference
+    unsafe { core::ptr::addr_of_mut!(PROVISIONING_REQUEST).write_volatile(PROVISIONING_REQUESTED) };
+    reboot();
+}
+
+/// Return whether provisioning mode was requested before the last reset,
+/// and reset the request.
+pub fn take_request() -> bool {
+    // SAFETY: Only accessed from the main thread, without creating a reference
+    unsafe {
+        let requested =
+            core::ptr::addr_of!(PROVISIONING_REQUEST).read_volatile() == PROVISIONING_REQUESTED;
+        core::ptr::addr_of_mut!(PROVISIONING_REQUEST).write_volatile(0);
+        requested
+    }
+}
+
+/// Run the captive portal (and the BLE provisioning service, if enabled)
+/// until new settings are stored, then reboot.
+///
+/// The form is pre-filled with `settings`, except for the password. The WiFi
+/// LED flashes twice per second while in provisioning mode. The portal also
+/// offers a test page, which shows individual digits on the `tubes`.
+///
+/// Without any `settings`, the guided setup is started.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    spawner: Spawner,
+    wifi_init: &'static EspWifiController<'static>,
+    wifi: WIFI,
+    bt: BT,
+    led: Output<'static>,
+    tubes: &mut impl CounterDisplay<TUBE_COUNT>,
+    seed: u64,
+    settings: Settings,
+) -> ! {
+    log::info!("Starting provisioning portal \"{}\"", PORTAL_SSID);
+    let guided_setup = !settings.is_complete();
+    let settings = Some(settings);
+    if guided_setup {
+        SetupStep::EnterSettings.show(tubes);
+    } else {
+        tubes.off();
+    }
+
+    // Start BLE provisioning service
+    #[cfg(feature = "ble-provisioning")]
+    let ble = crate::ble_provisioning::run(BleConnector::new(wifi_init, bt), settings.clone());
+    #[cfg(not(feature = "ble-provisioning"))]
+    let ble = {
+        // Bluetooth is not used
+        let _ = bt;
+        core::future::pending::<()>()
+    };
+
+    // Start access point
+    let (wifi_interface, mut controller) =
+        esp_wifi::wifi::new_with_mode(wifi_init, wifi, WifiApDevice).unwrap();
+    let ap_config = AccessPointConfiguration {
+        ssid: PORTAL_SSID.try_into().unwrap(),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+    controller
+        .set_configuration(&Configuration::AccessPoint(ap_config))
+        .unwrap();
+    controller.start_async().await.unwrap();
+
+    // Init network stack
+    let address = Ipv4Address::from_bytes(&PORTAL_ADDRESS);
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(address, 24),
+        gateway: Some(address),
+        dns_servers: Default::default(),
+    });
+    let stack = &*mk_static!(
+        Stack<EspApDevice<'_>>,
+        Stack::new(
+            wifi_interface,
+            config,
+            mk_static!(
+                StackResources<PORTAL_SOCKET_COUNT>,
+                StackResources::<PORTAL_SOCKET_COUNT>::new()
+            ),
+            seed
+        )
+    );
+    spawner.must_spawn(portal_net_task(stack));
+    log::info!("Provisioning portal running at http://{}/", address);
+
+    // Serve until the settings were saved, or until the timeout expired
+    let servers = select3(
+        dhcp_server(stack),
+        dns_server(stack),
+        http_server(stack, settings, tubes),
+    );
+    let saved = match select4(servers, ble, indicate(led), Timer::after(PORTAL_TIMEOUT)).await {
+        Either4::First(_) | Either4::Second(()) => {
+            // Give the client some time to receive the response
+            Timer::after(Duration::from_secs(1)).await;
+            true
+        }
+        Either4::Third(never) => match never {},
+        Either4::Fourth(()) => {
+            log::info!("Provisioning portal timed out");
+            false
+        }
+    };
+    if saved && guided_setup {
+        // SAFETY: Only accessed from the main thread, without creating a reference
+        unsafe { core::ptr::addr_of_mut!(SETUP_IN_PROGRESS).write_volatile(SETUP_MARKER) };
+        SetupStep::ConnectWifi.show(tubes);
+    }
+    reboot();
+}
+
+/// Task: Run network stack of the portal
+#[embassy_executor::task]
+async fn portal_net_task(stack: &'static Stack<EspApDevice<'static>>) {
+    stack.run().await
+}
+
+/// Flash the LED twice per second.
+async fn indicate(mut led: Output<'static>) -> core::convert::Infallible {
+    loop {
+        for delay in [100, 100, 100, 700] {
+            led.toggle();
+            Timer::after(Duration::from_millis(delay)).await;
+        }
+    }
+}
+
+fn reboot() -> ! {
+    esp_hal::reset::software_reset();
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Hand out addresses to the clients of the access point.
+///
+/// Every client (identified by its MAC address) gets a fixed address out of
+/// [`DHCP_LEASE_COUNT`] addresses after the address of the counter.
+async fn dhcp_server(stack: &Stack<EspApDevice<'static>>) {
+    const OP_REPLY: u8 = 2;
+    const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+    const OPTION_SUBNET_MASK: u8 = 1;
+    const OPTION_ROUTER: u8 = 3;
+    const OPTION_DNS_SERVER: u8 = 6;
+    const OPTION_LEASE_TIME: u8 = 51;
+    const OPTION_MESSAGE_TYPE: u8 = 53;
+    const OPTION_SERVER_ID: u8 = 54;
+    const OPTION_END: u8 = 255;
+    const DISCOVER: u8 = 1;
+    const OFFER: u8 = 2;
+    const REQUEST: u8 = 3;
+    const ACK: u8 = 5;
+
+    let mut socket = udp_socket(stack, mk_static!(UdpBuffers, UdpBuffers::new()));
+    socket.bind(67).expect("Failed to bind DHCP server socket");
+
+    let mut leases: [Option<[u8; 6]>; DHCP_LEASE_COUNT] = [None; DHCP_LEASE_COUNT];
+    let buf = mk_static!([u8; 576], [0; 576]);
+    loop {
+        let Ok((len, _)) = socket.recv_from(buf).await else {
+            continue;
+        };
+        let request = &buf[..len];
+        if len < 240 || request[0] != 1 || request[236..240] != MAGIC_COOKIE {
+            continue;
+        }
+        let reply_type = match find_dhcp_option(&request[240..], OPTION_MESSAGE_TYPE) {
+            Some([DISCOVER]) => OFFER,
+            Some([REQUEST]) => ACK,
+            _ => continue,
+        };
+
+        // Look up lease of the client, or assign a new one
+        let mac: [u8; 6] = request[28..34].try_into().unwrap();
+        let index = leases
+            .iter()
+            .position(|lease| *lease == Some(mac))
+            .or_else(|| leases.iter().position(Option::is_none))
+            .unwrap_or(usize::from(mac[5]) % DHCP_LEASE_COUNT);
+        leases[index] = Some(mac);
+        let mut client_address = PORTAL_ADDRESS;
+        client_address[3] += 1 + index as u8;
+
+        // Reply with the same transaction ID, flags and client hardware address
+        let mut reply = heapless::Vec::<u8, 300>::new();
+        let _ = reply.extend_from_slice(&[OP_REPLY, 1, 6, 0]);
+        let _ = reply.extend_from_slice(&request[4..12]);
+        let _ = reply.extend_from_slice(&[0; 4]);
+        let _ = reply.extend_from_slice(&client_address);
+        let _ = reply.extend_from_slice(&PORTAL_ADDRESS);
+        let _ = reply.extend_from_slice(&[0; 4]);
+        let _ = reply.extend_from_slice(&request[28..44]);
+        let _ = reply.resize(236, 0);
+        let _ = reply.extend_from_slice(&MAGIC_COOKIE);
+        let options: [(u8, &[u8]); 6] = [
+            (OPTION_MESSAGE_TYPE, &[reply_type]),
+            (OPTION_SERVER_ID, &PORTAL_ADDRESS),
+            (OPTION_LEASE_TIME, &DHCP_LEASE_TIME.to_be_bytes()),
+            (OPTION_SUBNET_MASK, &[255, 255, 255, 0]),
+            (OPTION_ROUTER, &PORTAL_ADDRESS),
+            (OPTION_DNS_SERVER, &PORTAL_ADDRESS),
+        ];
+        for (code, value) in options {
+            let _ = reply.extend_from_slice(&[code, value.len() as u8]);
+            let _ = reply.extend_from_slice(value);
+        }
+        let _ = reply.push(OPTION_END);
+
+        // The client has no address yet, so the reply is broadcast
+        let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), 68);
+        if let Err(e) = socket.send_to(&reply, broadcast).await {
+            log::warn!("Could not send DHCP reply: {:?}", e);
+        }
+    }
+}
+
+/// Create a UDP socket for one of the servers.
+fn udp_socket<'a>(
+    stack: &'a Stack<EspApDevice<'static>>,
+    buffers: &'a mut UdpBuffers,
+) -> UdpSocket<'a> {
+    UdpSocket::new(
+        stack,
+        &mut buffers.rx_meta,
+        &mut buffers.rx,
+        &mut buffers.tx_meta,
+        &mut buffers.tx,
+    )
+}
+
+/// Socket buffers of the servers. They are allocated statically, so that
+/// they don't take up space in the main task.
+struct UdpBuffers {
+    rx_meta: [PacketMetadata; 2],
+    rx: [u8; 1024],
+    tx_meta: [PacketMetadata; 2],
+    tx: [u8; 1024],
+}
+
+impl UdpBuffers {
+    const fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; 2],
+            rx: [0; 1024],
+            tx_meta: [PacketMetadata::EMPTY; 2],
+            tx: [0; 1024],
+        }
+    }
+}
+
+/// Find the value of a DHCP option.
+fn find_dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match options {
+            [255, ..] | [] => return None,
+            // Padding
+            [0, rest @ ..] => options = rest,
+            [option, len, rest @ ..] if rest.len() >= usize::from(*len) => {
+                let (value, rest) = rest.split_at(usize::from(*len));
+                if *option == code {
+                    return Some(value);
+                }
+                options = rest;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Answer all DNS queries for IPv4 addresses with the address of the
+/// counter, so that all requests end up at the portal.
+async fn dns_server(stack: &Stack<EspApDevice<'static>>) {
+    const TYPE_A: [u8; 2] = [0, 1];
+    const CLASS_IN: [u8; 2] = [0, 1];
+    const ANSWER_TTL: u32 = 60;
+
+    let mut socket = udp_socket(stack, mk_static!(UdpBuffers, UdpBuffers::new()));
+    socket.bind(53).expect("Failed to bind DNS server socket");
+
+    let buf = mk_static!([u8; 512], [0; 512]);
+    loop {
+        let Ok((len, remote)) = socket.recv_from(buf).await else {
+            continue;
+        };
+        let query = &buf[..len];
+        // Only handle standard queries with a single question
+        if len < 12 || query[2] & 0xF8 != 0 || query[4..6] != [0, 1] {
+            continue;
+        }
+        let mut name_end = 12;
+        while let Some(&label_len) = query.get(name_end) {
+            name_end += 1 + usize::from(label_len);
+            if label_len == 0 {
+                break;
+            }
+        }
+        let question_end = name_end + 4;
+        if len < question_end {
+            continue;
+        }
+        let question_type = &query[question_end - 4..question_end - 2];
+        let question_class = &query[question_end - 2..question_end];
+        let answer = question_type == TYPE_A && question_class == CLASS_IN;
+
+        // Echo the header and question, followed by the answer
+        let mut response = heapless::Vec::<u8, 512>::new();
+        let _ = response.extend_from_slice(&query[..2]);
+        // Response, authoritative, recursion desired was copied from the query
+        let _ = response.extend_from_slice(&[0x84 | (query[2] & 0x01), 0x00]);
+        let _ = response.extend_from_slice(&[0, 1, 0, u8::from(answer), 0, 0, 0, 0]);
+        let _ = response.extend_from_slice(&query[12..question_end]);
+        if answer {
+            // Name as pointer to the question
+            let _ = response.extend_from_slice(&[0xC0, 12]);
+            let _ = response.extend_from_slice(&TYPE_A);
+            let _ = response.extend_from_slice(&CLASS_IN);
+            let _ = response.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+            let _ = response.extend_from_slice(&[0, 4]);
+            let _ = response.extend_from_slice(&PORTAL_ADDRESS);
+        }
+        if let Err(e) = socket.send_to(&response, remote).await {
+            log::warn!("Could not send DNS response: {:?}", e);
+        }
+    }
+}
+
+/// Serve the settings form until valid settings were submitted and stored.
+async fn http_server(
+    stack: &Stack<EspApDevice<'static>>,
+    mut settings: Option<Settings>,
+    tubes: &mut impl CounterDisplay<TUBE_COUNT>,
+) {
+    let rx_buffer = mk_static!([u8; 1536], [0; 1536]);
+    let tx_buffer = mk_static!([u8; 1536], [0; 1536]);
+    let request_buffer = mk_static!([u8; 1024], [0; 1024]);
+    loop {
+        let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(80).await {
+            log::warn!("Could not accept HTTP connection: {:?}", e);
+            continue;
+        }
+        let result = handle_request(&mut socket, request_buffer, &mut settings, tubes).await;
+        socket.close();
+        let _ = socket.flush().await;
+        match result {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => log::warn!("Provisioning portal request failed: {}", e),
+        }
+    }
+}
+
+/// Handle a single HTTP request.
+///
+/// - `GET /`: Show the settings form
+/// - `POST /`: Store the submitted settings
+/// - `GET /tubes[?<pattern>]`: Show the tube test page, and the test pattern
+///   selected by the pressed button (see [`show_test_pattern`])
+/// - Anything else: Redirect to the form, to trigger the captive portal
+///
+/// Returns whether new settings were stored.
+async fn handle_request(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    settings: &mut Option<Settings>,
+    tubes: &mut impl CounterDisplay<TUBE_COUNT>,
+) -> anyhow::Result<bool> {
+    // Read request header
+    let mut len = 0;
+    let header_len = loop {
+        if let Some(i) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if len == buf.len() {
+            anyhow::bail!("Request header too long");
+        }
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => anyhow::bail!("Connection closed"),
+            Ok(n) => len += n,
+        }
+    };
+    let header = core::str::from_utf8(&buf[..header_len])?;
+    let mut lines = header.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    match (method, path) {
+        ("GET", "/") => {
+            write_form(socket, settings.as_ref(), None).await?;
+            Ok(false)
+        }
+        ("POST", "/") => {
+            // Read form data
+            let body_end = header_len + content_length;
+            if body_end > buf.len() {
+                anyhow::bail!("Request body too long");
+            }
+            while len < body_end {
+                match socket.read(&mut buf[len..body_end]).await {
+                    Ok(0) | Err(_) => anyhow::bail!("Connection closed"),
+                    Ok(n) => len += n,
+                }
+            }
+            let body = core::str::from_utf8(&buf[header_len..body_end])?;
+
+            let new_settings = match parse_form(body, settings.as_ref()) {
+                Ok(new_settings) => new_settings,
+                Err(message) => {
+                    write_form(socket, settings.as_ref(), Some(message)).await?;
+                    return Ok(false);
+                }
+            };
+            if new_settings.save().is_err() {
+                write_form(
+                    socket,
+                    Some(&new_settings),
+                    Some("Could not store settings"),
+                )
+                .await?;
+                *settings = Some(new_settings);
+                return Ok(false);
+            }
+            log::info!("Stored settings for WiFi \"{}\"", new_settings.wifi_ssid);
+            Changelog::new().record(Source::Portal, settings.as_ref(), &new_settings);
+            write_page(socket, "200 OK", SAVED_PAGE).await?;
+            Ok(true)
+        }
+        ("GET", path) if path == "/tubes" || path.starts_with("/tubes?") => {
+            if let Some((_, query)) = path.split_once('?') {
+                show_test_pattern(tubes, query).await;
+            }
+            write_page(socket, "200 OK", TUBES_PAGE).await?;
+            Ok(false)
+        }
+        _ => {
+            write_all(
+                socket,
+                b"HTTP/1.1 302 Found\r\n\
+                  Location: http://192.168.4.1/\r\n\
+                  Content-Length: 0\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Show the test pattern selected in the query of the tube test page.
+///
+/// - `left=<digit>`, `right=<digit>`: Show the digit on the leftmost or
+///   rightmost tube only
+/// - `both=<digit>`: Show the digit on all tubes
+/// - `cycle`: Show every digit on all tubes in turn, then turn them off
+/// - `off`: Turn off all tubes
+async fn show_test_pattern(tubes: &mut impl CounterDisplay<TUBE_COUNT>, query: &str) {
+    let (name, value) = query.split_once('=').unwrap_or((query, ""));
+    let digit = value.parse::<u8>().ok().filter(|digit| *digit <= 9);
+    let mut digits = [None; TUBE_COUNT];
+    match (name, digit) {
+        ("left", Some(digit)) => {
+            digits[0] = Some(digit);
+            tubes.show_digits(digits);
+        }
+        ("right", Some(digit)) => {
+            digits[TUBE_COUNT - 1] = Some(digit);
+            tubes.show_digits(digits);
+        }
+        ("both", Some(digit)) => tubes.show_digits([Some(digit); TUBE_COUNT]),
+        ("cycle", _) => tubes.selftest(Duration::from_millis(300)).await,
+        ("off", _) => tubes.off(),
+        _ => log::debug!("Unknown tube test pattern {}", query),
+    }
+}
+
+/// Parse the submitted form into settings, or return an error message. The
+/// settings not in the form are kept from the `previous` ones.
+fn parse_form(body: &str, previous: Option<&Settings>) -> Result<Settings, &'static str> {
+    let mut ssid = None;
+    let mut password = None;
+    #[cfg(not(feature = "coap"))]
+    let mut endpoint = None;
+    for (name, value) in body.split('&').filter_map(|field| field.split_once('=')) {
+        match name {
+            "ssid" => ssid = Some(decode_form_value(value).ok_or("SSID too long")?),
+            "password" => password = Some(decode_form_value(value).ok_or("Password too long")?),
+            #[cfg(not(feature = "coap"))]
+            "endpoint" => endpoint = Some(decode_form_value(value).ok_or("Endpoint URL too long")?),
+            _ => {}
+        }
+    }
+    let mut settings = previous.cloned().unwrap_or_default();
+    settings.wifi_ssid = ssid.unwrap_or_default();
+    settings.wifi_password = password.unwrap_or_default();
+    #[cfg(not(feature = "coap"))]
+    {
+        settings.sensor_endpoint = endpoint.unwrap_or_default();
+    }
+    validate(&settings)?;
+    Ok(settings)
+}
+
+/// Check submitted settings, returning an error message if they are invalid.
+pub fn validate(settings: &Settings) -> Result<(), &'static str> {
+    if settings.wifi_ssid.is_empty() {
+        return Err("SSID missing");
+    }
+    #[cfg(not(feature = "coap"))]
+    if settings.sensor_endpoint.is_empty() {
+        return Err("Endpoint URL missing");
+    }
+    settings.validate()
+}
+
+/// Decode a `application/x-www-form-urlencoded` value.
+fn decode_form_value<const N: usize>(value: &str) -> Option<heapless::String<N>> {
+    let mut decoded = heapless::Vec::<u8, N>::new();
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        };
+        decoded.push(byte).ok()?;
+    }
+    heapless::String::from_utf8(decoded).ok()
+}
+
+const FORM_PAGE_START: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>Nixie Counter Setup</title></head><body><h1>Nixie Counter Setup</h1>";
+
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>Nixie Counter Setup</title></head><body><h1>Nixie Counter Setup</h1>\
+<p>Settings stored. The counter restarts and connects to the WiFi network.</p>\
+</body></html>";
+
+const TUBES_PAGE: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>Nixie Counter Setup</title></head><body><h1>Tube Test</h1>\
+<p>Press a digit to show it. If a digit doesn't light up completely, please report the tube \
+and the digit.</p><form action=\"/tubes\">\
+<p>Left tube<br>\
+<button name=\"left\" value=\"0\">0</button><button name=\"left\" value=\"1\">1</button>\
+<button name=\"left\" value=\"2\">2</button><button name=\"left\" value=\"3\">3</button>\
+<button name=\"left\" value=\"4\">4</button><button name=\"left\" value=\"5\">5</button>\
+<button name=\"left\" value=\"6\">6</button><button name=\"left\" value=\"7\">7</button>\
+<button name=\"left\" value=\"8\">8</button><button name=\"left\" value=\"9\">9</button></p>\
+<p>Right tube<br>\
+<button name=\"right\" value=\"0\">0</button><button name=\"right\" value=\"1\">1</button>\
+<button name=\"right\" value=\"2\">2</button><button name=\"right\" value=\"3\">3</button>\
+<button name=\"right\" value=\"4\">4</button><button name=\"right\" value=\"5\">5</button>\
+<button name=\"right\" value=\"6\">6</button><button name=\"right\" value=\"7\">7</button>\
+<button name=\"right\" value=\"8\">8</button><button name=\"right\" value=\"9\">9</button></p>\
+<p>Both tubes<br>\
+<button name=\"both\" value=\"0\">0</button><button name=\"both\" value=\"1\">1</button>\
+<button name=\"both\" value=\"2\">2</button><button name=\"both\" value=\"3\">3</button>\
+<button name=\"both\" value=\"4\">4</button><button name=\"both\" value=\"5\">5</button>\
+<button name=\"both\" value=\"6\">6</button><button name=\"both\" value=\"7\">7</button>\
+<button name=\"both\" value=\"8\">8</button><button name=\"both\" value=\"9\">9</button></p>\
+<p><button name=\"cycle\">Cycle all digits</button> <button name=\"off\">Off</button></p>\
+</form><p><a href=\"/\">Back to the settings</a></p></body></html>";
+
+/// Send the settings form, pre-filled with `settings` (except for the
+/// password), and with an optional error message.
+async fn write_form(
+    socket: &mut TcpSocket<'_>,
+    settings: Option<&Settings>,
+    error: Option<&str>,
+) -> anyhow::Result<()> {
+    // The length of the escaped values is not known upfront
+    write_all(
+        socket,
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/html; charset=utf-8\r\n\
+          Connection: close\r\n\r\n",
+    )
+    .await?;
+    write_all(socket, FORM_PAGE_START.as_bytes()).await?;
+    if let Some(error) = error {
+        write_all(socket, b"<p><strong>").await?;
+        write_escaped(socket, error).await?;
+        write_all(socket, b"</strong></p>").await?;
+    }
+    write_all(
+        socket,
+        b"<form method=\"post\" action=\"/\"><p><label>WiFi SSID<br>\
+          <input name=\"ssid\" maxlength=\"32\" required value=\"",
+    )
+    .await?;
+    if let Some(settings) = settings {
+        write_escaped(socket, &settings.wifi_ssid).await?;
+    }
+    write_all(
+        socket,
+        b"\"></label></p><p><label>WiFi password<br>\
+          <input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>",
+    )
+    .await?;
+    #[cfg(not(feature = "coap"))]
+    {
+        write_all(
+            socket,
+            b"<p><label>Sensor endpoint URL<br><input name=\"endpoint\" maxlength=\"128\" \
+              required placeholder=\"http://example.com/sensors/people_now_present/\" value=\"",
+        )
+        .await?;
+        if let Some(settings) = settings {
+            write_escaped(socket, &settings.sensor_endpoint).await?;
+        }
+        write_all(socket, b"\"></label></p>").await?;
+    }
+    write_all(
+        socket,
+        b"<p><button>Save</button></p></form>\
+          <p><a href=\"/tubes\">Test the tubes</a></p></body></html>",
+    )
+    .await
+}
+
+/// Send a complete response with an HTML page.
+async fn write_page(socket: &mut TcpSocket<'_>, status: &str, page: &str) -> anyhow::Result<()> {
+    let mut header = heapless::String::<128>::new();
+    core::fmt::write(
+        &mut header,
+        format_args!(
+            "HTTP/1.1 {status}\r\n\
+             Content-Type: text/html; charset=utf-8\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            page.len()
+        ),
+    )?;
+    write_all(socket, header.as_bytes()).await?;
+    write_all(socket, page.as_bytes()).await
+}
+
+/// Send a string with the HTML special characters escaped.
+async fn write_escaped(socket: &mut TcpSocket<'_>, value: &str) -> anyhow::Result<()> {
+    let mut rest = value;
+    while let Some(i) = rest.find(['&', '<', '>', '"']) {
+        write_all(socket, rest[..i].as_bytes()).await?;
+        let entity: &[u8] = match rest.as_bytes()[i] {
+            b'&' => b"&amp;",
+            b'<' => b"&lt;",
+            b'>' => b"&gt;",
+            _ => b"&quot;",
+        };
+        write_all(socket, entity).await?;
+        rest = &rest[i + 1..];
+    }
+    write_all(socket, rest.as_bytes()).await
+}
+
+async fn write_all(socket: &mut TcpSocket<'_>, data: &[u8]) -> anyhow::Result<()> {
+    if socket.write_all(data).await.is_err() {
+        anyhow::bail!("Could not send HTTP response");
+    }
+    Ok(())
+}
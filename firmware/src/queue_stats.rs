@@ -0,0 +1,166 @@
+//! Diagnostics of the internal queues.
+//!
+//! The consumer of every queue records each received message in the
+//! [`QueueStats`] of the queue, which yields the exact high-water mark: The
+//! depth only grows between two receives. [`queue_stats_task`] additionally
+//! samples the current depth of the queues, warns if a queue stays full
+//! (e.g. because its consumer is stuck), and periodically logs a summary.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{
+        raw::{CriticalSectionRawMutex, NoopRawMutex},
+        Mutex,
+    },
+    channel::Channel,
+};
+use embassy_time::{Duration, Ticker};
+
+use crate::LedControlCommand;
+
+/// Interval between two samples of the queue depths
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of samples after which a summary is logged
+const SAMPLES_PER_REPORT: u16 = 60;
+
+/// Number of consecutive samples a queue must be full to be reported as
+/// stalled
+const STALLED_AFTER_SAMPLES: u8 = 3;
+
+/// Statistics of the LED command queue
+pub static LED_COMMANDS: QueueStats = QueueStats::new();
+
+/// Statistics of the syslog message queue
+#[cfg(feature = "syslog")]
+pub static SYSLOG: QueueStats = QueueStats::new();
+
+#[derive(Debug, Copy, Clone)]
+struct Counters {
+    /// Maximum depth since the last report
+    high_water: usize,
+    /// Number of messages dropped because the queue was full, since boot
+    dropped: u32,
+}
+
+/// Statistics of a queue, updated by its producers and consumer.
+pub struct QueueStats {
+    counters: Mutex<CriticalSectionRawMutex, Cell<Counters>>,
+}
+
+impl QueueStats {
+    const fn new() -> Self {
+        Self {
+            counters: Mutex::new(Cell::new(Counters {
+                high_water: 0,
+                dropped: 0,
+            })),
+        }
+    }
+
+    /// Record a received message, with the number of messages that remained
+    /// in the queue.
+    pub fn received(&self, remaining: usize) {
+        self.counters.lock(|counters| {
+            let mut value = counters.get();
+            value.high_water = value.high_water.max(remaining + 1);
+            counters.set(value);
+        });
+    }
+
+    /// Record a message that was dropped because the queue was full.
+    #[cfg(feature = "syslog")]
+    pub fn dropped(&self) {
+        self.counters.lock(|counters| {
+            let mut value = counters.get();
+            value.dropped = value.dropped.saturating_add(1);
+            counters.set(value);
+        });
+    }
+
+    /// Return the counters and reset the high-water mark.
+    fn take(&self) -> Counters {
+        self.counters.lock(|counters| {
+            let value = counters.get();
+            counters.set(Counters {
+                high_water: 0,
+                ..value
+            });
+            value
+        })
+    }
+}
+
+/// A monitored queue, as sampled.
+struct Sample {
+    name: &'static str,
+    depth: usize,
+    capacity: usize,
+    stats: &'static QueueStats,
+}
+
+#[cfg(not(feature = "syslog"))]
+const QUEUE_COUNT: usize = 1;
+#[cfg(feature = "syslog")]
+const QUEUE_COUNT: usize = 2;
+
+/// Task: Monitor the queues and log their statistics
+#[embassy_executor::task]
+pub async fn queue_stats_task(led_commands: &'static Channel<NoopRawMutex, LedControlCommand, 3>) {
+    log::info!("Start queue diagnostics task");
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    // Number of consecutive samples every queue was full
+    let mut full_samples = [0u8; QUEUE_COUNT];
+    let mut samples: u16 = 0;
+    loop {
+        ticker.next().await;
+        let queues: [Sample; QUEUE_COUNT] = [
+            Sample {
+                name: "LED commands",
+                depth: led_commands.len(),
+                capacity: led_commands.len() + led_commands.free_capacity(),
+                stats: &LED_COMMANDS,
+            },
+            #[cfg(feature = "syslog")]
+            Sample {
+                name: "syslog",
+                depth: crate::syslog::queue_len(),
+                capacity: crate::syslog::QUEUE_CAPACITY,
+                stats: &SYSLOG,
+            },
+        ];
+
+        for (queue, full) in queues.iter().zip(full_samples.iter_mut()) {
+            if queue.depth < queue.capacity {
+                *full = 0;
+                continue;
+            }
+            *full = full.saturating_add(1);
+            if *full == STALLED_AFTER_SAMPLES {
+                log::warn!(
+                    "Queue {} has been full for {} s, its consumer seems stuck",
+                    queue.name,
+                    SAMPLE_INTERVAL.as_secs() * u64::from(STALLED_AFTER_SAMPLES),
+                );
+            }
+        }
+
+        samples += 1;
+        if samples < SAMPLES_PER_REPORT {
+            continue;
+        }
+        samples = 0;
+        for queue in &queues {
+            let counters = queue.stats.take();
+            log::info!(
+                "Queue {}: {} of {} queued, at most {} since the last report, {} dropped",
+                queue.name,
+                queue.depth,
+                queue.capacity,
+                counters.high_water,
+                counters.dropped,
+            );
+        }
+    }
+}
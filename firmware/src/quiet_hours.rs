@@ -0,0 +1,77 @@
+//! Blanking of the tubes during quiet hours.
+//!
+//! The space is usually empty at night, so the tubes are turned off during
+//! the quiet hours configured in `QUIET_HOURS` (default `02:00-08:00`, local
+//! time) to save tube life. The count is still tracked and sent as usual.
+//! Pressing the toggle switch turns the tubes on again until the quiet hours
+//! end. Stored settings can override the quiet hours.
+
+use crate::clock::DailyPeriod;
+
+/// State of the quiet hours.
+pub struct QuietHours {
+    period: DailyPeriod,
+    /// Whether the tubes are currently blanked
+    blanked: bool,
+    /// Whether the tubes were turned on by a press during the current quiet
+    /// hours
+    woken: bool,
+}
+
+impl QuietHours {
+    /// Create a new instance with the stored quiet hours if any, otherwise
+    /// the configured ones.
+    pub fn new(configured: DailyPeriod, stored: Option<&str>) -> Self {
+        let period = stored.and_then(|stored| {
+            let period = DailyPeriod::parse(stored);
+            if period.is_none() {
+                log::warn!("Invalid stored quiet hours \"{}\", ignoring them", stored);
+            }
+            period
+        });
+        Self {
+            period: period.unwrap_or(configured),
+            blanked: false,
+            woken: false,
+        }
+    }
+
+    /// Check the current time. Returns whether the tubes must be blanked or
+    /// turned on again, if that changed.
+    ///
+    /// As long as the clock isn't synchronized, the tubes stay on.
+    pub fn update(&mut self) -> Option<bool> {
+        let quiet = self.period.is_now();
+        if !quiet {
+            self.woken = false;
+        }
+        let blanked = quiet && !self.woken;
+        if blanked == self.blanked {
+            return None;
+        }
+        self.blanked = blanked;
+        if blanked {
+            log::info!("Quiet hours started, blanking the tubes");
+        } else {
+            log::info!("Quiet hours ended");
+        }
+        Some(blanked)
+    }
+
+    /// Return whether the tubes are blanked for the quiet hours.
+    pub fn is_blanked(&self) -> bool {
+        self.blanked
+    }
+
+    /// Turn the tubes on again until the quiet hours end, e.g. after a press.
+    /// Returns whether they were blanked.
+    pub fn wake(&mut self) -> bool {
+        if !self.blanked {
+            return false;
+        }
+        log::info!("Woken up during quiet hours");
+        self.blanked = false;
+        self.woken = true;
+        true
+    }
+}
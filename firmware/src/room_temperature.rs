@@ -0,0 +1,123 @@
+//! Room temperature, alternately shown with the count.
+//!
+//! An LM75 compatible temperature sensor (e.g. LM75A or TMP75) is connected
+//! over I2C. [`room_temperature_task`] samples it periodically, and every
+//! [`ROTATION_INTERVAL`], the tubes show the latest reading in °C instead of
+//! the count for a while. With the `neon-dots` feature, the right dot is lit
+//! while the temperature is shown, so that it is not mistaken for a count.
+
+use core::cell::Cell;
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Ticker, Timer};
+use embedded_hal::i2c::I2c;
+
+use crate::{
+    display::{CounterDisplay, Overflow},
+    SharedI2c,
+};
+
+/// I2C address of the sensor, with all address pins low
+const SENSOR_ADDRESS: u8 = 0x48;
+
+/// Temperature register of the sensor
+const TEMPERATURE_REGISTER: u8 = 0x00;
+
+/// Interval between two samples
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time the count is shown before the temperature
+const ROTATION_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Time the temperature is shown
+const TEMPERATURE_DISPLAY_TIME: Duration = Duration::from_secs(3);
+
+/// The latest reading in °C, rounded
+static LATEST: Mutex<CriticalSectionRawMutex, Cell<Option<i16>>> = Mutex::new(Cell::new(None));
+
+/// An LM75 compatible temperature sensor.
+struct Lm75<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Lm75<I2C> {
+    /// Read the temperature in half degrees Celsius.
+    fn read_half_degrees(&mut self) -> Result<i16, I2C::Error> {
+        let mut buf = [0; 2];
+        self.i2c
+            .write_read(SENSOR_ADDRESS, &[TEMPERATURE_REGISTER], &mut buf)?;
+        // 9 bit two's complement, left-aligned
+        Ok(i16::from_be_bytes(buf) >> 7)
+    }
+}
+
+/// Task: Sample the room temperature
+#[embassy_executor::task]
+pub async fn room_temperature_task(i2c: SharedI2c) {
+    log::info!("Start room temperature task");
+    let mut sensor = Lm75 { i2c };
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    loop {
+        let reading = match sensor.read_half_degrees() {
+            Ok(half_degrees) => {
+                log::debug!("Room temperature: {} °C", f32::from(half_degrees) / 2.0);
+                Some((half_degrees + 1).div_euclid(2))
+            }
+            Err(e) => {
+                log::warn!("Could not read the room temperature: {:?}", e);
+                None
+            }
+        };
+        LATEST.lock(|latest| latest.set(reading));
+        ticker.next().await;
+    }
+}
+
+/// Keep showing the count (see [`show_overflowing`]), alternating with the
+/// room temperature. Temperatures that can't be shown (below 0 °C, or if the
+/// sensor can't be read) are skipped. Never returns, stop it by dropping the
+/// future.
+///
+/// [`show_overflowing`]: CounterDisplay::show_overflowing
+pub async fn show_alternating<D: CounterDisplay<N>, const N: usize>(
+    tubes: &mut D,
+    count: u32,
+    overflow: Overflow,
+    overflow_delay: Duration,
+) -> ! {
+    loop {
+        select(
+            tubes.show_overflowing(count, overflow, overflow_delay),
+            Timer::after(ROTATION_INTERVAL),
+        )
+        .await;
+        let temperature = LATEST.lock(Cell::get).and_then(|t| u32::try_from(t).ok());
+        if let Some(temperature) = temperature.filter(|_| !tubes.is_blanked()) {
+            let _indicator = TemperatureIndicator::new();
+            let temperature = temperature.min(D::MAX);
+            tubes
+                .show_each([temperature], TEMPERATURE_DISPLAY_TIME)
+                .await;
+        }
+    }
+}
+
+/// Lights the neon dot while the temperature is shown, and turns it off when
+/// dropped.
+struct TemperatureIndicator;
+
+impl TemperatureIndicator {
+    fn new() -> Self {
+        #[cfg(feature = "neon-dots")]
+        crate::neon_dots::set_temperature_shown(true);
+        Self
+    }
+}
+
+impl Drop for TemperatureIndicator {
+    fn drop(&mut self) {
+        #[cfg(feature = "neon-dots")]
+        crate::neon_dots::set_temperature_shown(false);
+    }
+}
@@ -0,0 +1,94 @@
+//! Monitoring of the WiFi signal strength.
+//!
+//! The RSSI of the access point is sampled periodically while connected. Every
+//! sample is logged at debug level, and a summary of the recent samples is
+//! logged at info level, so it also ends up on the syslog server.
+
+use embassy_time::{Duration, Ticker};
+use esp_wifi::wifi::WifiState;
+
+/// Interval between two RSSI samples
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of samples summarized in one report
+const SAMPLES_PER_REPORT: u16 = 30;
+
+/// Signal strength below which the connection is considered marginal, in dBm
+const WEAK_SIGNAL_THRESHOLD: i8 = -75;
+
+/// Statistics of the samples since the last report.
+struct RssiStats {
+    count: u16,
+    sum: i32,
+    min: i8,
+    max: i8,
+}
+
+impl RssiStats {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0,
+            min: i8::MAX,
+            max: i8::MIN,
+        }
+    }
+
+    fn record(&mut self, rssi: i8) {
+        self.count += 1;
+        self.sum += i32::from(rssi);
+        self.min = self.min.min(rssi);
+        self.max = self.max.max(rssi);
+    }
+
+    fn mean(&self) -> i32 {
+        self.sum / i32::from(self.count.max(1))
+    }
+}
+
+/// Return the RSSI of the access point the station is connected to, in dBm.
+fn current_rssi() -> Option<i8> {
+    if esp_wifi::wifi::wifi_state() != WifiState::StaConnected {
+        return None;
+    }
+    let mut rssi = 0;
+    // SAFETY: The WiFi driver is initialized, otherwise the station couldn't
+    // be connected. The function only writes to the passed integer.
+    let result = unsafe { esp_wifi_sys::include::esp_wifi_sta_get_rssi(&mut rssi) };
+    if result != 0 {
+        log::debug!("Could not read RSSI: error {result}");
+        return None;
+    }
+    i8::try_from(rssi).ok()
+}
+
+/// Task: Sample and report the WiFi signal strength
+#[embassy_executor::task]
+pub async fn rssi_task() {
+    log::info!("Start RSSI monitoring task");
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    let mut stats = RssiStats::new();
+    loop {
+        ticker.next().await;
+        let Some(rssi) = current_rssi() else {
+            continue;
+        };
+        log::debug!("WiFi RSSI: {rssi} dBm");
+        stats.record(rssi);
+
+        if stats.count >= SAMPLES_PER_REPORT {
+            let mean = stats.mean();
+            log::info!(
+                "WiFi RSSI over the last {} samples: mean {} dBm, min {} dBm, max {} dBm",
+                stats.count,
+                mean,
+                stats.min,
+                stats.max,
+            );
+            if mean < i32::from(WEAK_SIGNAL_THRESHOLD) {
+                log::warn!("WiFi signal is weak, the connection may be unreliable");
+            }
+            stats = RssiStats::new();
+        }
+    }
+}
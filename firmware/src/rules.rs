@@ -0,0 +1,194 @@
+//! Automations configured as rules, instead of a firmware feature each.
+//!
+//! The rules are a JSON array in `RULES`, checked by `build.rs`. Every rule
+//! has a condition on the count, optionally how long it must hold and the
+//! time of day it applies, and an action:
+//!
+//! ```json
+//! [
+//!   {"name": "full", "when": "count > 10", "for": 300, "then": "webhook"},
+//!   {"when": "count == 0", "after": "22:00", "then": "blank"}
+//! ]
+//! ```
+//!
+//! - `when`: `count` compared with `<`, `<=`, `==`, `!=`, `>=` or `>` to a
+//!   number
+//! - `for`: Seconds the condition must hold before the rule applies (default
+//!   0). The rules are checked whenever the count changes, and at least
+//!   every minute.
+//! - `during` (`HH:MM-HH:MM`) or `after` (`HH:MM`, until midnight): The local
+//!   time in which the rule applies. Until the clock is synchronized, such
+//!   rules don't apply.
+//! - `then`: `webhook` notifies the webhook (with the `webhook` feature)
+//!   once the rule applies, `blank` blanks the tubes while it applies. A
+//!   press turns them on again, until the rule no longer applies.
+//! - `name`: Shown in the log and the notification (default `rule 1` etc.)
+
+#[cfg(feature = "webhook")]
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Receiver};
+use embassy_time::{Duration, Instant};
+
+use crate::clock::DailyPeriod;
+
+/// Maximum number of rules, `build.rs` checks `RULES` against it
+pub const MAX_RULES: usize = 8;
+
+/// Length of the queue of webhook notifications, enough for every rule
+#[cfg(feature = "webhook")]
+pub const NOTIFICATION_QUEUE_LEN: usize = MAX_RULES;
+
+/// A rule with the `webhook` action started to apply at the count.
+#[cfg(feature = "webhook")]
+#[derive(Debug, Copy, Clone)]
+pub struct Notification {
+    pub rule: &'static Rule,
+    pub count: u8,
+}
+
+#[cfg(feature = "webhook")]
+pub type NotificationReceiver =
+    Receiver<'static, NoopRawMutex, Notification, NOTIFICATION_QUEUE_LEN>;
+
+/// How the count is compared to the value of a rule.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    /// Return whether the comparison of `count` with `value` holds.
+    fn holds(self, count: u8, value: u8) -> bool {
+        match self {
+            Self::Less => count < value,
+            Self::LessOrEqual => count <= value,
+            Self::Equal => count == value,
+            Self::NotEqual => count != value,
+            Self::GreaterOrEqual => count >= value,
+            Self::Greater => count > value,
+        }
+    }
+}
+
+/// What a rule does once it applies.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Notify the webhook, once
+    Webhook,
+    /// Blank the tubes, while the rule applies
+    Blank,
+}
+
+/// An automation rule, as checked by `build.rs`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub name: &'static str,
+    /// The condition on the count
+    pub comparison: Comparison,
+    pub value: u8,
+    /// How long the condition must hold
+    pub hold: Duration,
+    /// The time of day the rule applies in, if restricted
+    pub period: Option<DailyPeriod>,
+    pub action: Action,
+}
+
+impl Rule {
+    /// Return whether the condition holds for the count at the current time.
+    fn holds(&self, count: u8) -> bool {
+        self.comparison.holds(count, self.value)
+            && self.period.map_or(true, |period| period.is_now())
+    }
+}
+
+/// State of a rule.
+#[derive(Debug, Copy, Clone, Default)]
+struct RuleState {
+    /// Since when the condition holds, if it does
+    since: Option<Instant>,
+    /// Whether the rule applies, since the condition held long enough
+    applies: bool,
+}
+
+/// The rules, and which of them apply.
+pub struct Rules {
+    rules: &'static [Rule],
+    states: [RuleState; MAX_RULES],
+    /// Whether the tubes are blanked by a rule
+    blanked: bool,
+    /// Whether the tubes were turned on by a press while blanked, until no
+    /// blanking rule applies anymore
+    woken: bool,
+}
+
+impl Rules {
+    pub fn new(rules: &'static [Rule]) -> Self {
+        if !rules.is_empty() {
+            log::info!("{} automation rules configured", rules.len());
+        }
+        Self {
+            rules,
+            states: [RuleState::default(); MAX_RULES],
+            blanked: false,
+            woken: false,
+        }
+    }
+
+    /// Check the rules for the count at the current time. Calls `fired` for
+    /// every rule that notifies the webhook and just started to apply.
+    /// Returns whether the tubes must be blanked or turned on again, if that
+    /// changed.
+    pub fn update(&mut self, count: u8, mut fired: impl FnMut(&'static Rule)) -> Option<bool> {
+        let now = Instant::now();
+        let mut blank = false;
+        for (rule, state) in self.rules.iter().zip(&mut self.states) {
+            let since = match (rule.holds(count), state.since) {
+                (false, _) => None,
+                (true, None) => Some(now),
+                (true, since) => since,
+            };
+            let applies = since.is_some_and(|since| now - since >= rule.hold);
+            if applies && !state.applies {
+                log::info!("Rule \"{}\" applies at count {count}", rule.name);
+                if rule.action == Action::Webhook {
+                    fired(rule);
+                }
+            } else if !applies && state.applies {
+                log::info!("Rule \"{}\" no longer applies", rule.name);
+            }
+            *state = RuleState { since, applies };
+            blank |= applies && rule.action == Action::Blank;
+        }
+
+        if !blank {
+            self.woken = false;
+        }
+        let blanked = blank && !self.woken;
+        if blanked == self.blanked {
+            return None;
+        }
+        self.blanked = blanked;
+        Some(blanked)
+    }
+
+    /// Return whether the tubes are blanked by a rule.
+    pub fn is_blanked(&self) -> bool {
+        self.blanked
+    }
+
+    /// Turn the tubes on again until no blanking rule applies anymore, e.g.
+    /// after a press. Returns whether they were blanked.
+    pub fn wake(&mut self) -> bool {
+        if !self.blanked {
+            return false;
+        }
+        log::info!("Woken up while blanked by a rule");
+        self.blanked = false;
+        self.woken = true;
+        true
+    }
+}
@@ -0,0 +1,73 @@
+//! Screensaver, spreading the wear across all cathodes.
+//!
+//! While the count stays the same for a long time, the same cathodes glow
+//! all the time and wear out faster than the others. Once the count has been
+//! stable for [`IDLE_TIME`], the tubes drift through random digits for a
+//! moment every [`DRIFT_INTERVAL`], before the count is shown again. Any
+//! event, e.g. a press, ends a drift instantly.
+
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rng::Rng;
+
+use crate::display::CounterDisplay;
+
+/// Time the count must be stable before the first drift
+const IDLE_TIME: Duration = Duration::from_secs(15 * 60);
+
+/// Interval between two drifts while the count stays stable
+pub const DRIFT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Duration of a drift
+const DRIFT_DURATION: Duration = Duration::from_millis(1000);
+
+/// Time each set of random digits is shown during a drift
+const DRIFT_STEP_DELAY: Duration = Duration::from_millis(50);
+
+/// State of the screensaver.
+pub struct Screensaver {
+    rng: Rng,
+    /// The count last shown, and since when
+    count: u8,
+    stable_since: Instant,
+}
+
+impl Screensaver {
+    pub fn new(rng: Rng, count: u8) -> Self {
+        Self {
+            rng,
+            count,
+            stable_since: Instant::now(),
+        }
+    }
+
+    /// Return the time of the next drift, if the specified count is shown
+    /// from now on.
+    pub fn next_drift(&mut self, count: u8) -> Instant {
+        let now = Instant::now();
+        if count != self.count {
+            self.count = count;
+            self.stable_since = now;
+        }
+        let first = self.stable_since + IDLE_TIME;
+        if now <= first {
+            return first;
+        }
+        let intervals = (now - first).as_ticks().div_ceil(DRIFT_INTERVAL.as_ticks());
+        first + DRIFT_INTERVAL * intervals as u32
+    }
+
+    /// Drift through random digits. Afterwards, the tubes show the last
+    /// random digits until the count is shown again.
+    pub async fn drift<const N: usize>(&mut self, tubes: &mut impl CounterDisplay<N>) {
+        log::debug!("Screensaver drift");
+        let delay = tubes.frame_delay(DRIFT_STEP_DELAY);
+        let end = Instant::now() + DRIFT_DURATION;
+        while Instant::now() < end {
+            let random = self.rng.random();
+            tubes.show_digits(core::array::from_fn(|i| {
+                Some((random >> (8 * i)) as u8 % 10)
+            }));
+            Timer::after(delay).await;
+        }
+    }
+}
@@ -0,0 +1,287 @@
+#[cfg(feature = "config-store")]
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+#[cfg(feature = "config-store")]
+use esp_storage::FlashStorage;
+#[cfg(feature = "config-store")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "config-store")]
+use crate::storage::Sector;
+
+/// Settings specified at build time in `counter-config.toml`.
+///
+/// With the `config-store` feature, they are optional and only used as long as
+/// none are stored, so that the same image works on different networks and
+/// with different endpoints.
+mod build_env {
+    #[cfg(not(feature = "config-store"))]
+    pub const WIFI_SSID: Option<&str> = Some(crate::build_config::WIFI_SSID);
+    #[cfg(not(feature = "config-store"))]
+    pub const WIFI_PASS: Option<&str> = Some(crate::build_config::WIFI_PASS);
+    #[cfg(feature = "config-store")]
+    pub const WIFI_SSID: Option<&str> = crate::build_config::WIFI_SSID;
+    #[cfg(feature = "config-store")]
+    pub const WIFI_PASS: Option<&str> = crate::build_config::WIFI_PASS;
+    #[cfg(all(not(feature = "coap"), not(feature = "config-store")))]
+    pub const SPACEAPI_SENSOR_ENDPOINT: Option<&str> =
+        Some(crate::build_config::SPACEAPI_SENSOR_ENDPOINT);
+    #[cfg(all(not(feature = "coap"), feature = "config-store"))]
+    pub const SPACEAPI_SENSOR_ENDPOINT: Option<&str> =
+        crate::build_config::SPACEAPI_SENSOR_ENDPOINT;
+}
+
+/// Marker at the start of the settings sector, followed by the layout and
+/// the settings serialized with postcard.
+#[cfg(feature = "config-store")]
+const SETTINGS_MAGIC: u32 = 0x4E58_5332;
+
+/// Length of the magic and the layout
+#[cfg(feature = "config-store")]
+const HEADER_LEN: usize = 5;
+
+/// Size of the stored settings, a multiple of the flash word size.
+#[cfg(feature = "config-store")]
+const SETTINGS_SIZE: usize = 512;
+
+/// The fields of the stored settings, one bit for every feature adding some.
+/// postcard doesn't record the fields, so settings stored by a firmware with
+/// other features can't be read as a whole.
+#[cfg(feature = "config-store")]
+const LAYOUT: u8 = {
+    let mut layout = 0;
+    if cfg!(not(feature = "coap")) {
+        layout |= HAS_ENDPOINT;
+    }
+    if cfg!(feature = "dimming") {
+        layout |= 0x02;
+    }
+    if cfg!(feature = "quiet-hours") {
+        layout |= 0x04;
+    }
+    // Set with every feature, since earlier firmware didn't store them
+    layout |= HAS_STRIKE_DELAYS;
+    layout
+};
+
+/// Bit of the [`LAYOUT`] set if the sensor endpoint is stored
+#[cfg(feature = "config-store")]
+const HAS_ENDPOINT: u8 = 0x01;
+
+/// Bit of the [`LAYOUT`] set if the strike delays are stored
+#[cfg(feature = "config-store")]
+const HAS_STRIKE_DELAYS: u8 = 0x08;
+
+/// A daily period in the format `HH:MM-HH:MM`
+#[cfg(any(feature = "dimming", feature = "quiet-hours"))]
+pub type Period = heapless::String<11>;
+
+/// Runtime settings of the counter.
+///
+/// Besides the network settings, they can override the display settings
+/// specified at build time. Stored settings without them (stored by a
+/// firmware with other features) keep using the build time ones.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "config-store", derive(Serialize, Deserialize))]
+pub struct Settings {
+    pub wifi_ssid: heapless::String<32>,
+    pub wifi_password: heapless::String<64>,
+    /// URL of the SpaceAPI sensor endpoint
+    #[cfg(not(feature = "coap"))]
+    pub sensor_endpoint: heapless::String<128>,
+    /// Strike delay of the left and the right tube in milliseconds, instead
+    /// of `LEFT_TUBE_STRIKE_DELAY` and `RIGHT_TUBE_STRIKE_DELAY`
+    pub strike_delays: [Option<u16>; 2],
+    /// Brightness while dimmed in percent, instead of `DIMMED_BRIGHTNESS`
+    #[cfg(feature = "dimming")]
+    pub dimmed_brightness: Option<u8>,
+    /// Dimming period, instead of `DIMMING_HOURS`
+    #[cfg(feature = "dimming")]
+    pub dimming_hours: Option<Period>,
+    /// Quiet hours, instead of `QUIET_HOURS`
+    #[cfg(feature = "quiet-hours")]
+    pub quiet_hours: Option<Period>,
+}
+
+impl Settings {
+    /// Return the settings to use: The settings stored in flash if any,
+    /// otherwise the ones specified at build time. Stored settings without
+    /// WiFi credentials or sensor endpoint use the ones specified at build
+    /// time, if any.
+    ///
+    /// The settings may be incomplete, see [`is_complete`](Self::is_complete).
+    pub fn load() -> Self {
+        let build_settings = Self::from_build_env();
+        #[cfg(feature = "config-store")]
+        if let Some(mut settings) = Self::load_stored() {
+            log::info!("Using stored settings");
+            if settings.wifi_ssid.is_empty() {
+                settings.wifi_ssid = build_settings.wifi_ssid;
+                settings.wifi_password = build_settings.wifi_password;
+            }
+            #[cfg(not(feature = "coap"))]
+            if settings.sensor_endpoint.is_empty() {
+                settings.sensor_endpoint = build_settings.sensor_endpoint;
+            }
+            return settings;
+        }
+        build_settings
+    }
+
+    /// Return whether the settings are sufficient to connect, i.e. the WiFi
+    /// network and the sensor endpoint are set.
+    pub fn is_complete(&self) -> bool {
+        #[cfg(not(feature = "coap"))]
+        if self.sensor_endpoint.is_empty() {
+            return false;
+        }
+        !self.wifi_ssid.is_empty()
+    }
+
+    /// Return the settings specified at build time, empty if not specified.
+    fn from_build_env() -> Self {
+        Self {
+            wifi_ssid: build_env::WIFI_SSID
+                .unwrap_or_default()
+                .try_into()
+                .expect("Invalid WIFI_SSID"),
+            wifi_password: build_env::WIFI_PASS
+                .unwrap_or_default()
+                .try_into()
+                .expect("Invalid WIFI_PASS"),
+            #[cfg(not(feature = "coap"))]
+            sensor_endpoint: build_env::SPACEAPI_SENSOR_ENDPOINT
+                .unwrap_or_default()
+                .try_into()
+                .expect("Invalid SPACEAPI_SENSOR_ENDPOINT"),
+            strike_delays: [None; 2],
+            #[cfg(feature = "dimming")]
+            dimmed_brightness: None,
+            #[cfg(feature = "dimming")]
+            dimming_hours: None,
+            #[cfg(feature = "quiet-hours")]
+            quiet_hours: None,
+        }
+    }
+
+    /// Read the settings stored in flash, if any.
+    #[cfg(feature = "config-store")]
+    fn load_stored() -> Option<Self> {
+        let offset = Sector::Settings.offset()?;
+        let mut buf = [0; SETTINGS_SIZE];
+        if let Err(e) = FlashStorage::new().read(offset, &mut buf) {
+            log::error!("Could not read settings: {:?}", e);
+            return None;
+        }
+        let (header, data) = buf.split_at(HEADER_LEN);
+        if u32::from_le_bytes(header[..4].try_into().unwrap()) != SETTINGS_MAGIC {
+            return None;
+        }
+        let layout = header[4];
+        let settings = if layout == LAYOUT {
+            postcard::from_bytes(data)
+        } else {
+            log::warn!("Settings stored by a firmware with other features, only using the network settings");
+            Self::network_from_bytes(data, layout)
+        };
+        settings
+            .inspect_err(|e| log::error!("Could not read settings: {:?}", e))
+            .ok()
+    }
+
+    /// Deserialize only the network settings, which come first with every
+    /// layout.
+    #[cfg(feature = "config-store")]
+    fn network_from_bytes(data: &[u8], layout: u8) -> postcard::Result<Self> {
+        let mut settings = Self::default();
+        let data = {
+            let (wifi_ssid, data) = postcard::take_from_bytes(data)?;
+            let (wifi_password, data) = postcard::take_from_bytes(data)?;
+            settings.wifi_ssid = wifi_ssid;
+            settings.wifi_password = wifi_password;
+            data
+        };
+        #[cfg(not(feature = "coap"))]
+        if layout & HAS_ENDPOINT != 0 {
+            settings.sensor_endpoint = postcard::take_from_bytes(data)?.0;
+        }
+        #[cfg(feature = "coap")]
+        let _ = (data, layout);
+        Ok(settings)
+    }
+
+    /// Check the settings before storing them, returning an error message if
+    /// they are invalid. Empty fields are valid, the ones specified at build
+    /// time are used instead.
+    #[cfg(feature = "config-store")]
+    pub fn validate(&self) -> Result<(), &'static str> {
+        #[cfg(not(feature = "coap"))]
+        if !self.sensor_endpoint.is_empty()
+            && !crate::http::is_valid_endpoint(&self.sensor_endpoint)
+        {
+            return Err("Invalid endpoint URL, expected http://host[:port]/path");
+        }
+        let max_strike_delay = crate::config::MAX_STRIKE_DELAY.as_millis();
+        if self
+            .strike_delays
+            .iter()
+            .any(|delay| delay.is_some_and(|millis| u64::from(millis) > max_strike_delay))
+        {
+            return Err("Invalid strike delay, expected 0-100 ms");
+        }
+        #[cfg(feature = "dimming")]
+        if self.dimmed_brightness > Some(crate::dimming::FULL_BRIGHTNESS) {
+            return Err("Invalid dimmed brightness, expected 0-100 %");
+        }
+        #[cfg(feature = "dimming")]
+        if let Some(hours) = &self.dimming_hours {
+            crate::clock::DailyPeriod::parse(hours).ok_or("Invalid dimming hours")?;
+        }
+        #[cfg(feature = "quiet-hours")]
+        if let Some(hours) = &self.quiet_hours {
+            crate::clock::DailyPeriod::parse(hours).ok_or("Invalid quiet hours")?;
+        }
+        Ok(())
+    }
+
+    /// Store the settings in flash, replacing the previously stored ones.
+    /// Nothing is written if they didn't change, to spare the flash. Invalid
+    /// settings are rejected, see [`validate`](Self::validate).
+    #[cfg(feature = "config-store")]
+    #[cfg_attr(
+        not(any(feature = "provisioning", feature = "console")),
+        allow(dead_code)
+    )]
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Err(message) = self.validate() {
+            log::error!("Not storing invalid settings: {}", message);
+            anyhow::bail!(message);
+        }
+        let mut buf = [0xFF; SETTINGS_SIZE];
+        buf[..4].copy_from_slice(&SETTINGS_MAGIC.to_le_bytes());
+        buf[4] = LAYOUT;
+        if let Err(e) = postcard::to_slice(self, &mut buf[HEADER_LEN..]) {
+            log::error!("Could not serialize settings: {:?}", e);
+            anyhow::bail!("Could not store settings");
+        }
+
+        let Some(offset) = Sector::Settings.offset() else {
+            anyhow::bail!("Could not store settings");
+        };
+        let mut flash = FlashStorage::new();
+        let mut stored = [0; SETTINGS_SIZE];
+        if flash.read(offset, &mut stored).is_ok() && stored == buf {
+            log::info!("Settings unchanged");
+            return Ok(());
+        }
+        if let Err(e) = flash.erase(offset, offset + Sector::SIZE) {
+            log::error!("Could not erase settings: {:?}", e);
+            anyhow::bail!("Could not store settings");
+        }
+        if let Err(e) = flash.write(offset, &buf) {
+            log::error!("Could not write settings: {:?}", e);
+            anyhow::bail!("Could not store settings");
+        }
+        log::info!("Settings stored");
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Driving a TM1637 7-segment LED module instead of nixie tubes.
+//!
+//! Budget counters without a high voltage supply use a cheap four digit
+//! TM1637 module, connected through two GPIOs: CLK and DIO, bit-banged. The
+//! module is a [`CounterDisplay`] of its own, so that all animations work the
+//! same. The count is shown on the rightmost digits, one for every tube of
+//! the board, the others stay dark.
+
+use embassy_time::{block_for, Duration};
+use embedded_hal::digital::OutputPin;
+
+use crate::{
+    config::TUBE_COUNT,
+    display::{CounterDisplay, DisplayState},
+};
+
+/// Number of digits of the module
+pub const DIGIT_COUNT: usize = 4;
+
+/// Position of the leftmost digit showing the count (the one of the left
+/// tube), counted from the left
+const FIRST_DIGIT: usize = DIGIT_COUNT - TUBE_COUNT;
+
+/// Time between two edges on the bus, well below the maximum clock rate
+const EDGE_DELAY: Duration = Duration::from_micros(5);
+
+/// Data command: Write to the display registers, incrementing the address
+const DATA_COMMAND: u8 = 0x40;
+
+/// Address command, with the address of the first digit
+const ADDRESS_COMMAND: u8 = 0xC0;
+
+/// Display control command: The display is on with bit 3 set, bits 0-2 are
+/// the brightness
+const DISPLAY_COMMAND: u8 = 0x80;
+const DISPLAY_ON: u8 = 0x08;
+const MAX_BRIGHTNESS: u8 = 7;
+
+/// Segments of the digits 0-9, bit 0 being segment a and bit 6 segment g
+const SEGMENTS: [u8; 10] = [0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F];
+
+/// The TM1637 module.
+pub struct Tm1637<CLK, DIO> {
+    bus: Bus<CLK, DIO>,
+    state: DisplayState,
+}
+
+struct Bus<CLK, DIO> {
+    clk: CLK,
+    /// Open drain, the module pulls it low to acknowledge
+    dio: DIO,
+    /// Segments lit on every digit
+    segments: [u8; DIGIT_COUNT],
+    /// Brightness of every digit showing the count, in percent. The module
+    /// can only dim all digits at once, so the brightest one wins.
+    #[cfg(feature = "dimming")]
+    brightness: [u8; TUBE_COUNT],
+}
+
+impl<CLK: OutputPin, DIO: OutputPin> Tm1637<CLK, DIO> {
+    /// Create a new instance, and turn off all digits.
+    pub fn new(clk: CLK, dio: DIO) -> Self {
+        let mut bus = Bus {
+            clk,
+            dio,
+            segments: [0; DIGIT_COUNT],
+            #[cfg(feature = "dimming")]
+            brightness: [100; TUBE_COUNT],
+        };
+        bus.update();
+        Self {
+            bus,
+            state: DisplayState::new(),
+        }
+    }
+}
+
+impl<CLK: OutputPin, DIO: OutputPin> CounterDisplay<TUBE_COUNT> for Tm1637<CLK, DIO> {
+    fn state(&self) -> &DisplayState {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut DisplayState {
+        &mut self.state
+    }
+
+    fn show_digits(&mut self, digits: [Option<u8>; TUBE_COUNT]) {
+        let mut segments = self.bus.segments;
+        for (segments, digit) in segments[FIRST_DIGIT..].iter_mut().zip(digits) {
+            *segments = digit
+                .and_then(|digit| SEGMENTS.get(usize::from(digit)))
+                .copied()
+                .unwrap_or(0);
+        }
+        if segments != self.bus.segments {
+            self.bus.segments = segments;
+            self.bus.update();
+        }
+    }
+
+    #[cfg(feature = "dimming")]
+    fn apply_brightness(&mut self, selected: [bool; TUBE_COUNT], percent: u8) {
+        let mut brightness = self.bus.brightness;
+        for (brightness, _) in brightness
+            .iter_mut()
+            .zip(selected)
+            .filter(|(_, selected)| *selected)
+        {
+            *brightness = percent;
+        }
+        if brightness != self.bus.brightness {
+            self.bus.brightness = brightness;
+            self.bus.update();
+        }
+    }
+}
+
+impl<CLK: OutputPin, DIO: OutputPin> Bus<CLK, DIO> {
+    /// Write the segments of all digits, and turn the display on.
+    fn update(&mut self) {
+        self.start();
+        self.write(DATA_COMMAND);
+        self.stop();
+        self.start();
+        self.write(ADDRESS_COMMAND);
+        for segments in self.segments {
+            self.write(segments);
+        }
+        self.stop();
+        self.start();
+        self.write(self.display_control());
+        self.stop();
+    }
+
+    /// Return the display control command for the current brightness.
+    fn display_control(&self) -> u8 {
+        #[cfg(feature = "dimming")]
+        {
+            let percent = self.brightness.iter().copied().max().unwrap_or(100);
+            if percent == 0 {
+                return DISPLAY_COMMAND;
+            }
+            let level = u16::from(percent.min(100)) * u16::from(MAX_BRIGHTNESS) / 100;
+            DISPLAY_COMMAND | DISPLAY_ON | level as u8
+        }
+        #[cfg(not(feature = "dimming"))]
+        {
+            DISPLAY_COMMAND | DISPLAY_ON | MAX_BRIGHTNESS
+        }
+    }
+
+    /// Start condition: DIO falls while CLK is high.
+    fn start(&mut self) {
+        let _ = self.clk.set_high();
+        let _ = self.dio.set_high();
+        block_for(EDGE_DELAY);
+        let _ = self.dio.set_low();
+        block_for(EDGE_DELAY);
+    }
+
+    /// Stop condition: DIO rises while CLK is high.
+    fn stop(&mut self) {
+        let _ = self.clk.set_low();
+        block_for(EDGE_DELAY);
+        let _ = self.dio.set_low();
+        block_for(EDGE_DELAY);
+        let _ = self.clk.set_high();
+        block_for(EDGE_DELAY);
+        let _ = self.dio.set_high();
+        block_for(EDGE_DELAY);
+    }
+
+    /// Write a byte, least significant bit first. The acknowledgement isn't
+    /// checked, there is nothing to do about a missing module anyway.
+    fn write(&mut self, byte: u8) {
+        for i in 0..8 {
+            let _ = self.clk.set_low();
+            if byte & (1 << i) != 0 {
+                let _ = self.dio.set_high();
+            } else {
+                let _ = self.dio.set_low();
+            }
+            block_for(EDGE_DELAY);
+            let _ = self.clk.set_high();
+            block_for(EDGE_DELAY);
+        }
+        // Release DIO for the acknowledgement
+        let _ = self.clk.set_low();
+        let _ = self.dio.set_high();
+        block_for(EDGE_DELAY);
+        let _ = self.clk.set_high();
+        block_for(EDGE_DELAY);
+        let _ = self.clk.set_low();
+    }
+}
+
+/// Turn off the display of a module on the specified pins, e.g. before a
+/// panic is reported. The segments are left as they are.
+pub fn turn_off<CLK: OutputPin, DIO: OutputPin>(clk: CLK, dio: DIO) {
+    let mut bus = Bus {
+        clk,
+        dio,
+        segments: [0; DIGIT_COUNT],
+        #[cfg(feature = "dimming")]
+        brightness: [0; TUBE_COUNT],
+    };
+    bus.start();
+    bus.write(DISPLAY_COMMAND);
+    bus.stop();
+}
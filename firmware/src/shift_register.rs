@@ -0,0 +1,118 @@
+//! Driving the tubes through daisy-chained 74HC595 shift registers.
+//!
+//! Instead of four GPIOs per tube, the K155ID1 inputs are connected to the
+//! outputs of 74HC595 shift registers, which are loaded over SPI. Every
+//! register drives two tubes: Q0-Q3 are the inputs A-D of the first tube,
+//! Q4-Q7 those of the second one. The register connected to the MCU drives
+//! the two leftmost tubes, the next one in the chain the following two, and
+//! so on. The outputs are updated with a pulse on the latch pin (RCLK).
+
+use core::cell::RefCell;
+
+use embassy_time::Duration;
+use embedded_hal::{digital::OutputPin, spi::SpiBus};
+
+use crate::{
+    config::TUBE_COUNT,
+    nixie::{SymbolMap, Tube},
+};
+
+/// Number of daisy-chained shift registers on the board, which the panic
+/// handler blanks
+pub const REGISTER_COUNT: usize = TUBE_COUNT.div_ceil(2);
+
+/// The chain of shift registers, shared by the `N` tubes.
+pub struct ShiftRegisters<SPI, LATCH, const N: usize> {
+    inner: RefCell<Chain<SPI, LATCH, N>>,
+}
+
+struct Chain<SPI, LATCH, const N: usize> {
+    spi: SPI,
+    latch: LATCH,
+    /// Cathode lit on every tube (0x0F for off)
+    cathodes: [u8; N],
+}
+
+impl<SPI: SpiBus, LATCH: OutputPin, const N: usize> ShiftRegisters<SPI, LATCH, N> {
+    /// Create a new instance, and turn off all tubes.
+    pub fn new(spi: SPI, latch: LATCH) -> Self {
+        let mut chain = Chain {
+            spi,
+            latch,
+            cathodes: [0x0F; N],
+        };
+        chain.update();
+        Self {
+            inner: RefCell::new(chain),
+        }
+    }
+
+    /// Light the cathode with the specified index on the tube at `index`.
+    fn show_cathode(&self, index: usize, cathode: u8) {
+        let mut chain = self.inner.borrow_mut();
+        if chain.cathodes[index] != cathode {
+            chain.cathodes[index] = cathode;
+            chain.update();
+        }
+    }
+}
+
+impl<SPI: SpiBus, LATCH: OutputPin, const N: usize> Chain<SPI, LATCH, N> {
+    /// Shift out the cathodes of all tubes and latch them.
+    fn update(&mut self) {
+        // The first byte ends up in the last register of the chain
+        for tubes in self.cathodes.chunks(2).rev() {
+            let register = tubes.iter().enumerate().fold(0, |register, (i, cathode)| {
+                register | (cathode & 0x0F) << (4 * i)
+            });
+            let _ = self.spi.write(&[register]);
+        }
+        let _ = self.spi.flush();
+        let _ = self.latch.set_high();
+        let _ = self.latch.set_low();
+    }
+}
+
+/// A tube connected to the shift registers.
+pub struct ShiftRegisterTube<SPI: 'static, LATCH: 'static, const N: usize> {
+    registers: &'static ShiftRegisters<SPI, LATCH, N>,
+    index: usize,
+    symbols: SymbolMap,
+    strike_delay: Duration,
+}
+
+impl<SPI, LATCH, const N: usize> ShiftRegisterTube<SPI, LATCH, N> {
+    /// Create the tube at the specified position in the chain (counted from
+    /// the left), with its symbol map and strike delay.
+    pub fn new(
+        registers: &'static ShiftRegisters<SPI, LATCH, N>,
+        index: usize,
+        symbols: SymbolMap,
+        strike_delay: Duration,
+    ) -> Self {
+        Self {
+            registers,
+            index,
+            symbols,
+            strike_delay,
+        }
+    }
+}
+
+impl<SPI: SpiBus, LATCH: OutputPin, const N: usize> Tube for ShiftRegisterTube<SPI, LATCH, N> {
+    fn show_cathode(&mut self, cathode: u8) {
+        self.registers.show_cathode(self.index, cathode);
+    }
+
+    fn symbols(&self) -> SymbolMap {
+        self.symbols
+    }
+
+    fn strike_delay(&self) -> Duration {
+        self.strike_delay
+    }
+
+    fn set_strike_delay(&mut self, delay: Duration) {
+        self.strike_delay = delay;
+    }
+}
@@ -0,0 +1,174 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::{config::TUBE_COUNT, display};
+
+/// Number of consecutive failed requests after which the endpoint is
+/// considered unreachable.
+const UNREACHABLE_AFTER_FAILURES: u8 = 2;
+
+/// Since when the server hasn't confirmed the count, while it doesn't.
+/// Updated with every request result, see [`sync_lag`].
+static UNCONFIRMED_SINCE: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Return the current sync lag, e.g. for the console.
+#[cfg(feature = "console")]
+pub fn sync_lag() -> Duration {
+    UNCONFIRMED_SINCE
+        .lock(Cell::get)
+        .map_or(Duration::from_ticks(0), |since| since.elapsed())
+}
+
+/// Connection state of the WiFi station.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WifiStatus {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// Stage of the startup, shown on the tubes as code (e.g. `01`) until the
+/// first count update, so that it's visible why the counter doesn't react
+/// yet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StartupStage {
+    /// Connecting to the WiFi network
+    ConnectWifi = 1,
+    /// Connected, waiting for an IP address over DHCP
+    Dhcp = 2,
+    /// Sending the first count update
+    FirstUpdate = 3,
+}
+
+impl StartupStage {
+    /// Return the digits shown on the tubes for the stage.
+    pub fn digits(self) -> [Option<u8>; TUBE_COUNT] {
+        display::digits(self as u32).map(Some)
+    }
+}
+
+/// Pattern shown on the WiFi LED.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LedPattern {
+    Off,
+    On,
+    Blink { delay: Duration },
+}
+
+impl LedPattern {
+    /// Determine the pattern for the specified connection status.
+    ///
+    /// - Off: WiFi disconnected
+    /// - Fast blinking: Connecting to WiFi
+    /// - Slow blinking: WiFi connected, but the endpoint is not reachable
+    /// - On: WiFi connected and endpoint reachable
+    /// - Very fast blinking: The server didn't confirm the count for longer
+    ///   than the sync lag threshold, regardless of the connection status
+    pub fn for_status(wifi: WifiStatus, endpoint_reachable: bool, sync_lagging: bool) -> Self {
+        if sync_lagging {
+            return Self::Blink {
+                delay: Duration::from_millis(100),
+            };
+        }
+        match wifi {
+            WifiStatus::Disconnected => Self::Off,
+            WifiStatus::Connecting => Self::Blink {
+                delay: Duration::from_millis(250),
+            },
+            WifiStatus::Connected if endpoint_reachable => Self::On,
+            WifiStatus::Connected => Self::Blink {
+                delay: Duration::from_millis(1000),
+            },
+        }
+    }
+}
+
+/// Tracks whether the endpoint is reachable, based on the results of the
+/// recent requests.
+pub struct EndpointHealth {
+    consecutive_failures: u8,
+}
+
+impl EndpointHealth {
+    /// Create a new instance. The endpoint is assumed to be reachable.
+    pub fn new() -> Self {
+        Self {
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Return whether the endpoint is currently considered reachable.
+    pub fn is_reachable(&self) -> bool {
+        self.consecutive_failures < UNREACHABLE_AFTER_FAILURES
+    }
+
+    /// Record the result of a request.
+    ///
+    /// Returns the new reachability if it changed.
+    pub fn record(&mut self, success: bool) -> Option<bool> {
+        let was_reachable = self.is_reachable();
+        if success {
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        }
+        let reachable = self.is_reachable();
+        (reachable != was_reachable).then_some(reachable)
+    }
+}
+
+/// Tracks the sync lag: How long the server hasn't confirmed the count,
+/// counted from the last successful request (or the start) while requests
+/// are failing.
+pub struct SyncLag {
+    /// Time of the last successful request
+    confirmed_at: Instant,
+    /// Whether the requests since then failed
+    failing: bool,
+    /// Sync lag after which the LED pattern is escalated
+    threshold: Duration,
+}
+
+impl SyncLag {
+    /// Create a new instance. The count is assumed to be confirmed now.
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            confirmed_at: Instant::now(),
+            failing: false,
+            threshold,
+        }
+    }
+
+    /// Return the current sync lag.
+    pub fn lag(&self) -> Duration {
+        if self.failing {
+            self.confirmed_at.elapsed()
+        } else {
+            Duration::from_ticks(0)
+        }
+    }
+
+    /// Return whether the sync lag exceeds the threshold.
+    pub fn is_lagging(&self) -> bool {
+        self.lag() > self.threshold
+    }
+
+    /// Record the result of a request, and publish the lag for
+    /// [`sync_lag`].
+    ///
+    /// Returns whether the lag exceeds the threshold, if that changed.
+    pub fn record(&mut self, success: bool) -> Option<bool> {
+        let was_lagging = self.is_lagging();
+        if success {
+            self.confirmed_at = Instant::now();
+        }
+        self.failing = !success;
+        let unconfirmed_since = self.failing.then_some(self.confirmed_at);
+        UNCONFIRMED_SINCE.lock(|cell| cell.set(unconfirmed_since));
+        let lagging = self.is_lagging();
+        (lagging != was_lagging).then_some(lagging)
+    }
+}
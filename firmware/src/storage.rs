@@ -0,0 +1,118 @@
+//! Layout of the `nvs` partition.
+//!
+//! Every kind of data stored by the firmware has a flash sector of its own in
+//! the partition, see [`Sector`]. All but the settings are
+//! [`FlashLog`](crate::flash_log::FlashLog)s.
+//!
+//! The partition is not in the NVS format of ESP-IDF. The firmware doesn't
+//! link ESP-IDF, and each kind of data is at most a few hundred bytes, so a
+//! fixed format in a sector of its own is simpler than a key-value store and
+//! keeps the flash wear of the logs low. The partition is found through the
+//! partition table, so it can be moved in a custom partition table, but it
+//! must not be shared with anything expecting NVS data.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embedded_storage::nor_flash::ReadNorFlash;
+use esp_storage::FlashStorage;
+
+/// Flash offset of the partition table
+const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+
+/// Size of the partition table, including the checksum entry
+const PARTITION_TABLE_SIZE: u32 = 0xC00;
+
+/// Length of an entry of the partition table
+const ENTRY_LEN: usize = 32;
+
+/// Magic bytes at the start of every partition entry. The checksum entry and
+/// the erased flash after the last entry start differently.
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+
+/// Type, subtype and label of the `nvs` partition
+const TYPE_DATA: u8 = 0x01;
+const SUBTYPE_NVS: u8 = 0x02;
+const NVS_LABEL: &[u8] = b"nvs";
+
+/// Number of sectors in the layout. The partition must have at least as many.
+const SECTOR_COUNT: u32 = 5;
+
+/// Flash offset of the `nvs` partition if there is a usable one, once it was
+/// looked up
+static NVS_OFFSET: Mutex<CriticalSectionRawMutex, Cell<Option<Option<u32>>>> =
+    Mutex::new(Cell::new(None));
+
+/// A sector of the `nvs` partition.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sector {
+    /// The pending update journal
+    #[cfg(feature = "journal")]
+    Journal = 0,
+    /// The settings stored at runtime
+    #[cfg(feature = "config-store")]
+    Settings = 1,
+    /// The changelog of the settings
+    #[cfg(feature = "provisioning")]
+    Changelog = 2,
+    /// The child lock
+    #[cfg(feature = "lock-mode")]
+    Lock = 3,
+    /// The count before a reboot
+    #[cfg(feature = "persist-count")]
+    Count = 4,
+}
+
+impl Sector {
+    pub const SIZE: u32 = FlashStorage::SECTOR_SIZE;
+
+    /// Return the flash offset of the sector, or `None` if there is no usable
+    /// `nvs` partition (which is logged once).
+    pub fn offset(self) -> Option<u32> {
+        let nvs_offset = match NVS_OFFSET.lock(Cell::get) {
+            Some(nvs_offset) => nvs_offset,
+            None => {
+                let nvs_offset = find_nvs_partition();
+                NVS_OFFSET.lock(|cell| cell.set(Some(nvs_offset)));
+                nvs_offset
+            }
+        };
+        Some(nvs_offset? + self as u32 * Self::SIZE)
+    }
+}
+
+/// Look up the offset of the `nvs` partition in the partition table.
+fn find_nvs_partition() -> Option<u32> {
+    let mut flash = FlashStorage::new();
+    for position in (0..PARTITION_TABLE_SIZE).step_by(ENTRY_LEN) {
+        let mut entry = [0; ENTRY_LEN];
+        if let Err(e) = flash.read(PARTITION_TABLE_OFFSET + position, &mut entry) {
+            log::error!("Could not read the partition table: {:?}", e);
+            return None;
+        }
+        if entry[..2] != ENTRY_MAGIC {
+            break;
+        }
+        // The label is padded with zeroes
+        let label = &entry[12..28];
+        let label = &label[..label.iter().position(|&b| b == 0).unwrap_or(label.len())];
+        if entry[2] != TYPE_DATA || entry[3] != SUBTYPE_NVS || label != NVS_LABEL {
+            continue;
+        }
+        let offset = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+        let size = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+        if size < SECTOR_COUNT * Sector::SIZE {
+            log::error!(
+                "The nvs partition is too small ({} KiB instead of at least {} KiB), \
+                 nothing is stored",
+                size / 1024,
+                SECTOR_COUNT * Sector::SIZE / 1024,
+            );
+            return None;
+        }
+        log::debug!("Found the nvs partition at {:#x}", offset);
+        return Some(offset);
+    }
+    log::error!("No nvs partition in the partition table, nothing is stored");
+    None
+}
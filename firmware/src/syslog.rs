@@ -0,0 +1,155 @@
+//! Mirror log output to a remote syslog server over UDP.
+//!
+//! All log records are printed to the serial console like before. Records
+//! with level [`SYSLOG_MAX_LEVEL`] or more severe are additionally queued and
+//! sent to the server by [`syslog_task`] as RFC 5424 messages.
+
+use core::fmt::Write;
+
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use esp_println::println;
+
+use crate::EspWifiDevice;
+
+/// Least severe level that is sent to the syslog server.
+const SYSLOG_MAX_LEVEL: log::Level = log::Level::Info;
+
+/// Host name and app name used in the syslog messages.
+const SYSLOG_HOSTNAME: &str = "nixie-counter";
+const SYSLOG_APP_NAME: &str = "firmware";
+
+/// Syslog facility "user-level messages".
+const FACILITY_USER: u8 = 1;
+
+type Message = heapless::String<256>;
+
+/// Number of messages that can be queued
+pub const QUEUE_CAPACITY: usize = 8;
+
+/// Queue of formatted messages waiting to be sent. When it's full (e.g.
+/// because the network is down), further messages are dropped.
+static QUEUE: Channel<CriticalSectionRawMutex, Message, QUEUE_CAPACITY> = Channel::new();
+
+static LOGGER: SyslogLogger = SyslogLogger;
+
+/// Initialize the logger with the given maximum log level.
+pub fn init_logger(level: log::LevelFilter) {
+    unsafe {
+        log::set_logger_racy(&LOGGER).unwrap();
+        log::set_max_level_racy(level);
+    }
+}
+
+struct SyslogLogger;
+
+impl log::Log for SyslogLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Serial console, formatted like the esp-println logger
+        let color = match record.level() {
+            log::Level::Error => "\u{001B}[31m",
+            log::Level::Warn => "\u{001B}[33m",
+            log::Level::Info => "\u{001B}[32m",
+            log::Level::Debug => "\u{001B}[34m",
+            log::Level::Trace => "\u{001B}[35m",
+        };
+        println!("{}{} - {}\u{001B}[0m", color, record.level(), record.args());
+
+        // Syslog
+        if record.level() <= SYSLOG_MAX_LEVEL {
+            let severity = match record.level() {
+                log::Level::Error => 3,
+                log::Level::Warn => 4,
+                log::Level::Info => 6,
+                log::Level::Debug | log::Level::Trace => 7,
+            };
+            let mut message = Message::new();
+            // Overlong messages are truncated
+            let _ = write!(
+                message,
+                "<{}>1 - {SYSLOG_HOSTNAME} {SYSLOG_APP_NAME} - - - {}",
+                FACILITY_USER * 8 + severity,
+                record.args()
+            );
+            if QUEUE.try_send(message).is_err() {
+                #[cfg(feature = "queue-stats")]
+                crate::queue_stats::SYSLOG.dropped();
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Return the number of messages waiting to be sent.
+#[cfg(feature = "queue-stats")]
+pub fn queue_len() -> usize {
+    QUEUE.len()
+}
+
+/// Task: Send queued log messages to the syslog server.
+///
+/// Note: This task must not log itself, since that would feed back into the
+/// queue. Errors are printed to the serial console directly.
+#[embassy_executor::task]
+pub async fn syslog_task(
+    stack: &'static Stack<EspWifiDevice<'static>>,
+    server: (&'static str, u16),
+) {
+    let (host, port) = server;
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).expect("Failed to bind syslog UDP socket");
+
+    let mut endpoint: Option<IpEndpoint> = None;
+    loop {
+        let message = QUEUE.receive().await;
+        #[cfg(feature = "queue-stats")]
+        crate::queue_stats::SYSLOG.received(QUEUE.len());
+        stack.wait_config_up().await;
+
+        // Resolve the server address (again, if the previous lookup failed)
+        let remote = match endpoint {
+            Some(remote) => remote,
+            None => {
+                let address = if let Ok(address) = host.parse::<Ipv4Address>() {
+                    IpAddress::Ipv4(address)
+                } else {
+                    match stack.dns_query(host, DnsQueryType::A).await {
+                        Ok(addresses) if !addresses.is_empty() => addresses[0],
+                        _ => {
+                            println!("Syslog: Could not resolve {}", host);
+                            continue;
+                        }
+                    }
+                };
+                *endpoint.insert(IpEndpoint::new(address, port))
+            }
+        };
+
+        if let Err(e) = socket.send_to(message.as_bytes(), remote).await {
+            println!("Syslog: Could not send message: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Monitoring of the chip temperature.
+//!
+//! The internal temperature sensor of the ESP32-C3 is sampled periodically. If
+//! the chip gets hotter than [`OVERHEAT_THRESHOLD`], e.g. because the counter
+//! is mounted above a radiator, non-essential activity is throttled: The WiFi
+//! transmit power is reduced and the tubes don't flash anymore. Both are
+//! restored once the temperature dropped by [`OVERHEAT_HYSTERESIS`].
+//!
+//! esp-hal doesn't have a driver for the sensor yet, so it's accessed through
+//! the PAC. The reading is not calibrated and may be off by a few degrees.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::{Duration, Ticker, Timer};
+
+/// Interval between two temperature samples
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of samples after which the temperature is logged at info level
+const SAMPLES_PER_REPORT: u16 = 10;
+
+/// Chip temperature above which activity is throttled, in °C
+const OVERHEAT_THRESHOLD: f32 = 75.0;
+
+/// Amount the temperature must drop below the threshold until throttling
+/// ends, in °C
+const OVERHEAT_HYSTERESIS: f32 = 5.0;
+
+/// Maximum WiFi transmit power while throttled, in units of 0.25 dBm
+const THROTTLED_TX_POWER: i8 = 44;
+
+/// Set while the chip is overheated.
+static OVERHEATED: AtomicBool = AtomicBool::new(false);
+
+/// Return whether non-essential activity should be skipped because the chip
+/// is overheated.
+pub fn is_overheated() -> bool {
+    OVERHEATED.load(Ordering::Relaxed)
+}
+
+/// The internal temperature sensor.
+struct TemperatureSensor;
+
+impl TemperatureSensor {
+    /// Power up the sensor.
+    fn new() -> Self {
+        // SAFETY: Only the temperature sensor bits are modified, which aren't
+        // used by esp-hal or the WiFi driver.
+        let (system, saradc) = unsafe { (&*esp32c3::SYSTEM::PTR, &*esp32c3::APB_SARADC::PTR) };
+        system
+            .perip_clk_en1()
+            .modify(|_, w| w.tsens_clk_en().set_bit());
+        system
+            .perip_rst_en1()
+            .modify(|_, w| w.tsens_rst().set_bit());
+        system
+            .perip_rst_en1()
+            .modify(|_, w| w.tsens_rst().clear_bit());
+        // Clock the sensor from the crystal
+        saradc.tsens_ctrl2().modify(|_, w| w.clk_sel().set_bit());
+        saradc.tsens_ctrl().modify(|_, w| w.pu().set_bit());
+        Self
+    }
+
+    /// Read the temperature in °C.
+    fn read(&self) -> f32 {
+        // SAFETY: Reading the sensor output has no side effects.
+        let raw = unsafe { &*esp32c3::APB_SARADC::PTR }
+            .tsens_ctrl()
+            .read()
+            .out()
+            .bits();
+        // Conversion for the default measurement range (-10 °C to 80 °C), as
+        // done by ESP-IDF
+        0.4386 * f32::from(raw) - 20.52
+    }
+}
+
+/// Limit the WiFi transmit power to the specified value, in units of 0.25 dBm.
+fn set_max_tx_power(power: i8) {
+    // SAFETY: The function only changes a setting of the initialized driver.
+    let result = unsafe { esp_wifi_sys::include::esp_wifi_set_max_tx_power(power) };
+    if result != 0 {
+        log::warn!("Could not set WiFi transmit power: error {result}");
+    }
+}
+
+/// Return the current maximum WiFi transmit power, in units of 0.25 dBm.
+fn max_tx_power() -> Option<i8> {
+    let mut power = 0;
+    // SAFETY: The function only writes to the passed integer.
+    let result = unsafe { esp_wifi_sys::include::esp_wifi_get_max_tx_power(&mut power) };
+    (result == 0).then_some(power)
+}
+
+/// Task: Sample the chip temperature and throttle activity if it overheats
+#[embassy_executor::task]
+pub async fn temperature_task() {
+    log::info!("Start temperature monitoring task");
+    let sensor = TemperatureSensor::new();
+    // Give the sensor time to settle after powering up
+    Timer::after(Duration::from_millis(100)).await;
+
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    let mut samples = 0;
+    let mut normal_tx_power = None;
+    loop {
+        let temperature = sensor.read();
+        log::debug!("Chip temperature: {temperature:.1} °C");
+        samples += 1;
+        if samples >= SAMPLES_PER_REPORT {
+            log::info!("Chip temperature: {temperature:.1} °C");
+            samples = 0;
+        }
+
+        let overheated = is_overheated();
+        if !overheated && temperature > OVERHEAT_THRESHOLD {
+            log::warn!("Chip overheated ({temperature:.1} °C), throttling activity");
+            OVERHEATED.store(true, Ordering::Relaxed);
+            normal_tx_power = max_tx_power();
+            set_max_tx_power(THROTTLED_TX_POWER);
+        } else if overheated && temperature < OVERHEAT_THRESHOLD - OVERHEAT_HYSTERESIS {
+            log::info!("Chip cooled down ({temperature:.1} °C), no longer throttling");
+            OVERHEATED.store(false, Ordering::Relaxed);
+            if let Some(power) = normal_tx_power.take() {
+                set_max_tx_power(power);
+            }
+        }
+
+        ticker.next().await;
+    }
+}
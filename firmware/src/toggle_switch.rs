@@ -1,11 +1,19 @@
+use core::cell::Cell;
+#[cfg(feature = "edge-log")]
+use core::cell::RefCell;
+
 use embassy_futures::{
     join::join,
-    select::{select, Either},
-};
-use esp_hal::{
-    gpio::{Input, InputPin},
-    peripheral::Peripheral,
+    select::{select, select3, Either, Either3},
 };
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+
+/// Number of raw edges kept in the [`EdgeLog`]
+#[cfg(feature = "edge-log")]
+const EDGE_LOG_LEN: usize = 64;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Direction {
@@ -13,40 +21,482 @@ pub enum Direction {
     Down,
 }
 
-pub struct ToggleSwitch<'a, 'b> {
-    pin_up: Input<'a>,
-    pin_down: Input<'b>,
+/// An input on the toggle switch, see [`ToggleSwitch::wait_for_event`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SwitchEvent {
+    /// Pressed and released (or long presses aren't detected)
+    Press(Direction),
+    /// Held for the long press duration, and still held
+    LongPress(Direction),
+    /// Pressed again in the same direction within the double press window
+    /// after releasing, and still held
+    DoublePress(Direction),
+    /// Pressed in both directions at once, and still held
+    Chord,
+}
+
+/// How a held press ended, see [`ToggleSwitch::hold`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Hold {
+    Released,
+    /// Held until the deadline
+    Held,
+    /// The other direction was pressed as well
+    Chord,
+}
+
+/// Progress of the press classified by [`ToggleSwitch::wait_for_event`],
+/// kept if the future is dropped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pending {
+    /// Pressed, maybe held for a long press
+    Pressed(Direction),
+    /// Short press, waiting for the release
+    Short(Direction),
+    /// Released at the specified time, maybe pressed again
+    Released(Direction, Instant),
+}
+
+/// Timing of the toggle switch input, see [`set_config`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ToggleSwitchConfig {
+    /// Time the contacts must stay at the same level after an edge for the
+    /// press or release to count. Bounces restart the window.
+    pub debounce_time: Duration,
+    /// Time the switch must be held for a long press, if they are detected
+    pub long_press_duration: Option<Duration>,
+    /// Time after the release in which a second press makes a double press,
+    /// if they are detected
+    pub double_press_window: Option<Duration>,
+    /// How held presses are repeated, if they are
+    pub auto_repeat: Option<AutoRepeat>,
+    /// Whether pressing both directions at once is detected. This needs two
+    /// push buttons instead of a toggle switch.
+    pub detect_chords: bool,
+}
+
+impl ToggleSwitchConfig {
+    /// Debouncing for the original switch, without long or double presses
+    /// or auto-repeat
+    pub const DEFAULT: Self = Self {
+        debounce_time: Duration::from_millis(30),
+        long_press_duration: None,
+        double_press_window: None,
+        auto_repeat: None,
+        detect_chords: false,
+    };
+}
+
+/// The configuration used by the toggle switch
+static CONFIG: Mutex<CriticalSectionRawMutex, Cell<ToggleSwitchConfig>> =
+    Mutex::new(Cell::new(ToggleSwitchConfig::DEFAULT));
+
+/// Return the timing of the toggle switch input.
+pub fn config() -> ToggleSwitchConfig {
+    CONFIG.lock(Cell::get)
 }
 
-impl<'a, 'b> ToggleSwitch<'a, 'b> {
-    /// Construct a new [`ToggleSwitch`] and enable internal pull-up resistors for both specified pins.
-    pub fn new(
-        pin_up: impl Peripheral<P = impl InputPin> + 'a,
-        pin_down: impl Peripheral<P = impl InputPin> + 'b,
-    ) -> Self {
+/// Change the timing of the toggle switch input. It can be changed at any
+/// time (e.g. from the console), a press that is being classified continues
+/// with the new timing.
+pub fn set_config(config: ToggleSwitchConfig) {
+    CONFIG.lock(|c| c.set(config));
+}
+
+/// Repeating a press while the switch is held, see
+/// [`ToggleSwitch::wait_for_repeat`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AutoRepeat {
+    /// Time from the press to the first repetition
+    pub delay: Duration,
+    /// Time between two repetitions
+    pub interval: Duration,
+}
+
+/// A raw edge on one of the toggle switch pins.
+#[cfg(feature = "edge-log")]
+#[derive(Debug, Copy, Clone)]
+struct Edge {
+    /// Time of the edge
+    at: Instant,
+    /// Pin on which the edge occurred
+    pin: Direction,
+    /// Level of the pin after the edge (`true` for high, i.e. released)
+    high: bool,
+}
+
+/// Ring buffer of the most recent raw edges, recorded while pressing the
+/// switch.
+#[cfg(feature = "edge-log")]
+#[derive(Clone)]
+struct EdgeLog {
+    edges: heapless::HistoryBuffer<Edge, EDGE_LOG_LEN>,
+}
+
+/// The edges recorded by the toggle switch, shared with the console, see
+/// [`log_edges`].
+#[cfg(feature = "edge-log")]
+static EDGE_LOG: Mutex<CriticalSectionRawMutex, RefCell<EdgeLog>> =
+    Mutex::new(RefCell::new(EdgeLog::new()));
+
+/// Record a raw edge in the [`EdgeLog`].
+#[cfg(feature = "edge-log")]
+fn record_edge(pin: Direction, high: bool) {
+    EDGE_LOG.lock(|log| log.borrow_mut().record(pin, high));
+}
+
+/// Return a copy of the [`EdgeLog`], so that it can be logged outside of
+/// the critical section.
+#[cfg(feature = "edge-log")]
+fn edge_log() -> EdgeLog {
+    EDGE_LOG.lock(|log| log.borrow().clone())
+}
+
+/// Log all recorded edges, oldest first, e.g. from the console. With
+/// `clear`, they are forgotten afterwards, so that the next dump only shows
+/// the presses since.
+#[cfg(all(feature = "edge-log", feature = "console"))]
+pub fn log_edges(clear: bool) {
+    let log = EDGE_LOG.lock(|log| {
+        let copy = log.borrow().clone();
+        if clear {
+            log.borrow_mut().edges.clear();
+        }
+        copy
+    });
+    let mut previous = None;
+    for edge in log.edges() {
+        let since_previous = previous.map_or(Duration::from_ticks(0), |at| edge.at - at);
+        log::info!(
+            "Edge at {} us (+{} us): {:?} {}",
+            edge.at.as_micros(),
+            since_previous.as_micros(),
+            edge.pin,
+            if edge.high { "high" } else { "low" },
+        );
+        previous = Some(edge.at);
+    }
+    log::info!(
+        "{} edges recorded{}",
+        log.edges.len(),
+        if clear { ", cleared" } else { "" }
+    );
+}
+
+#[cfg(feature = "edge-log")]
+impl EdgeLog {
+    const fn new() -> Self {
         Self {
-            pin_up: Input::new(pin_up, esp_hal::gpio::Pull::Up),
-            pin_down: Input::new(pin_down, esp_hal::gpio::Pull::Up),
+            edges: heapless::HistoryBuffer::new(),
+        }
+    }
+
+    fn record(&mut self, pin: Direction, high: bool) {
+        self.edges.write(Edge {
+            at: Instant::now(),
+            pin,
+            high,
+        });
+    }
+
+    /// Return the recorded edges, oldest first.
+    fn edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges.oldest_ordered()
+    }
+
+    /// Log the edges recorded since the start of the press at `start`.
+    fn log_press(&self, start: Instant) {
+        let mut count = 0;
+        let mut bounce_time = Duration::from_ticks(0);
+        for edge in self.edges().filter(|edge| edge.at >= start) {
+            let offset = edge.at - start;
+            log::debug!(
+                "Edge +{} us: {:?} {}",
+                offset.as_micros(),
+                edge.pin,
+                if edge.high { "high" } else { "low" },
+            );
+            count += 1;
+            bounce_time = offset;
+        }
+        log::info!(
+            "Press bounced {} times within {} us",
+            count,
+            bounce_time.as_micros()
+        );
+    }
+}
+
+/// A 2-position momentary toggle switch, which pulls one of two pins low
+/// while it is pressed up or down.
+///
+/// The pins can be any [`Wait`] implementation, e.g. an esp-hal
+/// [`Input`](esp_hal::gpio::Input) with the internal pull-up resistor
+/// enabled. Pins that can't be read count as released.
+pub struct ToggleSwitch<UP, DOWN> {
+    pin_up: UP,
+    pin_down: DOWN,
+    /// Time of the last press
+    pressed_at: Instant,
+    /// Press that wasn't classified yet
+    pending: Option<Pending>,
+    /// Direction and time of the next repetition while the switch is held
+    next_repeat: Option<(Direction, Instant)>,
+}
+
+impl<UP: InputPin + Wait, DOWN: InputPin + Wait> ToggleSwitch<UP, DOWN> {
+    /// Construct a new [`ToggleSwitch`] from the pins pulled low when pressed
+    /// up or down.
+    ///
+    /// The timing of the input, and whether long and double presses are
+    /// detected and held presses repeated, is taken from [`config`].
+    pub fn new(pin_up: UP, pin_down: DOWN) -> Self {
+        Self {
+            pin_up,
+            pin_down,
+            pressed_at: Instant::MIN,
+            pending: None,
+            next_repeat: None,
+        }
+    }
+
+    /// Wait for the next press, and tell whether it is a long or a double
+    /// press, or a chord.
+    ///
+    /// While long presses or chords are detected, a short press is only
+    /// returned once the switch is released (before the long press duration),
+    /// a long press as soon as it was held long enough, and a chord as soon as
+    /// the other direction is pressed too. While double presses are
+    /// detected, a short press is only returned once the double press window
+    /// after the release passed, and a double press as soon as the switch is
+    /// pressed again. Otherwise, every press is returned right away, like
+    /// with [`wait_for_press`](Self::wait_for_press).
+    ///
+    /// If the future is dropped after the press, the next call continues
+    /// with it, so that the press isn't lost.
+    pub async fn wait_for_event(&mut self) -> SwitchEvent {
+        loop {
+            let next = match self.pending {
+                None => Pending::Pressed(self.wait_for_press().await),
+                Some(Pending::Pressed(direction)) => {
+                    let config = config();
+                    if config.long_press_duration.is_some() || config.detect_chords {
+                        let deadline = config
+                            .long_press_duration
+                            .map(|duration| self.pressed_at + duration);
+                        let event = match self.hold(direction, deadline, config.detect_chords).await
+                        {
+                            Hold::Released => None,
+                            Hold::Held => Some(SwitchEvent::LongPress(direction)),
+                            Hold::Chord => Some(SwitchEvent::Chord),
+                        };
+                        if let Some(event) = event {
+                            self.pending = None;
+                            return event;
+                        }
+                    }
+                    if config.double_press_window.is_none() {
+                        self.pending = None;
+                        return SwitchEvent::Press(direction);
+                    }
+                    Pending::Short(direction)
+                }
+                Some(Pending::Short(direction)) => {
+                    self.wait_for_release().await;
+                    Pending::Released(direction, Instant::now())
+                }
+                Some(Pending::Released(direction, released_at)) => {
+                    let deadline = released_at + config().double_press_window.unwrap_or_default();
+                    let again = match select(self.wait_for_press(), Timer::at(deadline)).await {
+                        Either::First(again) => Some(again),
+                        Either::Second(()) => None,
+                    };
+                    // A press in the other direction is the next event
+                    self.pending = again
+                        .filter(|again| *again != direction)
+                        .map(Pending::Pressed);
+                    return if again == Some(direction) {
+                        SwitchEvent::DoublePress(direction)
+                    } else {
+                        SwitchEvent::Press(direction)
+                    };
+                }
+            };
+            self.pending = Some(next);
         }
     }
 
-    /// Wait until the toggle switch is pressed or down
+    /// Wait until the toggle switch is pressed up or down, and the contacts
+    /// settled.
+    ///
+    /// The press starts with the first falling edge, but only counts once
+    /// the pin stayed low for the debounce time since the last bounce. Short
+    /// glitches are ignored. With the `edge-log` feature, the raw edges until
+    /// then are recorded in the [`EdgeLog`] and logged.
     pub async fn wait_for_press(&mut self) -> Direction {
-        // Prepare futures
-        let up_pressed = self.pin_up.wait_for_low();
-        let down_pressed = self.pin_down.wait_for_low();
+        loop {
+            // Prepare futures
+            let up_pressed = self.pin_up.wait_for_low();
+            let down_pressed = self.pin_down.wait_for_low();
 
-        // Wait for up or down press
-        match select(up_pressed, down_pressed).await {
-            Either::First(_) => Direction::Up,
-            Either::Second(_) => Direction::Down,
+            // Wait for up or down press
+            let direction = match select(up_pressed, down_pressed).await {
+                Either::First(_) => Direction::Up,
+                Either::Second(_) => Direction::Down,
+            };
+            self.pressed_at = Instant::now();
+            #[cfg(feature = "edge-log")]
+            record_edge(direction, false);
+            self.settle().await;
+            #[cfg(feature = "edge-log")]
+            edge_log().log_press(self.pressed_at);
+            let pressed = match direction {
+                Direction::Up => is_low(&mut self.pin_up),
+                Direction::Down => is_low(&mut self.pin_down),
+            };
+            if pressed {
+                self.next_repeat = config()
+                    .auto_repeat
+                    .map(|auto_repeat| (direction, self.pressed_at + auto_repeat.delay));
+                return direction;
+            }
+            log::debug!("Ignoring glitch on the {:?} pin", direction);
         }
     }
 
-    /// Wait until the toggle switch is pressed or down
+    /// Wait until there was no edge on either pin for the debounce time.
+    async fn settle(&mut self) {
+        let debounce_time = config().debounce_time;
+        loop {
+            let edge = select(
+                self.pin_up.wait_for_any_edge(),
+                self.pin_down.wait_for_any_edge(),
+            );
+            match select(edge, Timer::after(debounce_time)).await {
+                Either::First(Either::First(_)) => {
+                    #[cfg(feature = "edge-log")]
+                    {
+                        let high = !is_low(&mut self.pin_up);
+                        record_edge(Direction::Up, high);
+                    }
+                }
+                Either::First(Either::Second(_)) => {
+                    #[cfg(feature = "edge-log")]
+                    {
+                        let high = !is_low(&mut self.pin_down);
+                        record_edge(Direction::Down, high);
+                    }
+                }
+                Either::Second(()) => break,
+            }
+        }
+    }
+
+    /// Wait until the switch pressed in `direction` is released, or is still
+    /// held at the deadline (if any), or (if `chords` are detected) the other
+    /// direction is pressed as well, and the contacts settled.
+    async fn hold(
+        &mut self,
+        direction: Direction,
+        deadline: Option<Instant>,
+        chords: bool,
+    ) -> Hold {
+        loop {
+            let edge = match direction {
+                Direction::Up => {
+                    hold_edge(&mut self.pin_up, &mut self.pin_down, deadline, chords).await
+                }
+                Direction::Down => {
+                    hold_edge(&mut self.pin_down, &mut self.pin_up, deadline, chords).await
+                }
+            };
+            match edge {
+                Either3::First(()) => {
+                    self.wait_for_release().await;
+                    return Hold::Released;
+                }
+                Either3::Second(()) => return Hold::Held,
+                Either3::Third(()) => {
+                    self.settle().await;
+                    if is_low(&mut self.pin_up) && is_low(&mut self.pin_down) {
+                        return Hold::Chord;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait until the toggle switch is released, and the contacts settled
+    /// (see [`wait_for_press`](Self::wait_for_press)), so that bounces on
+    /// release don't count as another press.
     pub async fn wait_for_release(&mut self) {
-        let up_released = self.pin_up.wait_for_high();
-        let down_released = self.pin_down.wait_for_high();
-        join(up_released, down_released).await;
+        loop {
+            let up_released = self.pin_up.wait_for_high();
+            let down_released = self.pin_down.wait_for_high();
+            let _ = join(up_released, down_released).await;
+            self.settle().await;
+            if !is_low(&mut self.pin_up) && !is_low(&mut self.pin_down) {
+                self.next_repeat = None;
+                return;
+            }
+        }
     }
+
+    /// Wait until the toggle switch is released (see
+    /// [`wait_for_release`](Self::wait_for_release)) and return `None`, or,
+    /// with auto-repeat, return the direction of the last press every time
+    /// it should be repeated while it is held.
+    ///
+    /// The first repetition follows the delay after the press, so presses
+    /// that were only returned after their release (by
+    /// [`wait_for_event`](Self::wait_for_event) while long or double presses
+    /// are detected) aren't repeated.
+    pub async fn wait_for_repeat(&mut self) -> Option<Direction> {
+        let Some((direction, repeat_at)) = self.next_repeat else {
+            self.wait_for_release().await;
+            return None;
+        };
+        match select(self.wait_for_release(), Timer::at(repeat_at)).await {
+            Either::First(()) => None,
+            Either::Second(()) => {
+                self.next_repeat = config()
+                    .auto_repeat
+                    .map(|auto_repeat| (direction, repeat_at + auto_repeat.interval));
+                Some(direction)
+            }
+        }
+    }
+}
+
+/// Wait until the `pressed` pin is released (first), the deadline passed
+/// (second) or, if `chords` are detected, the `other` pin is pressed too
+/// (third), without debouncing.
+async fn hold_edge(
+    pressed: &mut impl Wait,
+    other: &mut impl Wait,
+    deadline: Option<Instant>,
+    chords: bool,
+) -> Either3<(), (), ()> {
+    let released = async {
+        let _ = pressed.wait_for_high().await;
+    };
+    let held = async {
+        match deadline {
+            Some(deadline) => Timer::at(deadline).await,
+            None => core::future::pending().await,
+        }
+    };
+    let chord = async {
+        if chords {
+            let _ = other.wait_for_low().await;
+        } else {
+            core::future::pending::<()>().await;
+        }
+    };
+    select3(released, held, chord).await
+}
+
+/// Return whether a pin is low, i.e. pressed.
+fn is_low(pin: &mut impl InputPin) -> bool {
+    pin.is_low().unwrap_or(false)
 }
@@ -0,0 +1,84 @@
+//! Touch pads instead of the toggle switch.
+//!
+//! The ESP32-C3 has no touch sensor peripheral, so each pad is read through a
+//! TTP223 touch module, connected to the pins of the toggle switch. Its
+//! output is high while the pad is touched (the default configuration), so
+//! [`TouchPad`] inverts it, and the pads work like the switch contacts,
+//! including debouncing, long presses and chords.
+//!
+//! The TTP223 calibrates its threshold when it is powered up, together with
+//! the counter. A pad touched meanwhile ends up with a wrong threshold, which
+//! [`check_calibration`] reports at boot.
+
+use core::convert::Infallible;
+
+use embedded_hal::digital::{ErrorType, InputPin};
+use embedded_hal_async::digital::Wait;
+
+/// A touch pad, read through the output of a TTP223 module.
+pub struct TouchPad<P> {
+    pin: P,
+}
+
+impl<P: InputPin + Wait> TouchPad<P> {
+    /// Create a touch pad on the pin connected to the module output.
+    pub fn new(pin: P) -> Self {
+        Self { pin }
+    }
+}
+
+impl<P> ErrorType for TouchPad<P> {
+    type Error = Infallible;
+}
+
+/// The pin is low while touched, like a pressed switch contact. Pins that
+/// can't be read count as not touched.
+impl<P: InputPin> InputPin for TouchPad<P> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.pin.is_high().unwrap_or(false))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.pin.is_high().unwrap_or(false))
+    }
+}
+
+impl<P: InputPin + Wait> Wait for TouchPad<P> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        let _ = self.pin.wait_for_low().await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        let _ = self.pin.wait_for_high().await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        let _ = self.pin.wait_for_falling_edge().await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        let _ = self.pin.wait_for_rising_edge().await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        let _ = self.pin.wait_for_any_edge().await;
+        Ok(())
+    }
+}
+
+/// Warn if a pad is touched at boot, since its module then calibrated with
+/// the finger on it. Power cycling the counter without touching the pads
+/// helps. Not available on the I/O expander, which isn't read yet at boot.
+#[cfg(not(feature = "io-expander"))]
+pub fn check_calibration<P: InputPin>(pad: &mut TouchPad<P>, name: &str) {
+    if pad.is_low() == Ok(true) {
+        log::warn!(
+            "The {} touch pad is touched at boot, its threshold may be wrong",
+            name
+        );
+    }
+}
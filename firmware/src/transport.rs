@@ -0,0 +1,21 @@
+/// A way of sending the people now present count to the server.
+pub trait CountTransport {
+    /// Fetch the current count from the server, if supported.
+    #[cfg(feature = "fetch-count")]
+    async fn fetch_count(&mut self) -> anyhow::Result<Option<u8>> {
+        Ok(None)
+    }
+
+    /// Set the open state of the space on the server.
+    #[cfg(feature = "space-state")]
+    async fn send_state(&mut self, _open: bool) -> anyhow::Result<()> {
+        anyhow::bail!("Setting the space state is not supported by this transport")
+    }
+
+    /// Send the specified count to the server.
+    ///
+    /// Returns the authoritative count, if the server reported one in its
+    /// response. It may differ from the sent count, e.g. if other counters
+    /// update the same sensor.
+    async fn send_count(&mut self, people_count: u8) -> anyhow::Result<Option<u8>>;
+}
@@ -0,0 +1,192 @@
+//! Webhook notifications when the count crosses thresholds.
+//!
+//! Whenever the count reaches one of the thresholds configured in
+//! `WEBHOOK_THRESHOLDS`, or drops below it again, a JSON object is POSTed to
+//! `WEBHOOK_URL`:
+//!
+//! ```json
+//! {"text":"20 people present, reached 20","count":20,"threshold":20,"rising":true}
+//! ```
+//!
+//! The `text` field can be forwarded to a chat as is, e.g. through a Matrix
+//! bridge. Notifications are sent once, failed ones are only logged.
+//!
+//! With the `rules` feature, rules with the `webhook` action notify the
+//! webhook once they start to apply (see [`crate::rules`]):
+//!
+//! ```json
+//! {"text":"12 people present, full","count":12,"rule":"full"}
+//! ```
+
+use core::fmt::Write;
+
+#[cfg(feature = "rules")]
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    dns::DnsSocket,
+    tcp::client::{TcpClient, TcpClientState},
+    Stack,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embedded_nal_async::{AddrType, Dns, SocketAddr, TcpConnect};
+use reqwless::{
+    client::{HttpConnection, HttpResource},
+    request::{Method, RequestBuilder},
+};
+
+#[cfg(feature = "rules")]
+use crate::rules::NotificationReceiver;
+use crate::{config::Url, EspWifiDevice};
+
+/// Return whether the count crossed the threshold upwards (`true`) or
+/// downwards (`false`) when changing from `previous` to `current`.
+fn crossing(previous: u8, current: u8, threshold: u8) -> Option<bool> {
+    if previous < threshold && current >= threshold {
+        Some(true)
+    } else if previous >= threshold && current < threshold {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Format the notification.
+fn format_payload(
+    count: u8,
+    threshold: u8,
+    rising: bool,
+) -> Result<heapless::String<128>, core::fmt::Error> {
+    let mut payload = heapless::String::new();
+    let event = if rising { "reached" } else { "dropped below" };
+    write!(
+        payload,
+        "{{\"text\":\"{count} people present, {event} {threshold}\",\
+         \"count\":{count},\"threshold\":{threshold},\"rising\":{rising}}}"
+    )?;
+    Ok(payload)
+}
+
+/// Format the notification of a rule that started to apply. The name was
+/// checked by `build.rs`, it needs no escaping.
+#[cfg(feature = "rules")]
+fn format_rule_payload(count: u8, rule: &str) -> Result<heapless::String<128>, core::fmt::Error> {
+    let mut payload = heapless::String::new();
+    write!(
+        payload,
+        "{{\"text\":\"{count} people present, {rule}\",\"count\":{count},\"rule\":\"{rule}\"}}"
+    )?;
+    Ok(payload)
+}
+
+/// POST the payload to the webhook.
+async fn notify(
+    tcp_client: &TcpClient<'static, EspWifiDevice<'static>, 1>,
+    dns: &DnsSocket<'static, EspWifiDevice<'static>>,
+    rx_buf: &mut [u8],
+    url: Url,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let Url {
+        host, port, path, ..
+    } = url;
+    let address = match dns.get_host_by_name(host, AddrType::Either).await {
+        Ok(address) => address,
+        Err(e) => {
+            log::debug!("DNS lookup for {} failed: {:?}", host, e);
+            anyhow::bail!("DNS lookup failed");
+        }
+    };
+    let connection = match tcp_client.connect(SocketAddr::new(address, port)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            log::debug!("Could not connect to {}: {:?}", host, e);
+            anyhow::bail!("connection failed");
+        }
+    };
+    let mut resource = HttpResource {
+        conn: HttpConnection::Plain(connection),
+        host,
+        base_path: "",
+    };
+
+    log::info!("-> POST {}", url.url);
+    let response = match resource
+        .request(Method::POST, path)
+        .headers(&[("content-type", "application/json")])
+        .body(payload)
+        .send(rx_buf)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            log::debug!("HTTP request error: {:?}", e);
+            anyhow::bail!("HTTP request failed");
+        }
+    };
+    log::info!("<- HTTP {}", response.status.0);
+    if !response.status.is_successful() {
+        anyhow::bail!("received unexpected HTTP status code {}", response.status.0);
+    }
+    Ok(())
+}
+
+/// Task: Notify the webhook at `url` whenever the count signalled through
+/// `count` crosses one of the `thresholds`, and for the rules received
+/// through `rules`
+#[embassy_executor::task]
+pub async fn webhook_task(
+    stack: &'static Stack<EspWifiDevice<'static>>,
+    url: Url,
+    thresholds: &'static [u8],
+    count: &'static Signal<NoopRawMutex, u8>,
+    #[cfg(feature = "rules")] rules: NotificationReceiver,
+) {
+    log::info!("Start webhook task");
+    let client_state = &*mk_static!(
+        TcpClientState<1, 1024, 1024>,
+        TcpClientState::<1, 1024, 1024>::new()
+    );
+    let tcp_client = TcpClient::new(stack, client_state);
+    let dns = DnsSocket::new(stack);
+    let rx_buf = mk_static!([u8; 1024], [0; 1024]);
+
+    // The count at boot is the baseline, it isn't notified
+    let mut previous = count.wait().await;
+    loop {
+        #[cfg(feature = "rules")]
+        let current = match select(count.wait(), rules.receive()).await {
+            Either::First(current) => current,
+            Either::Second(notification) => {
+                match format_rule_payload(notification.count, notification.rule.name) {
+                    Ok(payload) => {
+                        if let Err(e) =
+                            notify(&tcp_client, &dns, rx_buf, url, payload.as_bytes()).await
+                        {
+                            log::warn!("Could not notify the webhook: {}", e);
+                        }
+                    }
+                    Err(_) => log::warn!("Webhook payload too long"),
+                }
+                continue;
+            }
+        };
+        #[cfg(not(feature = "rules"))]
+        let current = count.wait().await;
+        for &threshold in thresholds {
+            let Some(rising) = crossing(previous, current, threshold) else {
+                continue;
+            };
+            let payload = match format_payload(current, threshold, rising) {
+                Ok(payload) => payload,
+                Err(_) => {
+                    log::warn!("Webhook payload too long");
+                    continue;
+                }
+            };
+            if let Err(e) = notify(&tcp_client, &dns, rx_buf, url, payload.as_bytes()).await {
+                log::warn!("Could not notify the webhook: {}", e);
+            }
+        }
+        previous = current;
+    }
+}
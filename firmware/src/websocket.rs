@@ -0,0 +1,262 @@
+//! Bidirectional count synchronization over a WebSocket connection.
+//!
+//! The protocol is intentionally minimal: both sides exchange text frames
+//! containing the current count as a decimal number (e.g. `"12"`). The device
+//! sends a frame whenever the count is changed locally, and the server sends a
+//! frame whenever the count was changed elsewhere (web UI, other counters).
+
+use core::fmt::Write as _;
+
+use base64::Engine;
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    dns::DnsQueryType,
+    tcp::{TcpReader, TcpSocket, TcpWriter},
+    IpAddress, Ipv4Address, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use esp_hal::rng::Rng;
+
+use crate::{config::Url, device_id::DeviceId, jitter::jittered, EspWifiDevice};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Maximum payload length of a frame we care about. Larger frames are skipped.
+const MAX_PAYLOAD_LEN: usize = 125;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+type Payload = heapless::Vec<u8, MAX_PAYLOAD_LEN>;
+
+/// Task: Keep a WebSocket connection to the sync server at `url` open.
+///
+/// Counts received from the server are signalled through `remote_count`,
+/// local changes signalled through `local_count` are sent to the server.
+#[embassy_executor::task]
+pub async fn websocket_task(
+    stack: &'static Stack<EspWifiDevice<'static>>,
+    url: Url,
+    mut rng: Rng,
+    local_count: &'static Signal<NoopRawMutex, u8>,
+    remote_count: &'static Signal<NoopRawMutex, u8>,
+) {
+    log::info!("Start WebSocket sync task");
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 512];
+    loop {
+        stack.wait_config_up().await;
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(120)));
+        socket.set_keep_alive(Some(Duration::from_secs(30)));
+        let result =
+            run_connection(stack, &mut socket, &mut rng, url, local_count, remote_count).await;
+        match result {
+            Ok(()) => log::info!("WebSocket connection closed by server"),
+            Err(e) => log::warn!("WebSocket connection failed: {}", e),
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+        Timer::after(jittered(RECONNECT_DELAY)).await;
+    }
+}
+
+/// Connect, perform the opening handshake and then exchange frames until
+/// the connection is closed or fails.
+async fn run_connection(
+    stack: &Stack<EspWifiDevice<'static>>,
+    socket: &mut TcpSocket<'_>,
+    rng: &mut Rng,
+    url: Url,
+    local_count: &Signal<NoopRawMutex, u8>,
+    remote_count: &Signal<NoopRawMutex, u8>,
+) -> anyhow::Result<()> {
+    let Url {
+        host, port, path, ..
+    } = url;
+    // Connect
+    let address = if let Ok(address) = host.parse::<Ipv4Address>() {
+        IpAddress::Ipv4(address)
+    } else {
+        match stack.dns_query(host, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => addresses[0],
+            Ok(_) => anyhow::bail!("DNS lookup returned no addresses"),
+            Err(e) => {
+                log::error!("DNS lookup for {} failed: {:?}", host, e);
+                anyhow::bail!("DNS lookup failed");
+            }
+        }
+    };
+    if let Err(e) = socket.connect((address, port)).await {
+        log::error!("Could not connect to WebSocket server: {:?}", e);
+        anyhow::bail!("TCP connect failed");
+    }
+
+    // Opening handshake
+    let mut key_bytes = [0; 16];
+    rng.read(&mut key_bytes);
+    let mut key_buf = [0; 24];
+    base64::engine::general_purpose::STANDARD
+        .encode_slice(key_bytes, &mut key_buf)
+        .map_err(|_| anyhow::anyhow!("Could not encode WebSocket key"))?;
+    let key = core::str::from_utf8(&key_buf)?;
+    let device_id = DeviceId::read();
+    let user_agent = device_id.user_agent();
+    let mut request = heapless::String::<512>::new();
+    write!(
+        request,
+        "GET {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         User-Agent: {user_agent}\r\n\
+         X-Device-ID: {device_id}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n"
+    )?;
+    if socket.write_all(request.as_bytes()).await.is_err() {
+        anyhow::bail!("Could not send WebSocket handshake");
+    }
+    read_handshake_response(socket).await?;
+    log::info!("WebSocket connected to {}", url.url);
+
+    // Exchange frames
+    let (mut reader, mut writer) = socket.split();
+    let pong = Signal::<NoopRawMutex, Payload>::new();
+    let receive_loop = async {
+        loop {
+            let (opcode, payload) = read_frame(&mut reader).await?;
+            match opcode {
+                OPCODE_TEXT => match core::str::from_utf8(&payload).map(str::parse::<u8>) {
+                    Ok(Ok(count)) => {
+                        log::info!("WebSocket: Received count {count}");
+                        remote_count.signal(count);
+                    }
+                    _ => log::warn!("WebSocket: Ignoring invalid message"),
+                },
+                OPCODE_PING => pong.signal(payload),
+                OPCODE_CLOSE => return Ok(()),
+                _ => {}
+            }
+        }
+    };
+    let send_loop = async {
+        loop {
+            let (opcode, payload) = match select(local_count.wait(), pong.wait()).await {
+                Either::First(count) => {
+                    let mut text = heapless::String::<3>::new();
+                    write!(text, "{count}")?;
+                    (OPCODE_TEXT, Payload::from_slice(text.as_bytes()).unwrap())
+                }
+                Either::Second(payload) => (OPCODE_PONG, payload),
+            };
+            let mut mask = [0; 4];
+            rng.read(&mut mask);
+            write_frame(&mut writer, opcode, &payload, mask).await?;
+        }
+    };
+    match select(receive_loop, send_loop).await {
+        Either::First(result) | Either::Second(result) => result,
+    }
+}
+
+/// Read the HTTP response to the opening handshake and ensure the server
+/// agreed to switch protocols.
+async fn read_handshake_response(socket: &mut TcpSocket<'_>) -> anyhow::Result<()> {
+    let mut response = heapless::Vec::<u8, 512>::new();
+    let mut byte = [0];
+    while !response.ends_with(b"\r\n\r\n") {
+        if socket.read_exact(&mut byte).await.is_err() {
+            anyhow::bail!("Connection closed during WebSocket handshake");
+        }
+        if response.push(byte[0]).is_err() {
+            anyhow::bail!("WebSocket handshake response too long");
+        }
+    }
+    if !response.starts_with(b"HTTP/1.1 101") {
+        anyhow::bail!("WebSocket handshake rejected by server");
+    }
+    Ok(())
+}
+
+/// Read a single frame, returning its opcode and payload.
+///
+/// Frames with a payload longer than [`MAX_PAYLOAD_LEN`] are skipped.
+async fn read_frame(reader: &mut TcpReader<'_>) -> anyhow::Result<(u8, Payload)> {
+    loop {
+        let mut header = [0; 2];
+        read_exact(reader, &mut header).await?;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                read_exact(reader, &mut len).await?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                read_exact(reader, &mut len).await?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        let mut mask = [0; 4];
+        if masked {
+            read_exact(reader, &mut mask).await?;
+        }
+
+        if len > MAX_PAYLOAD_LEN as u64 {
+            log::warn!("WebSocket: Skipping frame with {len} bytes payload");
+            let mut remaining = len;
+            let mut chunk = [0; 64];
+            while remaining > 0 {
+                let n = remaining.min(chunk.len() as u64) as usize;
+                read_exact(reader, &mut chunk[..n]).await?;
+                remaining -= n as u64;
+            }
+            continue;
+        }
+
+        let mut payload = Payload::new();
+        payload.resize_default(len as usize).unwrap();
+        read_exact(reader, &mut payload).await?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        return Ok((opcode, payload));
+    }
+}
+
+/// Write a single, final frame. Client frames must always be masked.
+async fn write_frame(
+    writer: &mut TcpWriter<'_>,
+    opcode: u8,
+    payload: &[u8],
+    mask: [u8; 4],
+) -> anyhow::Result<()> {
+    let mut frame = heapless::Vec::<u8, { MAX_PAYLOAD_LEN + 6 }>::new();
+    let _ = frame.push(0x80 | opcode);
+    let _ = frame.push(0x80 | payload.len() as u8);
+    let _ = frame.extend_from_slice(&mask);
+    for (i, byte) in payload.iter().enumerate() {
+        let _ = frame.push(byte ^ mask[i % 4]);
+    }
+    if writer.write_all(&frame).await.is_err() || writer.flush().await.is_err() {
+        anyhow::bail!("Could not send WebSocket frame");
+    }
+    Ok(())
+}
+
+async fn read_exact(reader: &mut TcpReader<'_>, buf: &mut [u8]) -> anyhow::Result<()> {
+    if reader.read_exact(buf).await.is_err() {
+        anyhow::bail!("Could not read from WebSocket connection");
+    }
+    Ok(())
+}